@@ -0,0 +1,39 @@
+#![feature(test)]
+
+extern crate test;
+
+use test::Bencher;
+
+use some_embedded_scripting_language::{
+    closure_compiler, cont_expr,
+    eval::Machine,
+    flat_expr::FExpr,
+    literals::Literal,
+    utils::test::{app, church, lam, lit},
+};
+
+/// (((4 10) (lambda (x) x)) void), applying the identity 10^4 times
+fn program() -> FExpr {
+    let expr = app(
+        app(app(church(4), church(10)), lam("x", |x| x)),
+        lit(Literal::Void),
+    );
+
+    cont_expr::program(expr).into_fexpr()
+}
+
+#[bench]
+fn tree_walker(b: &mut Bencher) {
+    let program = program();
+    let mut machine = Machine::new();
+
+    b.iter(|| machine.run(&program).unwrap());
+}
+
+#[bench]
+fn closure_compiled(b: &mut Bencher) {
+    let program = closure_compiler::compile(&program()).unwrap();
+    let mut machine = Machine::new();
+
+    b.iter(|| program.run(&mut machine).unwrap());
+}
@@ -0,0 +1,709 @@
+use moniker::FreeVar;
+
+use std::collections::HashMap;
+
+use crate::{
+    debugger,
+    eval::Machine,
+    literals::Literal,
+    parse::{self, Item, Sexp},
+    span::Span,
+};
+
+/// The forms of the language, which can't be used as names
+pub const KEYWORDS: [&str; 11] = [
+    "lambda",
+    "generator",
+    "async",
+    "raise",
+    "yield",
+    "await",
+    "try",
+    "catch",
+    "let",
+    "begin",
+    "define",
+];
+
+/// What binds a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Parameter,
+    /// Bound by `let`
+    Local,
+    /// Bound by `catch`
+    Exception,
+    /// Bound by a top level `define`
+    Global,
+    /// A global the host gave the machine
+    Host,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    /// What binds the name, or `None` for keywords
+    pub kind: Option<Kind>,
+    pub detail: String,
+}
+
+/// A name, with every place it is bound
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: String,
+    kind: Kind,
+    /// One for locals, each `define` of it for globals, none for host names
+    definitions: Vec<Span>,
+    /// Where a local is in scope
+    scope: Option<Span>,
+    /// The expressions bound to the name, for locals and globals
+    values: Vec<Span>,
+    /// The type of a host name's value
+    host_type: Option<&'static str>,
+}
+
+/// A place a name is written, binding or using it
+#[derive(Debug, Clone)]
+struct Reference {
+    span: Span,
+    /// `None` if the name is unbound
+    symbol: Option<usize>,
+    /// The binding this refers to
+    definition: Option<Span>,
+}
+
+/// What an editor wants to know about a script: its errors and what each
+/// name in it refers to
+///
+/// Scripts are read the way the command line runs them, a top level form at
+/// a time, with each `define` making a global for the forms after it. Names
+/// are resolved by the parser, so a use refers to the `moniker` binder its
+/// variable is bound by. There is no type checker, so the types shown are
+/// those evident from the source, named as `Value::type_name` names them.
+pub struct Analysis {
+    src: String,
+    forms: Vec<Sexp>,
+    host: Vec<(String, &'static str)>,
+    symbols: Vec<Symbol>,
+    references: Vec<Reference>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    /// Analyse a script to be run on a machine like `machine`, knowing the
+    /// names it has globals for
+    pub fn new(src: &str, machine: &Machine) -> Self {
+        let mut host: Vec<_> = machine
+            .globals()
+            .map(|(name, value)| (name.to_owned(), value.type_name()))
+            .collect();
+        host.sort();
+
+        Self::with_host(src, host)
+    }
+
+    fn with_host(src: &str, host: Vec<(String, &'static str)>) -> Self {
+        let mut analysis = Analysis {
+            src: src.to_owned(),
+            forms: Vec::new(),
+            host,
+            symbols: Vec::new(),
+            references: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+
+        match parse::read(src) {
+            Ok(forms) => analysis.forms = forms,
+            Err(e) => analysis.error(e.span, e.message),
+        }
+        analysis.resolve();
+
+        analysis
+    }
+
+    fn error(&mut self, span: Span, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            message: message.into(),
+        });
+    }
+
+    fn symbol(&mut self, name: &str, kind: Kind) -> usize {
+        self.symbols.push(Symbol {
+            name: name.to_owned(),
+            kind,
+            definitions: Vec::new(),
+            scope: None,
+            values: Vec::new(),
+            host_type: None,
+        });
+
+        self.symbols.len() - 1
+    }
+
+    /// Find the symbol of every name in the script
+    fn resolve(&mut self) {
+        let forms = std::mem::take(&mut self.forms);
+        let mut globals: HashMap<String, usize> = HashMap::new();
+
+        for form in &forms {
+            let names = match parse::item_with_names(form) {
+                Ok((Item::Define(name, _, _), names)) => {
+                    let (name_span, value) = match form {
+                        Sexp::List(items, _) => (items[1].span(), items[2].span()),
+                        _ => unreachable!("define is a list"),
+                    };
+                    let global = match globals.get(&name) {
+                        Some(&global) => global,
+                        None => {
+                            let global = self.symbol(&name, Kind::Global);
+                            globals.insert(name, global);
+                            global
+                        }
+                    };
+                    self.symbols[global].definitions.push(name_span);
+                    self.symbols[global].values.push(value);
+                    self.references.push(Reference {
+                        span: name_span,
+                        symbol: Some(global),
+                        definition: Some(name_span),
+                    });
+
+                    names
+                }
+                Ok((Item::Expr(..), names)) => names,
+                Err(e) => {
+                    self.error(e.span, e.message);
+                    continue;
+                }
+            };
+
+            let mut locals: HashMap<FreeVar<String>, usize> = HashMap::new();
+            for binding in names.binders {
+                let name = binding.var.pretty_name.clone().unwrap_or_default();
+                let kind = match binding.value {
+                    Some(_) => Kind::Local,
+                    None if self.binds_exception(&forms, binding.span) => Kind::Exception,
+                    None => Kind::Parameter,
+                };
+
+                let local = self.symbol(&name, kind);
+                let symbol = &mut self.symbols[local];
+                symbol.definitions.push(binding.span);
+                symbol.scope = Some(binding.scope);
+                symbol.values.extend(binding.value);
+                self.references.push(Reference {
+                    span: binding.span,
+                    symbol: Some(local),
+                    definition: Some(binding.span),
+                });
+                locals.insert(binding.var, local);
+            }
+
+            for (var, span) in names.uses {
+                let name = var.pretty_name.clone().unwrap_or_default();
+                let symbol = match locals.get(&var) {
+                    Some(&local) => Some(local),
+                    None => globals.get(&name).copied().or_else(|| self.host(&name)),
+                };
+                // a global is the latest `define` of it up to the use
+                let definition = symbol.and_then(|s| {
+                    let definitions = &self.symbols[s].definitions;
+                    definitions
+                        .iter()
+                        .rev()
+                        .find(|d| d.start <= span.start)
+                        .or_else(|| definitions.first())
+                        .copied()
+                });
+
+                if symbol.is_none() {
+                    self.error(span, format!("unbound variable: {}", name));
+                }
+                self.references.push(Reference {
+                    span,
+                    symbol,
+                    definition,
+                });
+            }
+        }
+
+        self.references.sort_by_key(|r| r.span.start);
+        self.forms = forms;
+    }
+
+    /// Whether the name at `span` is the one a `catch` binds
+    fn binds_exception(&self, forms: &[Sexp], span: Span) -> bool {
+        fn find(sexp: &Sexp, span: Span) -> bool {
+            match sexp {
+                Sexp::List(items, _) => match &items[..] {
+                    [Sexp::Atom(catch, _), Sexp::List(exc, _), ..] if catch == "catch" => {
+                        matches!(&exc[..], [e] if e.span() == span)
+                            || items.iter().any(|item| find(item, span))
+                    }
+                    _ => items.iter().any(|item| find(item, span)),
+                },
+                _ => false,
+            }
+        }
+
+        forms.iter().any(|form| find(form, span))
+    }
+
+    /// The symbol of a host name, made the first time it is used
+    fn host(&mut self, name: &str) -> Option<usize> {
+        let existing = self
+            .symbols
+            .iter()
+            .position(|s| s.kind == Kind::Host && s.name == name);
+        if existing.is_some() {
+            return existing;
+        }
+
+        let (_, ty) = *self.host.iter().find(|(host, _)| host == name)?;
+        let host = self.symbol(name, Kind::Host);
+        self.symbols[host].host_type = Some(ty);
+
+        Some(host)
+    }
+
+    /// Syntax errors and unbound names, in the order they are in the script
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The name written at a byte offset, counting the offset just after it
+    fn reference(&self, offset: usize) -> Option<&Reference> {
+        self.references
+            .iter()
+            .find(|r| r.span.start <= offset && offset <= r.span.end)
+    }
+
+    /// Where the name at `offset` is bound
+    pub fn definition(&self, offset: usize) -> Option<Span> {
+        self.reference(offset)?.definition
+    }
+
+    /// Every place the name at `offset` is written, binding or using the
+    /// same variable, in order
+    pub fn occurrences(&self, offset: usize) -> Vec<Span> {
+        match self.reference(offset).and_then(|r| r.symbol) {
+            Some(symbol) => self
+                .references
+                .iter()
+                .filter(|r| r.symbol == Some(symbol))
+                .map(|r| r.span)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// A description of what is at `offset`, a name or a literal, and its
+    /// span
+    pub fn hover(&self, offset: usize) -> Option<(Span, String)> {
+        if let Some(reference) = self.reference(offset) {
+            let symbol = &self.symbols[reference.symbol?];
+            let header = match self.symbol_type(symbol, 0) {
+                Some(ty) => format!("{}: {}", symbol.name, ty),
+                None => symbol.name.clone(),
+            };
+            let line = |span: Span| debugger::line_of(&self.src, span.start);
+            let kind = match symbol.kind {
+                Kind::Parameter => "parameter".to_owned(),
+                Kind::Local => "local".to_owned(),
+                Kind::Exception => "exception".to_owned(),
+                Kind::Host => "provided by the host".to_owned(),
+                Kind::Global => match reference.definition {
+                    Some(d) => format!("global, defined on line {}", line(d)),
+                    None => "global".to_owned(),
+                },
+            };
+
+            return Some((reference.span, format!("{}\n\n{}", header, kind)));
+        }
+
+        let literal = self.innermost(offset)?;
+        match literal {
+            Sexp::List(..) => None,
+            _ => Some((literal.span(), self.type_of(literal, 0)?)),
+        }
+    }
+
+    /// The type of the last top level form, or of the value it binds if it
+    /// is a `define`, if it is evident
+    pub fn last_type(&self) -> Option<String> {
+        let last = self.forms.last()?;
+        let value = match last {
+            Sexp::List(items, _) => match &items[..] {
+                [Sexp::Atom(define, _), _, value] if define == "define" => value,
+                _ => last,
+            },
+            _ => last,
+        };
+
+        self.type_of(value, 0)
+    }
+
+    /// The innermost form that `offset` is in
+    fn innermost(&self, offset: usize) -> Option<&Sexp> {
+        let contains = |sexp: &&Sexp| sexp.span().start <= offset && offset < sexp.span().end;
+        let mut found = self.forms.iter().find(contains)?;
+
+        while let Sexp::List(items, _) = found {
+            match items.iter().find(contains) {
+                Some(item) => found = item,
+                None => break,
+            }
+        }
+
+        Some(found)
+    }
+
+    /// The form written at exactly `span`
+    fn form(&self, span: Span) -> Option<&Sexp> {
+        let mut forms = &self.forms[..];
+
+        loop {
+            let form = forms
+                .iter()
+                .find(|f| f.span().start <= span.start && span.end <= f.span().end)?;
+            match form {
+                _ if form.span() == span => return Some(form),
+                Sexp::List(items, _) => forms = items,
+                _ => return None,
+            }
+        }
+    }
+
+    fn symbol_type(&self, symbol: &Symbol, depth: usize) -> Option<String> {
+        match symbol.kind {
+            Kind::Host => symbol.host_type.map(str::to_owned),
+            // a global could be any of the values it is defined as
+            Kind::Local | Kind::Global => {
+                let mut types = symbol
+                    .values
+                    .iter()
+                    .map(|&v| self.type_of(self.form(v)?, depth + 1));
+                let first = types.next()??;
+                types.all(|ty| ty.as_ref() == Some(&first)).then_some(first)
+            }
+            Kind::Parameter | Kind::Exception => None,
+        }
+    }
+
+    /// The type of an expression, if it is evident
+    fn type_of(&self, sexp: &Sexp, depth: usize) -> Option<String> {
+        // definitions in terms of each other
+        if depth > 16 {
+            return None;
+        }
+
+        match sexp {
+            Sexp::String(..) => Some("string".to_owned()),
+            Sexp::Atom(atom, span) => match parse::literal(atom, *span) {
+                Ok(Some(Literal::String(_))) => Some("string".to_owned()),
+                Ok(Some(Literal::Int(_))) => Some("int".to_owned()),
+                Ok(Some(Literal::Float(_))) => Some("float".to_owned()),
+                Ok(Some(Literal::Void)) => Some("void".to_owned()),
+                Err(_) => None,
+                Ok(None) => {
+                    let reference = self.references.iter().find(|r| r.span == *span)?;
+                    self.symbol_type(&self.symbols[reference.symbol?], depth + 1)
+                }
+            },
+            Sexp::List(items, _) => match &items[..] {
+                [Sexp::Atom(keyword, _), Sexp::List(params, _), _, ..]
+                    if matches!(&keyword[..], "lambda" | "generator" | "async") =>
+                {
+                    let params: Vec<_> = params.iter().map(|p| p.to_string()).collect();
+                    let kind = match &keyword[..] {
+                        "lambda" => "",
+                        "generator" => "generator ",
+                        _ => "async ",
+                    };
+                    Some(format!("{}function ({})", kind, params.join(" ")))
+                }
+                [Sexp::Atom(keyword, _), .., last] if keyword == "let" || keyword == "begin" => {
+                    self.type_of(last, depth + 1)
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// The names that could be written at `offset`: the locals in scope
+    /// there, innermost first, then the globals and host names, then the
+    /// keywords
+    pub fn completions(&self, offset: usize) -> Vec<Completion> {
+        let mut locals: Vec<&Symbol> = self
+            .symbols
+            .iter()
+            .filter(|s| {
+                let in_scope = s
+                    .scope
+                    .is_some_and(|scope| scope.start <= offset && offset < scope.end);
+                let bound_before = s.definitions.iter().all(|d| d.end <= offset);
+                in_scope && bound_before
+            })
+            .collect();
+        locals.sort_by_key(|s| std::cmp::Reverse(s.scope.map(|scope| scope.start)));
+
+        let mut globals: Vec<&Symbol> = self
+            .symbols
+            .iter()
+            .filter(|s| s.kind == Kind::Global)
+            .collect();
+        globals.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut completions: Vec<Completion> = Vec::new();
+        for symbol in locals.into_iter().chain(globals) {
+            if completions.iter().any(|c| c.label == symbol.name) {
+                continue;
+            }
+            completions.push(Completion {
+                label: symbol.name.clone(),
+                kind: Some(symbol.kind),
+                detail: self.symbol_type(symbol, 0).unwrap_or_default(),
+            });
+        }
+        for (name, ty) in &self.host {
+            if !completions.iter().any(|c| &c.label == name) {
+                completions.push(Completion {
+                    label: name.clone(),
+                    kind: Some(Kind::Host),
+                    detail: ty.to_string(),
+                });
+            }
+        }
+        completions.extend(KEYWORDS.iter().map(|keyword| Completion {
+            label: keyword.to_string(),
+            kind: None,
+            detail: "keyword".to_owned(),
+        }));
+
+        completions
+    }
+
+    /// The spans to replace with `name` to rename the name at `offset`
+    ///
+    /// Only the uses of the same variable are renamed, and the rename is
+    /// refused if it would make any name in the script, renamed or not,
+    /// refer to something else, by being shadowed or shadowing another.
+    pub fn rename(&self, offset: usize, name: &str) -> Result<Vec<Span>, String> {
+        let reference = self
+            .reference(offset)
+            .ok_or_else(|| "there's no name here".to_owned())?;
+        let symbol = &self.symbols[reference
+            .symbol
+            .ok_or_else(|| "an unbound name can't be renamed".to_owned())?];
+
+        if symbol.kind == Kind::Host {
+            return Err(format!(
+                "`{}` is provided by the host and can't be renamed",
+                symbol.name
+            ));
+        }
+        if !is_name(name) {
+            return Err(format!("`{}` isn't a name", name));
+        }
+
+        let spans = self.occurrences(offset);
+        let mut renamed = String::with_capacity(self.src.len());
+        let mut end = 0;
+        for span in &spans {
+            renamed.push_str(&self.src[end..span.start]);
+            renamed.push_str(name);
+            end = span.end;
+        }
+        renamed.push_str(&self.src[end..]);
+
+        // where a byte before the rename is after it
+        let grow = name.len() as isize - symbol.name.len() as isize;
+        let moved = |offset: usize| {
+            let before = spans.iter().filter(|s| s.start < offset).count() as isize;
+            (offset as isize + grow * before) as usize
+        };
+
+        let after = Self::with_host(&renamed, self.host.clone());
+        let resolved = |analysis: &Analysis, r: &Reference, moved: &dyn Fn(usize) -> usize| match r
+            .symbol
+            .map(|s| &analysis.symbols[s])
+        {
+            Some(s) if s.kind == Kind::Host => format!("host {}", s.name),
+            Some(s) => format!("{}", moved(s.definitions[0].start)),
+            None => "unbound".to_owned(),
+        };
+
+        for r in &self.references {
+            let before = resolved(self, r, &moved);
+            let now = after
+                .references
+                .iter()
+                .find(|a| a.span.start == moved(r.span.start))
+                .map(|a| resolved(&after, a, &|offset| offset));
+
+            if now.as_ref() != Some(&before) {
+                return Err(format!(
+                    "renaming `{}` to `{}` would change what the name on line {} refers to",
+                    symbol.name,
+                    name,
+                    debugger::line_of(&self.src, r.span.start)
+                ));
+            }
+        }
+
+        Ok(spans)
+    }
+}
+
+/// Whether `name` reads back as a name
+pub fn is_name(name: &str) -> bool {
+    let atom = matches!(parse::read(name).as_deref(), Ok([Sexp::Atom(atom, _)]) if atom == name);
+
+    atom && !KEYWORDS.contains(&name) && matches!(parse::literal(name, Span::default()), Ok(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = "\
+(define twice (lambda (fun arg) (fun (fun arg))))
+(define greeting \"hi\")
+(let ((x 1) (y x))
+  (twice (lambda (x) (print x)) y))
+(try (raise greeting) (catch (e) e))
+(missing 1)";
+
+    fn analyse(src: &str) -> Analysis {
+        let mut machine = Machine::new();
+        machine.register("print", |_, v| Ok(v));
+
+        Analysis::new(src, &machine)
+    }
+
+    /// The offset of the `n`th occurrence of `text`, counting from 0
+    fn at(src: &str, text: &str, n: usize) -> usize {
+        src.match_indices(text).nth(n).unwrap().0
+    }
+
+    fn text(src: &str, spans: &[Span]) -> Vec<String> {
+        spans
+            .iter()
+            .map(|s| format!("{}@{}", &src[s.start..s.end], s.start))
+            .collect()
+    }
+
+    #[test]
+    fn reports_errors() {
+        let analysis = analyse(SRC);
+        let messages: Vec<_> = analysis
+            .diagnostics()
+            .iter()
+            .map(|d| (d.message.as_str(), &SRC[d.span.start..d.span.end]))
+            .collect();
+        assert_eq!(messages, vec![("unbound variable: missing", "missing")]);
+
+        let analysis = analyse("(lambda (x)) (f (lambda");
+        assert_eq!(analysis.diagnostics()[0].message, "unfinished list");
+
+        let analysis = analyse("(lambda)\n(y)");
+        let messages: Vec<_> = analysis.diagnostics().iter().map(|d| &d.message).collect();
+        assert_eq!(messages, vec!["malformed `lambda`", "unbound variable: y"]);
+    }
+
+    #[test]
+    fn finds_definitions_by_binder() {
+        let analysis = analyse(SRC);
+        let definition = |text, n| {
+            let span = analysis.definition(at(SRC, text, n)).unwrap();
+            span.start
+        };
+
+        // the `x` of the inner lambda shadows the one `let` binds
+        assert_eq!(definition("x", 1), at(SRC, "x", 0));
+        assert_eq!(definition("x", 3), at(SRC, "x", 2));
+        assert_eq!(definition("y", 1), at(SRC, "y", 0));
+        assert_eq!(definition("twice", 1), at(SRC, "twice", 0));
+        assert_eq!(definition("e)", 1), at(SRC, "e)", 0));
+        assert_eq!(analysis.definition(at(SRC, "print", 0)), None);
+    }
+
+    #[test]
+    fn hovers_with_types() {
+        let analysis = analyse(SRC);
+        let hover = |text, n| analysis.hover(at(SRC, text, n)).unwrap().1;
+
+        assert_eq!(
+            hover("twice", 1),
+            "twice: function (fun arg)\n\nglobal, defined on line 1"
+        );
+        assert_eq!(hover("y", 1), "y: int\n\nlocal");
+        assert_eq!(
+            hover("greeting", 1),
+            "greeting: string\n\nglobal, defined on line 2"
+        );
+        assert_eq!(hover("fun", 1), "fun\n\nparameter");
+        assert_eq!(hover("print", 0), "print: function\n\nprovided by the host");
+        assert_eq!(hover("\"hi\"", 0), "string");
+        assert_eq!(analysis.hover(at(SRC, "(let", 0)), None);
+
+        let last_type = |src| analyse(src).last_type();
+        assert_eq!(last_type("(let ((x 1)) x)"), Some("int".to_owned()));
+        assert_eq!(
+            last_type("(define f (async (x) x))"),
+            Some("async function (x)".to_owned())
+        );
+        assert_eq!(last_type("(print 1)"), None);
+    }
+
+    #[test]
+    fn renames_respecting_scope() {
+        let analysis = analyse(SRC);
+
+        let spans = analysis.rename(at(SRC, "x", 0), "z").unwrap();
+        assert_eq!(text(SRC, &spans), vec!["x@80", "x@88"]);
+
+        let spans = analysis.rename(at(SRC, "twice", 0), "double").unwrap();
+        assert_eq!(text(SRC, &spans), vec!["twice@8", "twice@95"]);
+
+        // shadowing is fine as long as nothing it shadows is used inside
+        assert!(analysis.rename(at(SRC, "y", 0), "x").is_ok());
+        assert!(analysis.rename(at(SRC, "x", 0), "y").is_ok());
+        // the lambda's `x` would capture the host's `print`
+        assert!(analysis.rename(at(SRC, "x", 2), "print").is_err());
+        // `fun`'s uses would refer to `arg`
+        assert!(analysis.rename(at(SRC, "fun", 0), "arg").is_err());
+        assert!(analysis.rename(at(SRC, "greeting", 0), "print").is_err());
+        assert!(analysis.rename(at(SRC, "print", 0), "show").is_err());
+        assert!(analysis.rename(at(SRC, "x", 0), "lambda").is_err());
+        assert!(analysis.rename(at(SRC, "x", 0), "1").is_err());
+        assert!(analysis.rename(at(SRC, "x", 0), "a b").is_err());
+    }
+
+    #[test]
+    fn completes_names_in_scope() {
+        let analysis = analyse(SRC);
+        let labels = |offset| -> Vec<String> {
+            analysis
+                .completions(offset)
+                .into_iter()
+                .filter(|c| c.kind.is_some())
+                .map(|c| c.label)
+                .collect()
+        };
+
+        assert_eq!(
+            labels(at(SRC, "(print", 0)),
+            vec!["x", "y", "greeting", "twice", "print"]
+        );
+        assert_eq!(labels(0), vec!["greeting", "twice", "print"]);
+        assert!(analysis.completions(0).iter().any(|c| c.label == "lambda"));
+    }
+}
@@ -0,0 +1,227 @@
+use moniker::{Scope, ScopeOffset, Var};
+
+use std::{fmt, rc::Rc};
+
+use crate::{
+    eval::{Body, Env, Machine, Value},
+    flat_expr::FExpr,
+    gc::Object,
+    span::{CallSite, Span},
+};
+
+/// A source level call in progress, see `Machine::backtrace`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Where the call is, or for the innermost frame where the run failed
+    pub span: Span,
+    /// The name the function the call is in was called by, if it was called
+    /// by name
+    pub function: Option<String>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "in {} at {}", function, self.span),
+            None => write!(f, "at {}", self.span),
+        }
+    }
+}
+
+/// A continuation that a source call returns to
+pub(crate) struct Return {
+    pub(crate) site: Rc<CallSite>,
+    /// The body of the continuation, under its parameter, unless it was
+    /// compiled by `closure_compiler`
+    pub(crate) body: Option<Rc<FExpr>>,
+    pub(crate) env: Env,
+}
+
+/// The frames of a failure at `at` in the function returning to `k`,
+/// innermost first
+///
+/// Tail calls don't return to their caller, so they leave no frame.
+pub(crate) fn trace(machine: &Machine, at: Option<Span>, k: Option<Value>) -> Vec<TraceFrame> {
+    let returns = returns(machine, k);
+    // a call that fails before its callee starts returns to its own site
+    let at = at.filter(|&at| !returns.first().is_some_and(|r| r.site.span == at));
+    let spans = at.into_iter().chain(returns.iter().map(|r| r.site.span));
+    let functions = returns
+        .iter()
+        .map(|r| r.site.callee.clone())
+        .skip(if at.is_some() { 0 } else { 1 })
+        .chain(Some(None));
+
+    spans
+        .zip(functions)
+        .map(|(span, function)| TraceFrame { span, function })
+        .collect()
+}
+
+/// The span of the first call from the source in `term` that isn't in a
+/// lambda of its own, which is where a failure in `term` is in the source
+pub(crate) fn location(term: &FExpr) -> Option<Span> {
+    match term {
+        FExpr::CallTwo(..) | FExpr::Raise(..) if term.span().is_some() => term.span(),
+        FExpr::LamOne(Scope {
+            unsafe_body: body, ..
+        }) => location(body),
+        FExpr::CallOne(k, v) => location(k).or_else(|| location(v)),
+        FExpr::CallTwo(f, v, k, h, _) => [f, v, k, h].iter().find_map(|t| location(t)),
+        FExpr::Raise(h, v, _) => location(h).or_else(|| location(v)),
+        FExpr::LamTwo(_) | FExpr::Var(_) | FExpr::Lit(_) | FExpr::Prim(_) => None,
+    }
+}
+
+/// The continuations from `k` on that a source call returns to, innermost
+/// first
+///
+/// Continuations that aren't, like those of `let` and `begin`, are followed
+/// through to the continuation of the function they are part of, as are
+/// those of host functions waiting on a call they made. The chain ends at
+/// the end of the program, or of a task or generator.
+pub(crate) fn returns(machine: &Machine, mut k: Option<Value>) -> Vec<Return> {
+    let mut returns = Vec::new();
+
+    loop {
+        let gc = match k {
+            Some(Value::Cont(gc)) => gc,
+            // a host function waiting on a call returns where it would have
+            Some(Value::HostReturn(c)) => {
+                k = Some(machine.host_call(c).k.clone());
+                continue;
+            }
+            _ => break,
+        };
+        let closure = match machine.heap().get(gc) {
+            Object::Closure(closure) => closure,
+            _ => unreachable!("closure value is not a closure"),
+        };
+        let body = match &closure.body {
+            Body::Expr(body) => {
+                k = continuation(machine, body, 1, closure.env);
+                Some(body.clone())
+            }
+            Body::Compiled(code) => {
+                k = code.returns.map(|i| closure.captures[i].clone());
+                None
+            }
+        };
+
+        if let Some(site) = &closure.site {
+            returns.push(Return {
+                site: site.clone(),
+                body,
+                env: closure.env,
+            });
+        }
+    }
+
+    returns
+}
+
+/// The continuation from `env` that `term`, under `depth` binders of its
+/// own, passes its result on to, the return continuation of the function it
+/// is part of
+pub(crate) fn continuation(machine: &Machine, term: &FExpr, depth: u32, env: Env) -> Option<Value> {
+    machine.lookup(env, continuation_offset(term, depth)?)
+}
+
+/// Where `continuation` finds the continuation, as a scope offset from
+/// outside of `term`'s own binders
+pub(crate) fn continuation_offset(term: &FExpr, depth: u32) -> Option<ScopeOffset> {
+    let bound = |k: &FExpr| match k {
+        FExpr::Var(Var::Bound(b)) if b.scope.0 >= depth => Some(ScopeOffset(b.scope.0 - depth)),
+        _ => None,
+    };
+
+    match term {
+        FExpr::LamOne(Scope {
+            unsafe_body: body, ..
+        }) => continuation_offset(body, depth + 1),
+        FExpr::CallOne(k, v) => bound(k)
+            .or_else(|| continuation_offset(k, depth))
+            .or_else(|| continuation_offset(v, depth)),
+        FExpr::CallTwo(f, v, k, h, _) => bound(k).or_else(|| {
+            [k, f, v, h]
+                .iter()
+                .find_map(|operand| continuation_offset(operand, depth))
+        }),
+        // handlers are the last parameter of a user lambda, so the
+        // continuation is bound just outside of them
+        FExpr::Raise(h, v, _) => match &**h {
+            FExpr::Var(Var::Bound(b)) if b.scope.0 >= depth => {
+                Some(ScopeOffset(b.scope.0 - depth + 1))
+            }
+            _ => continuation_offset(h, depth).or_else(|| continuation_offset(v, depth)),
+        },
+        // a user lambda returns to whoever calls it
+        FExpr::LamTwo(_) | FExpr::Var(_) | FExpr::Lit(_) | FExpr::Prim(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, parse};
+
+    /// The frames of running `src`, by the source text and function of each
+    fn frames(src: &str) -> Vec<(String, Option<String>)> {
+        let program = cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+        let mut machine = Machine::new();
+        assert!(machine.run(&program).is_err());
+
+        machine
+            .backtrace()
+            .iter()
+            .map(|f| (src[f.span.start..f.span.end].to_owned(), f.function.clone()))
+            .collect()
+    }
+
+    fn frame(text: &str, function: Option<&str>) -> (String, Option<String>) {
+        (text.to_owned(), function.map(str::to_owned))
+    }
+
+    #[test]
+    fn traces_failures_back_to_callers() {
+        let calls = "
+(define outer (lambda (y) (begin (inner y) y)))
+(outer \"a\")
+\"done\"";
+
+        let raised = format!("(define inner (lambda (x) (begin (raise x) x))){}", calls);
+        assert_eq!(
+            frames(&raised),
+            vec![
+                frame("(raise x)", Some("inner")),
+                frame("(inner y)", Some("outer")),
+                frame("(outer \"a\")", None),
+            ]
+        );
+
+        let unbound = format!("(define inner (lambda (x) (missing x))){}", calls);
+        assert_eq!(frames(&unbound)[0], frame("(missing x)", Some("inner")));
+
+        let not_a_function = format!("(define inner (lambda (x) (\"f\" x))){}", calls);
+        assert_eq!(
+            frames(&not_a_function)[0],
+            frame("(\"f\" x)", Some("inner"))
+        );
+    }
+
+    #[test]
+    fn tail_calls_leave_no_frame() {
+        let src = "
+(define inner (lambda (x) (raise x)))
+(define outer (lambda (y) (inner y)))
+(begin (outer \"a\") \"done\")";
+
+        assert_eq!(
+            frames(src),
+            vec![
+                frame("(raise x)", Some("outer")),
+                frame("(outer \"a\")", None),
+            ]
+        );
+    }
+}
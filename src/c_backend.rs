@@ -0,0 +1,311 @@
+use moniker::{Ignore, Scope, Var};
+
+use std::{fmt, fmt::Write};
+
+use crate::{flat_expr::FExpr, literals::Literal, prim::Prim};
+
+/// The header every emitted program includes, it must be written next to the
+/// generated source as `RUNTIME_HEADER_NAME`
+pub const RUNTIME_HEADER: &str = include_str!("c_backend/runtime.h");
+pub const RUNTIME_HEADER_NAME: &str = "ses_runtime.h";
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Compiled programs have no host environment, so every free variable
+    /// is an error
+    UnboundVariable(String),
+    /// The primitives need the machine's runtime, so the forms built on
+    /// them can't be compiled
+    Unsupported(Prim),
+    NotACall,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnboundVariable(name) => write!(f, "unbound variable: {}", name),
+            Error::Unsupported(p) => write!(f, "unsupported primitive: {}", p),
+            Error::NotACall => write!(f, "lambda body is not a call"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Emit a C translation unit for a program produced by `cont_expr::program`
+///
+/// Each lambda becomes a C function taking its environment, closures are a
+/// code pointer paired with the environment they were created in. Running
+/// the resulting executable prints the value the program exits with, or fails
+/// printing the exception if one was not caught.
+pub fn emit(program: &FExpr) -> Result<String, Error> {
+    let mut emitter = Emitter::default();
+    let program = emitter.atom(program)?;
+
+    let mut out = String::new();
+    writeln!(out, "#include \"{}\"", RUNTIME_HEADER_NAME).unwrap();
+    writeln!(out).unwrap();
+
+    for idx in 0..emitter.bodies.len() {
+        writeln!(out, "static void ses_body_{}(ses_env *env);", idx).unwrap();
+    }
+
+    for (idx, body) in emitter.bodies.iter().enumerate() {
+        writeln!(out).unwrap();
+        writeln!(out, "static void ses_body_{}(ses_env *env) {{", idx).unwrap();
+        writeln!(out, "    {};", body).unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "int main(void) {{").unwrap();
+    writeln!(out, "    ses_env *env = NULL;").unwrap();
+    writeln!(
+        out,
+        "    ses_call_two({}, ses_void(), ses_halt(), ses_abort());",
+        program
+    )
+    .unwrap();
+    writeln!(out, "    ses_print(ses_run());").unwrap();
+    writeln!(out, "    printf(\"\\n\");").unwrap();
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Emitter {
+    bodies: Vec<String>,
+}
+
+impl Emitter {
+    fn atom(&mut self, expr: &FExpr) -> Result<String, Error> {
+        match expr {
+            FExpr::LamOne(Scope {
+                unsafe_body: body, ..
+            }) => {
+                let idx = self.lambda(body)?;
+                Ok(format!("ses_closure(SES_CONT, ses_body_{}, env)", idx))
+            }
+            FExpr::LamTwo(Scope {
+                unsafe_body:
+                    Scope {
+                        unsafe_body:
+                            Scope {
+                                unsafe_body: body, ..
+                            },
+                        ..
+                    },
+                ..
+            }) => {
+                let idx = self.lambda(body)?;
+                Ok(format!("ses_closure(SES_CLOSURE, ses_body_{}, env)", idx))
+            }
+            FExpr::Var(Var::Bound(b)) => Ok(format!("ses_lookup(env, {})", b.scope.0)),
+            FExpr::Var(v @ Var::Free(_)) => Err(Error::UnboundVariable(
+                v.pretty_name().cloned().unwrap_or_else(|| v.to_string()),
+            )),
+            FExpr::Prim(Ignore(p)) => Err(Error::Unsupported(*p)),
+            FExpr::Lit(Ignore(l)) => Ok(literal(l)),
+            FExpr::CallOne(..) | FExpr::CallTwo(..) | FExpr::Raise(..) => Err(Error::NotACall),
+        }
+    }
+
+    fn lambda(&mut self, body: &FExpr) -> Result<usize, Error> {
+        // reserve the slot first so the numbering follows the source order
+        let idx = self.bodies.len();
+        self.bodies.push(String::new());
+
+        let call = match body {
+            FExpr::CallOne(k, v) => format!("ses_call_one({}, {})", self.atom(k)?, self.atom(v)?),
+            FExpr::CallTwo(f, v, k, h, _) => format!(
+                "ses_call_two({}, {}, {}, {})",
+                self.atom(f)?,
+                self.atom(v)?,
+                self.atom(k)?,
+                self.atom(h)?
+            ),
+            FExpr::Raise(h, v, Ignore(span)) => format!(
+                "ses_raise({}, {}, {}, {})",
+                self.atom(h)?,
+                self.atom(v)?,
+                span.start,
+                span.end
+            ),
+            _ => return Err(Error::NotACall),
+        };
+
+        self.bodies[idx] = call;
+
+        Ok(idx)
+    }
+}
+
+fn literal(l: &Literal) -> String {
+    match l {
+        Literal::String(s) => format!("ses_string({})", c_string(s)),
+        Literal::Int(v) => format!("ses_int(UINT64_C({}))", v),
+        Literal::Float(v) if v.is_nan() => "ses_float(0.0 / 0.0)".to_owned(),
+        Literal::Float(v) if v.is_infinite() => {
+            format!("ses_float({}1.0 / 0.0)", if *v < 0.0 { "-" } else { "" })
+        }
+        Literal::Float(v) => format!("ses_float({:?})", v),
+        Literal::Void => "ses_void()".to_owned(),
+    }
+}
+
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' | b'?' => write!(out, "\\{}", b as char).unwrap(),
+            0x20..=0x7e => out.push(b as char),
+            // octal escapes are at most three digits, unlike hex escapes
+            // which would swallow any hex digits following them
+            _ => write!(out, "\\{:03o}", b).unwrap(),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, eval::Machine, expr::Expr, utils::test::*};
+
+    use std::{
+        env, fs,
+        process::{Command, Output},
+    };
+
+    fn compile_and_run(name: &str, expr: Expr, flags: &[String]) -> Output {
+        let program = cont_expr::program(expr).into_fexpr();
+        let source = emit(&program).unwrap();
+
+        let dir = env::temp_dir().join(format!("ses-c-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(RUNTIME_HEADER_NAME), RUNTIME_HEADER).unwrap();
+        fs::write(dir.join("main.c"), source).unwrap();
+
+        // the tests need a C compiler, set CC to use one other than cc
+        let cc = env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+        let status = Command::new(&cc)
+            .current_dir(&dir)
+            .args(["-std=c99", "-Wall", "-Werror", "-Wno-unused-function"])
+            .args(flags)
+            .args(["-o", "main", "main.c"])
+            .status()
+            .unwrap_or_else(|e| panic!("can't run the C compiler `{}`: {}", cc, e));
+        assert!(status.success());
+
+        let output = Command::new(dir.join("main")).output().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        output
+    }
+
+    fn assert_matches_interpreter(name: &str, expr: Expr) {
+        assert_matches_with_step_limit(name, expr, None)
+    }
+
+    fn assert_matches_with_step_limit(name: &str, expr: Expr, limit: Option<u64>) {
+        let program = cont_expr::program(expr.clone()).into_fexpr();
+        let mut machine = Machine::new();
+        machine.set_step_limit(limit);
+        let expected = machine.run(&program);
+
+        let flags: Vec<_> = limit
+            .map(|limit| format!("-DSES_STEP_LIMIT={}", limit))
+            .into_iter()
+            .collect();
+        let out = compile_and_run(name, expr, &flags);
+        match expected {
+            Ok(v) => {
+                assert!(out.status.success());
+                assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("{}\n", v));
+            }
+            Err(e) => {
+                assert!(!out.status.success());
+                assert_eq!(
+                    String::from_utf8(out.stderr).unwrap(),
+                    format!("error: {}\n", e)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn literals() {
+        assert_matches_interpreter("int", lit(Literal::Int(42)));
+        assert_matches_interpreter("float", lit(Literal::Float(1.5)));
+        assert_matches_interpreter("large-float", lit(Literal::Float(1e20)));
+        assert_matches_interpreter("largest-float", lit(Literal::Float(f64::MAX)));
+        assert_matches_interpreter("small-float", lit(Literal::Float(-1.25e-7)));
+        assert_matches_interpreter("long-float", lit(Literal::Float(0.1 + 0.2)));
+        assert_matches_interpreter("whole-float", lit(Literal::Float(-0.0)));
+        assert_matches_interpreter("infinite-float", lit(Literal::Float(f64::INFINITY)));
+        assert_matches_interpreter("nan-float", lit(Literal::Float(f64::NAN)));
+        assert_matches_interpreter("void", lit(Literal::Void));
+        assert_matches_interpreter("string", lit(Literal::String("a \"quoted\"?".to_owned())));
+    }
+
+    #[test]
+    fn application() {
+        // ((lambda (x) (lambda (y) x)) 1) "unused"
+        let k = lam("x", |x| lam("y", |_| x));
+        let expr = app(
+            app(k, lit(Literal::Int(1))),
+            lit(Literal::String("unused".to_owned())),
+        );
+
+        assert_matches_interpreter("application", expr);
+    }
+
+    #[test]
+    fn higher_order() {
+        // (lambda (f) (f (f "x"))) (lambda (x) x)
+        let twice = lam("f", |f| {
+            app(f.clone(), app(f, lit(Literal::String("x".to_owned()))))
+        });
+        let expr = app(twice, lam("x", |x| x));
+
+        assert_matches_interpreter("higher_order", expr);
+    }
+
+    #[test]
+    fn closure_result() {
+        assert_matches_interpreter("closure_result", lam("x", |x| x));
+    }
+
+    #[test]
+    fn exceptions() {
+        // (try ((lambda (x) (raise x)) 1) (catch (e) "caught"))
+        let throws = app(lam("x", |x| raise(x, 11, 20)), lit(Literal::Int(1)));
+        let caught = try_catch(throws.clone(), "e", |_| {
+            lit(Literal::String("caught".to_owned()))
+        });
+
+        assert_matches_interpreter("caught", caught);
+        assert_matches_interpreter("exception", try_catch(throws.clone(), "e", |e| e));
+        assert_matches_interpreter(
+            "reraised",
+            try_catch(throws.clone(), "e", |e| raise(e, 30, 35)),
+        );
+        assert_matches_interpreter("uncaught", throws);
+    }
+
+    #[test]
+    fn deep_recursion_runs_in_constant_stack() {
+        assert_matches_interpreter("deep_recursion", deep_recursion(5));
+    }
+
+    #[test]
+    fn step_limit_stops_infinite_loops() {
+        assert_matches_with_step_limit("step_limit", omega(), Some(1_000_000));
+    }
+}
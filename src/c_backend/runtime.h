@@ -0,0 +1,323 @@
+/* Runtime support for C emitted by some-embedded-scripting-language.
+ *
+ * Every compiled lambda body ends in exactly one call, which is written into
+ * the `ses_next_*` registers instead of being made directly. `ses_run` then
+ * bounces between bodies, so the C stack never grows.
+ *
+ * Values are never freed, programs are expected to be short lived. An
+ * exception that reaches the `ses_abort` handler is printed to stderr and
+ * exits with status 1.
+ *
+ * Define SES_STEP_LIMIT to make programs that run more calls than that fail
+ * instead of looping forever.
+ */
+#ifndef SES_RUNTIME_H
+#define SES_RUNTIME_H
+
+#include <inttypes.h>
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef struct ses_env ses_env;
+typedef struct ses_exception ses_exception;
+typedef struct ses_span ses_span;
+typedef void (*ses_code)(ses_env *env);
+
+typedef enum {
+    SES_STRING,
+    SES_INT,
+    SES_FLOAT,
+    SES_VOID,
+    SES_CLOSURE,
+    SES_CONT,
+    SES_HALT,
+    SES_EXCEPTION,
+    SES_ABORT,
+} ses_tag;
+
+typedef struct {
+    ses_tag tag;
+    union {
+        const char *s;
+        uint64_t i;
+        double f;
+        struct {
+            ses_code code;
+            ses_env *env;
+        } clo;
+        const ses_exception *exc;
+    } as;
+} ses_value;
+
+/* spans of the raises an exception went through, most recent first */
+struct ses_span {
+    size_t start, end;
+    const ses_span *next;
+};
+
+struct ses_exception {
+    ses_value value;
+    const ses_span *spans;
+};
+
+struct ses_env {
+    ses_value value;
+    ses_env *next;
+};
+
+static ses_code ses_next_code;
+static ses_env *ses_next_env;
+static int ses_halted;
+static ses_value ses_result;
+
+static void ses_panic(const char *msg) {
+    fprintf(stderr, "error: %s\n", msg);
+    exit(1);
+}
+
+static void *ses_alloc(size_t size) {
+    void *p = malloc(size);
+    if (!p)
+        ses_panic("out of memory");
+    return p;
+}
+
+static ses_value ses_string(const char *s) {
+    ses_value v;
+    v.tag = SES_STRING;
+    v.as.s = s;
+    return v;
+}
+
+static ses_value ses_int(uint64_t i) {
+    ses_value v;
+    v.tag = SES_INT;
+    v.as.i = i;
+    return v;
+}
+
+static ses_value ses_float(double f) {
+    ses_value v;
+    v.tag = SES_FLOAT;
+    v.as.f = f;
+    return v;
+}
+
+static ses_value ses_void(void) {
+    ses_value v;
+    v.tag = SES_VOID;
+    return v;
+}
+
+static ses_value ses_halt(void) {
+    ses_value v;
+    v.tag = SES_HALT;
+    return v;
+}
+
+static ses_value ses_abort(void) {
+    ses_value v;
+    v.tag = SES_ABORT;
+    return v;
+}
+
+static ses_value ses_closure(ses_tag tag, ses_code code, ses_env *env) {
+    ses_value v;
+    v.tag = tag;
+    v.as.clo.code = code;
+    v.as.clo.env = env;
+    return v;
+}
+
+static ses_env *ses_push(ses_env *env, ses_value value) {
+    ses_env *frame = ses_alloc(sizeof(ses_env));
+    frame->value = value;
+    frame->next = env;
+    return frame;
+}
+
+static ses_value ses_lookup(ses_env *env, unsigned scope) {
+    while (scope--)
+        env = env->next;
+    return env->value;
+}
+
+/* print a float like the interpreter does: the fewest significant digits
+ * that read back as the same float, written out in full without an
+ * exponent */
+static void ses_fprint_float(FILE *out, double f) {
+    char buf[32], digits[20];
+    int precision, exponent, len = 0, i;
+    const char *c;
+
+    if (isnan(f)) {
+        fprintf(out, "NaN");
+        return;
+    }
+    if (signbit(f)) {
+        fputc('-', out);
+        f = -f;
+    }
+    if (isinf(f)) {
+        fprintf(out, "inf");
+        return;
+    }
+    if (f == 0) {
+        fputc('0', out);
+        return;
+    }
+
+    /* 17 significant digits always read back the same */
+    for (precision = 0; precision < 16; precision++) {
+        snprintf(buf, sizeof buf, "%.*e", precision, f);
+        if (strtod(buf, NULL) == f)
+            break;
+    }
+    snprintf(buf, sizeof buf, "%.*e", precision, f);
+
+    for (c = buf; *c != 'e'; c++) {
+        if (*c != '.')
+            digits[len++] = *c;
+    }
+    exponent = atoi(c + 1);
+    while (len > 1 && digits[len - 1] == '0')
+        len--;
+
+    if (exponent < 0) {
+        fprintf(out, "0.");
+        for (i = -1; i > exponent; i--)
+            fputc('0', out);
+        fwrite(digits, 1, len, out);
+    } else if (exponent + 1 >= len) {
+        fwrite(digits, 1, len, out);
+        for (i = len; i <= exponent; i++)
+            fputc('0', out);
+    } else {
+        fwrite(digits, 1, exponent + 1, out);
+        fputc('.', out);
+        fwrite(digits + exponent + 1, 1, len - exponent - 1, out);
+    }
+}
+
+static void ses_fprint(FILE *out, ses_value v) {
+    switch (v.tag) {
+    case SES_STRING:
+        fprintf(out, "\"%s\"", v.as.s);
+        break;
+    case SES_INT:
+        fprintf(out, "%" PRIu64, v.as.i);
+        break;
+    case SES_FLOAT:
+        ses_fprint_float(out, v.as.f);
+        break;
+    case SES_VOID:
+        fprintf(out, "void");
+        break;
+    case SES_CLOSURE:
+        fprintf(out, "<closure>");
+        break;
+    case SES_CONT:
+        fprintf(out, "<continuation>");
+        break;
+    case SES_HALT:
+        fprintf(out, "<halt>");
+        break;
+    case SES_EXCEPTION:
+        fprintf(out, "<exception>");
+        break;
+    case SES_ABORT:
+        fprintf(out, "<abort>");
+        break;
+    }
+}
+
+static void ses_print(ses_value v) {
+    ses_fprint(stdout, v);
+}
+
+static void ses_print_spans(const ses_span *span) {
+    if (!span)
+        return;
+    ses_print_spans(span->next);
+    fprintf(stderr, "\n  raised at %zu..%zu", span->start, span->end);
+}
+
+static void ses_uncaught(ses_value v) {
+    fprintf(stderr, "error: uncaught exception: ");
+    if (v.tag == SES_EXCEPTION) {
+        ses_fprint(stderr, v.as.exc->value);
+        ses_print_spans(v.as.exc->spans);
+    } else {
+        ses_fprint(stderr, v);
+    }
+    fprintf(stderr, "\n");
+    exit(1);
+}
+
+static void ses_call_one(ses_value k, ses_value v) {
+    switch (k.tag) {
+    case SES_CONT:
+        ses_next_code = k.as.clo.code;
+        ses_next_env = ses_push(k.as.clo.env, v);
+        break;
+    case SES_HALT:
+        ses_halted = 1;
+        ses_result = v;
+        break;
+    case SES_ABORT:
+        ses_uncaught(v);
+        break;
+    default:
+        ses_panic("attempt to resume a non-continuation");
+    }
+}
+
+static void ses_call_two(ses_value f, ses_value v, ses_value k, ses_value h) {
+    if (f.tag != SES_CLOSURE)
+        ses_panic("attempt to call a non-function");
+    ses_next_code = f.as.clo.code;
+    ses_next_env = ses_push(ses_push(ses_push(f.as.clo.env, v), k), h);
+}
+
+/* wrap `v` as an exception raised at the span, re-raising an exception adds
+ * the span to it */
+static void ses_raise(ses_value h, ses_value v, size_t start, size_t end) {
+    ses_exception *exc = ses_alloc(sizeof(ses_exception));
+    ses_span *span = ses_alloc(sizeof(ses_span));
+
+    if (v.tag == SES_EXCEPTION) {
+        *exc = *v.as.exc;
+    } else {
+        exc->value = v;
+        exc->spans = NULL;
+    }
+    span->start = start;
+    span->end = end;
+    span->next = exc->spans;
+    exc->spans = span;
+
+    v.tag = SES_EXCEPTION;
+    v.as.exc = exc;
+    ses_call_one(h, v);
+}
+
+static ses_value ses_run(void) {
+#ifdef SES_STEP_LIMIT
+    unsigned long long steps = 0;
+#endif
+    while (!ses_halted) {
+#ifdef SES_STEP_LIMIT
+        if (++steps > SES_STEP_LIMIT) {
+            fprintf(stderr, "error: step limit of %llu exceeded\n",
+                    (unsigned long long)SES_STEP_LIMIT);
+            exit(1);
+        }
+#endif
+        ses_next_code(ses_next_env);
+    }
+    return ses_result;
+}
+
+#endif
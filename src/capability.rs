@@ -0,0 +1,250 @@
+use moniker::{BoundTerm, Scope};
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    eval::{Error, HostFn, Machine, Value},
+    flat_expr::FExpr,
+    prim::Prim,
+};
+
+/// The set of host bindings a script instance is allowed to use
+///
+/// Nothing is available to a script unless it was granted here, and a
+/// program referring to anything else is rejected by `link` before it runs.
+/// That includes the primitives `cont_expr` lowers `async`, `await` and
+/// generators to, which must be granted with `grant_prim` like any other.
+/// Granted functions are ordinary values, so a script can hand one on to
+/// code it calls.
+///
+/// Attenuation is up to the host: it can grant a weaker version of a
+/// function made with `HostFn::attenuate` in place of the function itself,
+/// but scripts have no way to attenuate what they were granted before
+/// passing it on.
+#[derive(Clone, Default)]
+pub struct Capabilities {
+    grants: BTreeMap<String, Value>,
+}
+
+impl Capabilities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn grant(&mut self, name: &str, capability: HostFn) -> &mut Self {
+        self.grants.insert(name.to_owned(), Value::Host(capability));
+        self
+    }
+
+    /// Grant a primitive of the machine under its name, such as those of
+    /// `Prim::SCHEDULER`
+    pub fn grant_prim(&mut self, prim: Prim) -> &mut Self {
+        self.grants
+            .insert(prim.name().to_owned(), Value::Prim(prim));
+        self
+    }
+
+    pub fn grant_fn(
+        &mut self,
+        name: &str,
+        fun: impl Fn(&mut Machine, Value) -> Result<Value, Error> + 'static,
+    ) -> &mut Self {
+        self.grant(name, HostFn::new(name, fun))
+    }
+
+    pub fn revoke(&mut self, name: &str) -> &mut Self {
+        self.grants.remove(name);
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.grants.keys().map(|k| k.as_str())
+    }
+
+    /// Check that every free variable and primitive of `program` has been
+    /// granted, and create an instance of it that can see only those grants
+    pub fn link(&self, program: &FExpr) -> Result<Instance, LinkError> {
+        let mut prims = Vec::new();
+        self::prims(program, &mut prims);
+
+        let mut unbound: Vec<_> = program
+            .free_vars()
+            .into_iter()
+            .map(|v| match v.pretty_name {
+                Some(name) => name,
+                None => v.to_string(),
+            })
+            .filter(|name| !self.grants.contains_key(name))
+            .chain(
+                prims
+                    .into_iter()
+                    .filter(|&p| !self.grants_prim(p))
+                    .map(|p| p.name().to_owned()),
+            )
+            .collect();
+
+        if !unbound.is_empty() {
+            unbound.sort();
+            unbound.dedup();
+            return Err(LinkError { unbound });
+        }
+
+        let mut machine = Machine::new();
+        for (name, capability) in &self.grants {
+            machine.define(name, capability.clone());
+        }
+
+        Ok(Instance {
+            machine,
+            program: program.clone(),
+        })
+    }
+
+    fn grants_prim(&self, prim: Prim) -> bool {
+        match self.grants.get(prim.name()) {
+            Some(Value::Prim(p)) => *p == prim,
+            _ => false,
+        }
+    }
+}
+
+/// Push the primitives used in `term` onto `prims`
+fn prims(term: &FExpr, prims: &mut Vec<Prim>) {
+    match term {
+        FExpr::LamOne(Scope {
+            unsafe_body: body, ..
+        }) => self::prims(body, prims),
+        FExpr::LamTwo(Scope {
+            unsafe_body:
+                Scope {
+                    unsafe_body:
+                        Scope {
+                            unsafe_body: body, ..
+                        },
+                    ..
+                },
+            ..
+        }) => self::prims(body, prims),
+        FExpr::CallOne(k, v) | FExpr::Raise(k, v, _) => {
+            self::prims(k, prims);
+            self::prims(v, prims);
+        }
+        FExpr::CallTwo(f, v, k, h, _) => {
+            for operand in &[f, v, k, h] {
+                self::prims(operand, prims);
+            }
+        }
+        FExpr::Prim(p) => prims.push(p.0),
+        FExpr::Var(_) | FExpr::Lit(_) => {}
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkError {
+    /// Names the program uses that were not granted to it, including those
+    /// of the primitives it was lowered to
+    pub unbound: Vec<String>,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unbound names: {}", self.unbound.join(", "))
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// A linked program together with the machine holding its grants
+pub struct Instance {
+    machine: Machine,
+    program: FExpr,
+}
+
+impl Instance {
+    pub fn machine(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    pub fn run(&mut self) -> Result<Value, Error> {
+        self.machine.run(&self.program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, literals::Literal, utils::test::*};
+
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn unbound_names_fail_to_link() {
+        // (read-file (clock void))
+        let expr = app(
+            global("read-file"),
+            app(global("clock"), lit(Literal::Void)),
+        );
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let mut caps = Capabilities::new();
+        caps.grant_fn("clock", |_, _| Ok(Value::Lit(Literal::Int(0))));
+
+        let err = caps.link(&program).err().unwrap();
+        assert_eq!(err.unbound, vec!["read-file".to_owned()]);
+
+        caps.grant_fn("read-file", |_, v| Ok(v));
+        assert_eq!(caps.link(&program).unwrap().run().unwrap().to_string(), "0");
+    }
+
+    #[test]
+    fn ungranted_async_fails_to_link() {
+        // (await ((async (x) x) 1))
+        let expr = await_(app(async_("x", |x| x), lit(Literal::Int(1))));
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let mut caps = Capabilities::new();
+        let err = caps.link(&program).err().unwrap();
+        assert_eq!(err.unbound, vec!["join".to_owned(), "spawn".to_owned()]);
+
+        // a host function under a primitive's name doesn't grant it
+        caps.grant_fn("spawn", |_, v| Ok(v));
+        caps.grant_prim(Prim::Join);
+        let err = caps.link(&program).err().unwrap();
+        assert_eq!(err.unbound, vec!["spawn".to_owned()]);
+
+        caps.grant_prim(Prim::Spawn);
+        assert_eq!(caps.link(&program).unwrap().run().unwrap().to_string(), "1");
+    }
+
+    #[test]
+    fn attenuated_capabilities_are_values() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let sink = log.clone();
+        let write = HostFn::new("write", move |_, v| {
+            sink.borrow_mut().push(v.to_string());
+            Ok(Value::Lit(Literal::Void))
+        });
+        let write_strings = write.attenuate(|_, v| match v {
+            Value::Lit(Literal::String(_)) => Ok(()),
+            _ => Err(Error::Host("write: only strings may be written".to_owned())),
+        });
+
+        // ((lambda (w) (w arg)) write), so the capability is passed around
+        let program = |arg| {
+            let expr = app(lam("w", |w| app(w, arg)), global("write"));
+            cont_expr::program(expr).into_fexpr()
+        };
+
+        let mut caps = Capabilities::new();
+        caps.grant("write", write_strings);
+
+        let strings = program(lit(Literal::String("ok".to_owned())));
+        assert!(caps.link(&strings).unwrap().run().is_ok());
+
+        let ints = program(lit(Literal::Int(1)));
+        assert!(caps.link(&ints).unwrap().run().is_err());
+
+        assert_eq!(*log.borrow(), vec!["\"ok\"".to_owned()]);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{cell::Cell, collections::VecDeque, rc::Rc};
+
+use crate::{
+    eval::Value,
+    gc::{Trace, Tracer},
+};
+
+/// A queue of values passed between tasks, made with `channel`
+///
+/// Sending to a full channel suspends the sender until a receiver makes
+/// room, and receiving from an empty one suspends the receiver until a value
+/// is sent. A channel with a capacity of zero hands each value straight from
+/// a sender to a receiver.
+pub(crate) struct Channel {
+    pub(crate) buffer: VecDeque<Value>,
+    /// `None` if the channel is unbounded
+    capacity: Option<usize>,
+    /// The continuations of blocked senders, with the values they are sending
+    pub(crate) senders: VecDeque<(Value, Value)>,
+    pub(crate) receivers: VecDeque<Receiver>,
+}
+
+/// A task blocked receiving from a channel
+pub(crate) struct Receiver {
+    pub(crate) k: Value,
+    /// Set for a task blocked in a `select`, shared between every channel it
+    /// is waiting on so that only the first value sent wakes it
+    pub(crate) select: Option<Rc<Cell<bool>>>,
+}
+
+impl Trace for Channel {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.buffer.iter().for_each(|v| tracer.value(v));
+
+        for (k, v) in &self.senders {
+            tracer.value(k);
+            tracer.value(v);
+        }
+
+        self.receivers
+            .iter()
+            .filter(|r| !r.is_stale())
+            .for_each(|r| tracer.value(&r.k));
+    }
+}
+
+impl Receiver {
+    /// Whether this is a `select` that another channel has already woken
+    fn is_stale(&self) -> bool {
+        self.select.as_ref().is_some_and(|woken| woken.get())
+    }
+}
+
+impl Channel {
+    pub(crate) fn new(capacity: Option<usize>) -> Self {
+        Channel {
+            buffer: VecDeque::new(),
+            capacity,
+            senders: VecDeque::new(),
+            receivers: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.buffer.len() >= capacity)
+    }
+
+    /// Take the next value, along with the continuation of a sender that
+    /// was waiting for the room this made
+    pub(crate) fn take(&mut self) -> Option<(Value, Option<Value>)> {
+        match self.buffer.pop_front() {
+            Some(v) => {
+                let sender = self.senders.pop_front().map(|(k, sent)| {
+                    self.buffer.push_back(sent);
+                    k
+                });
+
+                Some((v, sender))
+            }
+            None => self.senders.pop_front().map(|(k, sent)| (sent, Some(k))),
+        }
+    }
+
+    /// Wait for a value, forgetting any receivers of `select`s that have
+    /// since been woken by another channel, so that selecting over a channel
+    /// nobody sends on doesn't build them up
+    pub(crate) fn wait(&mut self, receiver: Receiver) {
+        self.receivers.retain(|r| !r.is_stale());
+        self.receivers.push_back(receiver);
+    }
+
+    /// The longest waiting receiver, skipping those whose `select` has
+    /// already been woken by another channel
+    pub(crate) fn receiver(&mut self) -> Option<Receiver> {
+        while let Some(receiver) = self.receivers.pop_front() {
+            match &receiver.select {
+                Some(woken) if woken.replace(true) => continue,
+                _ => return Some(receiver),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cont_expr,
+        eval::{Error, Machine, Outcome, Value},
+        expr::Expr,
+        literals::Literal,
+        prim::Prim,
+        scheduler::VirtualClock,
+        utils::test::*,
+    };
+
+    fn machine() -> (Machine, Log) {
+        let prims: Vec<_> = Prim::SCHEDULER.iter().chain(&Prim::CHANNELS).copied().collect();
+        let (mut machine, log) = logging_machine(&prims);
+        machine.set_clock(VirtualClock::default());
+
+        (machine, log)
+    }
+
+    /// (send ch v)
+    fn send(ch: Expr, v: Expr) -> Expr {
+        app(call("send", ch), v)
+    }
+
+    /// (let ((ch (channel capacity))) body)
+    fn with_channel(capacity: Expr, body: impl FnOnce(Expr) -> Expr) -> Expr {
+        app(lam("ch", body), call("channel", capacity))
+    }
+
+    #[test]
+    fn senders_block_on_full_channels() {
+        let (mut machine, log) = machine();
+
+        // the producer logs each value once it has been accepted
+        let producer = |ch: Expr| {
+            lam("_", |_| {
+                (1..=3).rev().fold(lit(Literal::Void), |rest, i| {
+                    seq(send(ch.clone(), int(i)), seq(call("log", int(i)), rest))
+                })
+            })
+        };
+        let consumer = |ch: Expr| {
+            seq(
+                call("yield-now", lit(Literal::Void)),
+                seq(
+                    call("log", call("recv", ch.clone())),
+                    seq(
+                        call("log", call("recv", ch.clone())),
+                        call("log", call("recv", ch)),
+                    ),
+                ),
+            )
+        };
+
+        let expr = with_channel(int(1), |ch| {
+            seq(call("spawn", producer(ch.clone())), consumer(ch))
+        });
+        run(&mut machine, expr).unwrap();
+
+        // the producer gets one value ahead of the consumer and no further
+        assert_eq!(*log.borrow(), vec!["1", "1", "2", "2", "3", "3"]);
+
+        let expr = with_channel(lit(Literal::Void), |ch| {
+            seq(
+                send(ch.clone(), int(1)),
+                seq(send(ch.clone(), int(2)), call("recv", ch)),
+            )
+        });
+        assert_eq!(run(&mut machine, expr).unwrap().to_string(), "1");
+
+        // nobody will ever receive, so sending blocks forever
+        let expr = with_channel(int(0), |ch| send(ch, int(1)));
+        assert!(matches!(run(&mut machine, expr), Err(Error::Deadlock)));
+    }
+
+    #[test]
+    fn select_takes_the_first_ready_channel() {
+        let (mut machine, _) = machine();
+
+        let a = machine.channel(None);
+        let b = machine.channel(Some(1));
+        let channels = machine.alloc_list(vec![a.clone(), b.clone()]);
+        machine.define("channels", channels);
+        machine.define("b", b.clone());
+
+        // a task sends to b after a while, no one ever sends to a
+        let expr = seq(
+            call(
+                "spawn",
+                lam("_", |_| {
+                    seq(call("sleep", int(10)), send(global("b"), int(5)))
+                }),
+            ),
+            call("select", global("channels")),
+        );
+        let selected = run(&mut machine, expr).unwrap();
+
+        match machine.list(&selected) {
+            Some([channel, value]) => {
+                assert!(matches!((channel, &b), (Value::Channel(x), Value::Channel(y)) if x == y));
+                assert_eq!(value.to_string(), "5");
+            }
+            _ => panic!("expected the channel and value, got {}", selected),
+        }
+
+        // the select no longer waits on a
+        machine.send(&a, Value::Lit(Literal::Int(1))).unwrap();
+        assert_eq!(machine.try_recv(&a).unwrap().unwrap().to_string(), "1");
+    }
+
+    /// ((lambda (loop) (loop loop)) (lambda (loop) body (loop loop)))
+    fn forever(body: Expr) -> Expr {
+        let looping = lam("loop", |l| seq(body, app(l.clone(), l)));
+
+        app(lam("loop", |l| app(l.clone(), l)), looping)
+    }
+
+    #[test]
+    fn selecting_over_idle_channels_uses_bounded_memory() {
+        let (mut machine, _) = machine();
+        machine.set_step_limit(Some(200_000));
+
+        let idle = machine.channel(None);
+        let busy = machine.channel(Some(0));
+        let channels = machine.alloc_list(vec![idle, busy.clone()]);
+        machine.define("channels", channels);
+        machine.define("busy", busy);
+
+        // one task keeps sending to busy, which has no room so each select
+        // waits for the send, while another keeps selecting over it and a
+        // channel nobody sends on
+        let expr = seq(
+            call("spawn", lam("_", |_| forever(send(global("busy"), int(1))))),
+            forever(call("select", global("channels"))),
+        );
+        assert!(matches!(
+            run(&mut machine, expr),
+            Err(Error::StepLimitExceeded(_))
+        ));
+
+        machine.collect();
+        assert!(machine.heap().live() < 100, "{} live", machine.heap().live());
+    }
+
+    #[test]
+    fn host_feeds_events_to_blocked_scripts() {
+        let (mut machine, log) = machine();
+
+        let events = machine.channel(Some(1));
+        machine.define("events", events.clone());
+
+        let expr = seq(
+            call("log", call("recv", global("events"))),
+            call("log", call("recv", global("events"))),
+        );
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let mut outcome = machine.run_with_fuel(&program, 1000).unwrap();
+        for event in ["click", "key"] {
+            let suspended = match outcome {
+                Outcome::Suspended(s) => s,
+                Outcome::Done(v) => panic!("expected the script to wait, got {}", v),
+            };
+            assert!(suspended.is_blocked());
+
+            let event = Value::Lit(Literal::String(event.to_owned()));
+            machine.send(&events, event).unwrap();
+            outcome = machine.resume(suspended, 1000).unwrap();
+        }
+
+        assert_eq!(*log.borrow(), vec!["\"click\"", "\"key\""]);
+        assert!(matches!(outcome, Outcome::Done(_)));
+
+        // the host can't wait for room, so sending to a full channel fails
+        machine.send(&events, Value::Lit(Literal::Void)).unwrap();
+        let full = machine.send(&events, Value::Lit(Literal::Void));
+        assert!(matches!(full, Err(Error::ChannelFull)));
+    }
+}
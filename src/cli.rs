@@ -0,0 +1,757 @@
+use moniker::BoundTerm;
+use pretty::{BoxAllocator, DocAllocator};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use some_embedded_scripting_language::{
+    c_backend, cont_expr, debugger, format,
+    eval::{Error, Machine, Value},
+    expr::Expr,
+    flat_expr::FExpr,
+    parse::{self, Item},
+    profile::{Function, Profiler},
+    render::{RenderOptions, Style, Target},
+    wasm_backend,
+};
+
+use crate::{
+    debug::{self, Console},
+    lsp,
+    repl::Repl,
+};
+
+pub const USAGE: &str = "\
+usage: some_embedded_scripting_language_bin [options] [command]
+
+commands:
+  repl                       start an interactive session, the default
+  run FILE                   run a script
+  check FILE                 check a script parses and uses only names it
+                             defines or the machine provides
+  debug [--break=LINE] FILE  run a script in the debugger, stopping at calls
+                             on each LINE given, or at its first call
+  profile [--interval=N] [-o OUT] FILE
+                             run a script, then report the steps,
+                             allocations and time of each function, and
+                             write the call stacks sampled every N steps,
+                             10 by default, to OUT as folded stacks
+  dump --stage=STAGE FILE    print a script at a stage of compilation, one
+                             of expr, cps or flat
+  compile -o OUT FILE        compile a script to C, or to a WebAssembly text
+                             module if OUT ends in .wat
+  fmt [--check] FILE...      reformat scripts in place, or with --check list
+                             those that aren't formatted and fail if any
+  lsp                        serve the language server protocol over stdin
+                             and stdout
+  help                       show this message
+
+options:
+  --color=auto|always|never  whether to colour output, by default only when
+                             writing to a terminal
+  --width=N                  the line width to pretty print to, 70 by default
+  --indent=N                 how far to indent nested forms, 1 by default
+  --format=text|html|latex   how to render pretty printed output, text by
+                             default
+
+exit status:
+  0  success
+  1  the script failed while running
+  2  bad usage, or a file couldn't be read or written
+  3  the script is invalid, or with fmt --check isn't formatted";
+
+/// The script failed while running
+pub const SCRIPT_FAILED: u8 = 1;
+/// Bad usage, or a file couldn't be read or written
+pub const USAGE_ERROR: u8 = 2;
+/// The script doesn't parse, check or compile
+pub const INVALID_SCRIPT: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub color: ColorChoice,
+    pub render: RenderOptions,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            color: ColorChoice::Auto,
+            render: RenderOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Repl,
+    Run(PathBuf),
+    Check(PathBuf),
+    Debug { path: PathBuf, breakpoints: Vec<usize> },
+    Profile {
+        path: PathBuf,
+        output: Option<PathBuf>,
+        interval: u64,
+    },
+    Dump(Stage, PathBuf),
+    Compile { output: PathBuf, input: PathBuf },
+    Fmt { paths: Vec<PathBuf>, check: bool },
+    Lsp,
+    Help,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Expr,
+    Cps,
+    Flat,
+}
+
+/// Parse the arguments following the program name, options may come before
+/// or after the command
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<(Options, Command), String> {
+    let mut options = Options::default();
+    let mut stage = None;
+    let mut output = None;
+    let mut breakpoints = Vec::new();
+    let mut interval = 10;
+    let mut check = false;
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} expects a value", name))
+        };
+
+        match flag.as_str() {
+            "--color" => {
+                options.color = match value("--color")?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    other => return Err(format!("unknown colour choice `{}`", other)),
+                }
+            }
+            "--width" => {
+                let width = value("--width")?;
+                options.render.width = width
+                    .parse()
+                    .map_err(|_| format!("bad width `{}`", width))?;
+            }
+            "--indent" => {
+                let indent = value("--indent")?;
+                options.render.indent = indent
+                    .parse()
+                    .map_err(|_| format!("bad indent `{}`", indent))?;
+            }
+            "--format" => {
+                options.render.target = match value("--format")?.as_str() {
+                    "text" => Target::Color,
+                    "html" => Target::Html,
+                    "latex" => Target::Latex,
+                    other => return Err(format!("unknown format `{}`", other)),
+                }
+            }
+            "--stage" => {
+                stage = Some(match value("--stage")?.as_str() {
+                    "expr" => Stage::Expr,
+                    "cps" => Stage::Cps,
+                    "flat" => Stage::Flat,
+                    other => return Err(format!("unknown stage `{}`", other)),
+                })
+            }
+            "--break" => {
+                let line = value("--break")?;
+                breakpoints.push(match line.parse() {
+                    Ok(line) if line > 0 => line,
+                    _ => return Err(format!("bad line `{}`", line)),
+                });
+            }
+            "--interval" => {
+                let n = value("--interval")?;
+                interval = match n.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("bad interval `{}`", n)),
+                };
+            }
+            "--check" => check = true,
+            "-o" | "--output" => output = Some(PathBuf::from(value("-o")?)),
+            "-h" | "--help" => return Ok((options, Command::Help)),
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option `{}`", flag))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = positional.next();
+    let files: Vec<_> = positional.map(PathBuf::from).collect();
+    if let (false, Some(extra)) = (command.as_deref() == Some("fmt"), files.get(1)) {
+        return Err(format!("unexpected argument `{}`", extra.display()));
+    }
+    let file = files.first().cloned();
+
+    let file = |command: &str| {
+        file.clone()
+            .ok_or_else(|| format!("{} expects a file", command))
+    };
+    let command = match command.as_deref() {
+        None | Some("repl") => Command::Repl,
+        Some("help") => Command::Help,
+        Some("run") => Command::Run(file("run")?),
+        Some("check") => Command::Check(file("check")?),
+        Some("debug") => Command::Debug {
+            path: file("debug")?,
+            breakpoints,
+        },
+        Some("profile") => Command::Profile {
+            path: file("profile")?,
+            output,
+            interval,
+        },
+        Some("dump") => match stage {
+            Some(stage) => Command::Dump(stage, file("dump")?),
+            None => return Err("dump expects --stage".to_owned()),
+        },
+        Some("compile") => match output {
+            Some(output) => Command::Compile {
+                output,
+                input: file("compile")?,
+            },
+            None => return Err("compile expects -o OUT".to_owned()),
+        },
+        Some("fmt") if files.is_empty() => return Err("fmt expects a file".to_owned()),
+        Some("fmt") => Command::Fmt {
+            paths: files,
+            check,
+        },
+        Some("lsp") => Command::Lsp,
+        Some(other) => return Err(format!("unknown command `{}`", other)),
+    };
+
+    Ok((options, command))
+}
+
+/// Run the command line, returning the exit status
+pub fn main(args: impl IntoIterator<Item = String>) -> u8 {
+    let (options, command) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let mut stderr =
+                StandardStream::stderr(color(ColorChoice::Auto, io::stderr().is_terminal()));
+            let _ = error(&mut stderr, e).and_then(|_| writeln!(stderr, "{}", USAGE));
+            return USAGE_ERROR;
+        }
+    };
+
+    let mut stdout = StandardStream::stdout(color(options.color, io::stdout().is_terminal()));
+    let mut stderr = StandardStream::stderr(color(options.color, io::stderr().is_terminal()));
+
+    match execute(options, command, &mut stdout, &mut stderr) {
+        Ok(status) => status,
+        Err(e) => {
+            let _ = error(&mut stderr, e);
+            USAGE_ERROR
+        }
+    }
+}
+
+/// `termcolor` colours whenever asked to automatically, so check whether
+/// the output is a terminal first
+fn color(choice: ColorChoice, terminal: bool) -> ColorChoice {
+    match choice {
+        ColorChoice::Auto if !terminal => ColorChoice::Never,
+        choice => choice,
+    }
+}
+
+/// Carry out a command, returning the exit status. Failing to read or write
+/// files is an `Err`, errors in the script are reported to `err`.
+pub fn execute(
+    options: Options,
+    command: Command,
+    out: &mut impl WriteColor,
+    err: &mut impl WriteColor,
+) -> io::Result<u8> {
+    match command {
+        Command::Help => {
+            writeln!(out, "{}", USAGE)?;
+            Ok(0)
+        }
+        Command::Repl => {
+            let stdin = io::stdin();
+            Repl::new(options.render).run(stdin.lock(), out)?;
+            Ok(0)
+        }
+        Command::Lsp => {
+            let stdin = io::stdin();
+            lsp::serve(stdin.lock(), out, crate::machine())
+        }
+        Command::Run(path) => {
+            let src = read(&path)?;
+            run(&mut crate::machine(), &path, &src, err, Machine::run)
+        }
+        Command::Profile {
+            path,
+            output,
+            interval,
+        } => {
+            let src = read(&path)?;
+            let mut machine = crate::machine();
+            machine.set_profiler(Some(Profiler::new(interval)));
+
+            // a script that fails still has a profile of what it did
+            let status = run(&mut machine, &path, &src, err, Machine::run)?;
+            if status == INVALID_SCRIPT {
+                return Ok(status);
+            }
+
+            let profiler = machine.take_profiler().expect("profiler was set");
+            let label = |function: &Function| label(&src, function);
+            profiler.write_report(out, label)?;
+            if let Some(output) = output {
+                let mut folded = Vec::new();
+                profiler.write_folded(&mut folded, label)?;
+                write(&output, &String::from_utf8_lossy(&folded))?;
+            }
+
+            Ok(status)
+        }
+        Command::Debug { path, breakpoints } => {
+            let src = read(&path)?;
+            let stdin = io::stdin();
+            let mut console = Console::new(&src, &breakpoints, stdin.lock().lines(), out);
+
+            run(&mut crate::machine(), &path, &src, err, |machine, program| {
+                machine.debug(program, &mut console)
+            })
+        }
+        Command::Check(path) => {
+            let src = read(&path)?;
+            let expr = match script(&path, &src, err)? {
+                Some(expr) => expr,
+                None => return Ok(INVALID_SCRIPT),
+            };
+
+            let unbound = unbound(&crate::machine(), &expr);
+            for name in &unbound {
+                error(
+                    err,
+                    format_args!("{}: unbound variable: {}", path.display(), name),
+                )?;
+            }
+
+            Ok(if unbound.is_empty() {
+                0
+            } else {
+                INVALID_SCRIPT
+            })
+        }
+        Command::Fmt { paths, check } => {
+            let mut status = 0;
+            for path in paths {
+                let src = read(&path)?;
+                let formatted = match format::format(&src) {
+                    Ok(formatted) => formatted,
+                    Err(e) => {
+                        error(err, format_args!("{}:{}", path.display(), e))?;
+                        status = INVALID_SCRIPT;
+                        continue;
+                    }
+                };
+
+                if formatted == src {
+                    continue;
+                } else if check {
+                    writeln!(out, "{}", path.display())?;
+                    status = INVALID_SCRIPT;
+                } else {
+                    write(&path, &formatted)?;
+                }
+            }
+
+            Ok(status)
+        }
+        Command::Dump(stage, path) => {
+            let src = read(&path)?;
+            let items = match items(&path, &src, err)? {
+                Some(items) => items,
+                None => return Ok(INVALID_SCRIPT),
+            };
+
+            for item in items {
+                dump(stage, item, &options.render, out)?;
+            }
+
+            Ok(0)
+        }
+        Command::Compile { output, input } => {
+            let src = read(&input)?;
+            let expr = match script(&input, &src, err)? {
+                Some(expr) => expr,
+                None => return Ok(INVALID_SCRIPT),
+            };
+            let program = cont_expr::program(expr).into_fexpr();
+
+            let emitted = match output.extension().and_then(|e| e.to_str()) {
+                Some("wat") => wasm_backend::emit(&program).map_err(|e| e.to_string()),
+                _ => c_backend::emit(&program).map_err(|e| e.to_string()),
+            };
+            let emitted = match emitted {
+                Ok(emitted) => emitted,
+                Err(e) => {
+                    error(err, format_args!("{}: {}", input.display(), e))?;
+                    return Ok(INVALID_SCRIPT);
+                }
+            };
+
+            write(&output, &emitted)?;
+            if output.extension().and_then(|e| e.to_str()) != Some("wat") {
+                let header = output.with_file_name(c_backend::RUNTIME_HEADER_NAME);
+                write(&header, c_backend::RUNTIME_HEADER)?;
+            }
+
+            Ok(0)
+        }
+    }
+}
+
+/// Read a file, naming it in any error
+fn read(path: &Path) -> io::Result<String> {
+    fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+    fs::write(path, contents)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Run a script with `exec`, reporting any error
+fn run(
+    machine: &mut Machine,
+    path: &Path,
+    src: &str,
+    err: &mut impl WriteColor,
+    exec: impl FnOnce(&mut Machine, &FExpr) -> Result<Value, Error>,
+) -> io::Result<u8> {
+    let expr = match script(path, src, err)? {
+        Some(expr) => expr,
+        None => return Ok(INVALID_SCRIPT),
+    };
+
+    match exec(machine, &cont_expr::program(expr).into_fexpr()) {
+        Ok(_) => Ok(0),
+        Err(e) => {
+            error(err, format_args!("{}: {}", path.display(), e))?;
+            debug::backtrace(err, src, machine.backtrace())?;
+            Ok(SCRIPT_FAILED)
+        }
+    }
+}
+
+/// The name of a profiled function in reports, placing lambdas by line
+fn label(src: &str, function: &Function) -> String {
+    match function {
+        Function::Lambda {
+            name: None,
+            at: Some(at),
+        } => format!("lambda at line {}", debugger::line_of(src, at.start)),
+        function => function.to_string(),
+    }
+}
+
+/// Parse a script as one expression, the same for running and compiling it,
+/// reporting any error
+fn script(path: &Path, src: &str, err: &mut impl WriteColor) -> io::Result<Option<Expr>> {
+    match parse::script(src) {
+        Ok(expr) => Ok(Some(expr)),
+        Err(e) => error(err, format_args!("{}:{}", path.display(), e)).map(|_| None),
+    }
+}
+
+/// Parse a script a top level form at a time, reporting any error
+fn items(path: &Path, src: &str, err: &mut impl WriteColor) -> io::Result<Option<Vec<Item>>> {
+    match parse::items(src) {
+        Ok(items) => Ok(Some(items)),
+        Err(e) => error(err, format_args!("{}:{}", path.display(), e)).map(|_| None),
+    }
+}
+
+/// The free variables of a script that the machine doesn't bind, in order
+fn unbound(machine: &Machine, expr: &Expr) -> Vec<String> {
+    let mut unbound: Vec<_> = expr
+        .free_vars()
+        .into_iter()
+        .filter_map(|v| v.pretty_name)
+        .filter(|name| machine.global(name).is_none())
+        .collect();
+    unbound.sort();
+    unbound.dedup();
+
+    unbound
+}
+
+fn dump(
+    stage: Stage,
+    item: Item,
+    options: &RenderOptions,
+    out: &mut impl WriteColor,
+) -> io::Result<()> {
+    let allocator = BoxAllocator;
+    let (name, expr) = match item {
+        Item::Define(name, expr, _) => (Some(name), expr),
+        Item::Expr(expr, _) => (None, expr),
+    };
+
+    let program = cont_expr::program(expr.clone());
+    let flat = program.clone().into_fexpr();
+    let doc = match stage {
+        Stage::Expr => expr.pretty(&allocator, options.indent),
+        Stage::Cps => program.pretty(&allocator, options.indent),
+        Stage::Flat => flat.pretty(&allocator, options.indent),
+    };
+    let doc = match name {
+        Some(name) => allocator
+            .text("define")
+            .annotate(Style::Keyword)
+            .append(allocator.space())
+            .append(allocator.text(name))
+            .append(allocator.line().append(doc).nest(options.indent))
+            .group()
+            .parens(),
+        None => doc,
+    };
+
+    options.render(doc, &mut *out)?;
+    match options.target {
+        Target::Html | Target::Latex => Ok(()),
+        _ => writeln!(out),
+    }
+}
+
+pub fn error(out: &mut impl WriteColor, msg: impl fmt::Display) -> io::Result<()> {
+    out.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?;
+    write!(out, "error")?;
+    out.reset()?;
+
+    writeln!(out, ": {}", msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termcolor::NoColor;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn args(args: &str) -> Result<(Options, Command), String> {
+        parse_args(args.split_whitespace().map(str::to_owned))
+    }
+
+    /// Run a command on a script, returning the exit status and what was
+    /// written to stdout and stderr
+    fn execute_on(command: &str, script: &str) -> (u8, String, String) {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ses-cli-{}-{}",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("script.ses");
+        fs::write(&path, script).unwrap();
+
+        let (options, command) = args(&format!("{} {}", command, path.display())).unwrap();
+        let (mut out, mut err) = (NoColor::new(Vec::new()), NoColor::new(Vec::new()));
+        let status = execute(options, command, &mut out, &mut err).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        let string = |w: NoColor<Vec<u8>>| String::from_utf8(w.into_inner()).unwrap();
+        (status, string(out), string(err))
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(args("").unwrap(), (Options::default(), Command::Repl));
+
+        let (options, command) = args("--color never dump --width=20 --stage=cps a.ses").unwrap();
+        assert_eq!(options.color, ColorChoice::Never);
+        assert_eq!(options.render.width, 20);
+        assert_eq!(command, Command::Dump(Stage::Cps, "a.ses".into()));
+
+        let (_, command) = args("compile a.ses -o a.c").unwrap();
+        let expected = Command::Compile {
+            output: "a.c".into(),
+            input: "a.ses".into(),
+        };
+        assert_eq!(command, expected);
+
+        let (_, command) = args("debug --break 3 a.ses --break=1").unwrap();
+        let expected = Command::Debug {
+            path: "a.ses".into(),
+            breakpoints: vec![3, 1],
+        };
+        assert_eq!(command, expected);
+
+        let (_, command) = args("profile --interval=5 a.ses -o a.folded").unwrap();
+        let expected = Command::Profile {
+            path: "a.ses".into(),
+            output: Some("a.folded".into()),
+            interval: 5,
+        };
+        assert_eq!(command, expected);
+
+        assert_eq!(args("lsp").unwrap().1, Command::Lsp);
+
+        let (_, command) = args("fmt --check a.ses b.ses").unwrap();
+        let expected = Command::Fmt {
+            paths: vec!["a.ses".into(), "b.ses".into()],
+            check: true,
+        };
+        assert_eq!(command, expected);
+
+        assert!(args("debug --break=0 a.ses").is_err());
+        assert!(args("profile --interval=0 a.ses").is_err());
+        assert!(args("dump a.ses").is_err());
+        assert!(args("run").is_err());
+        assert!(args("run a.ses b.ses").is_err());
+        assert!(args("fmt").is_err());
+        assert!(args("--color=sometimes run a.ses").is_err());
+    }
+
+    #[test]
+    fn exit_status_reflects_the_script() {
+        let script = "(define id (lambda (x) x))\n(id 1)\n";
+        assert_eq!(execute_on("run", script).0, 0);
+        assert_eq!(execute_on("check", script).0, 0);
+
+        let (status, _, err) = execute_on("run", "(raise \"oops\")");
+        assert_eq!(status, SCRIPT_FAILED);
+        assert!(err.contains("uncaught exception: \"oops\""));
+
+        let script = "(define f (lambda (x) (raise x)))\n(f \"oops\")\n";
+        let (_, _, err) = execute_on("run", script);
+        assert!(err.ends_with("  in f, line 1: (raise x)\n  line 2: (f \"oops\")\n"));
+
+        let (status, _, err) = execute_on("check", "(id (spawn missing))");
+        assert_eq!(status, INVALID_SCRIPT);
+        assert!(err.contains("unbound variable: id") && err.contains("unbound variable: missing"));
+        assert!(!err.contains("spawn"));
+
+        let (status, _, err) = execute_on("run", "(lambda (x)");
+        assert_eq!(status, INVALID_SCRIPT);
+        assert!(err.contains("unfinished list"));
+    }
+
+    #[test]
+    fn definitions_can_call_themselves() {
+        let script = "(define f (lambda (x) f))\n(print (f 1))\n";
+        assert_eq!(execute_on("run", script).0, 0);
+        assert_eq!(execute_on("check", script).0, 0);
+        // a breakpoint on no call, so the debugger never waits for commands
+        let (status, _, err) = execute_on("debug --break=9", script);
+        assert_eq!((status, err.as_str()), (0, ""));
+
+        let output = std::env::temp_dir().join(format!("ses-cli-{}.wat", std::process::id()));
+        let script = "(define f (lambda (x) f))\n((f 1) 2)\n";
+        let (status, _, err) = execute_on(&format!("compile -o {}", output.display()), script);
+        assert_eq!((status, err.as_str()), (0, ""));
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn running_and_compiling_agree_on_definitions() {
+        // g isn't defined yet where f refers to it
+        let script = "(define f (lambda (x) (g x)))\n(define g (lambda (x) x))\n(f 1)\n";
+        let (status, _, err) = execute_on("check", script);
+        assert_eq!(status, INVALID_SCRIPT);
+        assert!(err.contains("unbound variable: g"));
+
+        let (status, _, err) = execute_on("run", script);
+        assert_eq!(status, SCRIPT_FAILED);
+        assert!(err.contains("unbound variable: g"));
+
+        let output = std::env::temp_dir().join(format!("ses-cli-{}-agree.c", std::process::id()));
+        let (status, _, err) = execute_on(&format!("compile -o {}", output.display()), script);
+        assert_eq!(status, INVALID_SCRIPT);
+        assert!(err.contains("unbound variable: g"));
+    }
+
+    #[test]
+    fn profiles_scripts() {
+        let folded = std::env::temp_dir().join(format!("ses-cli-{}.folded", std::process::id()));
+        let script = "(define id (lambda (x) x))\n(define f (lambda (x) (begin (id x) x)))\n(f 1)\n";
+
+        let command = format!("profile --interval=1 -o {}", folded.display());
+        let (status, out, _) = execute_on(&command, script);
+        assert_eq!(status, 0);
+        assert!(out.starts_with("     steps"));
+        assert!(out.lines().any(|l| l.ends_with("  id")));
+        assert!(out.lines().last().unwrap().ends_with("  total"));
+
+        let contents = fs::read_to_string(&folded).unwrap();
+        fs::remove_file(&folded).unwrap();
+        assert!(contents.lines().any(|l| l == "top level;f;id 1"));
+    }
+
+    #[test]
+    fn formats_scripts() {
+        let (status, out, _) = execute_on("fmt --check", "(define x 1)\n");
+        assert_eq!((status, out.as_str()), (0, ""));
+
+        let (status, out, _) = execute_on("fmt --check", "(define   x 1) ; one\n");
+        assert_eq!(status, INVALID_SCRIPT);
+        assert!(out.trim_end().ends_with("script.ses"));
+
+        let path = std::env::temp_dir().join(format!("ses-cli-fmt-{}.ses", std::process::id()));
+        fs::write(&path, "(define   x 1) ; one").unwrap();
+        let (options, command) = args(&format!("fmt {}", path.display())).unwrap();
+        let (mut out, mut err) = (NoColor::new(Vec::new()), NoColor::new(Vec::new()));
+        assert_eq!(execute(options, command, &mut out, &mut err).unwrap(), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "(define x 1) ; one\n");
+        fs::remove_file(&path).unwrap();
+
+        let (status, _, err) = execute_on("fmt", "(f");
+        assert_eq!(status, INVALID_SCRIPT);
+        assert!(err.contains("unfinished list"));
+    }
+
+    #[test]
+    fn dumps_stages() {
+        let (status, out, _) = execute_on("dump --stage=expr", "(define x 1)\n(f x)");
+        assert_eq!(status, 0);
+        assert!(out.starts_with("(define x 1)\n(f"));
+
+        let (status, out, _) = execute_on("dump --stage=flat", "1");
+        assert_eq!(status, 0);
+        assert!(out.starts_with("(lambda (_"));
+
+        let (status, out, _) = execute_on("dump --format=html --stage=expr", "(raise \"<\")");
+        assert_eq!(status, 0);
+        assert_eq!(
+            out,
+            r#"<pre class="ir">(<span class="keyword">raise</span> "#.to_owned()
+                + r#"<span class="literal">"&lt;"</span>)</pre>"#
+                + "\n"
+        );
+
+        assert!(args("dump --stage=bytecode a.ses").is_err());
+    }
+}
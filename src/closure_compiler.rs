@@ -0,0 +1,521 @@
+use moniker::{Ignore, Scope, ScopeOffset, Var};
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use crate::{
+    backtrace,
+    eval::{unbound, Call, Code, CompiledBody, Error, Machine, MachineId, Outcome, Value},
+    flat_expr::FExpr,
+    gc::Gc,
+    span::{CallSite, Span},
+};
+
+/// A program compiled into rust closures, one for the body of each lambda
+///
+/// Each `FExpr` node is matched on once, when compiling, rather than every
+/// time it is evaluated. Compiled lambdas are flat closures: each copies the
+/// variables it refers to out of the lambdas around it when it is made, so
+/// every variable is found in one step, and the arguments of a call are
+/// passed straight to the compiled body rather than bound in frames on the
+/// heap. The continuations the CPS conversion calls straight away, for
+/// `let`s and the temporaries of calls, become local bindings of the body
+/// instead of closures, and those binding a variable or constant are free.
+/// Globals are looked up by name once per machine, then by slot.
+///
+/// Running the result still goes through the machine's trampoline, so
+/// compiled and interpreted closures can call each other freely, and tasks
+/// and the collector work as they do for the tree walker. Step limits and
+/// fuel count the calls left after inlining, so compiled code makes fewer.
+/// Backtraces follow the continuations of compiled code, though the
+/// debugger and profiler only see interpreted code.
+///
+/// On `benches/closure_compiler.rs` it takes well under half the time of
+/// the tree walker.
+pub struct Compiled {
+    program: Operand,
+}
+
+impl Compiled {
+    /// Run the program on `machine`, returning the value passed to the exit
+    /// continuation
+    pub fn run(&self, machine: &mut Machine) -> Result<Value, Error> {
+        let program = self.program.constant(machine)?;
+        let call = machine.start(program);
+
+        machine.exec(call, None).map(Outcome::unwrap_done)
+    }
+
+    /// Run the program, suspending it once it has made `fuel` calls. It can
+    /// be continued with `Machine::resume`.
+    pub fn run_with_fuel(&self, machine: &mut Machine, fuel: u64) -> Result<Outcome, Error> {
+        let program = self.program.constant(machine)?;
+        let call = machine.start(program);
+
+        machine.exec(call, Some(fuel))
+    }
+}
+
+/// Compile a program produced by `cont_expr::program`
+pub fn compile(program: &FExpr) -> Result<Compiled, Error> {
+    Ok(Compiled {
+        program: Compiler::default().operand(program)?,
+    })
+}
+
+/// An argument of a call, which is computed without making calls of its own
+enum Operand {
+    /// An argument of the lambda the operand is in, by scope offset
+    Arg(usize),
+    /// A value bound by a `let` in the body the operand is in
+    Let(usize),
+    /// A value captured by the lambda the operand is in
+    Captured(usize),
+    Global(Global),
+    Value(Value),
+    Lambda(Lambda),
+}
+
+/// A free variable, with the slot it was found in on the last machine
+/// that looked it up
+struct Global {
+    name: String,
+    var: Var<String>,
+    slot: Cell<Option<(MachineId, usize)>>,
+}
+
+/// A lambda, closed over the values of `captures` when it is made
+struct Lambda {
+    body: Rc<CompiledBody>,
+    captures: Vec<Operand>,
+    /// Whether it is a continuation rather than a user lambda
+    cont: bool,
+}
+
+/// What the operands of a compiled body refer to while it runs
+struct Locals<'a> {
+    closure: Gc,
+    args: &'a [Value],
+    lets: &'a [Value],
+}
+
+impl Operand {
+    fn value(&self, machine: &mut Machine, locals: &Locals) -> Result<Value, Error> {
+        match self {
+            Operand::Arg(index) => Ok(locals.args[*index].clone()),
+            Operand::Let(index) => Ok(locals.lets[*index].clone()),
+            Operand::Captured(index) => Ok(machine.captured(locals.closure, *index)),
+            Operand::Global(global) => global.value(machine),
+            Operand::Value(value) => Ok(value.clone()),
+            Operand::Lambda(lambda) => {
+                let mut captures = Vec::with_capacity(lambda.captures.len());
+                for capture in &lambda.captures {
+                    captures.push(capture.value(machine, locals)?);
+                }
+
+                Ok(lambda.close(machine, captures))
+            }
+        }
+    }
+
+    /// The value of an operand outside of any lambda
+    fn constant(&self, machine: &mut Machine) -> Result<Value, Error> {
+        match self {
+            Operand::Global(global) => global.value(machine),
+            Operand::Value(value) => Ok(value.clone()),
+            Operand::Lambda(lambda) => Ok(lambda.close(machine, Vec::new())),
+            Operand::Arg(_) | Operand::Let(_) | Operand::Captured(_) => {
+                unreachable!("a variable bound outside of every lambda")
+            }
+        }
+    }
+}
+
+impl Lambda {
+    fn close(&self, machine: &mut Machine, captures: Vec<Value>) -> Value {
+        let gc = machine.alloc_compiled(self.body.clone(), captures);
+
+        match self.cont {
+            true => Value::Cont(gc),
+            false => Value::Closure(gc),
+        }
+    }
+}
+
+impl Global {
+    fn value(&self, machine: &Machine) -> Result<Value, Error> {
+        let slot = match self.slot.get() {
+            Some((id, slot)) if id == machine.id() => slot,
+            // slots are only made when a global is first defined, so one
+            // that is missing is looked for again next time
+            _ => {
+                let slot = machine
+                    .global_slot(&self.name)
+                    .ok_or_else(|| unbound(&self.var))?;
+                self.slot.set(Some((machine.id(), slot)));
+                slot
+            }
+        };
+
+        Ok(machine.global_at(slot).clone())
+    }
+}
+
+/// The last call of a compiled body, made once its `let`s are bound
+enum Tail {
+    One(Operand, Operand),
+    Two(Operand, Operand, Operand, Operand, Option<Rc<CallSite>>),
+    Raise(Operand, Operand, Span, Option<Operand>),
+}
+
+impl Tail {
+    fn call(&self, machine: &mut Machine, locals: &Locals) -> Result<Call, Error> {
+        match self {
+            Tail::One(k, v) => Ok(Call::One(
+                k.value(machine, locals)?,
+                v.value(machine, locals)?,
+            )),
+            Tail::Two(f, v, k, h, site) => {
+                let f = f.value(machine, locals)?;
+                let v = v.value(machine, locals)?;
+                let k = k.value(machine, locals)?;
+                machine.mark_site(&k, site);
+
+                Ok(Call::Two(f, v, k, h.value(machine, locals)?))
+            }
+            Tail::Raise(h, v, span, k) => {
+                let h = h.value(machine, locals)?;
+                let v = v.value(machine, locals)?;
+                let k = match k {
+                    Some(k) => Some(k.value(machine, locals)?),
+                    None => None,
+                };
+
+                Ok(Call::One(h, machine.raise(v, Some(*span), k)))
+            }
+        }
+    }
+}
+
+/// The binders and lambdas around the expression being compiled
+#[derive(Default)]
+struct Compiler {
+    /// Innermost last, so a bound variable's scope offset counts back from
+    /// the end
+    binders: Vec<Binder>,
+    /// Innermost last
+    lambdas: Vec<Frame>,
+}
+
+#[derive(Clone)]
+enum Binder {
+    /// The `slot`th local of the `lambda`th lambda: its arguments by scope
+    /// offset, then its `let`s
+    Local { lambda: usize, slot: usize },
+    /// A `let` of a literal, primitive or global, which is used directly
+    Constant(FExpr),
+}
+
+struct Frame {
+    /// How many arguments it takes: one for a continuation, three for a user
+    /// lambda
+    params: usize,
+    /// The locals of the lambdas around it that it refers to, in the order
+    /// it captures them
+    captures: Vec<(usize, usize)>,
+}
+
+impl Compiler {
+    fn operand(&mut self, expr: &FExpr) -> Result<Operand, Error> {
+        Ok(match expr {
+            FExpr::LamOne(Scope {
+                unsafe_body: body, ..
+            }) => self.lambda(1, body)?,
+            FExpr::LamTwo(Scope {
+                unsafe_body:
+                    Scope {
+                        unsafe_body:
+                            Scope {
+                                unsafe_body: body, ..
+                            },
+                        ..
+                    },
+                ..
+            }) => self.lambda(3, body)?,
+            FExpr::Var(var @ Var::Bound(b)) => match self.binder(b.scope) {
+                Some(binder) => self.resolve(binder)?,
+                None => return Err(unbound(var)),
+            },
+            FExpr::Var(var @ Var::Free(f)) => Operand::Global(Global {
+                name: f.pretty_name.clone().unwrap_or_default(),
+                var: var.clone(),
+                slot: Cell::new(None),
+            }),
+            FExpr::Lit(Ignore(l)) => Operand::Value(Value::Lit(l.clone())),
+            FExpr::Prim(Ignore(p)) => Operand::Value(Value::Prim(*p)),
+            FExpr::CallOne(..) | FExpr::CallTwo(..) | FExpr::Raise(..) => {
+                return Err(Error::NotACall)
+            }
+        })
+    }
+
+    fn binder(&self, scope: ScopeOffset) -> Option<Binder> {
+        let index = self.binders.len().checked_sub(scope.0 as usize + 1)?;
+
+        Some(self.binders[index].clone())
+    }
+
+    fn resolve(&mut self, binder: Binder) -> Result<Operand, Error> {
+        match binder {
+            Binder::Local { lambda, slot } => Ok(self.local(self.lambdas.len() - 1, lambda, slot)),
+            Binder::Constant(expr) => self.operand(&expr),
+        }
+    }
+
+    /// A local of the `lambda`th lambda, used in the `from`th, which
+    /// captures it if it is bound outside of it
+    fn local(&mut self, from: usize, lambda: usize, slot: usize) -> Operand {
+        let frame = &mut self.lambdas[from];
+        if lambda == from {
+            return match slot.checked_sub(frame.params) {
+                Some(index) => Operand::Let(index),
+                None => Operand::Arg(slot),
+            };
+        }
+
+        match frame.captures.iter().position(|&c| c == (lambda, slot)) {
+            Some(index) => Operand::Captured(index),
+            None => {
+                frame.captures.push((lambda, slot));
+                Operand::Captured(frame.captures.len() - 1)
+            }
+        }
+    }
+
+    fn lambda(&mut self, params: usize, body: &FExpr) -> Result<Operand, Error> {
+        let lambda = self.lambdas.len();
+        self.lambdas.push(Frame {
+            params,
+            captures: Vec::new(),
+        });
+        // arguments are bound outermost first, so the last is at offset 0
+        for slot in (0..params).rev() {
+            self.binders.push(Binder::Local { lambda, slot });
+        }
+
+        // a continuation keeps the continuation of the function it is part
+        // of, for backtraces
+        let returns = match params {
+            1 => match backtrace::continuation_offset(body, 1) {
+                Some(offset) => match self.binder(ScopeOffset(offset.0 + 1)) {
+                    Some(binder) => Some(self.resolve(binder)?),
+                    None => None,
+                },
+                None => None,
+            },
+            _ => None,
+        };
+
+        let mut lets = Vec::new();
+        let tail = self.body(body, &mut lets)?;
+        let lets_len = lets.len();
+        let scratch = RefCell::new(Vec::with_capacity(lets_len));
+        let code: Code = Box::new(move |machine, closure, args| {
+            let mut values = scratch.borrow_mut();
+            let mut run = || {
+                for l in &lets {
+                    let locals = Locals {
+                        closure,
+                        args,
+                        lets: &values,
+                    };
+                    let value = l.value(machine, &locals)?;
+                    values.push(value);
+                }
+
+                let locals = Locals {
+                    closure,
+                    args,
+                    lets: &values,
+                };
+                tail.call(machine, &locals)
+            };
+            let call = run();
+            values.clear();
+
+            call
+        });
+
+        self.binders.truncate(self.binders.len() - params);
+        let frame = self.lambdas.pop().unwrap();
+        let captures = frame
+            .captures
+            .into_iter()
+            .map(|(lambda, slot)| self.local(self.lambdas.len() - 1, lambda, slot))
+            .collect();
+        let returns = match returns {
+            Some(Operand::Captured(index)) => Some(index),
+            _ => None,
+        };
+
+        Ok(Operand::Lambda(Lambda {
+            body: Rc::new(CompiledBody { code, returns }),
+            captures,
+            cont: params == 1,
+        }))
+    }
+
+    /// Compile a lambda body, binding the continuations it calls straight
+    /// away as `lets` of the body rather than making closures of them
+    fn body(&mut self, body: &FExpr, lets: &mut Vec<Operand>) -> Result<Tail, Error> {
+        Ok(match body {
+            FExpr::CallOne(k, v) => match &**k {
+                FExpr::LamOne(Scope {
+                    unsafe_body: rest, ..
+                }) => {
+                    let binder = match &**v {
+                        FExpr::Var(var @ Var::Bound(b)) => {
+                            self.binder(b.scope).ok_or_else(|| unbound(var))?
+                        }
+                        FExpr::Var(_) | FExpr::Lit(_) | FExpr::Prim(_) => {
+                            Binder::Constant((**v).clone())
+                        }
+                        _ => {
+                            let lambda = self.lambdas.len() - 1;
+                            let slot = self.lambdas[lambda].params + lets.len();
+                            lets.push(self.operand(v)?);
+                            Binder::Local { lambda, slot }
+                        }
+                    };
+
+                    self.binders.push(binder);
+                    let tail = self.body(rest, lets);
+                    self.binders.pop();
+
+                    tail?
+                }
+                _ => Tail::One(self.operand(k)?, self.operand(v)?),
+            },
+            FExpr::CallTwo(f, v, k, h, Ignore(site)) => {
+                let site = match **k {
+                    FExpr::LamOne(_) => site.clone(),
+                    _ => None,
+                };
+
+                Tail::Two(
+                    self.operand(f)?,
+                    self.operand(v)?,
+                    self.operand(k)?,
+                    self.operand(h)?,
+                    site,
+                )
+            }
+            FExpr::Raise(h, v, Ignore(span)) => {
+                // the continuation of the function raising, for backtraces
+                let k = match backtrace::continuation_offset(body, 0) {
+                    Some(offset) => match self.binder(offset) {
+                        Some(binder) => Some(self.resolve(binder)?),
+                        None => None,
+                    },
+                    None => None,
+                };
+
+                Tail::Raise(self.operand(h)?, self.operand(v)?, *span, k)
+            }
+            _ => return Err(Error::NotACall),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, expr::Expr, literals::Literal, parse, utils::test::*};
+
+    fn assert_matches_interpreter(expr: Expr) {
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let mut machine = Machine::new();
+        machine.register("id", |_, v| Ok(v));
+
+        let expected = machine.run(&program).unwrap();
+        let compiled = compile(&program).unwrap().run(&mut machine).unwrap();
+
+        assert_eq!(compiled.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        assert_matches_interpreter(lit(Literal::Int(1)));
+        assert_matches_interpreter(lam("x", |x| x));
+
+        // ((lambda (f) (f (f "x"))) id)
+        let twice = lam("f", |f| {
+            app(f.clone(), app(f, lit(Literal::String("x".to_owned()))))
+        });
+        assert_matches_interpreter(app(twice, global("id")));
+
+        // (try ((lambda (x) (raise x)) 1) (catch (e) (id e)))
+        let throws = app(lam("x", |x| raise(x, 0, 1)), lit(Literal::Int(1)));
+        assert_matches_interpreter(try_catch(throws, "e", |e| app(global("id"), e)));
+    }
+
+    #[test]
+    fn backtraces_match_interpreter() {
+        let src = "
+(define inner (lambda (x) (begin (raise x) x)))
+(define outer (lambda (y) (begin (inner y) y)))
+(begin (outer \"a\") \"done\")";
+        let program = cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+
+        let mut machine = Machine::new();
+        assert!(machine.run(&program).is_err());
+        let expected = machine.backtrace().to_vec();
+        assert_eq!(expected.len(), 3);
+
+        let mut machine = Machine::new();
+        assert!(compile(&program).unwrap().run(&mut machine).is_err());
+        assert_eq!(machine.backtrace(), &expected[..]);
+    }
+
+    #[test]
+    fn globals_are_looked_up_on_each_machine() {
+        let program = cont_expr::program(app(global("f"), lit(Literal::Int(1)))).into_fexpr();
+        let compiled = compile(&program).unwrap();
+
+        let mut first = Machine::new();
+        first.register("f", |_, _| Ok(Value::Lit(Literal::Int(2))));
+        let mut second = Machine::new();
+        second.define("g", Value::Lit(Literal::Void));
+        second.register("f", |_, _| Ok(Value::Lit(Literal::Int(3))));
+
+        for _ in 0..2 {
+            assert_eq!(compiled.run(&mut first).unwrap().to_string(), "2");
+            assert_eq!(compiled.run(&mut second).unwrap().to_string(), "3");
+        }
+    }
+
+    #[test]
+    fn deep_recursion_runs_in_constant_stack() {
+        let program = cont_expr::program(deep_recursion(5)).into_fexpr();
+
+        let result = compile(&program).unwrap().run(&mut Machine::new());
+
+        assert_eq!(result.unwrap().to_string(), "void");
+    }
+
+    #[test]
+    fn step_limit_stops_infinite_loops() {
+        let program = compile(&cont_expr::program(omega()).into_fexpr()).unwrap();
+
+        let mut machine = Machine::new();
+        machine.set_step_limit(Some(2_000_000));
+
+        match program.run(&mut machine) {
+            Err(Error::StepLimitExceeded(2_000_000)) => (),
+            r => panic!("expected the step limit to be hit, got {:?}", r),
+        }
+    }
+}
@@ -2,23 +2,34 @@ use moniker::BoundTerm;
 use moniker::{Binder, FreeVar, Ignore, Scope, Var};
 
 use pretty::{BoxAllocator, DocAllocator, DocBuilder};
-use termcolor::{Color, ColorSpec, WriteColor};
+use termcolor::WriteColor;
 
 use std::{io::Result, rc::Rc};
 
-use crate::{utils::clone_rc, expr::Expr, flat_expr::FExpr, literals::Literal};
+use crate::{
+    expr::Expr,
+    flat_expr::{FExpr, UserScope},
+    literals::Literal,
+    prim::Prim,
+    render::{RenderOptions, Style},
+    span::{CallSite, Span},
+    utils::clone_rc,
+};
 
+/// A value in CPS form, user lambdas take their argument along with a
+/// continuation to return to and a handler continuation to raise to
 #[derive(Debug, Clone, BoundTerm)]
 pub enum UExpr {
-    Lam(Scope<Binder<String>, Scope<Binder<String>, Rc<CCall>>>),
+    Lam(UserScope<Rc<CCall>>),
     Var(Var<String>),
     Lit(Ignore<Literal>),
+    Prim(Ignore<Prim>),
 }
 
 impl UExpr {
-    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, ColorSpec>
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D, indent: isize) -> DocBuilder<'a, D, Style>
     where
-        D: DocAllocator<'a, ColorSpec>,
+        D: DocAllocator<'a, Style>,
         D::Doc: Clone,
     {
         match self {
@@ -28,29 +39,38 @@ impl UExpr {
                     unsafe_body:
                         Scope {
                             unsafe_pattern: cont,
-                            unsafe_body: body,
+                            unsafe_body:
+                                Scope {
+                                    unsafe_pattern: handler,
+                                    unsafe_body: body,
+                                },
                         },
                 } = &s;
 
                 let pat_pret = allocator
                     .as_string(pat)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Green)).clone());
+                    .annotate(Style::Binder);
                 let cont_pret = allocator
                     .as_string(cont)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Red)).clone());
+                    .annotate(Style::Continuation);
+                let handler_pret = allocator
+                    .as_string(handler)
+                    .annotate(Style::Continuation);
                 let args_pret = pat_pret
                     .append(allocator.space())
                     .append(cont_pret)
+                    .append(allocator.space())
+                    .append(handler_pret)
                     .parens();
                 let body_pret = allocator
                     .line_()
-                    .append(body.pretty(allocator))
-                    .nest(1)
+                    .append(body.pretty(allocator, indent))
+                    .nest(indent)
                     .group();
 
                 allocator
                     .text("lambda")
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Magenta)).clone())
+                    .annotate(Style::Keyword)
                     .append(allocator.space())
                     .append(args_pret)
                     .append(allocator.space())
@@ -59,9 +79,18 @@ impl UExpr {
             }
             UExpr::Var(s) => allocator.as_string(s),
             UExpr::Lit(Ignore(l)) => l.pretty(allocator),
+            UExpr::Prim(Ignore(p)) => allocator
+                .text(p.name())
+                .annotate(Style::Prim),
         }
     }
 
+    /// Print in the format of the options' target, fitting lines into its
+    /// width where possible
+    pub fn pretty_print(&self, out: impl WriteColor, options: &RenderOptions) -> Result<()> {
+        options.render(self.pretty(&BoxAllocator, options.indent), out)
+    }
+
     pub fn into_fexpr(self) -> FExpr {
         match self {
             UExpr::Lam(s) => {
@@ -70,7 +99,11 @@ impl UExpr {
                     unsafe_body:
                         Scope {
                             unsafe_pattern: cont,
-                            unsafe_body: body,
+                            unsafe_body:
+                                Scope {
+                                    unsafe_pattern: handler,
+                                    unsafe_body: body,
+                                },
                         },
                 } = s;
 
@@ -78,12 +111,16 @@ impl UExpr {
                     unsafe_pattern: pat,
                     unsafe_body: Scope {
                         unsafe_pattern: cont,
-                        unsafe_body: Rc::new(clone_rc(body).into_fexpr()),
+                        unsafe_body: Scope {
+                            unsafe_pattern: handler,
+                            unsafe_body: Rc::new(clone_rc(body).into_fexpr()),
+                        },
                     },
                 })
             }
             UExpr::Var(s) => FExpr::Var(s),
             UExpr::Lit(l) => FExpr::Lit(l),
+            UExpr::Prim(p) => FExpr::Prim(p),
         }
     }
 }
@@ -96,9 +133,9 @@ pub enum KExpr {
 }
 
 impl KExpr {
-    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, ColorSpec>
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D, indent: isize) -> DocBuilder<'a, D, Style>
     where
-        D: DocAllocator<'a, ColorSpec>,
+        D: DocAllocator<'a, Style>,
         D::Doc: Clone,
     {
         match self {
@@ -110,17 +147,17 @@ impl KExpr {
 
                 let pat_pret = allocator
                     .as_string(pat)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Green)).clone())
+                    .annotate(Style::Binder)
                     .parens();
                 let body_pret = allocator
                     .line_()
-                    .append(body.pretty(allocator))
-                    .nest(1)
+                    .append(body.pretty(allocator, indent))
+                    .nest(indent)
                     .group();
 
                 allocator
                     .text("lambda")
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Magenta)).clone())
+                    .annotate(Style::Keyword)
                     .append(allocator.space())
                     .append(pat_pret)
                     .append(allocator.space())
@@ -153,127 +190,280 @@ impl KExpr {
 
 #[derive(Debug, Clone, BoundTerm)]
 pub enum CCall {
-    UCall(Rc<UExpr>, Rc<UExpr>, Rc<KExpr>),
+    /// Call a user lambda, with the source call this came from, which is
+    /// what the continuation returns to if it is a lambda
+    UCall(
+        Rc<UExpr>,
+        Rc<UExpr>,
+        Rc<KExpr>,
+        Rc<KExpr>,
+        Ignore<Option<Rc<CallSite>>>,
+    ),
     KCall(Rc<KExpr>, Rc<UExpr>),
+    /// Pass a value to a handler continuation as an exception raised at the
+    /// span
+    Raise(Rc<KExpr>, Rc<UExpr>, Ignore<Span>),
 }
 
 impl CCall {
-    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, ColorSpec>
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D, indent: isize) -> DocBuilder<'a, D, Style>
     where
-        D: DocAllocator<'a, ColorSpec>,
+        D: DocAllocator<'a, Style>,
         D::Doc: Clone,
     {
         match self {
-            CCall::UCall(f, v, c) => {
-                let f_pret = f.pretty(allocator);
-                let v_pret = v.pretty(allocator);
-                let c_pret = c.pretty(allocator);
+            CCall::UCall(f, v, c, h, _) => {
+                let f_pret = f.pretty(allocator, indent);
+                let v_pret = v.pretty(allocator, indent);
+                let c_pret = c.pretty(allocator, indent);
+                let h_pret = h.pretty(allocator, indent);
 
                 f_pret
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Blue)).clone())
+                    .annotate(Style::Callee)
                     .append(allocator.space())
                     .append(v_pret)
                     .append(allocator.space())
                     .append(c_pret)
+                    .append(allocator.space())
+                    .append(h_pret)
                     .parens()
             }
 
             CCall::KCall(f, c) => {
-                let f_pret = f.pretty(allocator);
-                let c_pret = c.pretty(allocator);
+                let f_pret = f.pretty(allocator, indent);
+                let c_pret = c.pretty(allocator, indent);
 
                 f_pret
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Blue)).clone())
+                    .annotate(Style::Callee)
                     .append(allocator.space())
                     .append(c_pret)
                     .parens()
             }
+
+            CCall::Raise(h, v, _) => allocator
+                .text("raise")
+                .annotate(Style::Keyword)
+                .append(allocator.space())
+                .append(h.pretty(allocator, indent))
+                .append(allocator.space())
+                .append(v.pretty(allocator, indent))
+                .parens(),
         }
     }
 
-    pub fn pretty_print(&self, out: impl WriteColor) -> Result<()> {
-        let allocator = BoxAllocator;
-
-        self.pretty(&allocator).1.render_colored(70, out)?;
-
-        Ok(())
+    /// Print in the format of the options' target, fitting lines into its
+    /// width where possible
+    pub fn pretty_print(&self, out: impl WriteColor, options: &RenderOptions) -> Result<()> {
+        options.render(self.pretty(&BoxAllocator, options.indent), out)
     }
 
     pub fn into_fexpr(self) -> FExpr {
         match self {
-            CCall::UCall(f, v, c) => FExpr::CallTwo(
+            CCall::UCall(f, v, c, h, site) => FExpr::CallTwo(
                 Rc::new(clone_rc(f).into_fexpr()),
                 Rc::new(clone_rc(v).into_fexpr()),
                 Rc::new(clone_rc(c).into_fexpr()),
+                Rc::new(clone_rc(h).into_fexpr()),
+                site,
             ),
             CCall::KCall(f, v) => FExpr::CallOne(
                 Rc::new(clone_rc(f).into_fexpr()),
                 Rc::new(clone_rc(v).into_fexpr()),
             ),
+            CCall::Raise(h, v, span) => FExpr::Raise(
+                Rc::new(clone_rc(h).into_fexpr()),
+                Rc::new(clone_rc(v).into_fexpr()),
+                span,
+            ),
         }
     }
 }
 
-pub fn t_k(expr: Expr, k: Rc<KExpr>) -> CCall {
+/// Convert a whole program, the result is a function that ignores its
+/// argument and expects the continuation to exit the program with, followed
+/// by the handler for exceptions nothing in the program caught
+pub fn program(expr: Expr) -> UExpr {
+    let exit = FreeVar::fresh_named("exit");
+    let abort = FreeVar::fresh_named("abort");
+    let body = t_k(
+        expr,
+        Rc::new(KExpr::Var(Var::Free(exit.clone()))),
+        abort.clone(),
+    );
+
+    lam(FreeVar::fresh_named("_"), exit, abort, body)
+}
+
+fn lam(
+    pat: FreeVar<String>,
+    cont: FreeVar<String>,
+    handler: FreeVar<String>,
+    body: CCall,
+) -> UExpr {
+    UExpr::Lam(Scope::new(
+        Binder(pat),
+        Scope::new(Binder(cont), Scope::new(Binder(handler), Rc::new(body))),
+    ))
+}
+
+fn var(v: FreeVar<String>) -> Rc<KExpr> {
+    Rc::new(KExpr::Var(Var::Free(v)))
+}
+
+/// Convert `expr`, passing its value to `k` or any exception it raises to the
+/// handler `h`
+///
+/// The handler is always a variable, as it is used once per call in `expr`.
+pub fn t_k(expr: Expr, k: Rc<KExpr>, h: FreeVar<String>) -> CCall {
     match expr {
-        e @ (Expr::Lam(_) | Expr::Var(_) | Expr::Lit(_)) => CCall::KCall(k, Rc::new(m(e))),
-        Expr::App(f, e) => {
+        e @ (Expr::Lam(_) | Expr::Gen(_) | Expr::Async(_) | Expr::Var(_) | Expr::Lit(_)) => {
+            CCall::KCall(k, Rc::new(m(e)))
+        }
+        Expr::App(f, e, Ignore(span)) => {
             let rv_v = FreeVar::fresh_named("rv");
             let cont = Rc::new(KExpr::Lam(Scope::new(
                 Binder(rv_v.clone()),
                 Rc::new(CCall::KCall(k, Rc::new(UExpr::Var(Var::Free(rv_v))))),
             )));
 
-            let f_v = FreeVar::fresh_named("f");
-            let e_v = FreeVar::fresh_named("e");
+            app(clone_rc(f), clone_rc(e), span, cont, h)
+        }
+        Expr::Yield(e) => t_k(Expr::App(Rc::new(yield_var()), e, Ignore(None)), k, h),
+        Expr::Await(e) => await_(clone_rc(e), k, h),
+        e @ (Expr::Raise(..) | Expr::Try(..)) => control(e, k, h),
+    }
+}
 
-            t_k(
-                clone_rc(f),
+fn t_c(expr: Expr, c: FreeVar<String>, h: FreeVar<String>) -> CCall {
+    let c_v = var(c);
+    match expr {
+        e @ (Expr::Lam(_) | Expr::Gen(_) | Expr::Async(_) | Expr::Var(_) | Expr::Lit(_)) => {
+            CCall::KCall(c_v, Rc::new(m(e)))
+        }
+        Expr::App(f, e, Ignore(span)) => app(clone_rc(f), clone_rc(e), span, c_v, h),
+        Expr::Yield(e) => app(yield_var(), clone_rc(e), None, c_v, h),
+        Expr::Await(e) => await_(clone_rc(e), c_v, h),
+        e @ (Expr::Raise(..) | Expr::Try(..)) => control(e, c_v, h),
+    }
+}
+
+fn app(f: Expr, e: Expr, span: Option<Span>, k: Rc<KExpr>, h: FreeVar<String>) -> CCall {
+    let f_v = FreeVar::fresh_named("f");
+    let e_v = FreeVar::fresh_named("e");
+    let site = span.map(|span| {
+        let callee = match &f {
+            Expr::Var(v) => v.pretty_name().cloned(),
+            _ => None,
+        };
+
+        Rc::new(CallSite { span, callee })
+    });
+
+    t_k(
+        f,
+        Rc::new(KExpr::Lam(Scope::new(
+            Binder(f_v.clone()),
+            Rc::new(t_k(
+                e,
                 Rc::new(KExpr::Lam(Scope::new(
-                    Binder(f_v.clone()),
-                    Rc::new(t_k(
-                        clone_rc(e),
-                        Rc::new(KExpr::Lam(Scope::new(
-                            Binder(e_v.clone()),
-                            Rc::new(CCall::UCall(
-                                Rc::new(UExpr::Var(Var::Free(f_v))),
-                                Rc::new(UExpr::Var(Var::Free(e_v))),
-                                cont,
-                            )),
-                        ))),
+                    Binder(e_v.clone()),
+                    Rc::new(CCall::UCall(
+                        Rc::new(UExpr::Var(Var::Free(f_v))),
+                        Rc::new(UExpr::Var(Var::Free(e_v))),
+                        k,
+                        var(h.clone()),
+                        Ignore(site),
                     )),
                 ))),
-            )
-        }
-    }
+                h.clone(),
+            )),
+        ))),
+        h,
+    )
+}
+
+/// Awaiting a task is joining it with the current continuation, so the task
+/// waiting is suspended by just not calling it until the result is ready
+fn await_(task: Expr, k: Rc<KExpr>, h: FreeVar<String>) -> CCall {
+    let t_v = FreeVar::fresh_named("t");
+
+    t_k(
+        task,
+        Rc::new(KExpr::Lam(Scope::new(
+            Binder(t_v.clone()),
+            Rc::new(CCall::UCall(
+                Rc::new(UExpr::Prim(Ignore(Prim::Join))),
+                Rc::new(UExpr::Var(Var::Free(t_v))),
+                k,
+                var(h.clone()),
+                Ignore(None),
+            )),
+        ))),
+        h,
+    )
 }
 
-fn t_c(expr: Expr, c: FreeVar<String>) -> CCall {
-    let c_v = Rc::new(KExpr::Var(Var::Free(c)));
+/// `raise` and `try`, `k` is used at most once
+fn control(expr: Expr, k: Rc<KExpr>, h: FreeVar<String>) -> CCall {
     match expr {
-        e @ (Expr::Lam(_) | Expr::Var(_) | Expr::Lit(_)) => CCall::KCall(c_v, Rc::new(m(e))),
-        Expr::App(f, e) => {
-            let f_v = FreeVar::fresh_named("f");
+        Expr::Raise(e, span) => {
             let e_v = FreeVar::fresh_named("e");
 
             t_k(
-                clone_rc(f),
+                clone_rc(e),
                 Rc::new(KExpr::Lam(Scope::new(
-                    Binder(f_v.clone()),
-                    Rc::new(t_k(
-                        clone_rc(e),
-                        Rc::new(KExpr::Lam(Scope::new(
-                            Binder(e_v.clone()),
-                            Rc::new(CCall::UCall(
-                                Rc::new(UExpr::Var(Var::Free(f_v))),
-                                Rc::new(UExpr::Var(Var::Free(e_v))),
-                                c_v,
-                            )),
-                        ))),
+                    Binder(e_v.clone()),
+                    Rc::new(CCall::Raise(
+                        var(h.clone()),
+                        Rc::new(UExpr::Var(Var::Free(e_v))),
+                        span,
                     )),
                 ))),
+                h,
             )
         }
+        Expr::Try(body, handler) => {
+            // the continuation is needed both after the body and after the
+            // handler, so first call a function to bind it to a variable:
+            //
+            // ((lambda (_ j h') ((lambda (_ k' h'') body) void j (lambda (x) handler)))
+            //  void k h)
+            let (exc, handler) = handler.unbind();
+            let (j, outer_h) = (FreeVar::fresh_named("j"), FreeVar::fresh_named("h"));
+            let (inner_k, inner_h) = (FreeVar::fresh_named("k"), FreeVar::fresh_named("h"));
+
+            let inner = lam(
+                FreeVar::fresh_named("_"),
+                inner_k.clone(),
+                inner_h.clone(),
+                t_c(clone_rc(body), inner_k, inner_h),
+            );
+            let catch = KExpr::Lam(Scope::new(
+                exc,
+                Rc::new(t_c(clone_rc(handler), j.clone(), outer_h.clone())),
+            ));
+            let outer = lam(
+                FreeVar::fresh_named("_"),
+                j.clone(),
+                outer_h,
+                CCall::UCall(
+                    Rc::new(inner),
+                    Rc::new(UExpr::Lit(Ignore(Literal::Void))),
+                    var(j),
+                    Rc::new(catch),
+                    Ignore(None),
+                ),
+            );
+
+            CCall::UCall(
+                Rc::new(outer),
+                Rc::new(UExpr::Lit(Ignore(Literal::Void))),
+                k,
+                var(h),
+                Ignore(None),
+            )
+        }
+        _ => unreachable!(),
     }
 }
 
@@ -282,11 +472,88 @@ fn m(expr: Expr) -> UExpr {
         Expr::Lam(s) => {
             let (p, t) = s.unbind();
             let k = FreeVar::fresh_named("k");
-            let body = t_c(clone_rc(t), k.clone());
-            UExpr::Lam(Scope::new(p, Scope::new(Binder(k), Rc::new(body))))
+            let h = FreeVar::fresh_named("h");
+            let body = t_c(clone_rc(t), k.clone(), h.clone());
+
+            UExpr::Lam(Scope::new(
+                p,
+                Scope::new(Binder(k), Scope::new(Binder(h), Rc::new(body))),
+            ))
+        }
+        Expr::Gen(s) => {
+            // (lambda (x) (%generator (lambda (yield) body)))
+            let (p, t) = s.unbind();
+            let y = FreeVar::fresh_named("yield");
+            let body = yields_to(clone_rc(t), &y);
+            let (k, h) = (FreeVar::fresh_named("k"), FreeVar::fresh_named("h"));
+
+            lam(
+                p.0,
+                k.clone(),
+                h.clone(),
+                CCall::UCall(
+                    Rc::new(UExpr::Prim(Ignore(Prim::Generator))),
+                    Rc::new(m(Expr::Lam(Scope::new(Binder(y), Rc::new(body))))),
+                    var(k),
+                    var(h),
+                    Ignore(None),
+                ),
+            )
+        }
+        Expr::Async(s) => {
+            // (lambda (x) (spawn (lambda (_) body)))
+            let (p, t) = s.unbind();
+            let (k, h) = (FreeVar::fresh_named("k"), FreeVar::fresh_named("h"));
+            let task = Expr::Lam(Scope::new(Binder(FreeVar::fresh_named("_")), t));
+
+            lam(
+                p.0,
+                k.clone(),
+                h.clone(),
+                CCall::UCall(
+                    Rc::new(UExpr::Prim(Ignore(Prim::Spawn))),
+                    Rc::new(m(task)),
+                    var(k),
+                    var(h),
+                    Ignore(None),
+                ),
+            )
         }
         Expr::Var(v) => UExpr::Var(v),
         Expr::Lit(v) => UExpr::Lit(v),
         _ => unreachable!(),
     }
 }
+
+/// A `yield` outside of any generator calls whatever `yield` is bound to in
+/// the environment
+fn yield_var() -> Expr {
+    Expr::Var(Var::Free(FreeVar::fresh_named("yield")))
+}
+
+/// Replace each `Yield` in a generator's body with a call to `y`, not looking
+/// inside nested generators, which have their own, or async functions, whose
+/// bodies run as tasks of their own
+fn yields_to(expr: Expr, y: &FreeVar<String>) -> Expr {
+    let go = |e: Rc<Expr>| Rc::new(yields_to(clone_rc(e), y));
+
+    match expr {
+        Expr::Lam(s) => {
+            let (p, t) = s.unbind();
+            Expr::Lam(Scope::new(p, go(t)))
+        }
+        Expr::App(f, e, span) => Expr::App(go(f), go(e), span),
+        Expr::Raise(e, span) => Expr::Raise(go(e), span),
+        Expr::Try(body, handler) => {
+            let (p, t) = handler.unbind();
+            Expr::Try(go(body), Scope::new(p, go(t)))
+        }
+        Expr::Yield(e) => Expr::App(
+            Rc::new(Expr::Var(Var::Free(y.clone()))),
+            go(e),
+            Ignore(None),
+        ),
+        Expr::Await(e) => Expr::Await(go(e)),
+        e @ (Expr::Gen(_) | Expr::Async(_) | Expr::Var(_) | Expr::Lit(_)) => e,
+    }
+}
@@ -0,0 +1,1015 @@
+use serde::{
+    de::{
+        self, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer,
+        MapAccess, SeqAccess, VariantAccess, Visitor,
+    },
+    forward_to_deserialize_any,
+    ser::{
+        self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+        SerializeTuple, SerializeTupleStruct, SerializeTupleVariant, Serializer,
+    },
+};
+
+use std::{collections::btree_map, convert::TryFrom, fmt, slice};
+
+use crate::{
+    eval::{Error, Machine, Value},
+    literals::Literal,
+    stdlib::{self, Map},
+};
+
+/// Convert a Rust value to a script value, allocating its lists and maps on
+/// `machine`
+///
+/// Structs and maps become maps, sequences and tuples become lists, `()` and
+/// `None` become void and `bool`s become 1 or 0. Negative integers become
+/// floats, as ints can't go below zero. An enum variant becomes its name if
+/// it has no fields, and a map from its name to its fields otherwise.
+///
+/// The value must be rooted or defined before the machine runs anything if
+/// it is held onto.
+pub fn to_value<T: Serialize + ?Sized>(machine: &mut Machine, value: &T) -> Result<Value, Error> {
+    value.serialize(ValueSerializer { machine })
+}
+
+/// Convert a script value to a Rust value, laid out as `to_value` would
+/// have made it
+///
+/// Functions and other values that aren't data fail to convert, as does
+/// anything that doesn't fit the type, with an `Error::Host` saying why.
+pub fn from_value<T: DeserializeOwned>(machine: &Machine, value: &Value) -> Result<T, Error> {
+    T::deserialize(ValueDeserializer { machine, value })
+}
+
+/// The script value of a JSON document
+///
+/// Objects become maps, keeping the last of any repeated keys, arrays become
+/// lists, null becomes void and booleans become 1 or 0, as `ValueSeed` makes
+/// them from any other format.
+///
+/// Room for the whole document is reserved before any of it is made, so one
+/// that won't fit fails with `Error::OutOfMemory` having allocated nothing.
+pub fn from_json(machine: &mut Machine, src: &str) -> Result<Value, Error> {
+    let json: serde_json::Value =
+        serde_json::from_str(src).map_err(|e| Error::Host(e.to_string()))?;
+    machine.reserve(json_size(&json))?;
+
+    ValueSeed(machine)
+        .deserialize(&json)
+        .map_err(|e| Error::Host(e.to_string()))
+}
+
+/// The bytes the script value of `json` takes up, for `Machine::reserve`
+fn json_size(json: &serde_json::Value) -> usize {
+    use serde_json::Value as Json;
+
+    match json {
+        Json::String(s) => s.len(),
+        Json::Array(items) => items
+            .iter()
+            .fold(stdlib::items_size(items.len()), |size, j| {
+                size.saturating_add(json_size(j))
+            }),
+        Json::Object(fields) => fields.iter().fold(0, |size, (key, value)| {
+            size.saturating_add(key.len() + stdlib::items_size(1) + json_size(value))
+        }),
+        Json::Null | Json::Bool(_) | Json::Number(_) => 0,
+    }
+}
+
+/// A script value written as compact JSON, void as null, lists as arrays
+/// and maps as objects, as `Serializable` writes it to any other format
+pub fn to_json(machine: &Machine, value: &Value) -> Result<String, Error> {
+    serde_json::to_string(&Serializable { machine, value }).map_err(|e| Error::Host(e.to_string()))
+}
+
+/// A script value, along with the machine it lives on, which serializes
+/// as the Rust value `to_value` would have made it from
+pub struct Serializable<'a> {
+    pub machine: &'a Machine,
+    pub value: &'a Value,
+}
+
+impl Serialize for Serializable<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let machine = self.machine;
+        match self.value {
+            Value::Lit(Literal::String(s)) => serializer.serialize_str(s),
+            Value::Lit(Literal::Int(i)) => serializer.serialize_u64(*i),
+            Value::Lit(Literal::Float(f)) => serializer.serialize_f64(*f),
+            Value::Lit(Literal::Void) => serializer.serialize_unit(),
+            value => {
+                if let Some(items) = machine.list(value) {
+                    return serializer
+                        .collect_seq(items.iter().map(|value| Serializable { machine, value }));
+                }
+                if let Some(map) = machine.host_object::<Map>(value) {
+                    return serializer.collect_map(
+                        map.0
+                            .iter()
+                            .map(|(k, value)| (k, Serializable { machine, value })),
+                    );
+                }
+
+                Err(ser::Error::custom(unsupported(machine, value)))
+            }
+        }
+    }
+}
+
+/// Deserializes a script value, allocating its lists and maps on the machine
+///
+/// Values need a machine to live on, so this stands in for a `Deserialize`
+/// implementation for `Value`. Numbers are converted as `to_value` converts
+/// them, and anything else that isn't a string, sequence or map with string
+/// keys fails to deserialize.
+pub struct ValueSeed<'a>(pub &'a mut Machine);
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string, number, sequence or map")
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::Int(b as u64)))
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<Value, E> {
+        signed(i).map_err(E::custom)
+    }
+
+    fn visit_u64<E>(self, i: u64) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::Int(i)))
+    }
+
+    fn visit_f64<E>(self, f: f64) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::Float(f)))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::String(s.to_owned())))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::String(s)))
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Value, E> {
+        let items = bytes.iter().map(|&b| Value::Lit(Literal::Int(b.into())));
+        Ok(self.0.alloc_list(items.collect()))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::Void))
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Lit(Literal::Void))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Value, D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        // nothing is collected until the machine runs again, so the items
+        // don't need rooting
+        let machine = self.0;
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element_seed(ValueSeed(&mut *machine))? {
+            items.push(item);
+        }
+
+        Ok(machine.alloc_list(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut entries: A) -> Result<Value, A::Error> {
+        let machine = self.0;
+        let mut map = Map::default();
+        while let Some(key) = entries.next_key::<String>()? {
+            let value = entries.next_value_seed(ValueSeed(&mut *machine))?;
+            map.0.insert(key, value);
+        }
+
+        Ok(machine.alloc_host(map))
+    }
+}
+
+/// Ints can't be negative, so negative integers are an error rather than
+/// quietly becoming floats
+fn signed(i: i64) -> Result<Value, Error> {
+    match u64::try_from(i) {
+        Ok(i) => Ok(Value::Lit(Literal::Int(i))),
+        Err(_) => Err(Error::Host(format!("can't convert negative int {}", i))),
+    }
+}
+
+fn unsupported(machine: &Machine, value: &Value) -> String {
+    format!(
+        "can't convert a value of type {}",
+        stdlib::type_name(machine, value)
+    )
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Host(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Host(msg.to_string())
+    }
+}
+
+struct ValueSerializer<'a> {
+    machine: &'a mut Machine,
+}
+
+impl<'a> Serializer for ValueSerializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = List<'a>;
+    type SerializeTuple = List<'a>;
+    type SerializeTupleStruct = List<'a>;
+    type SerializeTupleVariant = List<'a>;
+    type SerializeMap = Fields<'a>;
+    type SerializeStruct = Fields<'a>;
+    type SerializeStructVariant = Fields<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        signed(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        Ok(Value::Lit(Literal::Int(v)))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::Lit(Literal::Float(v)))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::Lit(Literal::String(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::Lit(Literal::String(v.to_owned())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        ValueSeed(self.machine).visit_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Lit(Literal::Void))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        let machine = self.machine;
+        let value = value.serialize(ValueSerializer {
+            machine: &mut *machine,
+        })?;
+        Ok(tagged(machine, variant, value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<List<'a>, Error> {
+        Ok(List {
+            machine: self.machine,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<List<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<List<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<List<'a>, Error> {
+        Ok(List {
+            variant: Some(variant),
+            ..self.serialize_seq(Some(len))?
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Fields<'a>, Error> {
+        Ok(Fields {
+            machine: self.machine,
+            map: Map::default(),
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Fields<'a>, Error> {
+        self.serialize_map(None)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Fields<'a>, Error> {
+        Ok(Fields {
+            variant: Some(variant),
+            ..self.serialize_map(None)?
+        })
+    }
+}
+
+/// A map from the name of an enum variant to its fields
+fn tagged(machine: &mut Machine, variant: &str, value: Value) -> Value {
+    let mut map = Map::default();
+    map.0.insert(variant.to_owned(), value);
+    machine.alloc_host(map)
+}
+
+/// The items of a list being serialized
+struct List<'a> {
+    machine: &'a mut Machine,
+    items: Vec<Value>,
+    /// The enum variant the list is the fields of
+    variant: Option<&'static str>,
+}
+
+impl List<'_> {
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let value = value.serialize(ValueSerializer {
+            machine: &mut *self.machine,
+        })?;
+        self.items.push(value);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let list = self.machine.alloc_list(self.items);
+        Ok(match self.variant {
+            Some(variant) => tagged(self.machine, variant, list),
+            None => list,
+        })
+    }
+}
+
+impl SerializeSeq for List<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl SerializeTuple for List<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl SerializeTupleStruct for List<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl SerializeTupleVariant for List<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+/// The entries of a map or struct being serialized
+struct Fields<'a> {
+    machine: &'a mut Machine,
+    map: Map,
+    /// The key of the entry whose value comes next
+    key: Option<String>,
+    /// The enum variant the map is the fields of
+    variant: Option<&'static str>,
+}
+
+impl Fields<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        let value = value.serialize(ValueSerializer {
+            machine: &mut *self.machine,
+        })?;
+        self.map.0.insert(key, value);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let map = self.machine.alloc_host(self.map);
+        Ok(match self.variant {
+            Some(variant) => tagged(self.machine, variant, map),
+            None => map,
+        })
+    }
+}
+
+impl SerializeMap for Fields<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = key.serialize(ValueSerializer {
+            machine: &mut *self.machine,
+        })?;
+        self.key = Some(match key {
+            Value::Lit(Literal::String(s)) => s,
+            // numbers are written as `Key` parses them
+            Value::Lit(number @ (Literal::Int(_) | Literal::Float(_))) => number.to_string(),
+            key => {
+                return Err(ser::Error::custom(format_args!(
+                    "map keys must be strings or numbers, got {}",
+                    stdlib::type_name(self.machine, &key)
+                )))
+            }
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl SerializeStruct for Fields<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl SerializeStructVariant for Fields<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+struct ValueDeserializer<'a> {
+    machine: &'a Machine,
+    value: &'a Value,
+}
+
+impl<'de> Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let machine = self.machine;
+        match self.value {
+            Value::Lit(Literal::String(s)) => visitor.visit_str(s),
+            Value::Lit(Literal::Int(i)) => visitor.visit_u64(*i),
+            // scripts have no negative ints, so a whole negative float
+            // stands in for one
+            Value::Lit(Literal::Float(f))
+                if f.fract() == 0.0 && *f < 0.0 && *f >= i64::MIN as f64 =>
+            {
+                visitor.visit_i64(*f as i64)
+            }
+            Value::Lit(Literal::Float(f)) => visitor.visit_f64(*f),
+            Value::Lit(Literal::Void) => visitor.visit_unit(),
+            value => {
+                if let Some(items) = machine.list(value) {
+                    return visitor.visit_seq(Items {
+                        machine,
+                        items: items.iter(),
+                    });
+                }
+                if let Some(map) = machine.host_object::<Map>(value) {
+                    return visitor.visit_map(Entries {
+                        machine,
+                        entries: map.0.iter(),
+                        value: None,
+                    });
+                }
+
+                Err(de::Error::custom(unsupported(machine, value)))
+            }
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Lit(Literal::Int(0)) => visitor.visit_bool(false),
+            Value::Lit(Literal::Int(1)) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Lit(Literal::Void) => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let machine = self.machine;
+        if let Value::Lit(Literal::String(name)) = self.value {
+            return visitor.visit_enum(name.as_str().into_deserializer());
+        }
+        match machine.host_object::<Map>(self.value) {
+            Some(map) if map.0.len() == 1 => {
+                let (name, value) = map.0.iter().next().unwrap();
+                visitor.visit_enum(Variant {
+                    machine,
+                    name,
+                    value,
+                })
+            }
+            _ => Err(de::Error::custom(format_args!(
+                "expected a variant name or a map with one key, got {}",
+                stdlib::type_name(machine, self.value)
+            ))),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct Items<'a> {
+    machine: &'a Machine,
+    items: slice::Iter<'a, Value>,
+}
+
+impl<'de> SeqAccess<'de> for Items<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        let machine = self.machine;
+        self.items
+            .next()
+            .map(|value| seed.deserialize(ValueDeserializer { machine, value }))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct Entries<'a> {
+    machine: &'a Machine,
+    entries: btree_map::Iter<'a, String, Value>,
+    /// The value of the entry whose key was last given
+    value: Option<&'a Value>,
+}
+
+impl<'de> MapAccess<'de> for Entries<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Key(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .expect("next_value called before next_key");
+        seed.deserialize(ValueDeserializer {
+            machine: self.machine,
+            value,
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// An enum variant written as a map from its name to its fields
+struct Variant<'a> {
+    machine: &'a Machine,
+    name: &'a str,
+    value: &'a Value,
+}
+
+impl<'a> Variant<'a> {
+    fn fields(&self) -> ValueDeserializer<'a> {
+        ValueDeserializer {
+            machine: self.machine,
+            value: self.value,
+        }
+    }
+}
+
+impl<'de, 'a> EnumAccess<'de> for Variant<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let name = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.name))?;
+        Ok((name, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self.fields())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.fields())
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.fields().deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.fields().deserialize_map(visitor)
+    }
+}
+
+/// Parse a map key for a number type, as keys are always strings
+macro_rules! parse_key {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match self.0.parse() {
+                Ok(n) => visitor.$visit(n),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+            }
+        }
+    )*};
+}
+
+/// The key of a map entry
+struct Key<'a>(&'a str);
+
+impl<'de> Deserializer<'de> for Key<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.0)
+    }
+
+    parse_key! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(self.0))
+    }
+
+    forward_to_deserialize_any! {
+        bool i128 u128 char str string bytes byte_buf option unit unit_struct
+        seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, parse};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Player {
+        name: String,
+        score: i32,
+        position: (f64, f64),
+        items: Vec<Item>,
+        friend: Option<Box<Player>>,
+        stats: HashMap<u8, bool>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Item {
+        Key,
+        Coins(u64),
+        Potion { strength: f32 },
+    }
+
+    fn player() -> Player {
+        Player {
+            name: "ann".to_owned(),
+            score: 3,
+            position: (1.5, -2.0),
+            items: vec![Item::Key, Item::Coins(7), Item::Potion { strength: 0.5 }],
+            friend: Some(Box::new(Player {
+                name: "bo".to_owned(),
+                score: 4,
+                position: (0.0, 0.0),
+                items: Vec::new(),
+                friend: None,
+                stats: HashMap::new(),
+            })),
+            stats: vec![(1, true)].into_iter().collect(),
+        }
+    }
+
+    fn run(machine: &mut Machine, src: &str) -> Value {
+        let program = cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+        machine.run(&program).unwrap()
+    }
+
+    #[test]
+    fn converts_rust_values() {
+        let mut machine = Machine::new();
+        stdlib::install(&mut machine);
+
+        let value = to_value(&mut machine, &player()).unwrap();
+        assert_eq!(from_value::<Player>(&machine, &value).unwrap(), player());
+
+        machine.define("player", value);
+        let json = run(&mut machine, "(json-stringify player)");
+        assert_eq!(
+            from_value::<String>(&machine, &json).unwrap(),
+            r#"{"friend":{"friend":null,"items":[],"name":"bo","position":[0.0,0.0],"score":4,"stats":{}},"items":["Key",{"Coins":7},{"Potion":{"strength":0.5}}],"name":"ann","position":[1.5,-2.0],"score":3,"stats":{"1":1}}"#
+        );
+
+        let changed = run(
+            &mut machine,
+            "(assoc (assoc player \"score\" 10) \"items\" (cons \"Key\" nil))",
+        );
+        let changed: Player = from_value(&machine, &changed).unwrap();
+        assert_eq!(changed.score, 10);
+        assert_eq!(changed.items, vec![Item::Key]);
+    }
+
+    #[test]
+    fn reports_values_that_dont_fit() {
+        let mut machine = Machine::new();
+        stdlib::install(&mut machine);
+
+        let error = |machine: &mut Machine, src: &str| {
+            let value = run(machine, src);
+            from_value::<Player>(machine, &value)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&mut machine, "(assoc empty-map \"name\" \"ann\")"),
+            "missing field `score`"
+        );
+        assert_eq!(
+            error(&mut machine, "1"),
+            "invalid type: integer `1`, expected struct Player"
+        );
+        assert_eq!(
+            from_value::<Vec<u8>>(&machine, &Value::Lit(Literal::Float(-1.0)))
+                .unwrap_err()
+                .to_string(),
+            "invalid type: integer `-1`, expected a sequence"
+        );
+
+        let function = run(&mut machine, "(cons +)");
+        assert_eq!(
+            from_value::<Vec<u8>>(&machine, &function)
+                .unwrap_err()
+                .to_string(),
+            "can't convert a value of type function"
+        );
+        assert_eq!(
+            to_json(&machine, &function).unwrap_err().to_string(),
+            "can't convert a value of type function"
+        );
+    }
+
+    #[test]
+    fn copies_values_between_machines() {
+        let mut machine = Machine::new();
+        stdlib::install(&mut machine);
+        let value = run(
+            &mut machine,
+            "(assoc (assoc empty-map \"a\" (cons 1 (cons -2.5 nil))) \"b\" void)",
+        );
+
+        let mut other = Machine::new();
+        let serializable = Serializable {
+            machine: &machine,
+            value: &value,
+        };
+        let copy = to_value(&mut other, &serializable).unwrap();
+        assert_eq!(
+            to_json(&other, &copy).unwrap(),
+            r#"{"a":[1,-2.5],"b":null}"#
+        );
+
+        let deserializer = ValueDeserializer {
+            machine: &machine,
+            value: &value,
+        };
+        let copy = ValueSeed(&mut other).deserialize(deserializer).unwrap();
+        assert_eq!(
+            to_json(&other, &copy).unwrap(),
+            to_json(&machine, &value).unwrap()
+        );
+
+        let json = from_json(&mut other, "[-2.0, 3, true]").unwrap();
+        assert_eq!(from_value(&other, &json).ok(), Some((-2i64, 3u8, true)));
+
+        let negative = from_json(&mut other, "[-2]");
+        assert!(matches!(negative, Err(Error::Host(_))));
+        assert!(to_value(&mut other, &-2i64).is_err());
+    }
+}
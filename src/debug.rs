@@ -0,0 +1,201 @@
+use termcolor::WriteColor;
+
+use std::{io, mem};
+
+use some_embedded_scripting_language::{
+    backtrace::TraceFrame,
+    debugger::{self, Breakpoints, Debugger, Reason, Resume, Stop},
+    eval::{Machine, Value},
+    span::Span,
+};
+
+/// The line and text of a span of `source`, up to the end of the line it
+/// starts on
+pub fn location(source: &str, span: Span) -> String {
+    let line = debugger::line_of(source, span.start);
+
+    match source
+        .get(span.start..span.end)
+        .map(|text| text.split_once('\n'))
+    {
+        Some(Some((first, _))) => format!("line {}: {} ...", line, first),
+        Some(None) => format!("line {}: {}", line, &source[span.start..span.end]),
+        None => format!("line {}", line),
+    }
+}
+
+/// Write the frames of a failed run of `source`, see `Machine::backtrace`
+pub fn backtrace(out: &mut impl WriteColor, source: &str, frames: &[TraceFrame]) -> io::Result<()> {
+    for frame in frames {
+        let location = location(source, frame.span);
+        match &frame.function {
+            Some(function) => writeln!(out, "  in {}, {}", function, location)?,
+            None => writeln!(out, "  {}", location)?,
+        }
+    }
+
+    Ok(())
+}
+
+pub const HELP: &str = "\
+step, s          run to the next call
+continue, c      run to the next breakpoint
+break, b LINE    stop at calls on a line
+delete, d LINE   remove the breakpoint on a line
+locals, l        show the variables in scope
+backtrace, bt    show the calls waiting to return, innermost first
+help, h          show this message";
+
+/// A debugger reading commands a line at a time and writing to a terminal,
+/// showing where it stopped by line and source text
+pub struct Console<'a, I, W> {
+    source: &'a str,
+    commands: I,
+    out: &'a mut W,
+    breakpoints: Breakpoints,
+    /// Stop at the first call, for when there are no breakpoints to run to
+    stop_first: bool,
+}
+
+impl<'a, I, W> Console<'a, I, W>
+where
+    I: Iterator<Item = io::Result<String>>,
+    W: WriteColor,
+{
+    /// A debugger for a program parsed from `source`, stopping at `lines`,
+    /// or at the first call if there are none
+    pub fn new(source: &'a str, lines: &[usize], commands: I, out: &'a mut W) -> Self {
+        let mut console = Console {
+            source,
+            commands,
+            out,
+            breakpoints: Breakpoints::new(),
+            stop_first: lines.is_empty(),
+        };
+        for &line in lines {
+            console.add(line);
+        }
+
+        console
+    }
+
+    fn add(&mut self, line: usize) -> bool {
+        match debugger::line_span(self.source, line) {
+            Some(span) => {
+                self.breakpoints.add(span);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn locals(&mut self, locals: &[(String, Value)]) -> io::Result<()> {
+        for (name, value) in locals {
+            writeln!(self.out, "  {} = {}", name, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Carry out one command, returning how to resume if it resumes
+    fn command(
+        &mut self,
+        command: &str,
+        machine: &Machine,
+        stop: &Stop,
+    ) -> io::Result<Option<Resume>> {
+        let (command, arg) = command
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((command.trim(), ""));
+        let line = arg.trim().parse::<usize>();
+
+        match (command, line) {
+            ("step" | "s" | "", _) => return Ok(Some(Resume::Step)),
+            ("continue" | "c", _) => return Ok(Some(Resume::Continue)),
+            ("break" | "b", Ok(line)) if self.add(line) => {
+                writeln!(self.out, "breakpoint on line {}", line)?
+            }
+            ("delete" | "d", Ok(line)) => {
+                let removed = debugger::line_span(self.source, line)
+                    .is_some_and(|span| self.breakpoints.remove(span));
+                if !removed {
+                    writeln!(self.out, "no breakpoint on line {}", line)?;
+                }
+            }
+            ("break" | "b" | "delete" | "d", _) => {
+                writeln!(self.out, "expected a line of the script, not `{}`", arg)?
+            }
+            ("locals" | "l", _) => self.locals(&stop.locals(machine))?,
+            ("backtrace" | "bt", _) => {
+                for (n, frame) in stop.backtrace(machine).iter().enumerate() {
+                    let location = location(self.source, frame.span);
+                    match &frame.function {
+                        Some(function) => {
+                            writeln!(self.out, "#{} in {}, {}", n, function, location)?
+                        }
+                        None => writeln!(self.out, "#{} {}", n, location)?,
+                    }
+                    self.locals(&frame.locals)?;
+                }
+            }
+            ("help" | "h", _) => writeln!(self.out, "{}", HELP)?,
+            (command, _) => writeln!(self.out, "unknown command `{}`, try `help`", command)?,
+        }
+
+        Ok(None)
+    }
+
+    fn prompt(&mut self, machine: &Machine, stop: &Stop, first: bool) -> io::Result<Resume> {
+        let reason = match stop.reason {
+            _ if first => "stopped",
+            Reason::Breakpoint => "breakpoint",
+            Reason::Step => "step",
+        };
+        writeln!(
+            self.out,
+            "{} at {}",
+            reason,
+            location(self.source, stop.span)
+        )?;
+
+        loop {
+            write!(self.out, "(debug) ")?;
+            self.out.flush()?;
+
+            // running out of commands runs the rest of the program
+            let command = match self.commands.next() {
+                Some(command) => command?,
+                None => {
+                    self.breakpoints = Breakpoints::new();
+                    writeln!(self.out)?;
+                    return Ok(Resume::Continue);
+                }
+            };
+
+            if let Some(resume) = self.command(&command, machine, stop)? {
+                return Ok(resume);
+            }
+        }
+    }
+}
+
+impl<'a, I, W> Debugger for Console<'a, I, W>
+where
+    I: Iterator<Item = io::Result<String>>,
+    W: WriteColor,
+{
+    fn breakpoint(&mut self, span: Span) -> bool {
+        self.stop_first || self.breakpoints.hits(span)
+    }
+
+    fn stop(&mut self, machine: &Machine, stop: &Stop) -> Resume {
+        let first = mem::replace(&mut self.stop_first, false);
+
+        // the debugger can't fail the run, so give up debugging instead
+        self.prompt(machine, stop, first).unwrap_or_else(|_| {
+            self.breakpoints = Breakpoints::new();
+            Resume::Continue
+        })
+    }
+}
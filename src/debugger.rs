@@ -0,0 +1,363 @@
+use moniker::ScopeOffset;
+
+use std::rc::Rc;
+
+use crate::{
+    backtrace,
+    eval::{Env, Machine, Value},
+    flat_expr::FExpr,
+    span::Span,
+};
+
+/// Implemented by the host to debug a run started with `Machine::debug`
+///
+/// Every step of a CPS program is one call, so the machine can stop before
+/// any call that came from the source. Lambdas compiled with
+/// `closure_compiler` run without stopping.
+pub trait Debugger {
+    /// Whether to stop before making the call at `span`
+    fn breakpoint(&mut self, span: Span) -> bool;
+
+    /// Look at the paused machine, returning how to carry on
+    fn stop(&mut self, machine: &Machine, stop: &Stop) -> Resume;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Stop again before the next call that came from the source
+    Step,
+    /// Run until the next breakpoint
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Breakpoint,
+    Step,
+}
+
+/// The call the machine is paused before
+pub struct Stop<'a> {
+    /// Where the call is in the source
+    pub span: Span,
+    pub reason: Reason,
+    pub(crate) program: &'a FExpr,
+    pub(crate) body: Rc<FExpr>,
+    pub(crate) env: Env,
+}
+
+/// A source level call that is waiting for a result, or the call being made
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    pub span: Span,
+    /// The function the call is in, see `backtrace::TraceFrame`
+    pub function: Option<String>,
+    /// The source variables in scope where the call returns to, innermost
+    /// first
+    pub locals: Vec<(String, Value)>,
+}
+
+impl<'a> Stop<'a> {
+    /// The call about to be made
+    pub fn call(&self) -> &FExpr {
+        &self.body
+    }
+
+    /// The source variables in scope, innermost first and leaving out any
+    /// that are shadowed
+    ///
+    /// Variables are only known within the program being debugged, not in
+    /// the functions of earlier runs it calls.
+    pub fn locals(&self, machine: &Machine) -> Vec<(String, Value)> {
+        locals(machine, self.program, &self.body, self.env, 0)
+    }
+
+    /// The call being made followed by the calls waiting for it to return,
+    /// found by following the chain of continuations, see `Machine::backtrace`
+    pub fn backtrace(&self, machine: &Machine) -> Vec<CallFrame> {
+        let k = backtrace::continuation(machine, &self.body, 0, self.env);
+        let returns = backtrace::returns(machine, k);
+        let functions = returns
+            .iter()
+            .map(|r| r.site.callee.clone())
+            .chain(Some(None));
+
+        let mut frames = vec![(self.span, self.locals(machine))];
+        for r in &returns {
+            // the continuation's parameter isn't bound until it's called
+            let locals = match &r.body {
+                Some(body) => locals(machine, self.program, body, r.env, 1),
+                None => Vec::new(),
+            };
+            frames.push((r.site.span, locals));
+        }
+
+        frames
+            .into_iter()
+            .zip(functions)
+            .map(|((span, locals), function)| CallFrame {
+                span,
+                function,
+                locals,
+            })
+            .collect()
+    }
+}
+
+/// The values of the source variables in scope at `body`, which is inside
+/// `program`, in an environment missing the innermost `unbound` binders
+fn locals(
+    machine: &Machine,
+    program: &FExpr,
+    body: &Rc<FExpr>,
+    env: Env,
+    unbound: usize,
+) -> Vec<(String, Value)> {
+    let mut scope = Vec::new();
+    if !binders(program, body, false, &mut scope) {
+        return Vec::new();
+    }
+
+    let mut locals: Vec<(String, Value)> = Vec::new();
+    for (offset, name) in scope.iter().rev().skip(unbound).enumerate() {
+        let name = match name {
+            Some(name) if name != "_" && !locals.iter().any(|(n, _)| n == name) => name,
+            _ => continue,
+        };
+
+        match machine.lookup(env, ScopeOffset(offset as u32)) {
+            Some(value) if value.type_name() != "continuation" => {
+                locals.push((name.clone(), value))
+            }
+            _ => {}
+        }
+    }
+
+    locals
+}
+
+/// Push the binders enclosing `target` within `term` onto `scope`,
+/// outermost first, returning whether `target` was found
+///
+/// The binders that came from the source are named, the parameters of user
+/// lambdas and of handlers, the rest are the continuations and temporaries
+/// of the CPS conversion.
+fn binders(
+    term: &FExpr,
+    target: &Rc<FExpr>,
+    handler: bool,
+    scope: &mut Vec<Option<String>>,
+) -> bool {
+    let (params, body) = match term {
+        FExpr::LamOne(s) => {
+            let name = s.unsafe_pattern.0.pretty_name.clone();
+            (vec![name.filter(|_| handler)], &s.unsafe_body)
+        }
+        FExpr::LamTwo(s) => {
+            let x = s.unsafe_pattern.0.pretty_name.clone();
+            (vec![x, None, None], &s.unsafe_body.unsafe_body.unsafe_body)
+        }
+        FExpr::CallOne(k, v) | FExpr::Raise(k, v, _) => {
+            return binders(k, target, false, scope) || binders(v, target, false, scope)
+        }
+        FExpr::CallTwo(f, v, k, h, _) => {
+            return binders(f, target, false, scope)
+                || binders(v, target, false, scope)
+                || binders(k, target, false, scope)
+                || binders(h, target, true, scope)
+        }
+        FExpr::Var(_) | FExpr::Lit(_) | FExpr::Prim(_) => return false,
+    };
+
+    let depth = scope.len();
+    scope.extend(params);
+    if Rc::ptr_eq(body, target) || binders(body, target, false, scope) {
+        return true;
+    }
+
+    scope.truncate(depth);
+    false
+}
+
+/// Spans of the source to stop in, a call stops at a breakpoint if it starts
+/// inside one
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    spans: Vec<Span>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, span: Span) {
+        if !self.spans.contains(&span) {
+            self.spans.push(span);
+        }
+    }
+
+    /// Remove a breakpoint, returning whether there was one
+    pub fn remove(&mut self, span: Span) -> bool {
+        let len = self.spans.len();
+        self.spans.retain(|s| *s != span);
+        self.spans.len() != len
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    pub fn hits(&self, span: Span) -> bool {
+        self.spans
+            .iter()
+            .any(|s| s.start <= span.start && span.start < s.end)
+    }
+}
+
+/// The span of a line of `src`, counting from 1, without its newline
+pub fn line_span(src: &str, line: usize) -> Option<Span> {
+    let mut start = 0;
+    for (n, text) in src.split('\n').enumerate() {
+        if n + 1 == line {
+            return Some(Span::new(start, start + text.len()));
+        }
+        start += text.len() + 1;
+    }
+
+    None
+}
+
+/// The line of `src`, counting from 1, that a byte offset is on
+pub fn line_of(src: &str, offset: usize) -> usize {
+    src[..offset.min(src.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, literals::Literal, parse};
+
+    /// Stops at the first breakpoint then steps, recording each stop
+    struct Script {
+        breakpoints: Breakpoints,
+        steps: usize,
+        stops: Vec<(Reason, String, Vec<String>)>,
+        backtrace: Vec<(String, Vec<String>)>,
+    }
+
+    impl Script {
+        fn new(src: &str, at: &str, steps: usize) -> Self {
+            let start = src.find(at).unwrap();
+            let mut breakpoints = Breakpoints::new();
+            breakpoints.add(Span::new(start, start + at.len()));
+
+            Script {
+                breakpoints,
+                steps,
+                stops: Vec::new(),
+                backtrace: Vec::new(),
+            }
+        }
+    }
+
+    impl Debugger for Script {
+        fn breakpoint(&mut self, span: Span) -> bool {
+            self.stops.is_empty() && self.breakpoints.hits(span)
+        }
+
+        fn stop(&mut self, machine: &Machine, stop: &Stop) -> Resume {
+            let names = |locals: Vec<(String, Value)>| locals.into_iter().map(|(n, _)| n).collect();
+            let span = format!("{}", stop.span);
+            self.stops
+                .push((stop.reason, span, names(stop.locals(machine))));
+            if self.stops.len() == 1 {
+                self.backtrace = stop
+                    .backtrace(machine)
+                    .into_iter()
+                    .map(|f| (f.span.to_string(), names(f.locals)))
+                    .collect();
+            }
+
+            match self.stops.len() > self.steps {
+                true => Resume::Continue,
+                false => Resume::Step,
+            }
+        }
+    }
+
+    fn debug(src: &str, debugger: &mut Script) -> Value {
+        let program = cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+        Machine::new().debug(&program, debugger).unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn span_of(src: &str, text: &str) -> String {
+        let start = src.find(text).unwrap();
+        Span::new(start, start + text.len()).to_string()
+    }
+
+    #[test]
+    fn stops_at_breakpoints_and_steps() {
+        let src = "\
+(define id (lambda (x) x))
+(define y (id \"a\"))
+(id y)";
+        let mut debugger = Script::new(src, "(id \"a\")", 1);
+
+        match debug(src, &mut debugger) {
+            Value::Lit(Literal::String(s)) => assert_eq!(s, "a"),
+            _ => panic!("expected a string"),
+        }
+
+        assert_eq!(
+            debugger.stops,
+            vec![
+                (
+                    Reason::Breakpoint,
+                    span_of(src, "(id \"a\")"),
+                    names(&["id"])
+                ),
+                (Reason::Step, span_of(src, "(id y)"), names(&["y", "id"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn backtraces_follow_continuations_to_callers() {
+        let src = "\
+(define id (lambda (z) z))
+(define inner (lambda (x) (id x)))
+(define outer (lambda (y) (begin (inner y) y)))
+(outer \"a\")
+\"done\"";
+        let mut debugger = Script::new(src, "(id x)", 0);
+        debug(src, &mut debugger);
+
+        assert_eq!(
+            debugger.backtrace,
+            vec![
+                (span_of(src, "(id x)"), names(&["x", "id"])),
+                (span_of(src, "(inner y)"), names(&["y", "inner", "id"])),
+                (
+                    span_of(src, "(outer \"a\")"),
+                    names(&["outer", "inner", "id"])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_lines() {
+        let src = "a\nbc\n\nd";
+
+        assert_eq!(line_span(src, 2), Some(Span::new(2, 4)));
+        assert_eq!(line_span(src, 3), Some(Span::new(5, 5)));
+        assert_eq!(line_span(src, 5), None);
+        assert_eq!(line_of(src, 3), 2);
+        assert_eq!(line_of(src, 6), 4);
+    }
+}
@@ -0,0 +1,189 @@
+use moniker::{Binder, Ignore};
+
+use std::fmt::Write;
+
+use crate::{
+    cont_expr::{CCall, UExpr},
+    flat_expr::FExpr,
+    syntax::{prim_name, Names},
+};
+
+/// Draw a CPS program as a Graphviz DOT graph, see `fexpr`
+pub fn uexpr(term: &UExpr) -> String {
+    fexpr(&term.clone().into_fexpr())
+}
+
+/// Draw a CPS call as a Graphviz DOT graph, see `fexpr`
+pub fn ccall(term: &CCall) -> String {
+    fexpr(&term.clone().into_fexpr())
+}
+
+/// Draw a flat term as a Graphviz DOT graph
+///
+/// User lambdas are boxes and continuation lambdas ellipses, each with an
+/// edge to the call that is its body. A call is drawn as its text, with the
+/// lambdas passed to it replaced by `•` and drawn as nodes of their own,
+/// reached by dashed edges labelled with their position: `fn`, `arg`, `k` or
+/// `h`.
+pub fn fexpr(term: &FExpr) -> String {
+    let mut graph = Graph {
+        out: String::from("digraph {\n  node [fontname=\"monospace\"];\n"),
+        nodes: 0,
+        names: Names::new(term),
+    };
+
+    let root = graph.draw(term);
+    graph.node_of(root);
+    graph.out.push_str("}\n");
+
+    graph.out
+}
+
+struct Graph {
+    out: String,
+    nodes: usize,
+    names: Names,
+}
+
+/// A term drawn inline as text, or as a node of its own
+enum Drawn {
+    Atom(String),
+    Node(usize),
+}
+
+impl Graph {
+    fn next(&mut self) -> usize {
+        self.nodes += 1;
+        self.nodes - 1
+    }
+
+    fn node(&mut self, id: usize, shape: &str, label: &str) {
+        let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(
+            self.out,
+            "  n{} [shape={}, label=\"{}\"];",
+            id, shape, label
+        )
+        .unwrap();
+    }
+
+    fn edge(&mut self, from: usize, to: usize, attrs: &str) {
+        match attrs {
+            "" => writeln!(self.out, "  n{} -> n{};", from, to).unwrap(),
+            attrs => writeln!(self.out, "  n{} -> n{} [{}];", from, to, attrs).unwrap(),
+        }
+    }
+
+    fn node_of(&mut self, drawn: Drawn) -> usize {
+        match drawn {
+            Drawn::Node(id) => id,
+            Drawn::Atom(text) => {
+                let id = self.next();
+                self.node(id, "plaintext", &text);
+                id
+            }
+        }
+    }
+
+    fn draw(&mut self, term: &FExpr) -> Drawn {
+        match term {
+            FExpr::Var(v) => Drawn::Atom(self.names.var(v)),
+            FExpr::Lit(Ignore(l)) => Drawn::Atom(l.to_string()),
+            FExpr::Prim(Ignore(p)) => Drawn::Atom(format!("%{}", prim_name(*p))),
+            FExpr::LamOne(s) => {
+                let (Binder(x), body) = s.clone().unbind();
+                let id = self.next();
+                let label = format!("λ {}", self.names.bind(&x));
+                self.node(id, "ellipse", &label);
+
+                let body = self.draw(&body);
+                let body = self.node_of(body);
+                self.edge(id, body, "");
+                self.names.unbind(&x);
+
+                Drawn::Node(id)
+            }
+            FExpr::LamTwo(s) => {
+                let (Binder(x), s) = s.clone().unbind();
+                let (Binder(k), s) = s.unbind();
+                let (Binder(h), body) = s.unbind();
+                let id = self.next();
+                let params = [&x, &k, &h].map(|v| self.names.bind(v));
+                self.node(id, "box", &format!("λ {}", params.join(" ")));
+
+                let body = self.draw(&body);
+                let body = self.node_of(body);
+                self.edge(id, body, "");
+                for v in &[h, k, x] {
+                    self.names.unbind(v);
+                }
+
+                Drawn::Node(id)
+            }
+            FExpr::CallOne(k, v) => self.call(None, &[("k", k), ("arg", v)]),
+            FExpr::CallTwo(f, v, k, h, _) => {
+                self.call(None, &[("fn", f), ("arg", v), ("k", k), ("h", h)])
+            }
+            FExpr::Raise(h, v, _) => self.call(Some("raise"), &[("h", h), ("arg", v)]),
+        }
+    }
+
+    fn call(&mut self, keyword: Option<&str>, operands: &[(&str, &FExpr)]) -> Drawn {
+        let id = self.next();
+        let mut text = keyword.into_iter().map(str::to_owned).collect::<Vec<_>>();
+
+        for (position, operand) in operands {
+            match self.draw(operand) {
+                Drawn::Atom(atom) => text.push(atom),
+                Drawn::Node(node) => {
+                    text.push("•".to_owned());
+                    let attrs = format!("label=\"{}\", style=dashed", position);
+                    self.edge(id, node, &attrs);
+                }
+            }
+        }
+
+        self.node(id, "plaintext", &format!("({})", text.join(" ")));
+        Drawn::Node(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, parse, syntax::Syntax};
+
+    #[test]
+    fn draws_lambdas_and_calls() {
+        let term = FExpr::parse("(lambda (_ k h) ((lambda (x) (k x)) \"a\"))").unwrap();
+
+        assert_eq!(
+            fexpr(&term),
+            "\
+digraph {
+  node [fontname=\"monospace\"];
+  n0 [shape=box, label=\"λ _ k h\"];
+  n2 [shape=ellipse, label=\"λ x\"];
+  n3 [shape=plaintext, label=\"(k x)\"];
+  n2 -> n3;
+  n1 -> n2 [label=\"k\", style=dashed];
+  n1 [shape=plaintext, label=\"(• \\\"a\\\")\"];
+  n0 -> n1;
+}
+"
+        );
+    }
+
+    #[test]
+    fn draws_converted_programs() {
+        let expr = parse::parse("((lambda (f) (f f)) (lambda (x) (raise x)))").unwrap();
+        let dot = uexpr(&cont_expr::program(expr));
+
+        // the program and the two user lambdas
+        assert_eq!(dot.matches("shape=box").count(), 3);
+        assert!(dot.contains("[shape=box, label=\"λ x k h\"]"));
+        assert!(dot.contains("[shape=plaintext, label=\"(raise h e)\"]"));
+        assert!(dot.contains("[shape=plaintext, label=\"(f_1 e k h)\"]"));
+        assert_eq!(dot.matches("style=dashed").count(), 8);
+    }
+}
@@ -0,0 +1,1901 @@
+use moniker::{Ignore, Scope, ScopeOffset, Var};
+
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt, mem,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    backtrace::{self, TraceFrame},
+    channel::{Channel, Receiver},
+    debugger::{Debugger, Reason, Resume, Stop},
+    flat_expr::FExpr,
+    gc::{Frame, Gc, Handle, Heap, Object, Trace},
+    generator::{self, Generator, GeneratorStream, Iter, State},
+    literals::Literal,
+    prim::Prim,
+    profile::Profiler,
+    scheduler::{AllJoin, Clock, Joiner, Scheduler, Task},
+    span::{CallSite, Span},
+};
+
+/// A runtime value produced by evaluating an `FExpr`
+///
+/// Values that refer to the machine's `Heap` are only valid while they are
+/// reachable from its globals, the running program or a `Handle`.
+#[derive(Clone)]
+pub enum Value {
+    Lit(Literal),
+    /// A user lambda (`FExpr::LamTwo`) closed over its environment
+    Closure(Gc),
+    /// A continuation lambda (`FExpr::LamOne`) closed over its environment
+    Cont(Gc),
+    List(Gc),
+    /// An object allocated by the host with `Machine::alloc_host`
+    Object(Gc),
+    Host(HostFn),
+    Prim(Prim),
+    /// A raised value, see `Exception`
+    Exception(Gc),
+    /// A generator, calling it resumes it, see `generator::Generator`
+    Generator(Gc),
+    /// The function a generator's body yields with
+    Yield(Gc),
+    /// The continuation a generator's body returns to
+    GenReturn(Gc),
+    /// The handler a generator's body raises to
+    GenRaise(Gc),
+    /// A task started with `spawn`, see `scheduler::Task`
+    Task(Gc),
+    /// The continuation and handler a task's function finishes with
+    TaskReturn(Gc),
+    TaskRaise(Gc),
+    /// The continuation a host function's `Machine::call_then` returns to,
+    /// see `HostCall`
+    HostReturn(Gc),
+    /// A channel made with `channel`, see `channel::Channel`
+    Channel(Gc),
+    /// A primitive or host function taking more than one argument, applied
+    /// to the first
+    Partial(Gc),
+    /// The continuation handed to a program, invoking it stops the machine
+    Halt,
+    /// The handler handed to a program, invoking it fails the run with
+    /// `Error::Uncaught`
+    Abort,
+}
+
+impl Value {
+    pub(crate) fn gc(&self) -> Option<Gc> {
+        match self {
+            Value::Closure(gc)
+            | Value::Cont(gc)
+            | Value::List(gc)
+            | Value::Object(gc)
+            | Value::Exception(gc)
+            | Value::Generator(gc)
+            | Value::Yield(gc)
+            | Value::GenReturn(gc)
+            | Value::GenRaise(gc)
+            | Value::Task(gc)
+            | Value::TaskReturn(gc)
+            | Value::TaskRaise(gc)
+            | Value::HostReturn(gc)
+            | Value::Channel(gc)
+            | Value::Partial(gc) => Some(*gc),
+            Value::Lit(_) | Value::Host(_) | Value::Prim(_) | Value::Halt | Value::Abort => None,
+        }
+    }
+
+    /// A short description of what kind of value this is
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Lit(Literal::String(_)) => "string",
+            Value::Lit(Literal::Int(_)) => "int",
+            Value::Lit(Literal::Float(_)) => "float",
+            Value::Lit(Literal::Void) => "void",
+            Value::Closure(_)
+            | Value::Host(_)
+            | Value::Prim(_)
+            | Value::Partial(_)
+            | Value::Yield(_) => "function",
+            Value::List(_) => "list",
+            Value::Object(_) => "object",
+            Value::Exception(_) => "exception",
+            Value::Generator(_) => "generator",
+            Value::Task(_) => "task",
+            Value::Channel(_) => "channel",
+            Value::Cont(_)
+            | Value::GenReturn(_)
+            | Value::GenRaise(_)
+            | Value::TaskReturn(_)
+            | Value::TaskRaise(_)
+            | Value::HostReturn(_)
+            | Value::Halt
+            | Value::Abort => "continuation",
+        }
+    }
+}
+
+/// A value passed to a handler, along with the spans of the `raise`s it went
+/// through, innermost first
+///
+/// Host functions failing with `Error::Host` or `Error::Raise` raise an
+/// exception spanning the call to them, when it came from the source.
+#[derive(Debug, Clone)]
+pub struct Exception {
+    pub value: Value,
+    pub spans: Vec<Span>,
+    /// The continuation of the function it was first raised in, which the
+    /// backtrace of the exception going uncaught starts from
+    pub(crate) continuation: Option<Value>,
+}
+
+pub struct Closure {
+    pub(crate) body: Body,
+    pub(crate) env: Env,
+    /// For the continuation a source call returns to, the call
+    pub(crate) site: Option<Rc<CallSite>>,
+    /// The values a compiled lambda closes over, whose `env` is empty
+    pub(crate) captures: Vec<Value>,
+}
+
+/// The code of a lambda, either its `FExpr` or the result of compiling it
+/// with `closure_compiler`
+#[derive(Clone)]
+pub(crate) enum Body {
+    Expr(Rc<FExpr>),
+    Compiled(Rc<CompiledBody>),
+}
+
+/// The body of a lambda compiled by `closure_compiler`
+pub(crate) struct CompiledBody {
+    /// Evaluate the call the body makes, given the closure, for the values it
+    /// captured, and the arguments, in scope offset order
+    pub(crate) code: Code,
+    /// The captured value that is the continuation of the function a
+    /// continuation is part of, for backtraces
+    pub(crate) returns: Option<usize>,
+}
+
+pub(crate) type Code = Box<dyn Fn(&mut Machine, Gc, &[Value]) -> Result<Call, Error>>;
+
+/// A function implemented in rust that can be called from scripts
+#[derive(Clone)]
+pub struct HostFn {
+    pub name: Rc<str>,
+    pub(crate) fun: Rc<HostCode>,
+    /// How many arguments it takes, calling it with fewer gives back a
+    /// `Value::Partial` waiting for the rest
+    pub arity: usize,
+}
+
+type HostCode = dyn Fn(&mut Machine, &[Value]) -> Result<Value, Error>;
+
+/// The rest of a host function, run once a call it made with
+/// `Machine::call_then` returns, with the values it kept followed by the
+/// result of the call
+pub type HostStep = fn(&mut Machine, &[Value]) -> Result<Value, Error>;
+
+/// A host function waiting on a call made with `Machine::call_then`
+#[derive(Clone)]
+pub(crate) struct HostCall {
+    pub(crate) step: HostStep,
+    pub(crate) state: Vec<Value>,
+    /// The continuation and handler of the host function
+    pub(crate) k: Value,
+    pub(crate) h: Value,
+    /// Where the host function was called from
+    pub(crate) span: Option<Span>,
+}
+
+/// A call requested by a host function, made once it returns
+struct Request {
+    f: Value,
+    arg: Value,
+    state: Vec<Value>,
+    step: HostStep,
+}
+
+impl HostFn {
+    pub fn new(
+        name: &str,
+        fun: impl Fn(&mut Machine, Value) -> Result<Value, Error> + 'static,
+    ) -> Self {
+        HostFn::curried(name, 1, move |machine, args| fun(machine, args[0].clone()))
+    }
+
+    /// A function taking `arity` arguments, which scripts pass one at a time
+    /// like they do to a lambda with that many parameters
+    pub fn curried(
+        name: &str,
+        arity: usize,
+        fun: impl Fn(&mut Machine, &[Value]) -> Result<Value, Error> + 'static,
+    ) -> Self {
+        assert!(arity > 0, "host functions take at least one argument");
+
+        HostFn {
+            name: name.into(),
+            fun: Rc::new(fun),
+            arity,
+        }
+    }
+
+    pub fn call(&self, machine: &mut Machine, arg: Value) -> Result<Value, Error> {
+        self.apply(machine, vec![arg])
+    }
+
+    /// Call the function with the arguments it has been given so far, only
+    /// running it once it has all of them
+    pub(crate) fn apply(&self, machine: &mut Machine, args: Vec<Value>) -> Result<Value, Error> {
+        match args.len() < self.arity {
+            true => {
+                let partial = Object::HostPartial(self.clone(), args);
+                Ok(Value::Partial(machine.heap.alloc(partial)))
+            }
+            false => (self.fun)(machine, &args),
+        }
+    }
+
+    /// A weaker version of this function, which calls `guard` on each
+    /// argument first and fails without calling the original if it does
+    pub fn attenuate(
+        &self,
+        guard: impl Fn(&mut Machine, &Value) -> Result<(), Error> + 'static,
+    ) -> HostFn {
+        let inner = self.fun.clone();
+
+        HostFn::curried(&self.name, self.arity, move |machine, args| {
+            for arg in args {
+                guard(machine, arg)?;
+            }
+            inner(machine, args)
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Lit(l) => write!(f, "{}", l),
+            Value::Closure(_) => write!(f, "<closure>"),
+            Value::Cont(_) => write!(f, "<continuation>"),
+            Value::List(_) => write!(f, "<list>"),
+            Value::Object(_) => write!(f, "<object>"),
+            Value::Host(h) => write!(f, "<host {}>", h.name),
+            Value::Prim(p) => write!(f, "<primitive {}>", p),
+            Value::Exception(_) => write!(f, "<exception>"),
+            Value::Generator(_) => write!(f, "<generator>"),
+            Value::Yield(_) => write!(f, "<yield>"),
+            Value::Task(_) => write!(f, "<task>"),
+            Value::Channel(_) => write!(f, "<channel>"),
+            Value::Partial(_) => write!(f, "<partial>"),
+            Value::GenReturn(_)
+            | Value::GenRaise(_)
+            | Value::TaskReturn(_)
+            | Value::TaskRaise(_)
+            | Value::HostReturn(_) => write!(f, "<continuation>"),
+            Value::Halt => write!(f, "<halt>"),
+            Value::Abort => write!(f, "<abort>"),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The environment of a closure, one frame per enclosing `Scope`
+///
+/// Bound variables are looked up by their de Bruijn scope offset, so the
+/// innermost binder is always at the head of the list.
+#[derive(Clone, Copy, Default)]
+pub struct Env(pub(crate) Option<Gc>);
+
+impl Env {
+    pub(crate) fn push(self, heap: &mut Heap, value: Value) -> Env {
+        Env(Some(heap.alloc(Object::Frame(Frame { value, next: self }))))
+    }
+
+    pub(crate) fn lookup(self, heap: &Heap, scope: ScopeOffset) -> Option<&Value> {
+        let mut frame = get_frame(heap, self.0?);
+
+        for _ in 0..scope.0 {
+            frame = get_frame(heap, frame.next.0?);
+        }
+
+        Some(&frame.value)
+    }
+
+}
+
+/// Tells machines apart, for what is cached outside of them
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MachineId(u64);
+
+impl Default for MachineId {
+    fn default() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        MachineId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+fn get_frame(heap: &Heap, gc: Gc) -> &Frame {
+    match heap.get(gc) {
+        Object::Frame(f) => f,
+        _ => unreachable!("environment is not a frame"),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    UnboundVariable(String),
+    NotAFunction(Value),
+    NotAContinuation(Value),
+    NotAGenerator(Value),
+    NotAChannel(Value),
+    /// A lambda body that isn't a call, `FExpr`s produced by `cont_expr`
+    /// never contain these
+    NotACall,
+    /// The machine ran more calls than its step limit allows
+    StepLimitExceeded(u64),
+    /// The script's live data grew past the machine's memory limit
+    OutOfMemory,
+    /// Every task is waiting to join another or on a channel
+    Deadlock,
+    /// The host sent to a channel with no room
+    ChannelFull,
+    /// A host function failure, scripts can catch it as an exception
+    /// carrying the message
+    Host(String),
+    /// Raise a value as an exception from a host function
+    Raise(Value),
+    /// An exception reached the top of the program
+    Uncaught(Exception),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnboundVariable(name) => write!(f, "unbound variable: {}", name),
+            Error::NotAFunction(v) => write!(f, "attempt to call a non-function: {}", v),
+            Error::NotAContinuation(v) => write!(f, "attempt to resume a non-continuation: {}", v),
+            Error::NotAGenerator(v) => write!(f, "attempt to iterate a non-generator: {}", v),
+            Error::NotAChannel(v) => write!(f, "attempt to use a non-channel: {}", v),
+            Error::NotACall => write!(f, "lambda body is not a call"),
+            Error::StepLimitExceeded(limit) => write!(f, "step limit of {} exceeded", limit),
+            Error::OutOfMemory => write!(f, "out of memory"),
+            Error::Deadlock => write!(f, "deadlock, every task is blocked"),
+            Error::ChannelFull => write!(f, "channel is full"),
+            Error::Host(msg) => write!(f, "{}", msg),
+            Error::Raise(v) => write!(f, "raised: {}", v),
+            Error::Uncaught(e) => {
+                write!(f, "uncaught exception: {}", e.value)?;
+                for span in &e.spans {
+                    write!(f, "\n  raised at {}", span)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A tree walking evaluator for `FExpr`
+///
+/// Every step of a CPS program is a tail call, so `run` drives the program
+/// with a loop and never recurses on the rust stack, however deep the
+/// recursion in the script.
+#[derive(Default)]
+pub struct Machine {
+    heap: Heap,
+    id: MachineId,
+    /// The slot in `globals` of each global's value
+    slots: HashMap<String, usize>,
+    globals: Vec<Value>,
+    /// Continuations of host calls in progress, which are live while the
+    /// host function runs scripts of its own
+    pending: Vec<Value>,
+    scheduler: Scheduler,
+    step_limit: Option<u64>,
+    /// Calls made so far by the current run, including those of the runs
+    /// nested in it by `apply`
+    steps: u64,
+    /// How many host functions are waiting on `apply` to return, during
+    /// which tasks can't switch
+    callbacks: usize,
+    /// The call the running host function asked for with `call_then`
+    request: Option<Request>,
+    backtrace: Vec<TraceFrame>,
+    profiler: Option<Profiler>,
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// The source level calls in progress when the last run failed,
+    /// innermost first, each by the span of the call or, for the innermost,
+    /// of where it failed
+    ///
+    /// Calls are found by following the continuation of the failure, as a
+    /// CPS program has no call stack. Tail calls don't return to their
+    /// caller so they leave no frame, and neither do calls in code compiled
+    /// by `closure_compiler`.
+    pub fn backtrace(&self) -> &[TraceFrame] {
+        &self.backtrace
+    }
+
+    /// Profile the runs started from now on, or stop profiling
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    /// Stop profiling, returning what was counted
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.slots.get(name).map(|&slot| &self.globals[slot])
+    }
+
+    /// Where the value of the global `name` is kept, which stays the same
+    /// for as long as the machine does
+    pub(crate) fn global_slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub(crate) fn global_at(&self, slot: usize) -> &Value {
+        &self.globals[slot]
+    }
+
+    pub(crate) fn id(&self) -> MachineId {
+        self.id
+    }
+
+    /// Every global, the host functions and primitives the machine was given
+    /// and whatever scripts defined, in no particular order
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.slots
+            .iter()
+            .map(move |(name, &slot)| (name.as_str(), &self.globals[slot]))
+    }
+
+    /// Limit the number of calls a single run may make, so that scripts that
+    /// loop forever fail with `Error::StepLimitExceeded`
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    /// Limit the estimated bytes of live data scripts may hold on to
+    ///
+    /// Going over it raises `Error::OutOfMemory`'s message to the handler of
+    /// the call that did, dropping what that call would have returned to so
+    /// that the handler can run. The run fails with `Error::OutOfMemory` if
+    /// there is no handler, or the handler goes over the limit too.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.heap.set_limit(bytes);
+    }
+
+    /// Bind a free variable of scripts run on this machine
+    pub fn define(&mut self, name: &str, value: Value) {
+        match self.slots.get(name) {
+            Some(&slot) => self.globals[slot] = value,
+            None => {
+                self.slots.insert(name.to_owned(), self.globals.len());
+                self.globals.push(value);
+            }
+        }
+    }
+
+    /// Make a primitive available to scripts under its name
+    pub fn define_prim(&mut self, prim: Prim) {
+        self.define(prim.name(), Value::Prim(prim));
+    }
+
+    /// Set the clock sleeping tasks wait on, by default this is real time
+    ///
+    /// Runs without a fuel limit block the thread while every task is asleep
+    /// on a real time clock. Runs with one are suspended instead, see
+    /// `Suspended::wake_at`.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.scheduler.clock = Box::new(clock);
+    }
+
+    /// The time on the machine's clock, in milliseconds
+    pub fn now(&self) -> u64 {
+        self.scheduler.clock.now()
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        fun: impl Fn(&mut Machine, Value) -> Result<Value, Error> + 'static,
+    ) {
+        self.define(name, Value::Host(HostFn::new(name, fun)));
+    }
+
+    /// Register a host function taking `arity` arguments, see
+    /// `HostFn::curried`
+    pub fn register_curried(
+        &mut self,
+        name: &str,
+        arity: usize,
+        fun: impl Fn(&mut Machine, &[Value]) -> Result<Value, Error> + 'static,
+    ) {
+        self.define(name, Value::Host(HostFn::curried(name, arity, fun)));
+    }
+
+    /// Keep a value alive across collections, values returned from `run`
+    /// must be rooted if they are held onto while scripts keep running
+    pub fn root(&mut self, value: Value) -> Handle {
+        self.heap.root(value)
+    }
+
+    /// Check that `bytes` more of live data fit under the memory limit,
+    /// collecting if they don't, before a host function makes something
+    /// sized by its arguments
+    ///
+    /// Host functions fail with the `Error::OutOfMemory` this returns, which
+    /// scripts can catch. Values the host function made itself must be
+    /// rooted across the call, as they must be across `apply`.
+    pub fn reserve(&mut self, bytes: usize) -> Result<(), Error> {
+        if !self.heap.has_room(bytes) {
+            self.collect();
+        }
+
+        match self.heap.has_room(bytes) {
+            true => Ok(()),
+            false => Err(Error::OutOfMemory),
+        }
+    }
+
+    /// Collect everything not reachable from the globals or a handle
+    pub fn collect(&mut self) {
+        self.heap.collect(
+            self.globals
+                .iter()
+                .chain(self.pending.iter())
+                .chain(self.scheduler.values()),
+        );
+    }
+
+    pub fn alloc_list(&mut self, items: Vec<Value>) -> Value {
+        Value::List(self.heap.alloc(Object::List(items)))
+    }
+
+    pub fn list(&self, value: &Value) -> Option<&[Value]> {
+        match value {
+            Value::List(gc) => match self.heap.get(*gc) {
+                Object::List(l) => Some(l),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Store a host object on the heap, any values it holds must be reported
+    /// by its `Trace` implementation
+    pub fn alloc_host<T: Trace + Any>(&mut self, object: T) -> Value {
+        Value::Object(self.heap.alloc(Object::Host(Box::new(object))))
+    }
+
+    pub fn exception(&self, value: &Value) -> Option<&Exception> {
+        match value {
+            Value::Exception(gc) => match self.heap.get(*gc) {
+                Object::Exception(e) => Some(e),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Wrap a value raised at `span` in the function returning to `k`,
+    /// re-raising an exception adds the span to it
+    pub(crate) fn raise(&mut self, value: Value, span: Option<Span>, k: Option<Value>) -> Value {
+        let mut exception = match self.exception(&value) {
+            Some(e) => e.clone(),
+            None => Exception {
+                value,
+                spans: Vec::new(),
+                continuation: k,
+            },
+        };
+        exception.spans.extend(span);
+
+        Value::Exception(self.heap.alloc(Object::Exception(exception)))
+    }
+
+    pub(crate) fn generator(&self, value: &Value) -> Option<&Generator> {
+        match value {
+            Value::Generator(gc) => match self.heap.get(*gc) {
+                Object::Generator(g) => Some(g),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn generator_mut(&mut self, gc: Gc) -> &mut Generator {
+        match self.heap.get_mut(gc) {
+            Object::Generator(g) => g,
+            _ => unreachable!("generator value is not a generator"),
+        }
+    }
+
+    /// Iterate over the values a script generator yields
+    pub fn iter(&mut self, generator: &Value) -> Result<Iter<'_>, Error> {
+        if self.generator(generator).is_none() {
+            return Err(Error::NotAGenerator(generator.clone()));
+        }
+
+        let generator = self.root(generator.clone());
+
+        Ok(Iter::new(self, generator))
+    }
+
+    /// Iterate over the values a script generator yields asynchronously,
+    /// giving other tasks a chance to run every `fuel` calls
+    pub fn stream(&mut self, generator: &Value, fuel: u64) -> Result<GeneratorStream<'_>, Error> {
+        Ok(GeneratorStream::new(self.iter(generator)?, fuel))
+    }
+
+    /// Make a channel holding up to `capacity` values, or any number if
+    /// `None`
+    pub fn channel(&mut self, capacity: Option<usize>) -> Value {
+        Value::Channel(self.heap.alloc(Object::Channel(Channel::new(capacity))))
+    }
+
+    /// Send a value to a channel from the host, waking a task waiting to
+    /// receive it. Fails if the channel is full, rather than waiting.
+    pub fn send(&mut self, channel: &Value, value: Value) -> Result<(), Error> {
+        let gc = channel_gc(channel)?;
+
+        self.deliver(gc, value).map_err(|_| Error::ChannelFull)
+    }
+
+    /// Take the next value from a channel, if there is one, waking a task
+    /// waiting to send to it
+    pub fn try_recv(&mut self, channel: &Value) -> Result<Option<Value>, Error> {
+        let gc = channel_gc(channel)?;
+
+        Ok(self.take(gc))
+    }
+
+    fn channel_mut(&mut self, gc: Gc) -> &mut Channel {
+        match self.heap.get_mut(gc) {
+            Object::Channel(c) => c,
+            _ => unreachable!("channel value is not a channel"),
+        }
+    }
+
+    /// Send a value without waiting, giving it back if the channel is full
+    fn deliver(&mut self, gc: Gc, value: Value) -> Result<(), Value> {
+        if let Some(receiver) = self.channel_mut(gc).receiver() {
+            let value = match receiver.select {
+                Some(_) => self.alloc_list(vec![Value::Channel(gc), value]),
+                None => value,
+            };
+            self.scheduler.ready(Call::One(receiver.k, value));
+
+            return Ok(());
+        }
+
+        let channel = self.channel_mut(gc);
+        if channel.is_full() {
+            return Err(value);
+        }
+        channel.buffer.push_back(value);
+
+        Ok(())
+    }
+
+    /// Receive a value without waiting
+    fn take(&mut self, gc: Gc) -> Option<Value> {
+        let (value, sender) = self.channel_mut(gc).take()?;
+
+        if let Some(k) = sender {
+            self.scheduler
+                .ready(Call::One(k, Value::Lit(Literal::Void)));
+        }
+
+        Some(value)
+    }
+
+    fn select(&mut self, channels: &Value, k: Value, h: Value) -> Result<Call, Error> {
+        let channels = match self.list(channels) {
+            Some(channels) => channels.iter().map(channel_gc).collect(),
+            None => Err(Error::NotAChannel(channels.clone())),
+        };
+        let channels: Vec<_> = match channels {
+            Ok(channels) => channels,
+            Err(_) => return Ok(self.raise_message(h, Some(k), "select: expected a list of channels")),
+        };
+
+        for &gc in &channels {
+            if let Some(value) = self.take(gc) {
+                let selected = self.alloc_list(vec![Value::Channel(gc), value]);
+                return Ok(Call::One(k, selected));
+            }
+        }
+
+        self.block(Prim::Select, k, h, |machine, k, _| {
+            let woken = Rc::new(Cell::new(false));
+            for gc in channels {
+                machine.channel_mut(gc).wait(Receiver {
+                    k: k.clone(),
+                    select: Some(woken.clone()),
+                });
+            }
+        })
+    }
+
+    pub fn host_object<T: Any>(&self, value: &Value) -> Option<&T> {
+        match value {
+            Value::Object(gc) => match self.heap.get(*gc) {
+                Object::Host(h) => h.as_any().downcast_ref(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn host_object_mut<T: Any>(&mut self, value: &Value) -> Option<&mut T> {
+        match value {
+            Value::Object(gc) => match self.heap.get_mut(*gc) {
+                Object::Host(h) => h.as_any_mut().downcast_mut(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Run a program produced by `cont_expr::program`, returning the value
+    /// passed to the exit continuation
+    ///
+    /// The run ends as soon as the program exits, abandoning any tasks it
+    /// spawned that are still running.
+    pub fn run(&mut self, program: &FExpr) -> Result<Value, Error> {
+        self.index(program);
+        let program = self.atom(program, Env::default())?;
+        let call = self.start(program);
+
+        self.exec(call, None).map(Outcome::unwrap_done)
+    }
+
+    /// Run a program, stopping before calls for the debugger to look at, see
+    /// `debugger::Debugger`
+    pub fn debug(&mut self, program: &FExpr, debugger: &mut dyn Debugger) -> Result<Value, Error> {
+        self.index(program);
+        let value = self.atom(program, Env::default())?;
+        let call = self.start(value);
+
+        let outcome = self.trampoline(call, None, Some((program, debugger)));
+        self.pause_profiler();
+        outcome.map(Outcome::unwrap_done)
+    }
+
+    /// Call a script's function from the host, running it until it returns
+    ///
+    /// Host functions can use this to call the functions scripts pass them.
+    /// Whatever they raise comes back as `Error::Raise` of the exception, so
+    /// a host function failing with it re-raises it to its caller. Values
+    /// the host holds on to while the function runs must be rooted, apart
+    /// from the arguments of the host function making the call.
+    ///
+    /// The function runs to completion in a run of its own, whose calls
+    /// count towards the step limit but not the fuel of the run calling the
+    /// host function, and it can't switch tasks, so waiting on a task,
+    /// channel or timer raises. Host functions that call back into scripts
+    /// should prefer `call_then`, which has neither restriction.
+    pub fn apply(&mut self, f: Value, arg: Value) -> Result<Value, Error> {
+        let call = Call::Two(f, arg, Value::Halt, Value::Abort);
+
+        self.callbacks += 1;
+        let outcome = self.exec(call, None);
+        self.callbacks -= 1;
+
+        match outcome {
+            Ok(outcome) => Ok(outcome.unwrap_done()),
+            Err(Error::Uncaught(e)) => Err(Error::Raise(Value::Exception(
+                self.heap.alloc(Object::Exception(e)),
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Call a script's function from a host function, then carry on with
+    /// `step` once it returns, rather than waiting for it as `apply` does
+    ///
+    /// The host function must return what this returns. The call is made
+    /// once it has, on the same loop as the rest of the run, after which
+    /// `step` is called with `state` followed by the result, as though it
+    /// were the host function called with those arguments. What `step`
+    /// returns, or raises, is what the host function returns, and `step`
+    /// can itself return a `call_then` to make another call.
+    ///
+    /// The values in `state` are kept alive until `step` runs. The call is
+    /// only made for host functions called by scripts, not through
+    /// `HostFn::call`.
+    pub fn call_then(
+        &mut self,
+        f: Value,
+        arg: Value,
+        state: Vec<Value>,
+        step: HostStep,
+    ) -> Result<Value, Error> {
+        self.request = Some(Request {
+            f,
+            arg,
+            state,
+            step,
+        });
+
+        Ok(Value::Lit(Literal::Void))
+    }
+
+    /// Run a program, suspending it once it has made `fuel` calls
+    pub fn run_with_fuel(&mut self, program: &FExpr, fuel: u64) -> Result<Outcome, Error> {
+        self.index(program);
+        let program = self.atom(program, Env::default())?;
+        let call = self.start(program);
+
+        self.exec(call, Some(fuel))
+    }
+
+    /// Let the profiler know about a program about to be run
+    fn index(&mut self, program: &FExpr) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.index(program);
+        }
+    }
+
+    /// Stop timing the step being made as the machine stops running
+    fn pause_profiler(&mut self) {
+        if let Some(mut profiler) = self.profiler.take() {
+            profiler.pause(self);
+            self.profiler = Some(profiler);
+        }
+    }
+
+    /// The first call of a run of `program`, forgetting the tasks of any
+    /// earlier run
+    pub(crate) fn start(&mut self, program: Value) -> Call {
+        self.scheduler.clear();
+        self.backtrace.clear();
+
+        Call::Two(
+            program,
+            Value::Lit(Literal::Void),
+            Value::Halt,
+            Value::Abort,
+        )
+    }
+
+    /// Continue a suspended run with a fresh budget of `fuel` calls
+    pub fn resume(&mut self, suspended: Suspended, fuel: u64) -> Result<Outcome, Error> {
+        let waits = mem::replace(&mut self.scheduler.waits, false);
+        let call = suspended.into_call().or_else(|| self.scheduler.next());
+        self.scheduler.waits = waits;
+
+        match call {
+            Some(call) => self.exec(call, Some(fuel)),
+            None => Ok(Outcome::Suspended(self.blocked())),
+        }
+    }
+
+    /// A suspension for when every task is blocked or asleep
+    fn blocked(&self) -> Suspended {
+        Suspended {
+            call: Vec::new(),
+            wake: self.scheduler.wake_at(),
+        }
+    }
+
+    pub(crate) fn alloc_closure(&mut self, body: Body, env: Env) -> Gc {
+        self.heap.alloc(Object::Closure(Closure {
+            body,
+            env,
+            site: None,
+            captures: Vec::new(),
+        }))
+    }
+
+    /// Allocate a compiled lambda closed over `captures`
+    pub(crate) fn alloc_compiled(&mut self, body: Rc<CompiledBody>, captures: Vec<Value>) -> Gc {
+        self.heap.alloc(Object::Closure(Closure {
+            body: Body::Compiled(body),
+            env: Env::default(),
+            site: None,
+            captures,
+        }))
+    }
+
+    /// The `index`th value captured by a compiled lambda
+    pub(crate) fn captured(&self, closure: Gc, index: usize) -> Value {
+        match self.heap.get(closure) {
+            Object::Closure(c) => c.captures[index].clone(),
+            _ => unreachable!("closure value is not a closure"),
+        }
+    }
+
+    /// Record that the continuation `k`, made by the call at `site`, is
+    /// where that call returns to
+    pub(crate) fn mark_site(&mut self, k: &Value, site: &Option<Rc<CallSite>>) {
+        if let (Value::Cont(gc), Some(_)) = (k, site) {
+            if let Object::Closure(c) = self.heap.get_mut(*gc) {
+                c.site = site.clone();
+            }
+        }
+    }
+
+    pub(crate) fn atom(&mut self, expr: &FExpr, env: Env) -> Result<Value, Error> {
+        match expr {
+            FExpr::LamOne(Scope {
+                unsafe_body: body, ..
+            }) => Ok(Value::Cont(
+                self.alloc_closure(Body::Expr(body.clone()), env),
+            )),
+            FExpr::LamTwo(Scope {
+                unsafe_body:
+                    Scope {
+                        unsafe_body:
+                            Scope {
+                                unsafe_body: body, ..
+                            },
+                        ..
+                    },
+                ..
+            }) => Ok(Value::Closure(
+                self.alloc_closure(Body::Expr(body.clone()), env),
+            )),
+            FExpr::Var(v) => self.var(v, env),
+            FExpr::Lit(Ignore(l)) => Ok(Value::Lit(l.clone())),
+            FExpr::Prim(Ignore(p)) => Ok(Value::Prim(*p)),
+            FExpr::CallOne(..) | FExpr::CallTwo(..) | FExpr::Raise(..) => Err(Error::NotACall),
+        }
+    }
+
+    pub(crate) fn lookup(&self, env: Env, scope: ScopeOffset) -> Option<Value> {
+        env.lookup(&self.heap, scope).cloned()
+    }
+
+    fn var(&self, var: &Var<String>, env: Env) -> Result<Value, Error> {
+        match var {
+            Var::Bound(b) => self.lookup(env, b.scope).ok_or_else(|| unbound(var)),
+            Var::Free(f) => f
+                .pretty_name
+                .as_ref()
+                .and_then(|name| self.global(name))
+                .cloned()
+                .ok_or_else(|| unbound(var)),
+        }
+    }
+
+    fn closure(&self, gc: Gc) -> (Body, Env) {
+        match self.heap.get(gc) {
+            Object::Closure(c) => (c.body.clone(), c.env),
+            _ => unreachable!("closure value is not a closure"),
+        }
+    }
+
+    pub(crate) fn exec(&mut self, call: Call, fuel: Option<u64>) -> Result<Outcome, Error> {
+        let waits = mem::replace(&mut self.scheduler.waits, fuel.is_none());
+        let outcome = self.trampoline(call, fuel, None);
+        self.scheduler.waits = waits;
+        self.pause_profiler();
+
+        match outcome {
+            // a run with a fuel limit goes back to the host rather than wait,
+            // the host can wake a task up by sending to a channel, or resume
+            // once a sleeping task is due
+            Err(Error::Deadlock) if fuel.is_some() => Ok(Outcome::Suspended(self.blocked())),
+            r => r,
+        }
+    }
+
+    fn trampoline(
+        &mut self,
+        mut call: Call,
+        mut fuel: Option<u64>,
+        mut debugger: Option<(&FExpr, &mut dyn Debugger)>,
+    ) -> Result<Outcome, Error> {
+        if self.callbacks == 0 {
+            self.steps = 0;
+        }
+        let mut stepping = false;
+        // running out of memory was raised, and a collection hasn't got back
+        // under the limit since
+        let mut raised_out_of_memory = false;
+        // the body that made the call, for where calls that fail are
+        let mut from: Option<Rc<FExpr>> = None;
+        let location = |from: &Option<Rc<FExpr>>| from.as_deref().and_then(backtrace::location);
+
+        loop {
+            self.steps += 1;
+            match self.step_limit {
+                Some(limit) if self.steps > limit => return Err(Error::StepLimitExceeded(limit)),
+                _ => (),
+            }
+
+            match &mut fuel {
+                Some(0) => return Ok(Outcome::Suspended(Suspended::new(self, call))),
+                Some(fuel) => *fuel -= 1,
+                None => (),
+            }
+
+            // between steps the call being made is the only live state
+            // outside of the globals
+            if self.heap.should_collect() || self.heap.over_limit() {
+                self.heap.collect(
+                    self.globals
+                        .iter()
+                        .chain(self.pending.iter())
+                        .chain(self.scheduler.values())
+                        .chain(call.values()),
+                );
+
+                match (&call, self.heap.over_limit()) {
+                    (_, false) => raised_out_of_memory = false,
+                    (_, true) if raised_out_of_memory => return Err(Error::OutOfMemory),
+                    (Call::Two(_, _, _, h), true) => {
+                        call = self.out_of_memory(h.clone())?;
+                        raised_out_of_memory = true;
+                        continue;
+                    }
+                    // a continuation has no handler to raise to, so the next
+                    // call raises instead
+                    (Call::One(..), true) => (),
+                }
+            }
+
+            let (body, env) = match call {
+                Call::One(Value::Cont(c), v) => match self.closure(c) {
+                    (Body::Expr(body), env) => (body, env.push(&mut self.heap, v)),
+                    (Body::Compiled(code), _) => {
+                        call = (code.code)(self, c, &[v])?;
+                        from = None;
+                        continue;
+                    }
+                },
+                Call::One(Value::Halt, v) => return Ok(Outcome::Done(v)),
+                Call::One(Value::Abort, v) => {
+                    let exception = match self.exception(&v) {
+                        Some(e) => e.clone(),
+                        None => Exception {
+                            value: v,
+                            spans: Vec::new(),
+                            continuation: None,
+                        },
+                    };
+                    let (at, k) = (exception.spans.first().copied(), exception.continuation.clone());
+
+                    return Err(self.failed(Error::Uncaught(exception), at, k));
+                }
+                Call::One(Value::GenReturn(g), v) => {
+                    let h = self.end_generator(g, State::Returned(v))?;
+                    call = self.raise_message(h, None, generator::FINISHED);
+                    continue;
+                }
+                Call::One(Value::GenRaise(g), e) => {
+                    call = Call::One(self.end_generator(g, State::Failed)?, e);
+                    continue;
+                }
+                Call::One(Value::TaskReturn(t), v) => {
+                    call = self.end_task(t, Ok(v))?;
+                    continue;
+                }
+                Call::One(Value::TaskRaise(t), e) => {
+                    call = self.end_task(t, Err(e))?;
+                    continue;
+                }
+                Call::One(Value::HostReturn(c), v) => {
+                    let HostCall {
+                        step,
+                        mut state,
+                        k,
+                        h,
+                        span,
+                    } = self.host_call(c).clone();
+                    state.push(v);
+                    call = self
+                        .run_host(state, k.clone(), h, span, |machine, args| {
+                            step(machine, &args)
+                        })
+                        .map_err(|e| self.failed(e, location(&from), Some(k)))?;
+                    continue;
+                }
+                Call::One(k, _) => return Err(Error::NotAContinuation(k)),
+                Call::Two(Value::Closure(c), v, k, h) => match self.closure(c) {
+                    (Body::Expr(body), env) => {
+                        let env = env
+                            .push(&mut self.heap, v)
+                            .push(&mut self.heap, k)
+                            .push(&mut self.heap, h);
+                        (body, env)
+                    }
+                    (Body::Compiled(code), _) => {
+                        call = (code.code)(self, c, &[h, k, v])?;
+                        from = None;
+                        continue;
+                    }
+                },
+                Call::Two(Value::Prim(p), v, k, h) => {
+                    call = self
+                        .prim(p, v, k.clone(), h)
+                        .map_err(|e| self.failed(e, location(&from), Some(k)))?;
+                    continue;
+                }
+                Call::Two(Value::Partial(gc), b, k, h) => {
+                    call = match self.heap.get(gc) {
+                        Object::Partial(p, a) => {
+                            let (p, a) = (*p, a.clone());
+                            self.prim_two(p, a, b, k.clone(), h)
+                        }
+                        Object::HostPartial(f, args) => {
+                            let (f, mut args) = (f.clone(), args.clone());
+                            args.push(b);
+                            self.host(&f, args, k.clone(), h, location(&from))
+                        }
+                        _ => unreachable!("partial value is not a partial"),
+                    }
+                    .map_err(|e| self.failed(e, location(&from), Some(k)))?;
+                    continue;
+                }
+                Call::Two(Value::Generator(g), v, k, h) => {
+                    call = self.resume_generator(g, v, k, h);
+                    continue;
+                }
+                Call::Two(Value::Yield(g), v, k, h) => {
+                    call = self.yield_generator(g, v, k, h);
+                    continue;
+                }
+                Call::Two(Value::Host(f), v, k, h) => {
+                    call = self
+                        .host(&f, vec![v], k.clone(), h, location(&from))
+                        .map_err(|e| self.failed(e, location(&from), Some(k)))?;
+                    continue;
+                }
+                Call::Two(f, _, k, _) => {
+                    return Err(self.failed(Error::NotAFunction(f), location(&from), Some(k)))
+                }
+            };
+
+            if let Some((program, debugger)) = &mut debugger {
+                let reason = match body.span() {
+                    Some(span) if debugger.breakpoint(span) => Some((span, Reason::Breakpoint)),
+                    Some(span) if stepping => Some((span, Reason::Step)),
+                    _ => None,
+                };
+
+                if let Some((span, reason)) = reason {
+                    let stop = Stop {
+                        span,
+                        reason,
+                        program,
+                        body: body.clone(),
+                        env,
+                    };
+                    stepping = debugger.stop(self, &stop) == Resume::Step;
+                }
+            }
+
+            // checked first, as taking the profiler moves all of it
+            if self.profiler.is_some() {
+                let mut profiler = self.profiler.take().unwrap();
+                profiler.step(self, &body, env);
+                self.profiler = Some(profiler);
+            }
+
+            call = self.call(&body, env).map_err(|e| {
+                let k = backtrace::continuation(self, &body, 0, env);
+                self.failed(e, backtrace::location(&body), k)
+            })?;
+            from = Some(body);
+        }
+    }
+
+    /// Record the backtrace of a run failing at `at` in the function
+    /// returning to `k`
+    fn failed(&mut self, e: Error, at: Option<Span>, k: Option<Value>) -> Error {
+        self.backtrace = backtrace::trace(self, at, k);
+        e
+    }
+
+    /// Raise running out of memory to `h`, or fail if it's the handler of the
+    /// whole run
+    ///
+    /// The continuation of whatever ran out isn't kept for the backtrace,
+    /// so that what it held can be collected before the handler runs.
+    fn out_of_memory(&mut self, h: Value) -> Result<Call, Error> {
+        match h {
+            Value::Abort => Err(Error::OutOfMemory),
+            h => {
+                let message = Value::Lit(Literal::String(Error::OutOfMemory.to_string()));
+                Ok(Call::One(h, self.raise(message, None, None)))
+            }
+        }
+    }
+
+    /// Raise a message to `h` from the function returning to `k`
+    fn raise_message(&mut self, h: Value, k: Option<Value>, msg: &str) -> Call {
+        let message = Value::Lit(Literal::String(msg.to_owned()));
+
+        Call::One(h, self.raise(message, None, k))
+    }
+
+    fn prim(&mut self, prim: Prim, v: Value, k: Value, h: Value) -> Result<Call, Error> {
+        let void = Value::Lit(Literal::Void);
+
+        Ok(match prim {
+            Prim::Generator => {
+                let generator = Generator {
+                    state: State::Start(v),
+                    resumer: None,
+                };
+                let gc = self.heap.alloc(Object::Generator(generator));
+
+                Call::One(k, Value::Generator(gc))
+            }
+            Prim::Spawn => {
+                let task = Task {
+                    result: None,
+                    joiners: Vec::new(),
+                };
+                let t = self.heap.alloc(Object::Task(task));
+
+                self.scheduler.ready(Call::Two(
+                    v,
+                    void,
+                    Value::TaskReturn(t),
+                    Value::TaskRaise(t),
+                ));
+
+                Call::One(k, Value::Task(t))
+            }
+            Prim::YieldNow => {
+                self.block(prim, k, h, |machine, k, _| {
+                    machine.scheduler.ready(Call::One(k, void))
+                })?
+            }
+            Prim::Sleep => match v {
+                Value::Lit(Literal::Int(millis)) => self.block(prim, k, h, |machine, k, _| {
+                    machine.scheduler.sleep(Call::One(k, void), millis)
+                })?,
+                _ => self.raise_message(h, Some(k), "sleep: expected a number of milliseconds"),
+            },
+            Prim::Channel => match v {
+                Value::Lit(Literal::Void) => Call::One(k, self.channel(None)),
+                Value::Lit(Literal::Int(capacity)) => {
+                    Call::One(k, self.channel(Some(capacity as usize)))
+                }
+                _ => self.raise_message(h, Some(k), "channel: expected a capacity or void"),
+            },
+            // takes the channel first, then the value
+            Prim::Send => Call::One(k, Value::Partial(self.heap.alloc(Object::Partial(prim, v)))),
+            Prim::Recv => match v {
+                Value::Channel(gc) => match self.take(gc) {
+                    Some(v) => Call::One(k, v),
+                    None => self.block(prim, k, h, |machine, k, _| {
+                        machine.channel_mut(gc).wait(Receiver { k, select: None })
+                    })?,
+                },
+                _ => self.raise_message(h, Some(k), "recv: expected a channel"),
+            },
+            Prim::Select => self.select(&v, k, h)?,
+            Prim::All => match self.tasks(&v) {
+                Some(tasks) => self.all(tasks, k, h)?,
+                None => self.raise_message(h, Some(k), "all: expected a list of tasks"),
+            },
+            Prim::Race => match self.tasks(&v) {
+                Some(tasks) => self.race(tasks, k, h)?,
+                None => self.raise_message(h, Some(k), "race: expected a list of tasks"),
+            },
+            Prim::Join => match v {
+                Value::Task(t) => match self.task(t).result.clone() {
+                    Some(Ok(v)) => Call::One(k, v),
+                    Some(Err(e)) => Call::One(h, e),
+                    None => self.block(prim, k, h, |machine, k, h| {
+                        machine.task_mut(t).joiners.push(Joiner::Join(k, h))
+                    })?,
+                },
+                _ => self.raise_message(h, Some(k), "join: expected a task"),
+            },
+        })
+    }
+
+    /// Call a host function from `span`, returning to `k` or raising its
+    /// failure to `h`
+    fn host(
+        &mut self,
+        f: &HostFn,
+        args: Vec<Value>,
+        k: Value,
+        h: Value,
+        span: Option<Span>,
+    ) -> Result<Call, Error> {
+        self.run_host(args, k, h, span, |machine, args| f.apply(machine, args))
+    }
+
+    pub(crate) fn host_call(&self, gc: Gc) -> &HostCall {
+        match self.heap.get(gc) {
+            Object::HostCall(c) => c,
+            _ => unreachable!("host return value is not a host call"),
+        }
+    }
+
+    /// Run host code, making the call it asks for with `call_then` or
+    /// returning to `k`, and raising its failure to `h`
+    ///
+    /// The continuations and arguments are live while it runs, as it may run
+    /// scripts of its own.
+    fn run_host(
+        &mut self,
+        args: Vec<Value>,
+        k: Value,
+        h: Value,
+        span: Option<Span>,
+        code: impl FnOnce(&mut Machine, Vec<Value>) -> Result<Value, Error>,
+    ) -> Result<Call, Error> {
+        let depth = self.pending.len();
+        self.pending.extend([k.clone(), h.clone()]);
+        self.pending.extend(args.iter().cloned());
+        let result = code(self, args);
+        self.pending.truncate(depth);
+        let request = self.request.take();
+
+        Ok(match result {
+            Ok(_) if request.is_some() => {
+                let Request {
+                    f,
+                    arg,
+                    state,
+                    step,
+                } = request.unwrap();
+                let call = HostCall {
+                    step,
+                    state,
+                    k,
+                    h: h.clone(),
+                    span,
+                };
+                let k = Value::HostReturn(self.heap.alloc(Object::HostCall(call)));
+
+                Call::Two(f, arg, k, h)
+            }
+            Ok(v) => Call::One(k, v),
+            Err(Error::Host(msg)) => {
+                let msg = Value::Lit(Literal::String(msg));
+                Call::One(h, self.raise(msg, span, Some(k)))
+            }
+            Err(Error::Raise(v)) => Call::One(h, self.raise(v, span, Some(k))),
+            Err(Error::OutOfMemory) => self.out_of_memory(h)?,
+            Err(e) => return Err(e),
+        })
+    }
+
+    /// Apply a primitive taking two arguments
+    fn prim_two(
+        &mut self,
+        prim: Prim,
+        a: Value,
+        b: Value,
+        k: Value,
+        h: Value,
+    ) -> Result<Call, Error> {
+        Ok(match prim {
+            Prim::Send => match a {
+                Value::Channel(gc) => match self.deliver(gc, b) {
+                    Ok(()) => Call::One(k, Value::Lit(Literal::Void)),
+                    Err(b) => self.block(prim, k, h, |machine, k, _| {
+                        machine.channel_mut(gc).senders.push_back((k, b))
+                    })?,
+                },
+                _ => self.raise_message(h, Some(k), "send: expected a channel"),
+            },
+            _ => unreachable!("{} takes one argument", prim),
+        })
+    }
+
+    fn task(&self, gc: Gc) -> &Task {
+        match self.heap.get(gc) {
+            Object::Task(t) => t,
+            _ => unreachable!("task value is not a task"),
+        }
+    }
+
+    fn task_mut(&mut self, gc: Gc) -> &mut Task {
+        match self.heap.get_mut(gc) {
+            Object::Task(t) => t,
+            _ => unreachable!("task value is not a task"),
+        }
+    }
+
+    /// Block the current task, with `wait` recording where to wake it up,
+    /// and continue with the next ready task
+    ///
+    /// A host function waiting on `apply` is returned to by whichever task
+    /// finishes the nested run, so tasks can't switch while one is waiting
+    /// and `prim` raises instead.
+    fn block(
+        &mut self,
+        prim: Prim,
+        k: Value,
+        h: Value,
+        wait: impl FnOnce(&mut Machine, Value, Value),
+    ) -> Result<Call, Error> {
+        if self.callbacks > 0 {
+            let msg = format!("{}: can't wait inside a function called by the host", prim);
+            return Ok(self.raise_message(h, Some(k), &msg));
+        }
+
+        wait(self, k, h);
+        self.switch()
+    }
+
+    /// Continue with the next ready task, the current one is blocked
+    fn switch(&mut self) -> Result<Call, Error> {
+        self.scheduler.next().ok_or(Error::Deadlock)
+    }
+
+    /// The tasks in a list, if that's all it holds
+    fn tasks(&self, list: &Value) -> Option<Vec<Gc>> {
+        self.list(list)?
+            .iter()
+            .map(|t| match t {
+                Value::Task(gc) => Some(*gc),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The result of waiting for every task, once it is known
+    fn all_finished(&mut self, tasks: &[Gc]) -> Option<Result<Value, Value>> {
+        let mut results = Vec::new();
+
+        for &t in tasks {
+            match &self.task(t).result {
+                Some(Ok(v)) => results.push(v.clone()),
+                Some(Err(e)) => return Some(Err(e.clone())),
+                None => (),
+            }
+        }
+
+        if results.len() < tasks.len() {
+            return None;
+        }
+
+        Some(Ok(self.alloc_list(results)))
+    }
+
+    fn all(&mut self, tasks: Vec<Gc>, k: Value, h: Value) -> Result<Call, Error> {
+        match self.all_finished(&tasks) {
+            Some(Ok(v)) => return Ok(Call::One(k, v)),
+            Some(Err(e)) => return Ok(Call::One(h, e)),
+            None => (),
+        }
+
+        self.block(Prim::All, k, h, |machine, k, h| {
+            let waiting = Rc::new(RefCell::new(Some(AllJoin {
+                tasks: tasks.iter().map(|&t| Value::Task(t)).collect(),
+                k,
+                h,
+            })));
+            for t in tasks {
+                let task = machine.task_mut(t);
+                if task.result.is_none() {
+                    task.joiners.push(Joiner::All(waiting.clone()));
+                }
+            }
+        })
+    }
+
+    fn race(&mut self, tasks: Vec<Gc>, k: Value, h: Value) -> Result<Call, Error> {
+        let finished = tasks.iter().find_map(|&t| self.task(t).result.clone());
+        match finished {
+            Some(Ok(v)) => return Ok(Call::One(k, v)),
+            Some(Err(e)) => return Ok(Call::One(h, e)),
+            None => (),
+        }
+
+        self.block(Prim::Race, k, h, |machine, k, h| {
+            let waiting = Rc::new(RefCell::new(Some((k, h))));
+            for t in tasks {
+                machine.task_mut(t).joiners.push(Joiner::Race(waiting.clone()));
+            }
+        })
+    }
+
+    /// Record the result of a task and wake up the tasks joining it
+    fn end_task(&mut self, t: Gc, result: Result<Value, Value>) -> Result<Call, Error> {
+        let task = self.task_mut(t);
+        task.result = Some(result.clone());
+        let joiners = mem::take(&mut task.joiners);
+
+        let wake = |(k, h)| match &result {
+            Ok(v) => Call::One(k, v.clone()),
+            Err(e) => Call::One(h, e.clone()),
+        };
+
+        for joiner in joiners {
+            let call = match joiner {
+                Joiner::Join(k, h) => Some(wake((k, h))),
+                Joiner::Race(race) => race.borrow_mut().take().map(wake),
+                Joiner::All(all) => {
+                    let tasks: Vec<_> = match &*all.borrow() {
+                        Some(all) => all.tasks.iter().filter_map(Value::gc).collect(),
+                        None => continue,
+                    };
+
+                    self.all_finished(&tasks).map(|result| {
+                        let AllJoin { k, h, .. } = all.borrow_mut().take().unwrap();
+                        match result {
+                            Ok(v) => Call::One(k, v),
+                            Err(e) => Call::One(h, e),
+                        }
+                    })
+                }
+            };
+
+            if let Some(call) = call {
+                self.scheduler.ready(call);
+            }
+        }
+
+        self.switch()
+    }
+
+    /// Run a generator until it next yields, `v` is the result of the yield
+    /// it is suspended at
+    fn resume_generator(&mut self, g: Gc, v: Value, k: Value, h: Value) -> Call {
+        let generator = self.generator_mut(g);
+
+        match mem::replace(&mut generator.state, State::Running) {
+            State::Start(f) => {
+                generator.resumer = Some((k, h));
+                Call::Two(f, Value::Yield(g), Value::GenReturn(g), Value::GenRaise(g))
+            }
+            State::Yielded(resume) => {
+                generator.resumer = Some((k, h));
+                Call::One(resume, v)
+            }
+            State::Running => self.raise_message(h, Some(k), "generator is already running"),
+            finished => {
+                generator.state = finished;
+                self.raise_message(h, Some(k), generator::FINISHED)
+            }
+        }
+    }
+
+    fn yield_generator(&mut self, g: Gc, v: Value, k: Value, h: Value) -> Call {
+        let generator = self.generator_mut(g);
+
+        match generator.resumer.take() {
+            Some((resumer, _)) => {
+                generator.state = State::Yielded(k);
+                Call::One(resumer, v)
+            }
+            None => self.raise_message(h, Some(k), "yield outside of its generator"),
+        }
+    }
+
+    /// The generator's body finished, returning the handler of whoever
+    /// resumed it
+    fn end_generator(&mut self, g: Gc, state: State) -> Result<Value, Error> {
+        let generator = self.generator_mut(g);
+        generator.state = state;
+
+        match generator.resumer.take() {
+            Some((_, h)) => Ok(h),
+            None => Err(Error::NotAContinuation(Value::GenReturn(g))),
+        }
+    }
+
+    /// Evaluate the operands of a lambda body
+    pub(crate) fn call(&mut self, body: &FExpr, env: Env) -> Result<Call, Error> {
+        match body {
+            FExpr::CallOne(k, v) => Ok(Call::One(self.atom(k, env)?, self.atom(v, env)?)),
+            FExpr::CallTwo(f, v, k, h, Ignore(site)) => {
+                let f = self.atom(f, env)?;
+                let v = self.atom(v, env)?;
+                let k_value = self.atom(k, env)?;
+                if let FExpr::LamOne(_) = **k {
+                    self.mark_site(&k_value, site);
+                }
+
+                Ok(Call::Two(f, v, k_value, self.atom(h, env)?))
+            }
+            FExpr::Raise(h, v, Ignore(span)) => {
+                let h = self.atom(h, env)?;
+                let v = self.atom(v, env)?;
+                let k = backtrace::continuation(self, body, 0, env);
+
+                Ok(Call::One(h, self.raise(v, Some(*span), k)))
+            }
+            _ => Err(Error::NotACall),
+        }
+    }
+}
+
+pub enum Outcome {
+    Done(Value),
+    /// The run ran out of fuel, it can be continued with `Machine::resume`
+    Suspended(Suspended),
+}
+
+impl Outcome {
+    pub(crate) fn unwrap_done(self) -> Value {
+        match self {
+            Outcome::Done(v) => v,
+            Outcome::Suspended(_) => unreachable!("suspended without a fuel limit"),
+        }
+    }
+}
+
+/// The call a suspended run was about to make, including the continuation
+/// of the program at that point
+///
+/// Everything the call refers to is kept alive until the run is resumed or
+/// this is dropped. A run also suspends when every task is blocked or asleep,
+/// in which case resuming it continues whichever task the host has since
+/// woken or is due to wake.
+pub struct Suspended {
+    /// Empty if every task was blocked
+    call: Vec<Handle>,
+    /// When the first sleeping task wakes, if every task was blocked
+    wake: Option<u64>,
+}
+
+impl Suspended {
+    fn new(machine: &mut Machine, call: Call) -> Self {
+        let values = match call {
+            Call::One(k, v) => vec![k, v],
+            Call::Two(f, v, k, h) => vec![f, v, k, h],
+        };
+
+        Suspended {
+            call: values.into_iter().map(|v| machine.root(v)).collect(),
+            wake: None,
+        }
+    }
+
+    /// Whether the run is waiting on the host rather than out of fuel
+    pub fn is_blocked(&self) -> bool {
+        self.call.is_empty()
+    }
+
+    /// The time on the machine's clock a sleeping task wakes at, if the run
+    /// is blocked and any task is asleep
+    ///
+    /// Resuming the run before then suspends it again straight away, unless
+    /// the host has woken another task in the meantime.
+    pub fn wake_at(&self) -> Option<u64> {
+        self.wake
+    }
+
+    pub(crate) fn into_call(self) -> Option<Call> {
+        match &self.call[..] {
+            [] => None,
+            [k, v] => Some(Call::One(k.get(), v.get())),
+            [f, v, k, h] => Some(Call::Two(f.get(), v.get(), k.get(), h.get())),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A fully evaluated `FExpr::CallOne`, `FExpr::CallTwo` or `FExpr::Raise`
+pub(crate) enum Call {
+    One(Value, Value),
+    Two(Value, Value, Value, Value),
+}
+
+impl Call {
+    pub(crate) fn values(&self) -> Vec<&Value> {
+        match self {
+            Call::One(k, v) => vec![k, v],
+            Call::Two(f, v, k, h) => vec![f, v, k, h],
+        }
+    }
+}
+
+fn channel_gc(value: &Value) -> Result<Gc, Error> {
+    match value {
+        Value::Channel(gc) => Ok(*gc),
+        _ => Err(Error::NotAChannel(value.clone())),
+    }
+}
+
+pub(crate) fn unbound(var: &Var<String>) -> Error {
+    Error::UnboundVariable(
+        var.pretty_name()
+            .cloned()
+            .unwrap_or_else(|| var.to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, parse, utils::test::*};
+
+    #[test]
+    fn runs_out_of_fuel_and_resumes() {
+        let program = cont_expr::program(deep_recursion(3)).into_fexpr();
+
+        let mut machine = Machine::new();
+        let mut outcome = machine.run_with_fuel(&program, 1000).unwrap();
+        let mut suspensions = 0;
+
+        let result = loop {
+            match outcome {
+                Outcome::Done(v) => break v,
+                Outcome::Suspended(s) => {
+                    suspensions += 1;
+                    // suspended runs must survive collections in between
+                    machine.collect();
+                    outcome = machine.resume(s, 1000).unwrap();
+                }
+            }
+        };
+
+        assert!(suspensions > 1);
+        assert_eq!(result.to_string(), "void");
+    }
+
+    #[test]
+    fn deep_recursion_runs_in_constant_stack() {
+        let program = cont_expr::program(deep_recursion(5)).into_fexpr();
+
+        let result = Machine::new().run(&program).unwrap();
+
+        assert_eq!(result.to_string(), "void");
+    }
+
+    #[test]
+    fn memory_limit_counts_live_data_only() {
+        let mut machine = Machine::new();
+        machine.set_memory_limit(Some(64 * 1024));
+
+        // garbage is collected to stay under the limit
+        machine.set_step_limit(Some(100_000));
+        let program = cont_expr::program(omega()).into_fexpr();
+        assert!(matches!(
+            machine.run(&program),
+            Err(Error::StepLimitExceeded(_))
+        ));
+
+        // but a deep chain of continuations is all live
+        machine.set_step_limit(None);
+        let program = cont_expr::program(deep_recursion(5)).into_fexpr();
+        assert!(matches!(machine.run(&program), Err(Error::OutOfMemory)));
+    }
+
+    #[test]
+    fn running_out_of_memory_can_be_caught() {
+        let mut machine = Machine::new();
+        machine.set_memory_limit(Some(64 * 1024));
+
+        let caught = try_catch(deep_recursion(5), "e", |_| string("caught"));
+        let program = cont_expr::program(caught).into_fexpr();
+        assert_eq!(machine.run(&program).unwrap().to_string(), "\"caught\"");
+
+        // unless the handler runs out too
+        let again = try_catch(deep_recursion(5), "e", |_| deep_recursion(5));
+        let program = cont_expr::program(again).into_fexpr();
+        assert!(matches!(machine.run(&program), Err(Error::OutOfMemory)));
+    }
+
+    #[test]
+    fn raised_exceptions_are_caught() {
+        let mut machine = Machine::new();
+
+        // ((lambda (x) x) (try ((lambda (x) (raise x)) 1) (catch (e) e)))
+        let expr = app(
+            lam("x", |x| x),
+            try_catch(
+                app(lam("x", |x| raise(x, 10, 19)), lit(Literal::Int(1))),
+                "e",
+                |e| e,
+            ),
+        );
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let result = machine.run(&program).unwrap();
+        let exception = machine.exception(&result).unwrap();
+        assert_eq!(exception.value.to_string(), "1");
+        assert_eq!(exception.spans, vec![Span::new(10, 19)]);
+
+        // the handler is skipped if nothing is raised
+        let expr = try_catch(string("fine"), "e", |_| string("caught"));
+        let program = cont_expr::program(expr).into_fexpr();
+        assert_eq!(machine.run(&program).unwrap().to_string(), "\"fine\"");
+    }
+
+    #[test]
+    fn reraising_records_spans() {
+        // (try (try (raise 1) (catch (e) (raise e))) (catch (e) e))
+        let inner = try_catch(raise(lit(Literal::Int(1)), 0, 1), "e", |e| raise(e, 2, 3));
+        let expr = try_catch(inner.clone(), "e", |e| e);
+
+        let mut machine = Machine::new();
+        let program = cont_expr::program(expr).into_fexpr();
+        let result = machine.run(&program).unwrap();
+        let spans = &machine.exception(&result).unwrap().spans;
+        assert_eq!(spans, &vec![Span::new(0, 1), Span::new(2, 3)]);
+
+        let program = cont_expr::program(inner).into_fexpr();
+        match machine.run(&program) {
+            Err(e @ Error::Uncaught(_)) => assert_eq!(
+                e.to_string(),
+                "uncaught exception: 1\n  raised at 0..1\n  raised at 2..3"
+            ),
+            r => panic!("expected an uncaught exception, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn host_errors_are_exceptions() {
+        let mut machine = Machine::new();
+        machine.register("fail", |_, _| Err(Error::Host("no thanks".to_owned())));
+
+        let call = app(global("fail"), lit(Literal::Void));
+
+        let expr = try_catch(call.clone(), "e", |_| string("caught"));
+        let program = cont_expr::program(expr).into_fexpr();
+        assert_eq!(machine.run(&program).unwrap().to_string(), "\"caught\"");
+
+        let program = cont_expr::program(call).into_fexpr();
+        match machine.run(&program) {
+            Err(Error::Uncaught(e)) => {
+                assert_eq!(e.value.to_string(), "\"no thanks\"");
+                assert!(e.spans.is_empty());
+            }
+            r => panic!("expected an uncaught exception, got {:?}", r),
+        }
+
+        // a call from the source is where the exception was raised
+        let src = "(define g (lambda (x) (begin (fail x) x)))\n(g 1)";
+        let program = cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+        let at = |text: &str| {
+            let start = src.find(text).unwrap();
+            Span::new(start, start + text.len())
+        };
+        match machine.run(&program) {
+            Err(Error::Uncaught(e)) => assert_eq!(e.spans, vec![at("(fail x)")]),
+            r => panic!("expected an uncaught exception, got {:?}", r),
+        }
+        let frames: Vec<_> = machine
+            .backtrace()
+            .iter()
+            .map(|f| (f.span, f.function.as_deref()))
+            .collect();
+        assert_eq!(frames, vec![(at("(fail x)"), Some("g")), (at("(g 1)"), None)]);
+    }
+
+    #[test]
+    fn step_limit_stops_infinite_loops() {
+        let program = cont_expr::program(omega()).into_fexpr();
+
+        let mut machine = Machine::new();
+        machine.set_step_limit(Some(2_000_000));
+
+        match machine.run(&program) {
+            Err(Error::StepLimitExceeded(2_000_000)) => (),
+            r => panic!("expected the step limit to be hit, got {:?}", r),
+        }
+    }
+}
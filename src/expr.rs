@@ -2,72 +2,141 @@ use moniker::BoundTerm;
 use moniker::{Binder, Scope, Var, Ignore};
 
 use pretty::{BoxAllocator, DocAllocator, DocBuilder};
-use termcolor::{Color, ColorSpec, WriteColor};
+use termcolor::WriteColor;
 
 use std::{io::Result, rc::Rc};
 
-use crate::literals::Literal;
+use crate::{
+    literals::Literal,
+    render::{RenderOptions, Style},
+    span::Span,
+};
 
 #[derive(Debug, Clone, BoundTerm)]
 pub enum Expr {
     Var(Var<String>),
     Lit(Ignore<Literal>),
     Lam(Scope<Binder<String>, Rc<Expr>>),
-    App(Rc<Expr>, Rc<Expr>),
+    /// Call a function, the span is where the call is in the source, if it
+    /// came from there
+    App(Rc<Expr>, Rc<Expr>, Ignore<Option<Span>>),
+    /// Throw a value to the nearest enclosing `Try`, the span is recorded on
+    /// the exception
+    Raise(Rc<Expr>, Ignore<Span>),
+    /// Evaluate the body, running the handler with the exception if it raises
+    Try(Rc<Expr>, Scope<Binder<String>, Rc<Expr>>),
+    /// A generator function, calling it returns a generator that runs the
+    /// body a `Yield` at a time
+    Gen(Scope<Binder<String>, Rc<Expr>>),
+    /// Suspend the innermost enclosing generator, passing the value to
+    /// whoever resumed it
+    Yield(Rc<Expr>),
+    /// An async function, calling it starts a task running the body and
+    /// returns the task without waiting for it
+    Async(Scope<Binder<String>, Rc<Expr>>),
+    /// Wait for a task to finish, giving its result or raising its exception
+    Await(Rc<Expr>),
 }
 
 impl Expr {
-    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, ColorSpec>
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D, indent: isize) -> DocBuilder<'a, D, Style>
     where
-        D: DocAllocator<'a, ColorSpec>,
+        D: DocAllocator<'a, Style>,
         D::Doc: Clone,
     {
         match self {
             Expr::Var(s) => allocator.as_string(s),
             Expr::Lit(Ignore(l)) => l.pretty(allocator),
-            Expr::Lam(s) => {
+            Expr::Lam(s) | Expr::Gen(s) | Expr::Async(s) => {
                 let Scope {
                     unsafe_pattern: pat,
                     unsafe_body: body,
                 } = &s;
+                let keyword = match self {
+                    Expr::Gen(_) => "generator",
+                    Expr::Async(_) => "async",
+                    _ => "lambda",
+                };
 
                 let pat_pret = allocator
                     .as_string(pat)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Green)).clone())
+                    .annotate(Style::Binder)
                     .parens();
                 let body_pret = allocator
                     .line_()
-                    .append(body.pretty(allocator))
-                    .nest(1)
+                    .append(body.pretty(allocator, indent))
+                    .nest(indent)
                     .group();
 
                 allocator
-                    .text("lambda")
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Magenta)).clone())
+                    .text(keyword)
+                    .annotate(Style::Keyword)
                     .append(allocator.space())
                     .append(pat_pret)
                     .append(allocator.space())
                     .append(body_pret)
                     .parens()
             }
-            Expr::App(f, v) => {
-                let f_pret = f.pretty(allocator);
-                let v_pret = v.pretty(allocator);
+            Expr::App(f, v, _) => {
+                let f_pret = f.pretty(allocator, indent);
+                let v_pret = v.pretty(allocator, indent);
 
                 f_pret
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Blue)).clone())
+                    .annotate(Style::Callee)
                     .append(allocator.space())
                     .append(v_pret)
                     .parens()
             }
-        }
-    }
+            Expr::Yield(e) | Expr::Await(e) => allocator
+                .text(match self {
+                    Expr::Yield(_) => "yield",
+                    _ => "await",
+                })
+                .annotate(Style::Keyword)
+                .append(allocator.space())
+                .append(e.pretty(allocator, indent))
+                .parens(),
+            Expr::Raise(e, _) => allocator
+                .text("raise")
+                .annotate(Style::Keyword)
+                .append(allocator.space())
+                .append(e.pretty(allocator, indent))
+                .parens(),
+            Expr::Try(body, handler) => {
+                let Scope {
+                    unsafe_pattern: pat,
+                    unsafe_body: handler,
+                } = &handler;
 
-    pub fn pretty_print(&self, out: impl WriteColor) -> Result<()> {
-        let allocator = BoxAllocator;
+                let keyword = |name| {
+                    allocator
+                        .text(name)
+                        .annotate(Style::Keyword)
+                };
 
-        self.pretty(&allocator).1.render_colored(70, out)?;
+                let pat_pret = allocator
+                    .as_string(pat)
+                    .annotate(Style::Binder)
+                    .parens();
+                let catch_pret = keyword("catch")
+                    .append(allocator.space())
+                    .append(pat_pret)
+                    .append(allocator.line().append(handler.pretty(allocator, indent)).nest(indent))
+                    .group()
+                    .parens();
+
+                keyword("try")
+                    .append(allocator.line().append(body.pretty(allocator, indent)).nest(indent))
+                    .append(allocator.line().append(catch_pret).nest(indent))
+                    .group()
+                    .parens()
+            }
+        }
+    }
 
-        Ok(())
+    /// Print in the format of the options' target, fitting lines into its
+    /// width where possible
+    pub fn pretty_print(&self, out: impl WriteColor, options: &RenderOptions) -> Result<()> {
+        options.render(self.pretty(&BoxAllocator, options.indent), out)
     }
 }
@@ -2,27 +2,45 @@ use moniker::BoundTerm;
 use moniker::{Binder, Ignore, Scope, Var};
 
 use pretty::{BoxAllocator, DocAllocator, DocBuilder};
-use termcolor::{Color, ColorSpec, WriteColor};
+use termcolor::WriteColor;
 
 use std::{io::Result, rc::Rc};
 
 use crate::literals::Literal;
+use crate::prim::Prim;
+use crate::render::{RenderOptions, Style};
+use crate::span::{CallSite, Span};
 use crate::utils::clone_rc;
 
+/// The binders of a user lambda, its argument, return continuation and
+/// handler
+pub type UserScope<T> = Scope<Binder<String>, Scope<Binder<String>, Scope<Binder<String>, T>>>;
+
 #[derive(Debug, Clone, BoundTerm)]
 pub enum FExpr {
     LamOne(Scope<Binder<String>, Rc<FExpr>>),
-    LamTwo(Scope<Binder<String>, Scope<Binder<String>, Rc<FExpr>>>),
+    LamTwo(UserScope<Rc<FExpr>>),
     Var(Var<String>),
     Lit(Ignore<Literal>),
+    Prim(Ignore<Prim>),
     CallOne(Rc<FExpr>, Rc<FExpr>),
-    CallTwo(Rc<FExpr>, Rc<FExpr>, Rc<FExpr>),
+    /// Call a user lambda with an argument, continuation and handler, see
+    /// `cont_expr::CCall::UCall`
+    CallTwo(
+        Rc<FExpr>,
+        Rc<FExpr>,
+        Rc<FExpr>,
+        Rc<FExpr>,
+        Ignore<Option<Rc<CallSite>>>,
+    ),
+    /// Raise a value to a handler, see `cont_expr::CCall::Raise`
+    Raise(Rc<FExpr>, Rc<FExpr>, Ignore<Span>),
 }
 
 impl FExpr {
-    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, ColorSpec>
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D, indent: isize) -> DocBuilder<'a, D, Style>
     where
-        D: DocAllocator<'a, ColorSpec>,
+        D: DocAllocator<'a, Style>,
         D::Doc: Clone,
     {
         match self {
@@ -34,17 +52,17 @@ impl FExpr {
 
                 let pat_pret = allocator
                     .as_string(pat)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Green)).clone())
+                    .annotate(Style::Binder)
                     .parens();
                 let body_pret = allocator
                     .line_()
-                    .append(body.pretty(allocator))
-                    .nest(1)
+                    .append(body.pretty(allocator, indent))
+                    .nest(indent)
                     .group();
 
                 allocator
                     .text("lambda")
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Magenta)).clone())
+                    .annotate(Style::Keyword)
                     .append(allocator.space())
                     .append(pat_pret)
                     .append(allocator.space())
@@ -57,29 +75,38 @@ impl FExpr {
                     unsafe_body:
                         Scope {
                             unsafe_pattern: cont,
-                            unsafe_body: body,
+                            unsafe_body:
+                                Scope {
+                                    unsafe_pattern: handler,
+                                    unsafe_body: body,
+                                },
                         },
                 } = &s;
 
                 let pat_pret = allocator
                     .as_string(pat)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Green)).clone());
+                    .annotate(Style::Binder);
                 let cont_pret = allocator
                     .as_string(cont)
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Red)).clone());
+                    .annotate(Style::Continuation);
+                let handler_pret = allocator
+                    .as_string(handler)
+                    .annotate(Style::Continuation);
                 let args_pret = pat_pret
                     .append(allocator.space())
                     .append(cont_pret)
+                    .append(allocator.space())
+                    .append(handler_pret)
                     .parens();
                 let body_pret = allocator
                     .line_()
-                    .append(body.pretty(allocator))
-                    .nest(1)
+                    .append(body.pretty(allocator, indent))
+                    .nest(indent)
                     .group();
 
                 allocator
                     .text("lambda")
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Magenta)).clone())
+                    .annotate(Style::Keyword)
                     .append(allocator.space())
                     .append(args_pret)
                     .append(allocator.space())
@@ -88,38 +115,59 @@ impl FExpr {
             }
             FExpr::Var(s) => allocator.as_string(s),
             FExpr::Lit(Ignore(l)) => l.pretty(allocator),
+            FExpr::Prim(Ignore(p)) => allocator
+                .text(p.name())
+                .annotate(Style::Prim),
             FExpr::CallOne(f, c) => {
-                let f_pret = f.pretty(allocator);
-                let c_pret = c.pretty(allocator);
+                let f_pret = f.pretty(allocator, indent);
+                let c_pret = c.pretty(allocator, indent);
 
                 f_pret
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Blue)).clone())
+                    .annotate(Style::Callee)
                     .append(allocator.space())
                     .append(c_pret)
                     .parens()
             }
-            FExpr::CallTwo(f, v, c) => {
-                let f_pret = f.pretty(allocator);
-                let v_pret = v.pretty(allocator);
-                let c_pret = c.pretty(allocator);
+            FExpr::CallTwo(f, v, c, h, _) => {
+                let f_pret = f.pretty(allocator, indent);
+                let v_pret = v.pretty(allocator, indent);
+                let c_pret = c.pretty(allocator, indent);
+                let h_pret = h.pretty(allocator, indent);
 
                 f_pret
-                    .annotate(ColorSpec::new().set_fg(Some(Color::Blue)).clone())
+                    .annotate(Style::Callee)
                     .append(allocator.space())
                     .append(v_pret)
                     .append(allocator.space())
                     .append(c_pret)
+                    .append(allocator.space())
+                    .append(h_pret)
                     .parens()
             }
+            FExpr::Raise(h, v, _) => allocator
+                .text("raise")
+                .annotate(Style::Keyword)
+                .append(allocator.space())
+                .append(h.pretty(allocator, indent))
+                .append(allocator.space())
+                .append(v.pretty(allocator, indent))
+                .parens(),
         }
     }
 
-    pub fn pretty_print(&self, out: impl WriteColor) -> Result<()> {
-        let allocator = BoxAllocator;
-
-        self.pretty(&allocator).1.render_colored(70, out)?;
+    /// The span of the source a call came from, if it has one
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            FExpr::CallTwo(.., Ignore(site)) => site.as_ref().map(|site| site.span),
+            FExpr::Raise(.., Ignore(span)) => Some(*span),
+            _ => None,
+        }
+    }
 
-        Ok(())
+    /// Print in the format of the options' target, fitting lines into its
+    /// width where possible
+    pub fn pretty_print(&self, out: impl WriteColor, options: &RenderOptions) -> Result<()> {
+        options.render(self.pretty(&BoxAllocator, options.indent), out)
     }
 
     // I should really just functor huh
@@ -144,7 +192,11 @@ impl FExpr {
                     unsafe_body:
                         Scope {
                             unsafe_pattern: cont,
-                            unsafe_body: body,
+                            unsafe_body:
+                                Scope {
+                                    unsafe_pattern: handler,
+                                    unsafe_body: body,
+                                },
                         },
                 } = s;
 
@@ -154,7 +206,10 @@ impl FExpr {
                     unsafe_pattern: pat,
                     unsafe_body: Scope {
                         unsafe_pattern: cont,
-                        unsafe_body: body,
+                        unsafe_body: Scope {
+                            unsafe_pattern: handler,
+                            unsafe_body: body,
+                        },
                     },
                 })
             }
@@ -166,14 +221,22 @@ impl FExpr {
                 }
             }
             l @ FExpr::Lit(_) => l,
+            p @ FExpr::Prim(_) => p,
             FExpr::CallOne(f, v) => FExpr::CallOne(
                 Rc::new(clone_rc(f).subst(name, rep.clone())),
                 Rc::new(clone_rc(v).subst(name, rep)),
             ),
-            FExpr::CallTwo(f, v, c) => FExpr::CallTwo(
+            FExpr::CallTwo(f, v, c, h, site) => FExpr::CallTwo(
                 Rc::new(clone_rc(f).subst(name, rep.clone())),
                 Rc::new(clone_rc(v).subst(name, rep.clone())),
                 Rc::new(clone_rc(c).subst(name, rep.clone())),
+                Rc::new(clone_rc(h).subst(name, rep)),
+                site,
+            ),
+            FExpr::Raise(h, v, span) => FExpr::Raise(
+                Rc::new(clone_rc(h).subst(name, rep.clone())),
+                Rc::new(clone_rc(v).subst(name, rep)),
+                span,
             ),
         }
     }
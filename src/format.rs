@@ -0,0 +1,258 @@
+use pretty::{BoxAllocator, DocAllocator, DocBuilder};
+
+use std::{iter::Peekable, vec};
+
+use crate::{
+    parse::{self, ParseError, Sexp},
+    span::Span,
+};
+
+/// The line width scripts are formatted to
+pub const WIDTH: usize = 80;
+
+/// Reformat a script in the canonical style, laying out each form as
+/// `Sexp::pretty` does, one top level form to a line
+///
+/// Comments are kept where they were, either on their own line or after the
+/// form they followed on the same line, and a run of blank lines between two
+/// forms or comments is kept as a single blank line. Formatting a formatted
+/// script gives it back unchanged.
+pub fn format(src: &str) -> Result<String, ParseError> {
+    let (sexps, comments) = parse::read_with_comments(src)?;
+    let mut reader = Reader {
+        src,
+        comments: comments.into_iter().peekable(),
+    };
+    let elements = reader.elements(&sexps, 0, src.len());
+
+    let mut formatted = String::new();
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            formatted.push_str(match element {
+                _ if element.trailing => " ",
+                _ if element.blank => "\n\n",
+                _ => "\n",
+            });
+        }
+        element
+            .tree
+            .pretty(&BoxAllocator)
+            .1
+            .render_fmt(WIDTH, &mut formatted)
+            .expect("writing to a string can't fail");
+    }
+
+    // blank lines within a list are indented along with it
+    let mut lines: Vec<_> = formatted.lines().map(str::trim_end).collect();
+    if !lines.is_empty() {
+        lines.push("");
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Whether a script is already formatted
+pub fn is_formatted(src: &str) -> Result<bool, ParseError> {
+    Ok(format(src)? == src)
+}
+
+/// A form or comment and how it was separated from the one before it
+struct Element<'a> {
+    tree: Tree<'a>,
+    /// There was a blank line between it and the element before
+    blank: bool,
+    /// A comment on the same line as the element before
+    trailing: bool,
+}
+
+enum Tree<'a> {
+    /// An atom or a string
+    Atom(&'a Sexp),
+    List(&'a [Sexp], Vec<Element<'a>>),
+    Comment(&'a str),
+}
+
+impl<'a> Tree<'a> {
+    fn pretty<D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D>
+    where
+        D: DocAllocator<'a>,
+        D::Doc: Clone,
+    {
+        match self {
+            Tree::Atom(sexp) => sexp.pretty(allocator),
+            Tree::Comment(comment) => allocator.text(*comment),
+            Tree::List(items, elements) => {
+                // the head stays on the first line unless a comment comes
+                // between its items
+                let head = elements
+                    .iter()
+                    .take(parse::first_line(items))
+                    .take_while(|e| !matches!(e.tree, Tree::Comment(_)))
+                    .count();
+                let (first, rest) = elements.split_at(head);
+
+                let first = allocator.intersperse(
+                    first.iter().map(|e| e.tree.pretty(allocator)),
+                    allocator.space(),
+                );
+                let mut after_comment = false;
+                let rest = allocator.concat(rest.iter().map(|e| {
+                    let separator = match () {
+                        _ if e.trailing => allocator.space(),
+                        _ if e.blank => allocator.hardline().append(allocator.hardline()),
+                        // a comment runs to the end of its line
+                        _ if after_comment => allocator.hardline(),
+                        _ => allocator.line(),
+                    };
+                    after_comment = matches!(e.tree, Tree::Comment(_));
+
+                    separator.append(e.tree.pretty(allocator))
+                }));
+                let close = match elements.last() {
+                    Some(Element {
+                        tree: Tree::Comment(_),
+                        ..
+                    }) => allocator.hardline(),
+                    _ => allocator.nil(),
+                };
+
+                first.append(rest.nest(2)).append(close).group().parens()
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    comments: Peekable<vec::IntoIter<Span>>,
+}
+
+impl<'a> Reader<'a> {
+    /// The elements of `sexps`, with the comments between them, from `start`
+    /// up to `end`
+    fn elements(&mut self, sexps: &'a [Sexp], start: usize, end: usize) -> Vec<Element<'a>> {
+        let mut elements = Vec::new();
+        let mut last = None;
+
+        for sexp in sexps {
+            let span = sexp.span();
+            self.comments(span.start, start, &mut last, &mut elements);
+
+            let tree = match sexp {
+                Sexp::List(items, _) => {
+                    Tree::List(items, self.elements(items, span.start + 1, span.end - 1))
+                }
+                _ => Tree::Atom(sexp),
+            };
+            elements.push(self.element(tree, span, start, &mut last));
+        }
+        self.comments(end, start, &mut last, &mut elements);
+
+        elements
+    }
+
+    /// Add the comments before `end`
+    fn comments(
+        &mut self,
+        end: usize,
+        start: usize,
+        last: &mut Option<usize>,
+        elements: &mut Vec<Element<'a>>,
+    ) {
+        while let Some(span) = self.comments.next_if(|c| c.start < end) {
+            let comment = Tree::Comment(self.src[span.start..span.end].trim_end());
+            elements.push(self.element(comment, span, start, last));
+        }
+    }
+
+    fn element(
+        &self,
+        tree: Tree<'a>,
+        span: Span,
+        start: usize,
+        last: &mut Option<usize>,
+    ) -> Element<'a> {
+        let newlines = self.src[last.unwrap_or(start)..span.start]
+            .matches('\n')
+            .count();
+        let element = Element {
+            blank: last.is_some() && newlines > 1,
+            trailing: last.is_some() && newlines == 0 && matches!(tree, Tree::Comment(_)),
+            tree,
+        };
+        *last = Some(span.end);
+
+        element
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_formats(src: &str, expected: &str) {
+        let formatted = format(src).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        assert!(is_formatted(&formatted).unwrap());
+    }
+
+    #[test]
+    fn formats_canonically() {
+        assert_formats("", "");
+        assert_formats(
+            "  (define   id (lambda (x)\n x))\n(id   \"a\\\"b\")",
+            "(define id (lambda (x) x))\n(id \"a\\\"b\")\n",
+        );
+
+        let long = format!("(f {})", "argument ".repeat(10));
+        let expected = format!("(f{})\n", "\n  argument".repeat(10));
+        assert_formats(&long, &expected);
+
+        let lambda = format!("(lambda (x) {})", "(g x)".repeat(16));
+        let expected = format!("(lambda (x){})\n", "\n  (g x)".repeat(16));
+        assert_formats(&lambda, &expected);
+        assert!(!is_formatted(&lambda).unwrap());
+    }
+
+    #[test]
+    fn keeps_comments_and_blank_lines() {
+        let src = "\
+; leading
+(define x 1)   ; after x
+
+
+
+(define f
+  ; the identity
+  (lambda (y)
+
+     y))
+(f x ; last
+)
+";
+        let expected = "\
+; leading
+(define x 1) ; after x
+
+(define f
+  ; the identity
+  (lambda (y)
+
+    y))
+(f
+  x ; last
+)
+";
+        assert_formats(src, expected);
+        assert_eq!(
+            parse::read(expected).unwrap().len(),
+            parse::read(src).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(format("(f").unwrap_err().message, "unfinished list");
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    mem,
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    channel::Channel,
+    eval::{Closure, Env, Exception, HostCall, HostFn, Value},
+    generator::Generator,
+    literals::Literal,
+    prim::Prim,
+    scheduler::Task,
+};
+
+/// A reference to an object on a `Heap`
+///
+/// References are only kept alive by being reachable from a root, using one
+/// after the object was collected panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Gc {
+    index: u32,
+    generation: u32,
+}
+
+pub(crate) enum Object {
+    Closure(Closure),
+    Frame(Frame),
+    List(Vec<Value>),
+    Host(Box<dyn HostObject>),
+    Exception(Exception),
+    Generator(Generator),
+    Task(Task),
+    Channel(Channel),
+    /// A primitive applied to its first argument
+    Partial(Prim, Value),
+    /// A host function applied to fewer arguments than it takes
+    HostPartial(HostFn, Vec<Value>),
+    HostCall(HostCall),
+}
+
+/// One binding of an environment, see `eval::Env`
+pub(crate) struct Frame {
+    pub(crate) value: Value,
+    pub(crate) next: Env,
+}
+
+/// Implemented by anything that holds script values, so the collector can
+/// find them
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+/// A host object that can be stored on the heap with `Machine::alloc_host`
+pub trait HostObject: Trace {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Trace + Any> HostObject for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub struct Tracer {
+    grey: Vec<Gc>,
+}
+
+impl Tracer {
+    pub fn value(&mut self, value: &Value) {
+        if let Some(gc) = value.gc() {
+            self.grey.push(gc);
+        }
+    }
+
+    pub(crate) fn env(&mut self, env: Env) {
+        self.grey.extend(env.0);
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.value(self)
+    }
+}
+
+impl Object {
+    /// An estimate of the bytes this object keeps alive, not counting other
+    /// objects it refers to
+    fn size(&self) -> usize {
+        mem::size_of::<Object>()
+            + match self {
+                Object::Closure(c) => c
+                    .captures
+                    .iter()
+                    .map(|v| mem::size_of_val(v) + value_size(v))
+                    .sum(),
+                Object::Frame(f) => value_size(&f.value),
+                Object::List(l) => l.iter().map(|v| mem::size_of_val(v) + value_size(v)).sum(),
+                Object::Host(h) => mem::size_of_val(&**h),
+                Object::Exception(e) => value_size(&e.value) + mem::size_of_val(&e.spans[..]),
+                Object::Partial(_, v) => value_size(v),
+                Object::HostPartial(_, args) => args.iter().map(value_size).sum(),
+                Object::HostCall(c) => c.state.iter().map(value_size).sum(),
+                Object::Generator(_) | Object::Task(_) | Object::Channel(_) => 0,
+            }
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Lit(Literal::String(s)) => s.len(),
+        _ => 0,
+    }
+}
+
+impl Trace for Object {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Object::Closure(c) => {
+                tracer.env(c.env);
+                c.captures.iter().for_each(|v| tracer.value(v));
+            }
+            Object::Frame(f) => {
+                tracer.value(&f.value);
+                tracer.env(f.next);
+            }
+            Object::List(l) => l.iter().for_each(|v| tracer.value(v)),
+            Object::Host(h) => h.trace(tracer),
+            Object::Exception(e) => {
+                tracer.value(&e.value);
+                if let Some(k) = &e.continuation {
+                    tracer.value(k);
+                }
+            }
+            Object::Generator(g) => g.trace(tracer),
+            Object::Task(t) => t.trace(tracer),
+            Object::Channel(c) => c.trace(tracer),
+            Object::Partial(_, v) => tracer.value(v),
+            Object::HostPartial(_, args) => args.iter().for_each(|v| tracer.value(v)),
+            Object::HostCall(c) => {
+                c.state.iter().for_each(|v| tracer.value(v));
+                tracer.value(&c.k);
+                tracer.value(&c.h);
+            }
+        }
+    }
+}
+
+/// A value kept alive across collections for as long as the handle exists
+#[derive(Clone)]
+pub struct Handle(Rc<RefCell<Value>>);
+
+impl Handle {
+    pub fn get(&self) -> Value {
+        self.0.borrow().clone()
+    }
+
+    pub fn set(&self, value: Value) {
+        *self.0.borrow_mut() = value;
+    }
+}
+
+struct Slot {
+    generation: u32,
+    marked: bool,
+    size: usize,
+    object: Option<Object>,
+}
+
+/// Collections are triggered once this many objects have been allocated
+/// since the last one, or twice the number that survived it if that is more
+const MIN_THRESHOLD: usize = 1024;
+
+/// A mark and sweep heap holding every closure, environment frame and
+/// compound value of a `Machine`
+///
+/// Cycles are collected like anything else. Marking uses an explicit work
+/// list, so arbitrarily deep structures can be traced.
+pub struct Heap {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    live: usize,
+    bytes: usize,
+    limit: Option<usize>,
+    allocated: usize,
+    /// Objects allocated over the heap's lifetime
+    allocations: u64,
+    threshold: usize,
+    handles: Vec<Weak<RefCell<Value>>>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            live: 0,
+            bytes: 0,
+            limit: None,
+            allocated: 0,
+            allocations: 0,
+            threshold: MIN_THRESHOLD,
+            handles: Vec::new(),
+        }
+    }
+}
+
+impl Heap {
+    /// The number of objects currently allocated
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    /// The number of objects allocated since the heap was made, including
+    /// those since freed
+    pub fn allocations(&self) -> u64 {
+        self.allocations
+    }
+
+    /// The estimated number of bytes used by objects currently allocated
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Allocations never fail, instead the machine checks this at the end of
+    /// every step and collects, raising if that doesn't free enough, and host
+    /// functions check `has_room` before making anything large
+    pub(crate) fn over_limit(&self) -> bool {
+        self.limit.is_some_and(|limit| self.bytes > limit)
+    }
+
+    /// Whether `bytes` more can be allocated without going over the limit
+    pub(crate) fn has_room(&self, bytes: usize) -> bool {
+        !self
+            .limit
+            .is_some_and(|limit| self.bytes.saturating_add(bytes) > limit)
+    }
+
+    pub(crate) fn alloc(&mut self, object: Object) -> Gc {
+        let size = object.size();
+
+        self.live += 1;
+        self.allocated += 1;
+        self.allocations += 1;
+        self.bytes += size;
+
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.object = Some(object);
+                slot.size = size;
+
+                Gc {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    marked: false,
+                    size,
+                    object: Some(object),
+                });
+
+                Gc {
+                    index: self.slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, gc: Gc) -> &Object {
+        let slot = &self.slots[gc.index as usize];
+
+        match &slot.object {
+            Some(object) if slot.generation == gc.generation => object,
+            _ => panic!("use of a collected value"),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, gc: Gc) -> &mut Object {
+        let slot = &mut self.slots[gc.index as usize];
+
+        match &mut slot.object {
+            Some(object) if slot.generation == gc.generation => object,
+            _ => panic!("use of a collected value"),
+        }
+    }
+
+    /// Keep `value` alive until every clone of the returned handle is dropped
+    pub fn root(&mut self, value: Value) -> Handle {
+        let handle = Rc::new(RefCell::new(value));
+        self.handles.push(Rc::downgrade(&handle));
+
+        Handle(handle)
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        self.allocated >= self.threshold
+    }
+
+    /// Free every object not reachable from `roots` or a live handle
+    pub(crate) fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Value>) {
+        let mut tracer = Tracer { grey: Vec::new() };
+
+        roots.into_iter().for_each(|v| tracer.value(v));
+
+        self.handles.retain(|h| match h.upgrade() {
+            Some(h) => {
+                tracer.value(&h.borrow());
+                true
+            }
+            None => false,
+        });
+
+        while let Some(gc) = tracer.grey.pop() {
+            let slot = &mut self.slots[gc.index as usize];
+
+            if slot.marked {
+                continue;
+            }
+            slot.marked = true;
+
+            if let Some(object) = &slot.object {
+                object.trace(&mut tracer);
+            }
+        }
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.marked {
+                slot.marked = false;
+            } else if slot.object.is_some() {
+                // dropped in place, objects are too big to move out cheaply
+                slot.object = None;
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index as u32);
+                self.live -= 1;
+                self.bytes -= slot.size;
+            }
+        }
+
+        self.allocated = 0;
+        self.threshold = MIN_THRESHOLD.max(self.live * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, eval::Machine, literals::Literal, utils::test::*};
+
+    /// A host object holding a single value
+    struct Cell(Value);
+
+    impl Trace for Cell {
+        fn trace(&self, tracer: &mut Tracer) {
+            tracer.value(&self.0);
+        }
+    }
+
+    #[test]
+    fn collects_cycles() {
+        let mut machine = Machine::new();
+
+        let a = machine.alloc_host(Cell(Value::Lit(Literal::Void)));
+        let b = machine.alloc_list(vec![a.clone()]);
+        machine.host_object_mut::<Cell>(&a).unwrap().0 = b;
+        assert_eq!(machine.heap().live(), 2);
+
+        machine.collect();
+        assert_eq!(machine.heap().live(), 0);
+    }
+
+    #[test]
+    fn handles_and_globals_are_roots() {
+        let mut machine = Machine::new();
+
+        let list = machine.alloc_list(vec![]);
+        let held = machine.alloc_host(Cell(list.clone()));
+        let handle = machine.root(held);
+
+        let global = machine.alloc_list(vec![]);
+        machine.define("global", global);
+
+        machine.collect();
+        assert_eq!(machine.heap().live(), 3);
+        assert_eq!(machine.list(&list).unwrap().len(), 0);
+
+        drop(handle);
+        machine.collect();
+        assert_eq!(machine.heap().live(), 1);
+    }
+
+    #[test]
+    fn long_running_programs_use_bounded_memory() {
+        let program = cont_expr::program(omega()).into_fexpr();
+
+        let mut machine = Machine::new();
+        machine.set_step_limit(Some(100_000));
+
+        assert!(machine.run(&program).is_err());
+        assert!(machine.heap().live() < 2 * MIN_THRESHOLD);
+    }
+}
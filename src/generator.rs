@@ -0,0 +1,396 @@
+use futures_core::Stream;
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{
+    eval::{Call, Error, Machine, Outcome, Suspended, Value},
+    gc::{Handle, Trace, Tracer},
+    literals::Literal,
+};
+
+/// The state of a generator made by calling an `Expr::Gen` function
+///
+/// A generator is resumed by calling it. Its body then runs until it yields,
+/// with the value going to the continuation of whoever resumed it. Once the
+/// body returns, resuming raises `"generator finished"`.
+pub(crate) struct Generator {
+    pub(crate) state: State,
+    /// The continuation and handler of the call that last resumed the
+    /// generator, while it is running
+    pub(crate) resumer: Option<(Value, Value)>,
+}
+
+pub(crate) enum State {
+    /// Not resumed yet, holding the function to call with the yielder
+    Start(Value),
+    /// Suspended by a yield, holding its continuation
+    Yielded(Value),
+    Running,
+    /// The body returned this value
+    Returned(Value),
+    /// The body raised an exception
+    Failed,
+}
+
+impl Trace for Generator {
+    fn trace(&self, tracer: &mut Tracer) {
+        match &self.state {
+            State::Start(v) | State::Yielded(v) | State::Returned(v) => tracer.value(v),
+            State::Running | State::Failed => (),
+        }
+
+        if let Some((k, h)) = &self.resumer {
+            tracer.value(k);
+            tracer.value(h);
+        }
+    }
+}
+
+pub(crate) const FINISHED: &str = "generator finished";
+
+impl Generator {
+    pub(crate) fn is_finished(&self) -> bool {
+        matches!(self.state, State::Returned(_) | State::Failed)
+    }
+}
+
+/// A script generator driven from rust, see `Machine::iter`
+///
+/// Each call to `next` runs the generator until it next yields. Exceptions it
+/// raises are returned once, after which it is finished.
+pub struct Iter<'m> {
+    machine: &'m mut Machine,
+    generator: Handle,
+}
+
+impl<'m> Iter<'m> {
+    pub(crate) fn new(machine: &'m mut Machine, generator: Handle) -> Self {
+        Iter { machine, generator }
+    }
+
+    /// The value the generator's body returned, once it has
+    pub fn returned(&self) -> Option<Value> {
+        match &self.machine.generator(&self.generator.get())?.state {
+            State::Returned(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    fn resume(&self) -> Call {
+        let void = Value::Lit(Literal::Void);
+
+        Call::Two(self.generator.get(), void, Value::Halt, Value::Abort)
+    }
+
+    fn finished(&self) -> bool {
+        self.machine
+            .generator(&self.generator.get())
+            .is_none_or(Generator::is_finished)
+    }
+
+    /// Turn the outcome of resuming the generator into an item
+    fn item(&self, outcome: Result<Value, Error>) -> Option<Result<Value, Error>> {
+        match outcome {
+            // resuming after the body returns raises, but that's the end of
+            // iteration rather than an error
+            Err(Error::Uncaught(_)) if self.returned().is_some() => None,
+            r => Some(r),
+        }
+    }
+}
+
+impl<'m> Iterator for Iter<'m> {
+    type Item = Result<Value, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished() {
+            return None;
+        }
+
+        let call = self.resume();
+        let outcome = self.machine.exec(call, None).map(Outcome::unwrap_done);
+
+        self.item(outcome)
+    }
+}
+
+/// A script generator driven as an asynchronous `Stream`, see
+/// `Machine::stream`
+///
+/// The generator is run `fuel` calls at a time, between which the stream
+/// returns `Poll::Pending`, so that long running generators don't block
+/// other tasks of the executor. While every task of the machine is asleep the
+/// stream is pending without waking itself, and the host polls it again once
+/// the time given by `wake_at` comes, such as from a timer of its executor.
+/// If every task blocks otherwise, nothing can wake them while the stream
+/// holds the machine, so the stream ends with `Error::Deadlock`.
+pub struct GeneratorStream<'m> {
+    iter: Iter<'m>,
+    fuel: u64,
+    suspended: Option<Suspended>,
+    deadlocked: bool,
+}
+
+impl<'m> GeneratorStream<'m> {
+    pub(crate) fn new(iter: Iter<'m>, fuel: u64) -> Self {
+        GeneratorStream {
+            iter,
+            fuel,
+            suspended: None,
+            deadlocked: false,
+        }
+    }
+
+    /// When, by the machine's clock, the first sleeping task wakes, while the
+    /// stream is pending because every task is asleep
+    pub fn wake_at(&self) -> Option<u64> {
+        self.suspended.as_ref()?.wake_at()
+    }
+}
+
+impl<'m> Stream for GeneratorStream<'m> {
+    type Item = Result<Value, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let outcome = match this.suspended.take() {
+            Some(suspended) => this.iter.machine.resume(suspended, this.fuel),
+            None if this.deadlocked || this.iter.finished() => return Poll::Ready(None),
+            None => {
+                let call = this.iter.resume();
+                this.iter.machine.exec(call, Some(this.fuel))
+            }
+        };
+
+        match outcome {
+            Ok(Outcome::Suspended(suspended)) => {
+                match suspended.wake_at() {
+                    // the host polls again once it is time
+                    Some(_) => (),
+                    None if suspended.is_blocked() => {
+                        this.deadlocked = true;
+                        return Poll::Ready(Some(Err(Error::Deadlock)));
+                    }
+                    None => cx.waker().wake_by_ref(),
+                }
+
+                this.suspended = Some(suspended);
+                Poll::Pending
+            }
+            Ok(Outcome::Done(v)) => Poll::Ready(this.iter.item(Ok(v))),
+            Err(e) => Poll::Ready(this.iter.item(Err(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expr::Expr, prim::Prim, utils::test::*};
+
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        task::{Wake, Waker},
+        thread,
+        time::Duration,
+    };
+
+    /// (generator (x) (yield x) (log "between") (yield "two") "done")
+    fn two_then_done() -> Expr {
+        gen("x", |x| {
+            seq(
+                yield_(x),
+                seq(log("between"), seq(yield_(string("two")), string("done"))),
+            )
+        })
+    }
+
+    /// (generator (x)
+    ///   (let ((loop (lambda (loop) (yield x) (loop loop)))) (loop loop)))
+    fn forever() -> Expr {
+        gen("x", |x| {
+            let looping = lam("loop", |l| seq(yield_(x), app(l.clone(), l)));
+
+            app(lam("loop", |l| app(l.clone(), l)), looping)
+        })
+    }
+
+    #[test]
+    fn host_iterates_lazily() {
+        let (mut machine, log) = logging_machine(&[]);
+
+        let generator = run(&mut machine, app(two_then_done(), lit(Literal::Int(1)))).unwrap();
+        let mut iter = machine.iter(&generator).unwrap();
+
+        assert_eq!(iter.next().unwrap().unwrap().to_string(), "1");
+        assert!(log.borrow().is_empty());
+
+        assert_eq!(iter.next().unwrap().unwrap().to_string(), "\"two\"");
+        assert_eq!(*log.borrow(), vec!["\"between\"".to_owned()]);
+
+        assert!(iter.next().is_none());
+        assert_eq!(iter.returned().unwrap().to_string(), "\"done\"");
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn infinite_generators() {
+        let mut machine = Machine::new();
+
+        let generator = run(&mut machine, app(forever(), lit(Literal::Int(7)))).unwrap();
+        let values: Vec<_> = machine
+            .iter(&generator)
+            .unwrap()
+            .take(3)
+            .map(|v| v.unwrap().to_string())
+            .collect();
+
+        assert_eq!(values, vec!["7", "7", "7"]);
+    }
+
+    #[test]
+    fn scripts_resume_generators_by_calling_them() {
+        let (mut machine, _) = logging_machine(&[]);
+
+        // ((lambda (g) (g void) ... (g void)) (two_then_done 1))
+        let resume = |times| {
+            let expr = lam("g", |g| {
+                (1..times).fold(app(g.clone(), lit(Literal::Void)), |acc, _| {
+                    seq(acc, app(g.clone(), lit(Literal::Void)))
+                })
+            });
+
+            app(expr, app(two_then_done(), lit(Literal::Int(1))))
+        };
+
+        let result = run(&mut machine, resume(2)).unwrap();
+        assert_eq!(result.to_string(), "\"two\"");
+
+        // resuming after the body returns raises in the script
+        match run(&mut machine, resume(3)) {
+            Err(Error::Uncaught(e)) => assert_eq!(e.value.to_string(), "\"generator finished\""),
+            r => panic!("expected the generator to be finished, got {:?}", r),
+        }
+
+        // outside of a generator, yield is an ordinary name
+        match run(&mut machine, yield_(lit(Literal::Void))) {
+            Err(Error::UnboundVariable(name)) => assert_eq!(name, "yield"),
+            r => panic!("expected yield to be unbound, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn exceptions_end_iteration() {
+        let mut machine = Machine::new();
+
+        let expr = app(
+            gen("x", |x| seq(yield_(x), raise(string("oops"), 0, 4))),
+            string("a"),
+        );
+        let generator = run(&mut machine, expr).unwrap();
+        let mut iter = machine.iter(&generator).unwrap();
+
+        assert!(iter.next().unwrap().is_ok());
+        match iter.next() {
+            Some(Err(Error::Uncaught(e))) => assert_eq!(e.value.to_string(), "\"oops\""),
+            r => panic!("expected an exception, got {:?}", r),
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn streams_yield_to_the_executor() {
+        let (mut machine, _) = logging_machine(&[]);
+
+        let generator = run(&mut machine, app(two_then_done(), lit(Literal::Int(1)))).unwrap();
+        let mut stream = machine.stream(&generator, 5).unwrap();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let (mut items, mut pending) = (Vec::new(), 0);
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(v)) => items.push(v.unwrap().to_string()),
+                Poll::Ready(None) => break,
+                Poll::Pending => pending += 1,
+            }
+        }
+
+        assert_eq!(items, vec!["1", "\"two\""]);
+        assert!(pending > 0);
+    }
+
+    #[test]
+    fn streams_end_when_every_task_blocks() {
+        let (mut machine, _) = logging_machine(&Prim::CHANNELS);
+
+        // (generator (x) (recv (channel 0)))
+        let blocks = gen("x", |_| {
+            app(global("recv"), app(global("channel"), lit(Literal::Int(0))))
+        });
+        let generator = run(&mut machine, app(blocks, lit(Literal::Void))).unwrap();
+        let mut stream = machine.stream(&generator, 5).unwrap();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 0;
+        let result = loop {
+            polls += 1;
+            assert!(polls < 100, "the stream never ended");
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(result) => break result,
+                Poll::Pending => (),
+            }
+        };
+
+        assert!(matches!(result, Some(Err(Error::Deadlock))));
+        let next = Pin::new(&mut stream).poll_next(&mut cx);
+        assert!(matches!(next, Poll::Ready(None)));
+    }
+
+    /// Wakes by sending on a channel
+    struct Signal(Mutex<mpsc::Sender<()>>);
+
+    impl Wake for Signal {
+        fn wake(self: Arc<Self>) {
+            let _ = self.0.lock().unwrap().send(());
+        }
+    }
+
+    #[test]
+    fn sleeping_streams_wait_for_the_host() {
+        let (mut machine, _) = logging_machine(&Prim::SCHEDULER);
+
+        // (generator (x) (sleep 10) (yield x))
+        let sleeps = gen("x", |x| {
+            seq(app(global("sleep"), lit(Literal::Int(10))), yield_(x))
+        });
+        let generator = run(&mut machine, app(sleeps, string("awake"))).unwrap();
+        let mut stream = machine.stream(&generator, 1000).unwrap();
+
+        let (send, woken) = mpsc::channel();
+        let waker = Waker::from(Arc::new(Signal(Mutex::new(send))));
+        let mut cx = Context::from_waker(&waker);
+
+        let item = loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(item) => break item,
+                Poll::Pending => (),
+            }
+
+            match stream.wake_at() {
+                // the clock counts from when the machine was made
+                Some(wake) => {
+                    assert!(wake >= 10);
+                    assert!(woken.try_recv().is_err());
+                    thread::sleep(Duration::from_millis(wake));
+                }
+                None => woken.recv().unwrap(),
+            }
+        };
+
+        assert_eq!(item.unwrap().unwrap().to_string(), "\"awake\"");
+    }
+}
@@ -1,10 +1,33 @@
 #![feature(or_patterns)]
 
+pub mod analysis;
 pub mod expr;
 pub mod cont_expr;
+pub mod backtrace;
+pub mod debugger;
+pub mod dot;
 pub mod flat_expr;
+pub mod format;
 pub mod literals;
-mod utils;
+pub mod parse;
+pub mod render;
+pub mod syntax;
+pub mod span;
+pub mod stdlib;
+pub mod eval;
+pub mod gc;
+pub mod generator;
+pub mod prim;
+pub mod profile;
+pub mod scheduler;
+pub mod capability;
+pub mod channel;
+pub mod closure_compiler;
+pub mod convert;
+pub mod c_backend;
+pub mod wasm_backend;
+#[doc(hidden)]
+pub mod utils;
 
 #[cfg(test)]
 mod tests {
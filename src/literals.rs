@@ -1,5 +1,8 @@
 use pretty::{DocAllocator, DocBuilder};
-use termcolor::{Color, ColorSpec};
+
+use std::fmt;
+
+use crate::render::Style;
 
 #[derive(Debug, Clone)]
 pub enum Literal {
@@ -10,24 +13,29 @@ pub enum Literal {
 }
 
 impl Literal {
-    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, ColorSpec>
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D, Style>
     where
-        D: DocAllocator<'a, ColorSpec>,
+        D: DocAllocator<'a, Style>,
         D::Doc: Clone,
     {
         match self {
             Literal::String(s) => allocator
                 .text(format!("\"{}\"", s))
-                .annotate(ColorSpec::new().set_fg(Some(Color::Yellow)).clone()),
-            Literal::Int(v) => allocator
-                .as_string(v)
-                .annotate(ColorSpec::new().set_fg(Some(Color::Yellow)).clone()),
-            Literal::Float(v) => allocator
-                .as_string(v)
-                .annotate(ColorSpec::new().set_fg(Some(Color::Yellow)).clone()),
-            Literal::Void => allocator
-                .text("void")
-                .annotate(ColorSpec::new().set_fg(Some(Color::Yellow)).clone()),
+                .annotate(Style::Literal),
+            Literal::Int(v) => allocator.as_string(v).annotate(Style::Literal),
+            Literal::Float(v) => allocator.as_string(v).annotate(Style::Literal),
+            Literal::Void => allocator.text("void").annotate(Style::Literal),
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::String(s) => write!(f, "\"{}\"", s),
+            Literal::Int(v) => write!(f, "{}", v),
+            Literal::Float(v) => write!(f, "{}", v),
+            Literal::Void => write!(f, "void"),
         }
     }
 }
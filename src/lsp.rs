@@ -0,0 +1,516 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use serde_json::{json, Value as Json};
+
+use some_embedded_scripting_language::{
+    analysis::{Analysis, Kind},
+    eval::Machine,
+    span::Span,
+};
+
+const NAME: &str = "some-embedded-scripting-language";
+
+// JSON-RPC and LSP error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+
+/// An error response's code and message
+struct Failure(i64, String);
+
+/// Serve the language server protocol over `input` and `output` until the
+/// client says to exit, returning the exit status
+///
+/// Documents are synced in full on every change, and analysed as scripts
+/// for `machine`, whose globals are the host names completed.
+pub fn serve(mut input: impl BufRead, output: impl Write, machine: Machine) -> io::Result<u8> {
+    let mut server = Server {
+        output,
+        machine,
+        documents: HashMap::new(),
+        shut_down: false,
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        if let Some(status) = server.message(&message)? {
+            return Ok(status);
+        }
+    }
+
+    // the client went away without asking the server to exit
+    Ok(1)
+}
+
+/// Read a message framed by a `Content-Length` header, or `None` at the end
+/// of the input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        match line.trim_end().split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                length = value.trim().parse().ok()
+            }
+            Some(_) => {}
+            None if line.trim_end().is_empty() => break,
+            None => return Err(invalid("bad header")),
+        }
+    }
+
+    let mut body = vec![0; length.ok_or_else(|| invalid("missing Content-Length"))?];
+    input.read_exact(&mut body)?;
+
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|_| invalid("message isn't UTF-8"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+struct Server<W> {
+    output: W,
+    machine: Machine,
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+impl<W: Write> Server<W> {
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, id: Json, result: Result<Json, Failure>) -> io::Result<()> {
+        self.send(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(Failure(code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        })
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    /// Handle a message, returning the exit status once told to exit
+    fn message(&mut self, message: &str) -> io::Result<Option<u8>> {
+        let message: Json = match serde_json::from_str(message) {
+            Ok(message) => message,
+            Err(e) => {
+                let failure = Failure(PARSE_ERROR, e.to_string());
+                self.respond(Json::Null, Err(failure))?;
+                return Ok(None);
+            }
+        };
+        let method = message.get("method").and_then(Json::as_str);
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+
+        match (message.get("id").cloned(), method) {
+            (_, Some("exit")) => return Ok(Some(if self.shut_down { 0 } else { 1 })),
+            (Some(id), Some(method)) => {
+                let result = self.request(method, &params);
+                self.respond(id, result)?;
+            }
+            (None, Some(method)) => self.notification(method, &params)?,
+            (id, None) => {
+                let failure = Failure(INVALID_REQUEST, "expected a method".to_owned());
+                self.respond(id.unwrap_or(Json::Null), Err(failure))?;
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, Failure> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // documents are sent whole on every change
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "renameProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": NAME },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => {
+                let (_, document, offset) = self.position(params)?;
+                Ok(match document.analysis.hover(offset) {
+                    Some((span, text)) => json!({
+                        "contents": { "kind": "plaintext", "value": text },
+                        "range": range(&document.text, span),
+                    }),
+                    None => Json::Null,
+                })
+            }
+            "textDocument/definition" => {
+                let (uri, document, offset) = self.position(params)?;
+                Ok(match document.analysis.definition(offset) {
+                    Some(span) => location(uri, &document.text, span),
+                    None => Json::Null,
+                })
+            }
+            "textDocument/rename" => {
+                let (uri, document, offset) = self.position(params)?;
+                let name = params
+                    .get("newName")
+                    .and_then(Json::as_str)
+                    .ok_or_else(|| Failure(INVALID_PARAMS, "expected newName".to_owned()))?;
+                let spans = document
+                    .analysis
+                    .rename(offset, name)
+                    .map_err(|e| Failure(REQUEST_FAILED, e))?;
+
+                let edits: Vec<_> = spans
+                    .into_iter()
+                    .map(|span| json!({ "range": range(&document.text, span), "newText": name }))
+                    .collect();
+                Ok(json!({ "changes": { uri: edits } }))
+            }
+            "textDocument/completion" => {
+                let (_, document, offset) = self.position(params)?;
+                let items: Vec<_> = document
+                    .analysis
+                    .completions(offset)
+                    .into_iter()
+                    .map(|c| {
+                        // the kinds of `CompletionItemKind`
+                        let kind = match c.kind {
+                            None => 14,
+                            Some(_) if c.detail.contains("function") => 3,
+                            Some(Kind::Host | Kind::Global) => 21,
+                            Some(_) => 6,
+                        };
+                        json!({ "label": c.label, "kind": kind, "detail": c.detail })
+                    })
+                    .collect();
+                Ok(Json::from(items))
+            }
+            _ => Err(Failure(
+                METHOD_NOT_FOUND,
+                format!("unknown method `{}`", method),
+            )),
+        }
+    }
+
+    /// The document and byte offset of a request's `TextDocumentPositionParams`
+    fn position<'a>(&'a self, params: &'a Json) -> Result<(&'a str, &'a Document, usize), Failure> {
+        let missing = || Failure(INVALID_PARAMS, "expected a document position".to_owned());
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .ok_or_else(missing)?;
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| Failure(INVALID_PARAMS, format!("`{}` isn't open", uri)))?;
+        let offset = params
+            .get("position")
+            .and_then(|p| offset(&document.text, p))
+            .ok_or_else(missing)?;
+
+        Ok((uri, document, offset))
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let document = params.get("textDocument");
+        let uri = document.and_then(|d| d.get("uri")).and_then(Json::as_str);
+
+        let text = match method {
+            "textDocument/didOpen" => document.and_then(|d| d.get("text")),
+            // with full sync the last change is the whole document
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .and_then(Json::as_array)
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text")),
+            "textDocument/didClose" => {
+                if let Some(uri) = uri {
+                    self.documents.remove(uri);
+                    self.publish(uri, Vec::new())?;
+                }
+                return Ok(());
+            }
+            // notifications can be ignored
+            _ => return Ok(()),
+        };
+
+        if let (Some(uri), Some(text)) = (uri, text.and_then(Json::as_str)) {
+            let analysis = Analysis::new(text, &self.machine);
+            let diagnostics = analysis
+                .diagnostics()
+                .iter()
+                .map(|d| {
+                    json!({
+                        "range": range(text, d.span),
+                        "severity": 1,
+                        "source": NAME,
+                        "message": d.message,
+                    })
+                })
+                .collect();
+
+            let document = Document {
+                text: text.to_owned(),
+                analysis,
+            };
+            self.documents.insert(uri.to_owned(), document);
+            self.publish(uri, diagnostics)?;
+        }
+
+        Ok(())
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+        let params = json!({ "uri": uri, "diagnostics": diagnostics });
+        self.notify("textDocument/publishDiagnostics", params)
+    }
+}
+
+/// The byte offset of an LSP `Position`, whose character counts UTF-16 code
+/// units, clamped to the end of its line
+fn offset(text: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line")?.as_u64()? as usize;
+    let character = position.get("character")?.as_u64()? as usize;
+
+    let start = match line {
+        0 => 0,
+        _ => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(start + i);
+        }
+        units += c.len_utf16();
+    }
+
+    Some(text.len())
+}
+
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+
+    json!({ "line": before.matches('\n').count(), "character": character })
+}
+
+fn range(text: &str, span: Span) -> Json {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+fn location(uri: &str, text: &str, span: Span) -> Json {
+    json!({ "uri": uri, "range": range(text, span) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(message: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+    }
+
+    /// Serve the messages, returning the exit status and every message sent
+    /// back
+    fn session(messages: &[&str]) -> (u8, Vec<Json>) {
+        let input: String = messages.iter().map(|m| frame(m)).collect();
+        let mut output = Vec::new();
+        let status = serve(input.as_bytes(), &mut output, crate::machine()).unwrap();
+
+        let mut output = &output[..];
+        let mut replies = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            replies.push(serde_json::from_str(&message).unwrap());
+        }
+
+        (status, replies)
+    }
+
+    fn open(text: &str) -> String {
+        let params = json!({
+            "textDocument": {
+                "uri": "file:///a.ses",
+                "languageId": "ses",
+                "version": 1,
+                "text": text,
+            },
+        });
+
+        format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{}}}"#,
+            params
+        )
+    }
+
+    fn at(id: u64, method: &str, line: u64, character: u64, extra: &str) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{{"textDocument":{{"uri":"file:///a.ses"}},"position":{{"line":{},"character":{}}}{}}}}}"#,
+            id, method, line, character, extra
+        )
+    }
+
+    const SHUTDOWN: &str = r#"{"jsonrpc":"2.0","id":99,"method":"shutdown"}"#;
+    const EXIT: &str = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+
+    #[test]
+    fn converts_positions() {
+        let text = "ab\n\u{1f600}x\n";
+        let pos = |line: u64, character: u64| {
+            offset(text, &json!({ "line": line, "character": character }))
+        };
+
+        assert_eq!(pos(0, 1), Some(1));
+        assert_eq!(pos(1, 2), Some(7));
+        assert_eq!(pos(1, 9), Some(8));
+        assert_eq!(pos(2, 0), Some(9));
+        assert_eq!(pos(3, 0), None);
+        assert_eq!(position(text, 7), json!({ "line": 1, "character": 2 }));
+    }
+
+    #[test]
+    fn answers_requests_about_open_documents() {
+        let src = "(define id (lambda (x) x))\n(id (print 1))\n";
+        let (status, replies) = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            &open(src),
+            &at(2, "textDocument/definition", 1, 1, ""),
+            &at(3, "textDocument/hover", 0, 23, ""),
+            &at(4, "textDocument/rename", 0, 20, r#","newName":"y""#),
+            &at(5, "textDocument/completion", 1, 5, ""),
+            &at(6, "textDocument/rename", 1, 5, r#","newName":"y""#),
+            r#"{"jsonrpc":"2.0","id":7,"method":"unknown"}"#,
+            SHUTDOWN,
+            EXIT,
+        ]);
+        assert_eq!(status, 0);
+
+        let result = |id| {
+            let reply = replies.iter().find(|r| r.get("id") == Some(&json!(id)));
+            reply.unwrap().get("result").cloned()
+        };
+        let error = |id| {
+            let reply = replies.iter().find(|r| r.get("id") == Some(&json!(id)));
+            let error = reply.unwrap().get("error").unwrap();
+            error.get("message").unwrap().as_str().unwrap().to_owned()
+        };
+
+        let capabilities = result(1).unwrap();
+        assert!(
+            capabilities
+                .get("capabilities")
+                .unwrap()
+                .get("hoverProvider")
+                == Some(&true.into())
+        );
+
+        let diagnostics = replies[1]
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        assert_eq!(diagnostics, &json!([]));
+
+        let definition = result(2).unwrap();
+        assert_eq!(
+            definition.get("range"),
+            Some(&json!({
+                "start": { "line": 0, "character": 8 },
+                "end": { "line": 0, "character": 10 },
+            }))
+        );
+
+        let hover = result(3)
+            .unwrap()
+            .get("contents")
+            .unwrap()
+            .get("value")
+            .cloned();
+        assert_eq!(hover, Some("x\n\nparameter".into()));
+
+        let edits = result(4).unwrap();
+        let edits = edits.get("changes").unwrap().get("file:///a.ses").unwrap();
+        assert_eq!(edits.as_array().unwrap().len(), 2);
+
+        let completions = result(5).unwrap();
+        let labels: Vec<_> = completions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c.get("label").unwrap().as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"id") && labels.contains(&"print") && labels.contains(&"spawn"));
+
+        assert_eq!(
+            error(6),
+            "`print` is provided by the host and can't be renamed"
+        );
+        assert_eq!(error(7), "unknown method `unknown`");
+    }
+
+    #[test]
+    fn publishes_diagnostics_on_change() {
+        let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.ses","version":2},"contentChanges":[{"text":"(f\n  g)"}]}}"#;
+        let (status, replies) = session(&[&open("1"), change, EXIT]);
+
+        // exiting without shutting down first is an error
+        assert_eq!(status, 1);
+        let diagnostics = replies[1]
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        let messages: Vec<_> = diagnostics
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| (d.get("message").unwrap(), d.get("range").unwrap()))
+            .collect();
+
+        let range = |line, start, end| {
+            json!({
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": end },
+            })
+        };
+        assert_eq!(
+            messages,
+            vec![
+                (&json!("unbound variable: f"), &range(0, 1, 2)),
+                (&json!("unbound variable: g"), &range(1, 2, 3)),
+            ]
+        );
+    }
+}
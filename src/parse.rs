@@ -0,0 +1,773 @@
+use moniker::{Binder, BoundTerm, FreeVar, Ignore, Scope, Var};
+use pretty::{BoxAllocator, DocAllocator, DocBuilder};
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{expr::Expr, literals::Literal, span::Span};
+
+/// An s-expression read from a script's source, before it is given meaning
+/// as an `Expr`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(String, Span),
+    String(String, Span),
+    List(Vec<Sexp>, Span),
+}
+
+impl Sexp {
+    pub fn span(&self) -> Span {
+        match self {
+            Sexp::Atom(_, span) | Sexp::String(_, span) | Sexp::List(_, span) => *span,
+        }
+    }
+
+    /// Lay out a list on one line if it fits, otherwise with each item after
+    /// the first on its own line, except for the parameters of a binding
+    /// form, which stay next to its keyword
+    pub fn pretty<'a, D>(&'a self, allocator: &'a D) -> DocBuilder<'a, D>
+    where
+        D: DocAllocator<'a>,
+        D::Doc: Clone,
+    {
+        match self {
+            Sexp::Atom(atom, _) => allocator.text(atom.as_str()),
+            Sexp::String(s, _) => allocator.text(escape(s)),
+            Sexp::List(items, _) => {
+                let (first, rest) = items.split_at(first_line(items));
+
+                let first = allocator.intersperse(
+                    first.iter().map(|item| item.pretty(allocator)),
+                    allocator.space(),
+                );
+                let rest = allocator.concat(
+                    rest.iter()
+                        .map(|item| allocator.line().append(item.pretty(allocator))),
+                );
+
+                first.append(rest.nest(2)).group().parens()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.pretty(&BoxAllocator).1.render_fmt(80, f)
+    }
+}
+
+/// How many items at the start of a list stay on its first line, the
+/// keyword of a binding form and the names it binds, or just the head
+/// otherwise
+pub(crate) fn first_line(items: &[Sexp]) -> usize {
+    let binds = match items.first() {
+        Some(Sexp::Atom(keyword, _)) => {
+            matches!(
+                &keyword[..],
+                "lambda" | "generator" | "async" | "catch" | "define" | "let"
+            )
+        }
+        _ => false,
+    };
+
+    if binds { 2 } else { 1 }.min(items.len())
+}
+
+/// Quote a string the way the reader reads it back
+pub(crate) fn escape(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Read a number or `void`, returning `None` for any other atom
+///
+/// Floats that aren't finite are written like in Scheme, as `+inf.0`,
+/// `-inf.0` and `+nan.0`.
+pub(crate) fn literal(atom: &str, span: Span) -> Result<Option<Literal>, ParseError> {
+    match atom {
+        "void" => return Ok(Some(Literal::Void)),
+        "+inf.0" => return Ok(Some(Literal::Float(f64::INFINITY))),
+        "-inf.0" => return Ok(Some(Literal::Float(f64::NEG_INFINITY))),
+        "+nan.0" => return Ok(Some(Literal::Float(f64::NAN))),
+        _ => (),
+    }
+
+    let digits = atom.strip_prefix('-').unwrap_or(atom);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+    // ints can't be negative, and making it a float would hide that
+    if digits.len() < atom.len() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::new(
+            format!("negative int `{}`, write `{}.0` for a float", atom, atom),
+            span,
+        ));
+    }
+
+    match atom.parse() {
+        Ok(i) => Ok(Some(Literal::Int(i))),
+        Err(_) => match atom.parse() {
+            Ok(f) => Ok(Some(Literal::Float(f))),
+            Err(_) => Err(ParseError::new(format!("bad number `{}`", atom), span)),
+        },
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+    /// The source ended in the middle of a form, so more input could make
+    /// it parse
+    pub incomplete: bool,
+}
+
+impl ParseError {
+    pub(crate) fn new(message: impl Into<String>, span: Span) -> Self {
+        ParseError {
+            message: message.into(),
+            span,
+            incomplete: false,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Read every s-expression in `src`, `;` starts a comment running to the end
+/// of the line
+pub fn read(src: &str) -> Result<Vec<Sexp>, ParseError> {
+    read_with_comments(src).map(|(sexps, _)| sexps)
+}
+
+/// Read every s-expression in `src`, along with the span of each comment,
+/// from its `;` to the end of its line, in the order they appear
+pub fn read_with_comments(src: &str) -> Result<(Vec<Sexp>, Vec<Span>), ParseError> {
+    let mut reader = Reader {
+        src,
+        pos: 0,
+        comments: Vec::new(),
+    };
+    let mut sexps = Vec::new();
+
+    while reader.skip_whitespace() {
+        sexps.push(reader.sexp()?);
+    }
+
+    Ok((sexps, reader.comments))
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    pos: usize,
+    comments: Vec<Span>,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Skip whitespace and comments, returning whether there's anything left
+    fn skip_whitespace(&mut self) -> bool {
+        while let Some(c) = self.peek() {
+            match c {
+                ';' => {
+                    let start = self.pos;
+                    while !matches!(self.peek(), Some('\n') | None) {
+                        self.bump();
+                    }
+                    self.comments.push(Span::new(start, self.pos));
+                }
+                c if c.is_whitespace() => {
+                    self.bump();
+                }
+                _ => return true,
+            }
+        }
+
+        false
+    }
+
+    fn unfinished(&self, what: &str, start: usize) -> ParseError {
+        ParseError {
+            incomplete: true,
+            ..ParseError::new(format!("unfinished {}", what), Span::new(start, self.pos))
+        }
+    }
+
+    fn sexp(&mut self) -> Result<Sexp, ParseError> {
+        let start = self.pos;
+
+        match self.bump() {
+            Some('(') => {
+                let mut items = Vec::new();
+                loop {
+                    if !self.skip_whitespace() {
+                        return Err(self.unfinished("list", start));
+                    }
+                    if self.peek() == Some(')') {
+                        self.bump();
+                        return Ok(Sexp::List(items, Span::new(start, self.pos)));
+                    }
+                    items.push(self.sexp()?);
+                }
+            }
+            Some(')') => Err(ParseError::new(
+                "unexpected `)`",
+                Span::new(start, self.pos),
+            )),
+            Some('"') => {
+                let mut s = String::new();
+                loop {
+                    match self.bump() {
+                        Some('"') => return Ok(Sexp::String(s, Span::new(start, self.pos))),
+                        Some('\\') => match self.bump() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c @ ('"' | '\\')) => s.push(c),
+                            Some(c) => {
+                                let span = Span::new(self.pos - c.len_utf8() - 1, self.pos);
+                                return Err(ParseError::new(
+                                    format!("unknown escape `\\{}`", c),
+                                    span,
+                                ));
+                            }
+                            None => return Err(self.unfinished("string", start)),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err(self.unfinished("string", start)),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(c) = self.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';') {
+                        break;
+                    }
+                    self.bump();
+                }
+
+                let atom = self.src[start..self.pos].to_owned();
+                Ok(Sexp::Atom(atom, Span::new(start, self.pos)))
+            }
+            None => Err(self.unfinished("input", start)),
+        }
+    }
+}
+
+/// A top level form of a script
+#[derive(Debug, Clone)]
+pub enum Item {
+    /// `(define name expr)`, binding a global for the forms after it
+    Define(String, Expr, Span),
+    Expr(Expr, Span),
+}
+
+/// Parse every top level form of a script
+pub fn items(src: &str) -> Result<Vec<Item>, ParseError> {
+    read(src)?.iter().map(item).collect()
+}
+
+pub fn item(sexp: &Sexp) -> Result<Item, ParseError> {
+    item_with_names(sexp).map(|(item, _)| item)
+}
+
+/// Parse a top level form like `item`, also finding where the names in it
+/// are bound and used
+pub fn item_with_names(sexp: &Sexp) -> Result<(Item, Names), ParseError> {
+    let mut env = Env::default();
+    let item = match sexp {
+        Sexp::List(items, span) if head(items) == Some("define") => match &items[..] {
+            [_, Sexp::Atom(name, _), value] => Item::Define(name.clone(), env.expr(value)?, *span),
+            _ => return Err(ParseError::new("expected `(define name expr)`", *span)),
+        },
+        _ => Item::Expr(env.expr(sexp)?, sexp.span()),
+    };
+
+    Ok((item, env.names))
+}
+
+/// Where a form binds and uses names, see `item_with_names`
+#[derive(Debug, Clone, Default)]
+pub struct Names {
+    /// The local names bound, in the order they are bound
+    pub binders: Vec<Binding>,
+    /// Every use of a name, by the variable it is. Uses of a local share the
+    /// variable of its binder, and uses of a global name share one that no
+    /// binder has.
+    pub uses: Vec<(FreeVar<String>, Span)>,
+}
+
+/// A local name bound in the source
+#[derive(Debug, Clone)]
+pub struct Binding {
+    /// The variable of the `Binder`
+    pub var: FreeVar<String>,
+    /// Where the name is written
+    pub span: Span,
+    /// Where the name is in scope
+    pub scope: Span,
+    /// The expression a `let` or `define` binds the name to
+    pub value: Option<Span>,
+}
+
+/// Parse a whole script as one expression, which has the value of its last
+/// form, so it means the same whether it is run or compiled to a backend
+/// without globals
+///
+/// Each `define` is a `let` around the forms after it. A function that
+/// refers to itself is bound in its own body through a fixed point, so it
+/// can call itself like the global `Item::Define` makes could, but unlike a
+/// global it can't refer to definitions after it.
+pub fn script(src: &str) -> Result<Expr, ParseError> {
+    Env::default().script(&read(src)?)
+}
+
+/// Parse a single expression
+pub fn parse(src: &str) -> Result<Expr, ParseError> {
+    match &read(src)?[..] {
+        [sexp] => expr(sexp),
+        [] => Err(ParseError {
+            incomplete: true,
+            ..ParseError::new("expected an expression", Span::new(0, src.len()))
+        }),
+        [_, extra, ..] => Err(ParseError::new(
+            "expected a single expression",
+            extra.span(),
+        )),
+    }
+}
+
+/// Give an s-expression meaning as an `Expr`
+///
+/// Besides the forms `Expr::pretty` prints, this accepts some sugar:
+///
+/// - `(f a b)` is `((f a) b)`, and `(f)` is `(f void)`
+/// - `(lambda (x y) ..)` is `(lambda (x) (lambda (y) ..))`, and
+///   `(lambda () ..)` ignores its argument
+/// - bodies may be several expressions, evaluated in order
+/// - `(let ((x a) (y b)) ..)` binds each name in turn
+/// - `(begin a b ..)` evaluates each expression in order
+pub fn expr(sexp: &Sexp) -> Result<Expr, ParseError> {
+    Env::default().expr(sexp)
+}
+
+fn head(items: &[Sexp]) -> Option<&str> {
+    match items.first() {
+        Some(Sexp::Atom(a, _)) => Some(a),
+        _ => None,
+    }
+}
+
+/// The names bound around the expression being parsed
+#[derive(Default)]
+struct Env {
+    bound: Vec<(String, FreeVar<String>)>,
+    /// Every use of a global name is the same free variable
+    free: HashMap<String, FreeVar<String>>,
+    names: Names,
+}
+
+impl Env {
+    fn expr(&mut self, sexp: &Sexp) -> Result<Expr, ParseError> {
+        match sexp {
+            Sexp::Atom(atom, span) => self.atom(atom, *span),
+            Sexp::String(s, _) => Ok(lit(Literal::String(s.clone()))),
+            Sexp::List(items, span) => self.list(items, *span),
+        }
+    }
+
+    fn atom(&mut self, atom: &str, span: Span) -> Result<Expr, ParseError> {
+        if let Some(literal) = literal(atom, span)? {
+            return Ok(lit(literal));
+        }
+
+        let free = &mut self.free;
+        let var = self
+            .bound
+            .iter()
+            .rev()
+            .find(|(name, _)| name == atom)
+            .map(|(_, v)| v.clone())
+            .unwrap_or_else(|| {
+                free.entry(atom.to_owned())
+                    .or_insert_with(|| FreeVar::fresh_named(atom))
+                    .clone()
+            });
+        self.names.uses.push((var.clone(), span));
+
+        Ok(Expr::Var(Var::Free(var)))
+    }
+
+    fn list(&mut self, items: &[Sexp], span: Span) -> Result<Expr, ParseError> {
+        let args = items.get(1..).unwrap_or_default();
+
+        match (head(items), args) {
+            (None, _) if items.is_empty() => Err(ParseError::new("empty application", span)),
+            (Some(keyword @ ("lambda" | "generator" | "async")), [params, body @ ..]) => {
+                let params = self.params(params, span)?;
+                let lam = self.lambda(&params, body, span)?;
+
+                Ok(match (keyword, lam) {
+                    ("generator", Expr::Lam(s)) => Expr::Gen(s),
+                    ("async", Expr::Lam(s)) => Expr::Async(s),
+                    (_, lam) => lam,
+                })
+            }
+            (Some("raise"), [e]) => Ok(Expr::Raise(Rc::new(self.expr(e)?), Ignore(span))),
+            (Some("yield"), [e]) => Ok(Expr::Yield(Rc::new(self.expr(e)?))),
+            (Some("await"), [e]) => Ok(Expr::Await(Rc::new(self.expr(e)?))),
+            (Some("try"), [body @ .., Sexp::List(catch, catch_span)])
+                if head(catch) == Some("catch") =>
+            {
+                let (exc, exc_span, handler) = match &catch[1..] {
+                    [Sexp::List(exc, _), handler @ ..] => match &exc[..] {
+                        [Sexp::Atom(exc, exc_span)] => (exc, *exc_span, handler),
+                        _ => {
+                            return Err(ParseError::new(
+                                "expected `(catch (name) ..)`",
+                                *catch_span,
+                            ))
+                        }
+                    },
+                    _ => return Err(ParseError::new("expected `(catch (name) ..)`", *catch_span)),
+                };
+
+                let body = self.body(body, span)?;
+                let exc = FreeVar::fresh_named(exc.as_str());
+                self.binding(&exc, exc_span, *catch_span, None);
+                let handler = self.bind(&exc, |env| env.body(handler, *catch_span))?;
+
+                Ok(Expr::Try(
+                    Rc::new(body),
+                    Scope::new(Binder(exc), Rc::new(handler)),
+                ))
+            }
+            (Some("let"), [Sexp::List(bindings, _), body @ ..]) => {
+                self.bindings(bindings, body, span)
+            }
+            (Some("begin"), body) => self.body(body, span),
+            (
+                Some(
+                    keyword @ ("lambda" | "generator" | "async" | "raise" | "yield" | "await"
+                    | "try" | "catch" | "let" | "define"),
+                ),
+                _,
+            ) => Err(ParseError::new(format!("malformed `{}`", keyword), span)),
+            _ => {
+                let f = self.expr(&items[0])?;
+                match args {
+                    [] => Ok(call(f, lit(Literal::Void), span)),
+                    args => args
+                        .iter()
+                        .try_fold(f, |f, arg| Ok(call(f, self.expr(arg)?, span))),
+                }
+            }
+        }
+    }
+
+    fn params(&mut self, params: &Sexp, scope: Span) -> Result<Vec<FreeVar<String>>, ParseError> {
+        let params = match params {
+            Sexp::List(params, _) => params,
+            _ => return Err(ParseError::new("expected a parameter list", params.span())),
+        };
+
+        if params.is_empty() {
+            return Ok(vec![FreeVar::fresh_named("_")]);
+        }
+
+        params
+            .iter()
+            .map(|p| match p {
+                Sexp::Atom(name, span) => {
+                    let var = FreeVar::fresh_named(name.as_str());
+                    self.binding(&var, *span, scope, None);
+                    Ok(var)
+                }
+                _ => Err(ParseError::new("expected a parameter name", p.span())),
+            })
+            .collect()
+    }
+
+    fn lambda(
+        &mut self,
+        params: &[FreeVar<String>],
+        body: &[Sexp],
+        span: Span,
+    ) -> Result<Expr, ParseError> {
+        match params {
+            [] => self.body(body, span),
+            [param, rest @ ..] => {
+                let body = self.bind(param, |env| env.lambda(rest, body, span))?;
+
+                Ok(Expr::Lam(Scope::new(Binder(param.clone()), Rc::new(body))))
+            }
+        }
+    }
+
+    fn bindings(
+        &mut self,
+        bindings: &[Sexp],
+        body: &[Sexp],
+        span: Span,
+    ) -> Result<Expr, ParseError> {
+        let (binding, rest) = match bindings.split_first() {
+            Some(split) => split,
+            None => return self.body(body, span),
+        };
+
+        let (name, name_span, value) = match binding {
+            Sexp::List(binding, _) => match &binding[..] {
+                [Sexp::Atom(name, name_span), value] => (name, *name_span, value),
+                _ => {
+                    return Err(ParseError::new(
+                        "expected `(name expr)`",
+                        binding_span(binding, span),
+                    ))
+                }
+            },
+            _ => return Err(ParseError::new("expected `(name expr)`", binding.span())),
+        };
+
+        let value_span = value.span();
+        let value = self.expr(value)?;
+        let var = FreeVar::fresh_named(name.as_str());
+        let scope = Span::new(binding.span().end, span.end);
+        self.binding(&var, name_span, scope, Some(value_span));
+        let body = self.bind(&var, |env| env.bindings(rest, body, span))?;
+
+        Ok(app(
+            Expr::Lam(Scope::new(Binder(var), Rc::new(body))),
+            value,
+        ))
+    }
+
+    fn script(&mut self, forms: &[Sexp]) -> Result<Expr, ParseError> {
+        let (form, rest) = match forms.split_first() {
+            Some(split) => split,
+            None => return Ok(lit(Literal::Void)),
+        };
+
+        match form {
+            Sexp::List(items, span) if head(items) == Some("define") => {
+                let (name, name_span, value) = match &items[..] {
+                    [_, Sexp::Atom(name, name_span), value] => (name, *name_span, value),
+                    _ => return Err(ParseError::new("expected `(define name expr)`", *span)),
+                };
+                let value_span = value.span();
+                let function = match value {
+                    Sexp::List(items, _) => {
+                        matches!(head(items), Some("lambda" | "generator" | "async"))
+                    }
+                    _ => false,
+                };
+                let var = FreeVar::fresh_named(name.as_str());
+                let end = rest.last().map_or(span.end, |form| form.span().end);
+                let start = if function { value_span.start } else { span.end };
+                self.binding(&var, name_span, Span::new(start, end), Some(value_span));
+                let value = match function {
+                    true => recursive(&var, self.bind(&var, |env| env.expr(value))?),
+                    false => self.expr(value)?,
+                };
+                let body = self.bind(&var, |env| env.script(rest))?;
+
+                Ok(app(lam(var, body), value))
+            }
+            // the last form isn't a tail call, so like the others a failure
+            // in it is traced back to it
+            _ if rest.is_empty() => {
+                let result = FreeVar::fresh_named("result");
+                let id = lam(result.clone(), Expr::Var(Var::Free(result)));
+
+                Ok(app(id, self.expr(form)?))
+            }
+            _ => {
+                let first = self.expr(form)?;
+                let rest = self.script(rest)?;
+                let ignored = FreeVar::fresh_named("_");
+
+                Ok(app(
+                    Expr::Lam(Scope::new(Binder(ignored), Rc::new(rest))),
+                    first,
+                ))
+            }
+        }
+    }
+
+    /// A sequence of expressions, returning the value of the last
+    fn body(&mut self, body: &[Sexp], span: Span) -> Result<Expr, ParseError> {
+        let (last, init) = match body.split_last() {
+            Some(split) => split,
+            None => return Err(ParseError::new("expected an expression", span)),
+        };
+
+        let mut result = self.expr(last)?;
+        for e in init.iter().rev() {
+            let first = self.expr(e)?;
+            let ignored = FreeVar::fresh_named("_");
+            result = app(
+                Expr::Lam(Scope::new(Binder(ignored), Rc::new(result))),
+                first,
+            );
+        }
+
+        Ok(result)
+    }
+
+    fn binding(&mut self, var: &FreeVar<String>, span: Span, scope: Span, value: Option<Span>) {
+        self.names.binders.push(Binding {
+            var: var.clone(),
+            span,
+            scope,
+            value,
+        });
+    }
+
+    fn bind<T>(&mut self, var: &FreeVar<String>, f: impl FnOnce(&mut Self) -> T) -> T {
+        let name = var.pretty_name.clone().unwrap_or_default();
+        self.bound.push((name, var.clone()));
+        let result = f(self);
+        self.bound.pop();
+
+        result
+    }
+}
+
+fn binding_span(binding: &[Sexp], span: Span) -> Span {
+    match (binding.first(), binding.last()) {
+        (Some(first), Some(last)) => Span::new(first.span().start, last.span().end),
+        _ => span,
+    }
+}
+
+fn lit(l: Literal) -> Expr {
+    Expr::Lit(Ignore(l))
+}
+
+/// A call written in the source, at `span`
+fn call(f: Expr, e: Expr, span: Span) -> Expr {
+    Expr::App(Rc::new(f), Rc::new(e), Ignore(Some(span)))
+}
+
+/// A call introduced by desugaring, with no source call of its own
+fn app(f: Expr, e: Expr) -> Expr {
+    Expr::App(Rc::new(f), Rc::new(e), Ignore(None))
+}
+
+fn lam(var: FreeVar<String>, body: Expr) -> Expr {
+    Expr::Lam(Scope::new(Binder(var), Rc::new(body)))
+}
+
+/// `function`, in which `var` refers to the function itself
+fn recursive(var: &FreeVar<String>, function: Expr) -> Expr {
+    if !function.free_vars().contains(var) {
+        return function;
+    }
+
+    // (lambda (g) ((lambda (x) (g (lambda (v) ((x x) v))))
+    //              (lambda (x) (g (lambda (v) ((x x) v))))))
+    let g = FreeVar::fresh_named("g");
+    let half = || {
+        let (x, v) = (FreeVar::fresh_named("x"), FreeVar::fresh_named("v"));
+        let x_x = app(
+            Expr::Var(Var::Free(x.clone())),
+            Expr::Var(Var::Free(x.clone())),
+        );
+        let delayed = lam(v.clone(), app(x_x, Expr::Var(Var::Free(v))));
+
+        lam(x, app(Expr::Var(Var::Free(g.clone())), delayed))
+    };
+    let fix = lam(g.clone(), app(half(), half()));
+
+    app(fix, lam(var.clone(), function))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, eval::Machine};
+
+    fn eval(src: &str) -> String {
+        let program = cont_expr::program(parse(src).unwrap()).into_fexpr();
+
+        match Machine::new().run(&program) {
+            Ok(v) => v.to_string(),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    #[test]
+    fn parses_and_runs() {
+        assert_eq!(eval("((lambda (x y) x) 1 2)"), "1");
+        assert_eq!(eval("(let ((x \"a\\\"b\") (y x)) y) ; comment"), "\"a\"b\"");
+        assert_eq!(eval("(try (raise 1) (catch (e) 2 3))"), "3");
+        assert_eq!(eval("((lambda () 4.5))"), "4.5");
+        assert_eq!(eval("((lambda (x) x) -2.5)"), "-2.5");
+        assert_eq!(
+            eval("(raise void)"),
+            "error: uncaught exception: void\n  raised at 0..12"
+        );
+    }
+
+    #[test]
+    fn errors_have_spans() {
+        let err = parse("(lambda (x) x").unwrap_err();
+        assert!(err.incomplete);
+        assert_eq!(err.span, Span::new(0, 13));
+
+        let err = parse("(f (lambda))").unwrap_err();
+        assert!(!err.incomplete);
+        assert_eq!(err.to_string(), "3..11: malformed `lambda`");
+
+        let err = parse("(f -5)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "3..5: negative int `-5`, write `-5.0` for a float"
+        );
+
+        let items = items("(define x 1) (x)").unwrap();
+        assert!(matches!(&items[..], [Item::Define(x, _, _), Item::Expr(..)] if x == "x"));
+    }
+
+    #[test]
+    fn script_functions_can_call_themselves() {
+        let expr = script("(define f (lambda (x) f)) (((f 1) 2) 3)").unwrap();
+        let program = cont_expr::program(expr).into_fexpr();
+        assert!(Machine::new().run(&program).is_ok());
+
+        let expr = script("(define x x) x").unwrap();
+        let free: Vec<_> = expr
+            .free_vars()
+            .into_iter()
+            .filter_map(|v| v.pretty_name)
+            .collect();
+        assert_eq!(free, ["x"]);
+    }
+}
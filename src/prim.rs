@@ -0,0 +1,91 @@
+use std::fmt;
+
+/// Operations built into the machine, which need access to the
+/// continuations of their caller and so can't be host functions
+///
+/// Some are introduced by `cont_expr` when lowering the forms built on them,
+/// others are made available to scripts by the host with
+/// `Machine::define_prim`, under their `name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Prim {
+    /// Make a generator from a function taking the function to yield with
+    Generator,
+    /// Start a task calling the function with void, returning the task
+    Spawn,
+    /// Let every other ready task run before continuing
+    YieldNow,
+    /// Suspend the task for a number of milliseconds
+    Sleep,
+    /// Wait for a task to finish, returning its result or raising its
+    /// exception
+    Join,
+    /// Wait for every task in a list to finish, returning a list of their
+    /// results or raising the first exception
+    All,
+    /// Wait for the first task in a list to finish, returning its result or
+    /// raising its exception
+    Race,
+    /// Make a channel holding up to an int number of values, or any number
+    /// given void
+    Channel,
+    /// Send a value to a channel, waiting while it is full. Takes the
+    /// channel then the value.
+    Send,
+    /// Take the next value from a channel, waiting while it is empty
+    Recv,
+    /// Take the next value from the first of a list of channels to have
+    /// one, returning a list of the channel and the value
+    Select,
+}
+
+impl Prim {
+    /// The primitives of the green thread scheduler, see `scheduler`
+    pub const SCHEDULER: [Prim; 6] = [
+        Prim::Spawn,
+        Prim::YieldNow,
+        Prim::Sleep,
+        Prim::Join,
+        Prim::All,
+        Prim::Race,
+    ];
+
+    /// The primitives for passing messages between tasks, see `channel`
+    pub const CHANNELS: [Prim; 4] = [Prim::Channel, Prim::Send, Prim::Recv, Prim::Select];
+
+    /// Every primitive, in the order they are declared
+    pub const ALL: [Prim; 11] = [
+        Prim::Generator,
+        Prim::Spawn,
+        Prim::YieldNow,
+        Prim::Sleep,
+        Prim::Join,
+        Prim::All,
+        Prim::Race,
+        Prim::Channel,
+        Prim::Send,
+        Prim::Recv,
+        Prim::Select,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Prim::Generator => "%generator",
+            Prim::Spawn => "spawn",
+            Prim::YieldNow => "yield-now",
+            Prim::Sleep => "sleep",
+            Prim::Join => "join",
+            Prim::All => "all",
+            Prim::Race => "race",
+            Prim::Channel => "channel",
+            Prim::Send => "send",
+            Prim::Recv => "recv",
+            Prim::Select => "select",
+        }
+    }
+}
+
+impl fmt::Display for Prim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
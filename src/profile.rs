@@ -0,0 +1,429 @@
+use moniker::Scope;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    backtrace,
+    eval::{Env, Machine},
+    flat_expr::FExpr,
+    span::{CallSite, Span},
+};
+
+/// What the steps of a profiled run are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Function {
+    /// The top level of the programs run, outside of any lambda
+    TopLevel,
+    /// A source lambda
+    Lambda {
+        /// The name of the first call by name that entered the lambda
+        name: Option<String>,
+        /// The first call from the source in the body, see
+        /// `backtrace::TraceFrame`
+        at: Option<Span>,
+    },
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Function::TopLevel => write!(f, "top level"),
+            Function::Lambda {
+                name: Some(name), ..
+            } => write!(f, "{}", name),
+            Function::Lambda { at: Some(at), .. } => write!(f, "lambda at {}", at),
+            Function::Lambda { .. } => write!(f, "lambda"),
+        }
+    }
+}
+
+/// What a function did, not counting the functions it called
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Calls made by the function, each a step of the machine
+    pub steps: u64,
+    /// Heap objects allocated, including the environment frames of the
+    /// calls made
+    pub allocations: u64,
+    pub time: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.steps += other.steps;
+        self.allocations += other.allocations;
+        self.time += other.time;
+    }
+}
+
+struct Entry {
+    function: Function,
+    stats: Stats,
+    entered: bool,
+    /// For lambdas that don't come from the source, like those `let` and
+    /// `begin` become, the function they are counted as part of
+    merged: Option<usize>,
+}
+
+/// Counts the steps, allocations and time of each source lambda while a
+/// machine runs, and every so many steps samples the source calls in
+/// progress, see `Machine::set_profiler`
+///
+/// Only programs started while profiling are known, so lambdas from earlier
+/// runs and lambdas compiled with `closure_compiler` aren't counted. As the
+/// stacks sampled are found the same way as `Machine::backtrace`, tail calls
+/// replace their caller in them.
+pub struct Profiler {
+    interval: u64,
+    until_sample: u64,
+    functions: Vec<Entry>,
+    top_level: Option<usize>,
+    /// The function of every lambda body in the programs run and whether
+    /// it is the body of the function itself, keeping the bodies alive so
+    /// their addresses aren't reused
+    bodies: HashMap<*const FExpr, (Rc<FExpr>, usize, bool)>,
+    /// Stacks of functions, outermost first, by how often they were sampled
+    samples: HashMap<Vec<usize>, u64>,
+    /// The function of the step being made, and when and after how many
+    /// allocations it started
+    current: Option<(usize, Instant, u64)>,
+    /// If the last step was a call, its function and where the call is
+    last_call: Option<(usize, Option<Rc<CallSite>>)>,
+}
+
+impl Profiler {
+    /// A profiler sampling once every `interval` steps
+    pub fn new(interval: u64) -> Self {
+        let interval = interval.max(1);
+
+        Profiler {
+            interval,
+            until_sample: interval,
+            functions: Vec::new(),
+            top_level: None,
+            bodies: HashMap::new(),
+            samples: HashMap::new(),
+            current: None,
+            last_call: None,
+        }
+    }
+
+    /// Learn the lambdas of a program about to be run
+    pub(crate) fn index(&mut self, program: &FExpr) {
+        let top_level = match self.top_level {
+            Some(top_level) => top_level,
+            None => {
+                let top_level = self.add(Function::TopLevel);
+                self.functions[top_level].entered = true;
+                self.top_level = Some(top_level);
+                top_level
+            }
+        };
+
+        match program {
+            // the program is a lambda, but one the machine calls itself
+            FExpr::LamTwo(s) => {
+                let body = &s.unsafe_body.unsafe_body.unsafe_body;
+                self.bodies.insert(&**body, (body.clone(), top_level, true));
+                self.lambdas(body, top_level);
+            }
+            _ => self.lambdas(program, top_level),
+        }
+    }
+
+    fn add(&mut self, function: Function) -> usize {
+        self.functions.push(Entry {
+            function,
+            stats: Stats::default(),
+            entered: false,
+            merged: None,
+        });
+
+        self.functions.len() - 1
+    }
+
+    /// Index the lambdas in `term`, which is part of `function`
+    fn lambdas(&mut self, term: &FExpr, function: usize) {
+        match term {
+            FExpr::LamOne(Scope {
+                unsafe_body: body, ..
+            }) => {
+                self.bodies.insert(&**body, (body.clone(), function, false));
+                self.lambdas(body, function);
+            }
+            FExpr::LamTwo(s) => {
+                let body = &s.unsafe_body.unsafe_body.unsafe_body;
+                let lambda = self.add(Function::Lambda {
+                    name: None,
+                    at: backtrace::location(body),
+                });
+                self.bodies.insert(&**body, (body.clone(), lambda, true));
+                self.lambdas(body, lambda);
+            }
+            FExpr::CallOne(k, v) | FExpr::Raise(k, v, _) => {
+                self.lambdas(k, function);
+                self.lambdas(v, function);
+            }
+            FExpr::CallTwo(f, v, k, h, _) => {
+                for term in [f, v, k, h].iter() {
+                    self.lambdas(term, function);
+                }
+            }
+            FExpr::Var(_) | FExpr::Lit(_) | FExpr::Prim(_) => {}
+        }
+    }
+
+    /// Count a step running `body` in `env`, ending the last one
+    pub(crate) fn step(&mut self, machine: &Machine, body: &FExpr, env: Env) {
+        self.pause(machine);
+
+        let last_call = self.last_call.take();
+        let (function, entry) = match self.bodies.get(&(body as *const FExpr)) {
+            Some((_, function, entry)) => (self.resolve(*function), *entry),
+            None => return,
+        };
+
+        // lambdas are known by the first call into them, calls that didn't
+        // come from the source are to lambdas that didn't either
+        let function = match last_call {
+            Some((caller, site)) if entry && !self.functions[function].entered => {
+                let lambda = &mut self.functions[function];
+                lambda.entered = true;
+                match (&mut lambda.function, site) {
+                    (_, None) => {
+                        lambda.merged = Some(caller);
+                        caller
+                    }
+                    (Function::Lambda { name, .. }, Some(site)) => {
+                        *name = site.callee.clone();
+                        function
+                    }
+                    (Function::TopLevel, Some(_)) => function,
+                }
+            }
+            _ => function,
+        };
+        if let FExpr::CallTwo(.., site) = body {
+            self.last_call = Some((function, site.0.clone()));
+        }
+        self.functions[function].entered = true;
+
+        self.functions[function].stats.steps += 1;
+
+        self.until_sample -= 1;
+        if self.until_sample == 0 {
+            self.until_sample = self.interval;
+            self.sample(machine, function, body, env);
+        }
+
+        self.current = Some((function, Instant::now(), machine.heap().allocations()));
+    }
+
+    /// End the step being made, for when the machine stops running
+    pub(crate) fn pause(&mut self, machine: &Machine) {
+        if let Some((function, start, allocations)) = self.current.take() {
+            let stats = &mut self.functions[function].stats;
+            stats.time += start.elapsed();
+            stats.allocations += machine.heap().allocations() - allocations;
+        }
+    }
+
+    fn resolve(&self, mut function: usize) -> usize {
+        while let Some(merged) = self.functions[function].merged {
+            function = merged;
+        }
+
+        function
+    }
+
+    fn sample(&mut self, machine: &Machine, function: usize, body: &FExpr, env: Env) {
+        let k = backtrace::continuation(machine, body, 0, env);
+        let mut stack: Vec<usize> = backtrace::returns(machine, k)
+            .iter()
+            .rev()
+            .filter_map(|r| {
+                let function = self.bodies.get(&Rc::as_ptr(r.body.as_ref()?))?.1;
+                Some(self.resolve(function))
+            })
+            .collect();
+        stack.push(function);
+
+        *self.samples.entry(stack).or_insert(0) += 1;
+    }
+
+    /// The functions that ran, by the steps they made, most first
+    pub fn functions(&self) -> Vec<(&Function, Stats)> {
+        let mut functions: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|e| e.stats.steps > 0)
+            .map(|e| (&e.function, e.stats))
+            .collect();
+        functions.sort_by(|(_, a), (_, b)| b.steps.cmp(&a.steps).then(b.time.cmp(&a.time)));
+
+        functions
+    }
+
+    /// Everything counted
+    pub fn total(&self) -> Stats {
+        let mut total = Stats::default();
+        for e in &self.functions {
+            total.add(&e.stats);
+        }
+
+        total
+    }
+
+    /// The stacks sampled, outermost function first, with how many times
+    /// each was
+    pub fn samples(&self) -> Vec<(Vec<&Function>, u64)> {
+        let mut samples: Vec<_> = self.samples.iter().collect();
+        samples.sort();
+
+        samples
+            .into_iter()
+            .map(|(stack, &count)| {
+                let stack = stack.iter().map(|&f| &self.functions[f].function).collect();
+                (stack, count)
+            })
+            .collect()
+    }
+
+    /// Write the samples in the folded format flame graph tools read, one
+    /// stack to a line with its functions separated by `;` and then the
+    /// number of times it was sampled, naming functions with `label`
+    pub fn write_folded(
+        &self,
+        out: &mut impl Write,
+        label: impl Fn(&Function) -> String,
+    ) -> io::Result<()> {
+        let mut lines: Vec<_> = self
+            .samples()
+            .into_iter()
+            .map(|(stack, count)| {
+                let stack: Vec<_> = stack
+                    .into_iter()
+                    .map(|f| label(f).replace(';', ":"))
+                    .collect();
+                (stack.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+
+        for (stack, count) in lines {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a table of the functions that ran, most steps first, naming
+    /// functions with `label`
+    pub fn write_report(
+        &self,
+        out: &mut impl Write,
+        label: impl Fn(&Function) -> String,
+    ) -> io::Result<()> {
+        let total = self.total();
+        let percent = |steps: u64| 100.0 * steps as f64 / total.steps.max(1) as f64;
+
+        writeln!(
+            out,
+            "{:>10} {:>6} {:>11} {:>10}  function",
+            "steps", "%", "allocations", "time (ms)"
+        )?;
+        for (function, stats) in self.functions() {
+            writeln!(
+                out,
+                "{:>10} {:>6.2} {:>11} {:>10.3}  {}",
+                stats.steps,
+                percent(stats.steps),
+                stats.allocations,
+                stats.time.as_secs_f64() * 1000.0,
+                label(function)
+            )?;
+        }
+        writeln!(
+            out,
+            "{:>10} {:>6.2} {:>11} {:>10.3}  total",
+            total.steps,
+            percent(total.steps),
+            total.allocations,
+            total.time.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, parse};
+
+    fn profile(src: &str, interval: u64) -> Profiler {
+        let mut machine = Machine::new();
+        machine.set_profiler(Some(Profiler::new(interval)));
+        for item in parse::items(src).unwrap() {
+            let (name, expr) = match item {
+                parse::Item::Define(name, expr, _) => (Some(name), expr),
+                parse::Item::Expr(expr, _) => (None, expr),
+            };
+            let value = machine.run(&cont_expr::program(expr).into_fexpr()).unwrap();
+            if let Some(name) = name {
+                machine.define(&name, value);
+            }
+        }
+
+        machine.take_profiler().unwrap()
+    }
+
+    fn folded(profiler: &Profiler) -> String {
+        let mut out = Vec::new();
+        profiler.write_folded(&mut out, |f| f.to_string()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const SRC: &str = "\
+(define id (lambda (x) x))
+(define leaf (lambda (x) (begin (id x) (id x) x)))
+(define branch (lambda (x) (begin (leaf x) (leaf x) x)))
+(branch \"a\")
+\"done\"";
+
+    #[test]
+    fn counts_each_function_separately() {
+        let profiler = profile(SRC, 1);
+        let functions = profiler.functions();
+        let names: Vec<_> = functions.iter().map(|(f, _)| f.to_string()).collect();
+        let steps: Vec<_> = functions.iter().map(|(_, s)| s.steps).collect();
+
+        // the lambdas `begin` makes count as part of the function they're in
+        assert_eq!(names, vec!["leaf", "branch", "top level", "id"]);
+        assert_eq!(steps[0], 2 * steps[1]);
+        assert_eq!(steps[3], 4);
+        assert!(functions.iter().all(|(_, s)| s.allocations > 0));
+        assert_eq!(profiler.total().steps, steps.iter().sum::<u64>());
+    }
+
+    #[test]
+    fn samples_stacks_of_calls() {
+        let profiler = profile(SRC, 1);
+        let leaves: Vec<_> = folded(&profiler)
+            .lines()
+            .filter(|l| l.contains(";id "))
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(leaves, vec!["top level;branch;leaf;id 4"]);
+
+        let sampled: u64 = profiler.samples().iter().map(|(_, n)| n).sum();
+        assert_eq!(sampled, profiler.total().steps);
+
+        let sparse: u64 = profile(SRC, 10).samples().iter().map(|(_, n)| n).sum();
+        assert_eq!(sparse, sampled / 10);
+    }
+}
@@ -0,0 +1,281 @@
+use pretty::{BoxAllocator, DocBuilder, Render, RenderAnnotated};
+use termcolor::{Color, ColorSpec, WriteColor};
+
+use std::io::{self, Write};
+
+/// What part of a program a piece of pretty printed text is, which each
+/// render target shows in its own way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// `lambda`, `raise` and the other keywords
+    Keyword,
+    /// The parameter of a lambda or handler
+    Binder,
+    /// The continuation and handler parameters of a user lambda
+    Continuation,
+    Literal,
+    /// The function or continuation being called
+    Callee,
+    Prim,
+}
+
+impl Style {
+    pub fn color(self) -> ColorSpec {
+        let color = match self {
+            Style::Keyword => Color::Magenta,
+            Style::Binder => Color::Green,
+            Style::Continuation => Color::Red,
+            Style::Literal => Color::Yellow,
+            Style::Callee => Color::Blue,
+            Style::Prim => Color::Cyan,
+        };
+
+        ColorSpec::new().set_fg(Some(color)).clone()
+    }
+
+    /// The CSS class of HTML output, and the LaTeX macro `\ir<class>` of
+    /// LaTeX output
+    pub fn class(self) -> &'static str {
+        match self {
+            Style::Keyword => "keyword",
+            Style::Binder => "binder",
+            Style::Continuation => "continuation",
+            Style::Literal => "literal",
+            Style::Callee => "callee",
+            Style::Prim => "prim",
+        }
+    }
+}
+
+/// A stylesheet for HTML output, colouring it as a terminal would
+pub const STYLESHEET: &str = "\
+pre.ir .keyword { color: darkmagenta; }
+pre.ir .binder { color: green; }
+pre.ir .continuation { color: darkred; }
+pre.ir .literal { color: darkgoldenrod; }
+pre.ir .callee { color: blue; }
+pre.ir .prim { color: darkcyan; }
+";
+
+/// Definitions of the macros LaTeX output uses, for a document's preamble,
+/// needing the `alltt` and `xcolor` packages
+pub const LATEX_PREAMBLE: &str = "\
+\\newcommand{\\irkeyword}[1]{\\textcolor{magenta}{#1}}
+\\newcommand{\\irbinder}[1]{\\textcolor{green!50!black}{#1}}
+\\newcommand{\\ircontinuation}[1]{\\textcolor{red!70!black}{#1}}
+\\newcommand{\\irliteral}[1]{\\textcolor{orange!80!black}{#1}}
+\\newcommand{\\ircallee}[1]{\\textcolor{blue}{#1}}
+\\newcommand{\\irprim}[1]{\\textcolor{cyan!60!black}{#1}}
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Text coloured with the terminal colours of each `Style`, or plain
+    /// text when written somewhere that doesn't support colour
+    Color,
+    /// Text without any styling, even when written to a terminal
+    Plain,
+    /// A `<pre class="ir">` element with a `<span>` classed by `Style` for
+    /// each styled part, see `STYLESHEET`
+    Html,
+    /// An `alltt` environment with each styled part in a macro named by its
+    /// `Style`, see `LATEX_PREAMBLE`
+    Latex,
+}
+
+/// How the `pretty_print` methods of the IRs lay out and render their output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    pub target: Target,
+    /// The line width to fit output into where possible
+    pub width: usize,
+    /// How far the bodies of nested forms are indented
+    pub indent: isize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            target: Target::Color,
+            width: 70,
+            indent: 1,
+        }
+    }
+}
+
+impl RenderOptions {
+    pub fn render<'a>(
+        &self,
+        doc: DocBuilder<'a, BoxAllocator, Style>,
+        mut out: impl WriteColor,
+    ) -> io::Result<()> {
+        match self.target {
+            Target::Color => doc.1.render_raw(
+                self.width,
+                &mut Colored {
+                    out,
+                    styles: Vec::new(),
+                },
+            ),
+            Target::Plain => doc.1.render(self.width, &mut out),
+            Target::Html => {
+                write!(out, "<pre class=\"ir\">")?;
+                doc.1.render_raw(
+                    self.width,
+                    &mut Markup {
+                        out: &mut out,
+                        target: self.target,
+                    },
+                )?;
+                writeln!(out, "</pre>")
+            }
+            Target::Latex => {
+                writeln!(out, "\\begin{{alltt}}")?;
+                doc.1.render_raw(
+                    self.width,
+                    &mut Markup {
+                        out: &mut out,
+                        target: self.target,
+                    },
+                )?;
+                writeln!(out, "\n\\end{{alltt}}")
+            }
+        }
+    }
+}
+
+/// Writes text coloured by its innermost style
+struct Colored<W> {
+    out: W,
+    styles: Vec<Style>,
+}
+
+impl<W: WriteColor> Render for Colored<W> {
+    type Error = io::Error;
+
+    fn write_str(&mut self, s: &str) -> io::Result<usize> {
+        self.out.write(s.as_bytes())
+    }
+}
+
+impl<W: WriteColor> RenderAnnotated<Style> for Colored<W> {
+    fn push_annotation(&mut self, style: &Style) -> io::Result<()> {
+        self.styles.push(*style);
+        self.out.set_color(&style.color())
+    }
+
+    fn pop_annotation(&mut self) -> io::Result<()> {
+        self.styles.pop();
+        match self.styles.last() {
+            Some(style) => self.out.set_color(&style.color()),
+            None => self.out.reset(),
+        }
+    }
+}
+
+/// Writes HTML or LaTeX, escaping text and marking up styles
+struct Markup<W> {
+    out: W,
+    target: Target,
+}
+
+impl<W: Write> Render for Markup<W> {
+    type Error = io::Error;
+
+    fn write_str(&mut self, s: &str) -> io::Result<usize> {
+        for c in s.chars() {
+            match (self.target, c) {
+                (Target::Html, '<') => write!(self.out, "&lt;")?,
+                (Target::Html, '>') => write!(self.out, "&gt;")?,
+                (Target::Html, '&') => write!(self.out, "&amp;")?,
+                (Target::Latex, '\\') => write!(self.out, "\\textbackslash{{}}")?,
+                (Target::Latex, '{' | '}') => write!(self.out, "\\{}", c)?,
+                (_, c) => write!(self.out, "{}", c)?,
+            }
+        }
+
+        Ok(s.len())
+    }
+}
+
+impl<W: Write> RenderAnnotated<Style> for Markup<W> {
+    fn push_annotation(&mut self, style: &Style) -> io::Result<()> {
+        match self.target {
+            Target::Html => write!(self.out, "<span class=\"{}\">", style.class()),
+            _ => write!(self.out, "\\ir{}{{", style.class()),
+        }
+    }
+
+    fn pop_annotation(&mut self) -> io::Result<()> {
+        match self.target {
+            Target::Html => write!(self.out, "</span>"),
+            _ => write!(self.out, "}}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expr::Expr, syntax::Syntax};
+    use termcolor::Ansi;
+
+    fn render(src: &str, options: RenderOptions) -> String {
+        let expr = Expr::parse(src).unwrap();
+        let mut out = Ansi::new(Vec::new());
+        expr.pretty_print(&mut out, &options).unwrap();
+
+        // variables print with ids that depend on what else has run
+        let out = String::from_utf8(out.into_inner()).unwrap();
+        let mut chars = out.chars().peekable();
+        let mut without_ids = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '$' => while chars.next_if(char::is_ascii_digit).is_some() {},
+                c => without_ids.push(c),
+            }
+        }
+
+        without_ids
+    }
+
+    #[test]
+    fn renders_every_target() {
+        let src = "(lambda (x) ((f \"{a\\\\b}\") <x>))";
+        let options = |target| RenderOptions {
+            target,
+            ..RenderOptions::default()
+        };
+
+        assert_eq!(
+            render(src, options(Target::Plain)),
+            r#"(lambda (x) ((f "{a\b}") <x>))"#
+        );
+        assert_eq!(
+            render(src, options(Target::Html)),
+            r#"<pre class="ir">(<span class="keyword">lambda</span> (<span class="binder">x</span>) "#
+                .to_owned()
+                + r#"(<span class="callee">(<span class="callee">f</span> "#
+                + r#"<span class="literal">"{a\b}"</span>)</span> &lt;x&gt;))</pre>"#
+                + "\n"
+        );
+        assert_eq!(
+            render(src, options(Target::Latex)),
+            "\\begin{alltt}\n".to_owned()
+                + r#"(\irkeyword{lambda} (\irbinder{x}) "#
+                + r#"(\ircallee{(\ircallee{f} \irliteral{"\{a\textbackslash{}b\}"})} <x>))"#
+                + "\n\\end{alltt}\n"
+        );
+        assert!(render(src, options(Target::Color)).contains("\x1b["));
+
+        let narrow = RenderOptions {
+            target: Target::Plain,
+            width: 10,
+            indent: 4,
+        };
+        assert_eq!(
+            render(src, narrow),
+            "(lambda (x) \n    ((f \"{a\\b}\") <x>))"
+        );
+    }
+}
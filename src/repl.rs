@@ -0,0 +1,328 @@
+use pretty::BoxAllocator;
+use termcolor::WriteColor;
+
+use std::{
+    io::{self, BufRead},
+    mem,
+    time::Instant,
+};
+
+use some_embedded_scripting_language::{
+    analysis::Analysis,
+    cont_expr,
+    eval::{Machine, Value},
+    expr::Expr,
+    parse::{self, Item},
+    render::RenderOptions,
+    span::Span,
+};
+
+use crate::{cli::error, debug::Console};
+
+const HELP: &str = "\
+Expressions are evaluated and their values printed, and (define name expr)
+binds a name for every later input.
+
+:cps [expr]   show the expression in continuation passing style
+:flat [expr]  show the flattened form the machine runs
+:type [expr]  show the type of the expression where it is evident from the
+              source and the globals, without evaluating it
+:time [expr]  evaluate the expression, timing each stage
+:debug expr   evaluate the expression in the debugger, stopping at its
+              first call, `help` there lists the debugger's commands
+:help         show this message
+:quit         leave
+
+Without an expression, the commands inspect the last input.";
+
+/// An interactive session, definitions persist between inputs as globals of
+/// its machine
+pub struct Repl {
+    machine: Machine,
+    /// The source and expression of the last input, inspected by the
+    /// meta-commands
+    last: Option<(String, Expr)>,
+    /// How to pretty print IR and values
+    render: RenderOptions,
+}
+
+impl Repl {
+    pub fn new(render: RenderOptions) -> Self {
+        Repl {
+            machine: crate::machine(),
+            last: None,
+            render,
+        }
+    }
+
+    /// Read inputs until the end of `input` or `:quit`, an input continues
+    /// over several lines until its forms are finished
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl WriteColor) -> io::Result<()> {
+        let mut lines = input.lines();
+        let mut buffer = String::new();
+
+        loop {
+            write!(out, "{}", if buffer.is_empty() { "> " } else { ". " })?;
+            out.flush()?;
+
+            match lines.next() {
+                Some(line) => buffer.push_str(&line?),
+                None => return Ok(()),
+            }
+            buffer.push('\n');
+
+            let unfinished = matches!(parse::read(&buffer), Err(e) if e.incomplete);
+            if unfinished && !buffer.trim_start().starts_with(':') {
+                continue;
+            }
+
+            if !self.input(&mem::take(&mut buffer), &mut lines, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle one complete input, returning `false` if it asks to quit
+    ///
+    /// The debugger reads its commands from the `lines` after the input.
+    pub fn input(
+        &mut self,
+        input: &str,
+        lines: &mut impl Iterator<Item = io::Result<String>>,
+        out: &mut impl WriteColor,
+    ) -> io::Result<bool> {
+        if let Some(command) = input.trim().strip_prefix(':') {
+            let (command, arg) = command
+                .split_once(char::is_whitespace)
+                .unwrap_or((command, ""));
+
+            return self.command(command, arg, lines, out);
+        }
+
+        match parse::items(input) {
+            Ok(items) => {
+                for item in items {
+                    self.item(input, item, out)?;
+                }
+            }
+            Err(e) => error(out, e)?,
+        }
+
+        Ok(true)
+    }
+
+    fn item(&mut self, input: &str, item: Item, out: &mut impl WriteColor) -> io::Result<()> {
+        let source = |span: Span| input[span.start..span.end].to_owned();
+
+        match item {
+            Item::Define(name, expr, span) => {
+                self.last = Some((source(span), expr.clone()));
+                if let Some(v) = self.eval(expr, out)? {
+                    self.machine.define(&name, v);
+                    writeln!(out, "{}", name)?;
+                }
+            }
+            Item::Expr(expr, span) => {
+                self.last = Some((source(span), expr.clone()));
+                if let Some(v) = self.eval(expr, out)? {
+                    self.value(out, &v)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluate an expression, reporting any error
+    fn eval(&mut self, expr: Expr, out: &mut impl WriteColor) -> io::Result<Option<Value>> {
+        let program = cont_expr::program(expr).into_fexpr();
+
+        match self.machine.run(&program) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => error(out, e).map(|_| None),
+        }
+    }
+
+    fn command(
+        &mut self,
+        command: &str,
+        arg: &str,
+        lines: &mut impl Iterator<Item = io::Result<String>>,
+        out: &mut impl WriteColor,
+    ) -> io::Result<bool> {
+        let last = match command {
+            "quit" | "q" => return Ok(false),
+            "help" | "h" => {
+                writeln!(out, "{}", HELP)?;
+                return Ok(true);
+            }
+            "debug" => {
+                self.debug(arg, lines, out)?;
+                return Ok(true);
+            }
+            "cps" | "flat" | "type" | "time" => match arg.trim() {
+                "" => self.last.clone(),
+                arg => match parse::parse(arg) {
+                    Ok(expr) => Some((arg.to_owned(), expr)),
+                    Err(e) => return error(out, e).map(|_| true),
+                },
+            },
+            _ => {
+                let msg = format!("unknown command `:{}`, try `:help`", command);
+                return error(out, msg).map(|_| true);
+            }
+        };
+
+        let (source, expr) = match last {
+            Some(last) => last,
+            None => return error(out, "nothing has been entered yet").map(|_| true),
+        };
+        self.last = Some((source.clone(), expr.clone()));
+
+        match command {
+            "cps" => {
+                cont_expr::program(expr).pretty_print(&mut *out, &self.render)?;
+                writeln!(out)?;
+            }
+            "flat" => {
+                cont_expr::program(expr)
+                    .into_fexpr()
+                    .pretty_print(&mut *out, &self.render)?;
+                writeln!(out)?;
+            }
+            // the REPL's definitions are globals of its machine, so the
+            // analysis knows their types from their values
+            "type" => match Analysis::new(&source, &self.machine).last_type() {
+                Some(ty) => writeln!(out, "{}", ty)?,
+                None => writeln!(out, "unknown without evaluating it")?,
+            },
+            _ => self.time(expr, out)?,
+        }
+
+        Ok(true)
+    }
+
+    /// Evaluate an expression in the debugger, which takes its commands from
+    /// `lines`
+    fn debug(
+        &mut self,
+        source: &str,
+        lines: &mut impl Iterator<Item = io::Result<String>>,
+        out: &mut impl WriteColor,
+    ) -> io::Result<()> {
+        let expr = match parse::parse(source) {
+            Ok(expr) => expr,
+            Err(e) => return error(out, e),
+        };
+        self.last = Some((source.to_owned(), expr.clone()));
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let mut console = Console::new(source, &[], lines, &mut *out);
+        match self.machine.debug(&program, &mut console) {
+            Ok(v) => self.value(out, &v),
+            Err(e) => error(out, e),
+        }
+    }
+
+    fn time(&mut self, expr: Expr, out: &mut impl WriteColor) -> io::Result<()> {
+        let start = Instant::now();
+        let program = cont_expr::program(expr);
+        let cps = start.elapsed();
+
+        let start = Instant::now();
+        let program = program.into_fexpr();
+        let flat = start.elapsed();
+
+        let start = Instant::now();
+        let result = self.machine.run(&program);
+        let run = start.elapsed();
+
+        match result {
+            Ok(v) => self.value(out, &v)?,
+            Err(e) => error(out, e)?,
+        }
+
+        writeln!(out, "cps: {:?}, flat: {:?}, run: {:?}", cps, flat, run)
+    }
+
+    fn value(&self, out: &mut impl WriteColor, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Lit(l) => self.render.render(l.pretty(&BoxAllocator), &mut *out)?,
+            v => write!(out, "{}", v)?,
+        }
+
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termcolor::NoColor;
+
+    fn session(input: &str) -> String {
+        let mut out = NoColor::new(Vec::new());
+        Repl::new(RenderOptions::default())
+            .run(input.as_bytes(), &mut out)
+            .unwrap();
+
+        String::from_utf8(out.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn definitions_persist() {
+        let out = session("(define id (lambda (x) x))\n(id\n  \"hi\")\n(id missing)\n");
+
+        assert_eq!(
+            out,
+            "> id\n> . \"hi\"\n> error: unbound variable: missing\n> "
+        );
+    }
+
+    #[test]
+    fn commands_inspect_the_last_input() {
+        let out = session("(lambda (x) x)\n:type\n:cps\n:flat (f 1)\n:nope\n:quit\n1\n");
+        let outputs: Vec<_> = out.split("> ").collect();
+
+        assert_eq!(outputs[2], "function (x)\n");
+        assert!(outputs[3].starts_with("(lambda (_"));
+        assert!(outputs[4].starts_with("(lambda (_") && outputs[4].contains(" 1"));
+        assert_eq!(outputs[5], "error: unknown command `:nope`, try `:help`\n");
+        assert_eq!(outputs.len(), 7);
+    }
+
+    #[test]
+    fn types_are_shown_without_evaluating() {
+        let out = session(
+            "(define n 1)\n:type (let ((x n)) x)\n:type (missing 1)\n:type\n\
+             :type (raise \"side effect\")\n",
+        );
+
+        assert_eq!(
+            out,
+            "> n\n> int\n> unknown without evaluating it\n\
+             > unknown without evaluating it\n> unknown without evaluating it\n> "
+        );
+    }
+
+    #[test]
+    fn debugs_expressions() {
+        let out = session(
+            "(define id (lambda (x) x))\n\
+             :debug ((lambda (y) (id (id y))) \"a\")\n\
+             step\nlocals\nbacktrace\nb 2\ncontinue\n",
+        );
+
+        assert_eq!(
+            out,
+            "> id\n\
+             > stopped at line 1: ((lambda (y) (id (id y))) \"a\")\n\
+             (debug) step at line 1: (id y)\n\
+             (debug)   y = \"a\"\n\
+             (debug) #0 line 1: (id y)\n  y = \"a\"\n\
+             #1 line 1: ((lambda (y) (id (id y))) \"a\")\n\
+             (debug) expected a line of the script, not `2`\n\
+             (debug) \"a\"\n> "
+        );
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, VecDeque},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    eval::{Call, Value},
+    gc::{Trace, Tracer},
+};
+
+/// The time source sleeping tasks are woken by, in milliseconds
+pub trait Clock {
+    fn now(&self) -> u64;
+
+    /// Block until `now` reaches `deadline`, called when every task is asleep
+    fn wait_until(&mut self, deadline: u64);
+
+    /// Whether `wait_until` blocks the thread, runs with a fuel limit go
+    /// back to the host rather than call it if so
+    fn blocks(&self) -> bool {
+        true
+    }
+}
+
+/// Real time, measured from when the clock was made
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn wait_until(&mut self, deadline: u64) {
+        thread::sleep(Duration::from_millis(deadline.saturating_sub(self.now())));
+    }
+}
+
+/// A clock that only moves when every task is asleep, jumping straight to
+/// the next wake up, so runs are deterministic and never wait
+#[derive(Default)]
+pub struct VirtualClock {
+    now: u64,
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> u64 {
+        self.now
+    }
+
+    fn wait_until(&mut self, deadline: u64) {
+        self.now = self.now.max(deadline);
+    }
+
+    fn blocks(&self) -> bool {
+        false
+    }
+}
+
+/// A task started by `spawn`, which other tasks can `join`
+pub(crate) struct Task {
+    /// What the task returned, or the exception it raised
+    pub(crate) result: Option<Result<Value, Value>>,
+    /// The tasks waiting for this one
+    pub(crate) joiners: Vec<Joiner>,
+}
+
+/// A task waiting for another to finish
+pub(crate) enum Joiner {
+    /// With `join`, holding the continuation and handler to pass the result to
+    Join(Value, Value),
+    /// With `race`, shared between every task in the race and emptied by the
+    /// first to finish
+    Race(Rc<RefCell<Option<(Value, Value)>>>),
+    /// With `all`, shared between every task it waits for and emptied once
+    /// they have all finished or one has failed
+    All(Rc<RefCell<Option<AllJoin>>>),
+}
+
+pub(crate) struct AllJoin {
+    /// Every task waited for, finished or not, in order
+    pub(crate) tasks: Vec<Value>,
+    pub(crate) k: Value,
+    pub(crate) h: Value,
+}
+
+impl Trace for Task {
+    fn trace(&self, tracer: &mut Tracer) {
+        match &self.result {
+            Some(Ok(v)) | Some(Err(v)) => tracer.value(v),
+            None => (),
+        }
+
+        for joiner in &self.joiners {
+            match joiner {
+                Joiner::Join(k, h) => {
+                    tracer.value(k);
+                    tracer.value(h);
+                }
+                Joiner::Race(race) => {
+                    if let Some((k, h)) = &*race.borrow() {
+                        tracer.value(k);
+                        tracer.value(h);
+                    }
+                }
+                Joiner::All(all) => {
+                    if let Some(all) = &*all.borrow() {
+                        all.tasks.iter().for_each(|t| tracer.value(t));
+                        tracer.value(&all.k);
+                        tracer.value(&all.h);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Sleeper {
+    wake: u64,
+    /// Tasks due at the same time wake in the order they went to sleep
+    seq: u64,
+    call: Call,
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Sleeper {}
+
+impl PartialOrd for Sleeper {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sleeper {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.wake, self.seq).cmp(&(other.wake, other.seq))
+    }
+}
+
+/// The tasks of a `Machine` that aren't running, each as the call that
+/// continues it
+///
+/// Ready tasks are run round robin in the order they became ready, and the
+/// running task only gives way when it yields, sleeps, joins or finishes.
+pub(crate) struct Scheduler {
+    ready: VecDeque<Call>,
+    sleeping: BinaryHeap<Reverse<Sleeper>>,
+    seq: u64,
+    pub(crate) clock: Box<dyn Clock>,
+    /// Whether `next` may block waiting on the clock, false during runs
+    /// with a fuel limit
+    pub(crate) waits: bool,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler {
+            ready: VecDeque::new(),
+            sleeping: BinaryHeap::new(),
+            seq: 0,
+            clock: Box::new(SystemClock::default()),
+            waits: true,
+        }
+    }
+}
+
+impl Scheduler {
+    pub(crate) fn ready(&mut self, call: Call) {
+        self.ready.push_back(call);
+    }
+
+    pub(crate) fn sleep(&mut self, call: Call, millis: u64) {
+        let wake = self.clock.now().saturating_add(millis);
+        self.seq += 1;
+
+        self.sleeping.push(Reverse(Sleeper {
+            wake,
+            seq: self.seq,
+            call,
+        }));
+    }
+
+    /// The next task to run, waiting for one to wake up if they are all
+    /// asleep. `None` if every task is blocked, or asleep when the scheduler
+    /// can't wait on its clock.
+    pub(crate) fn next(&mut self) -> Option<Call> {
+        loop {
+            if let Some(call) = self.next_ready() {
+                return Some(call);
+            }
+
+            let wake = self.wake_at()?;
+            if !self.waits && self.clock.blocks() {
+                return None;
+            }
+            self.clock.wait_until(wake);
+        }
+    }
+
+    /// The next task to run without waiting, after waking every task due
+    pub(crate) fn next_ready(&mut self) -> Option<Call> {
+        let now = self.clock.now();
+        while let Some(Reverse(sleeper)) = self.sleeping.peek() {
+            if sleeper.wake > now {
+                break;
+            }
+            let Reverse(sleeper) = self.sleeping.pop().unwrap();
+            self.ready.push_back(sleeper.call);
+        }
+
+        self.ready.pop_front()
+    }
+
+    /// When the first sleeping task wakes, if any are asleep
+    pub(crate) fn wake_at(&self) -> Option<u64> {
+        self.sleeping.peek().map(|Reverse(sleeper)| sleeper.wake)
+    }
+
+    /// Forget every task, they can never be resumed
+    pub(crate) fn clear(&mut self) {
+        self.ready.clear();
+        self.sleeping.clear();
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Value> {
+        let sleeping = self.sleeping.iter().map(|Reverse(s)| &s.call);
+
+        self.ready
+            .iter()
+            .chain(sleeping)
+            .flat_map(|call| call.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cont_expr,
+        eval::{Error, HostFn, Machine, Outcome},
+        expr::Expr,
+        literals::Literal,
+        prim::Prim,
+        utils::test::*,
+    };
+
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    fn machine() -> (Machine, Log) {
+        let (mut machine, log) = logging_machine(&Prim::SCHEDULER);
+        machine.set_clock(VirtualClock::default());
+
+        (machine, log)
+    }
+
+    /// (spawn (lambda (_) body))
+    fn spawn(body: Expr) -> Expr {
+        call("spawn", lam("_", |_| body))
+    }
+
+    #[test]
+    fn tasks_run_round_robin() {
+        let (mut machine, log_) = machine();
+
+        let task = |name: &str| {
+            seq(
+                log(&format!("{}1", name)),
+                seq(
+                    call("yield-now", lit(Literal::Void)),
+                    log(&format!("{}2", name)),
+                ),
+            )
+        };
+
+        // (let ((a (spawn ..)) (b (spawn ..))) (log "main") (join a) (join b))
+        let expr = app(
+            lam("a", |a| {
+                app(
+                    lam("b", |b| {
+                        seq(log("main"), seq(call("join", a), call("join", b)))
+                    }),
+                    spawn(task("b")),
+                )
+            }),
+            spawn(task("a")),
+        );
+
+        assert_eq!(run(&mut machine, expr).unwrap().to_string(), "\"b2\"");
+        assert_eq!(
+            *log_.borrow(),
+            vec!["\"main\"", "\"a1\"", "\"b1\"", "\"a2\"", "\"b2\""]
+        );
+    }
+
+    #[test]
+    fn sleeping_uses_the_clock() {
+        let (mut machine, log_) = machine();
+
+        let sleepy = |name: &str, millis| seq(call("sleep", lit(Literal::Int(millis))), log(name));
+
+        let expr = app(
+            lam("a", |a| {
+                app(
+                    lam("b", |b| seq(call("join", a), call("join", b))),
+                    spawn(sleepy("fifty", 50)),
+                )
+            }),
+            spawn(sleepy("hundred", 100)),
+        );
+
+        run(&mut machine, expr).unwrap();
+        assert_eq!(*log_.borrow(), vec!["\"fifty\"", "\"hundred\""]);
+        assert_eq!(machine.now(), 100);
+    }
+
+    /// A clock only the test moves, which fails the test if waited on
+    struct ManualClock(Rc<Cell<u64>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+
+        fn wait_until(&mut self, _: u64) {
+            panic!("blocked waiting on the clock");
+        }
+    }
+
+    #[test]
+    fn runs_with_fuel_go_back_to_the_host_while_tasks_sleep() {
+        let (mut machine, log_) = machine();
+        let time = Rc::new(Cell::new(0));
+        machine.set_clock(ManualClock(time.clone()));
+
+        let expr = seq(call("sleep", lit(Literal::Int(100))), log("awake"));
+        let program = cont_expr::program(expr).into_fexpr();
+
+        let mut outcome = machine.run_with_fuel(&program, 1000).unwrap();
+        for now in [50, 100] {
+            let suspended = match outcome {
+                Outcome::Suspended(s) => s,
+                Outcome::Done(v) => panic!("expected the script to sleep, got {}", v),
+            };
+            assert!(suspended.is_blocked());
+            assert_eq!(suspended.wake_at(), Some(100));
+            assert!(log_.borrow().is_empty());
+
+            time.set(now);
+            outcome = machine.resume(suspended, 1000).unwrap();
+        }
+
+        assert!(matches!(outcome, Outcome::Done(_)));
+        assert_eq!(*log_.borrow(), vec!["\"awake\""]);
+    }
+
+    #[test]
+    fn joining_raises_a_tasks_exceptions() {
+        let (mut machine, _) = machine();
+
+        let failing = spawn(raise(lit(Literal::Int(1)), 0, 1));
+        let expr = try_catch(call("join", failing), "e", |_| {
+            lit(Literal::String("caught".to_owned()))
+        });
+        assert_eq!(run(&mut machine, expr).unwrap().to_string(), "\"caught\"");
+
+        // tasks still running when the program exits are abandoned
+        let forever = spawn(omega());
+        assert_eq!(
+            run(&mut machine, seq(forever, lit(Literal::Int(2))))
+                .unwrap()
+                .to_string(),
+            "2"
+        );
+
+        let expr = call("join", lit(Literal::Int(3)));
+        assert!(run(&mut machine, expr).is_err());
+    }
+
+    /// (async (x) (sleep x) (log x) x)
+    fn after(fail: bool) -> Expr {
+        async_("x", |x| {
+            let result = match fail {
+                true => raise(x.clone(), 0, 1),
+                false => x.clone(),
+            };
+
+            seq(call("sleep", x.clone()), seq(call("log", x), result))
+        })
+    }
+
+    fn pair_machine() -> (Machine, Rc<RefCell<Vec<String>>>) {
+        let (mut machine, log) = machine();
+
+        machine.register("pair", |machine, a| {
+            let a = machine.root(a);
+            Ok(Value::Host(HostFn::new("pair", move |machine, b| {
+                Ok(machine.alloc_list(vec![a.get(), b]))
+            })))
+        });
+
+        (machine, log)
+    }
+
+    /// (f (pair a b))
+    fn on_pair(f: &str, a: Expr, b: Expr) -> Expr {
+        call(f, app(call("pair", a), b))
+    }
+
+    #[test]
+    fn async_functions_run_as_tasks() {
+        let (mut machine, logged) = machine();
+
+        // ((async (x) ..) 10) starts a task without waiting for it
+        let expr = app(
+            lam("t", |t| seq(log("main"), await_(t))),
+            app(after(false), lit(Literal::Int(10))),
+        );
+        assert_eq!(run(&mut machine, expr).unwrap().to_string(), "10");
+        assert_eq!(*logged.borrow(), vec!["\"main\"", "10"]);
+
+        let expr = try_catch(await_(app(after(true), lit(Literal::Int(1)))), "e", |_| {
+            lit(Literal::String("caught".to_owned()))
+        });
+        assert_eq!(run(&mut machine, expr).unwrap().to_string(), "\"caught\"");
+    }
+
+    #[test]
+    fn all_and_race_wait_on_many_tasks() {
+        let (mut machine, log) = pair_machine();
+        let int = |i| lit(Literal::Int(i));
+
+        let expr = on_pair(
+            "all",
+            app(after(false), int(20)),
+            app(after(false), int(10)),
+        );
+        let results = run(&mut machine, expr).unwrap();
+        let results: Vec<_> = machine
+            .list(&results)
+            .unwrap()
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(results, vec!["20", "10"]);
+        assert_eq!(*log.borrow(), vec!["10", "20"]);
+
+        let expr = on_pair(
+            "race",
+            app(after(false), int(20)),
+            app(after(false), int(10)),
+        );
+        assert_eq!(run(&mut machine, expr).unwrap().to_string(), "10");
+
+        // all fails as soon as any task does
+        log.borrow_mut().clear();
+        let expr = on_pair("all", app(after(false), int(20)), app(after(true), int(10)));
+        match run(&mut machine, expr) {
+            Err(Error::Uncaught(e)) => assert_eq!(e.value.to_string(), "10"),
+            r => panic!("expected the failing task's exception, got {:?}", r),
+        }
+        assert_eq!(*log.borrow(), vec!["10"]);
+    }
+}
@@ -0,0 +1,1075 @@
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display, mem};
+
+use crate::{
+    convert,
+    eval::{Error, HostFn, Machine, Value},
+    gc::{Trace, Tracer},
+    literals::Literal,
+    parse,
+};
+
+/// A function of the standard library, which `install` registers as a host
+/// function under its name
+///
+/// Arguments are passed one at a time as they are to lambdas, so applying a
+/// function to fewer than its arity gives back a function waiting for the
+/// rest. A function given arguments it can't use raises a string naming it
+/// and what it expected, like `+: expected a number, got string`, which
+/// scripts can catch.
+pub struct Function {
+    pub name: &'static str,
+    /// How it is called and what it returns, like `(+ number number) -> number`
+    pub signature: &'static str,
+    pub doc: &'static str,
+    pub arity: usize,
+    fun: fn(&mut Machine, &[Value]) -> Result<Value, Error>,
+}
+
+impl Function {
+    pub fn host_fn(&self) -> HostFn {
+        HostFn::curried(self.name, self.arity, self.fun)
+    }
+}
+
+/// A map from strings to values, made by `assoc`ing keys onto `empty-map`
+///
+/// Maps are never changed once made, `assoc` and `dissoc` give back a copy.
+#[derive(Clone, Default)]
+pub struct Map(pub BTreeMap<String, Value>);
+
+impl Trace for Map {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.values().for_each(|v| tracer.value(v));
+    }
+}
+
+/// Make the standard library available to scripts run on `machine`, the
+/// functions of the `PRELUDE` along with `nil`, the empty list, and
+/// `empty-map`
+pub fn install(machine: &mut Machine) {
+    for function in PRELUDE {
+        machine.define(function.name, Value::Host(function.host_fn()));
+    }
+
+    let nil = machine.alloc_list(Vec::new());
+    machine.define("nil", nil);
+    let empty = machine.alloc_host(Map::default());
+    machine.define("empty-map", empty);
+}
+
+/// Every function of the standard library
+///
+/// Comparisons return 1 for true and 0 for false, and functions taking the
+/// result of a predicate treat 0 as false and anything else as true.
+pub const PRELUDE: &[Function] = &[
+    Function {
+        name: "+",
+        signature: "(+ number number) -> number",
+        doc: "Add two numbers, giving an int if both are ints and a float otherwise",
+        arity: 2,
+        fun: |_, args| arithmetic("+", args, u64::checked_add, |a, b| a + b),
+    },
+    Function {
+        name: "-",
+        signature: "(- number number) -> number",
+        doc: "Subtract the second number from the first, ints can't go below zero",
+        arity: 2,
+        fun: |_, args| arithmetic("-", args, u64::checked_sub, |a, b| a - b),
+    },
+    Function {
+        name: "*",
+        signature: "(* number number) -> number",
+        doc: "Multiply two numbers",
+        arity: 2,
+        fun: |_, args| arithmetic("*", args, u64::checked_mul, |a, b| a * b),
+    },
+    Function {
+        name: "/",
+        signature: "(/ number number) -> number",
+        doc: "Divide the first number by the second, rounding ints down",
+        arity: 2,
+        fun: |_, args| arithmetic("/", args, u64::checked_div, |a, b| a / b),
+    },
+    Function {
+        name: "%",
+        signature: "(% number number) -> number",
+        doc: "The remainder of dividing the first number by the second",
+        arity: 2,
+        fun: |_, args| arithmetic("%", args, u64::checked_rem, |a, b| a % b),
+    },
+    Function {
+        name: "=",
+        signature: "(= value value) -> int",
+        doc: "Whether two values are equal, numbers by value whatever their type, \
+              lists and maps by their contents, and anything else by identity",
+        arity: 2,
+        fun: |machine, args| Ok(truth(equal(machine, &args[0], &args[1]))),
+    },
+    Function {
+        name: "<",
+        signature: "(< a a) -> int",
+        doc: "Whether the first number or string is less than the second",
+        arity: 2,
+        fun: |_, args| Ok(truth(compare("<", args)? == Some(Ordering::Less))),
+    },
+    Function {
+        name: "<=",
+        signature: "(<= a a) -> int",
+        doc: "Whether the first number or string is at most the second",
+        arity: 2,
+        fun: |_, args| {
+            let order = compare("<=", args)?;
+            Ok(truth(matches!(
+                order,
+                Some(Ordering::Less | Ordering::Equal)
+            )))
+        },
+    },
+    Function {
+        name: ">",
+        signature: "(> a a) -> int",
+        doc: "Whether the first number or string is greater than the second",
+        arity: 2,
+        fun: |_, args| Ok(truth(compare(">", args)? == Some(Ordering::Greater))),
+    },
+    Function {
+        name: ">=",
+        signature: "(>= a a) -> int",
+        doc: "Whether the first number or string is at least the second",
+        arity: 2,
+        fun: |_, args| {
+            let order = compare(">=", args)?;
+            Ok(truth(matches!(
+                order,
+                Some(Ordering::Greater | Ordering::Equal)
+            )))
+        },
+    },
+    Function {
+        name: "not",
+        signature: "(not value) -> int",
+        doc: "1 given 0, and 0 given anything else",
+        arity: 1,
+        fun: |_, args| Ok(truth(!truthy(&args[0]))),
+    },
+    Function {
+        name: "exception-value",
+        signature: "(exception-value exception) -> value",
+        doc: "The value a caught exception was raised with",
+        arity: 1,
+        fun: |machine, args| match machine.exception(&args[0]) {
+            Some(e) => Ok(e.value.clone()),
+            None => Err(expected(
+                machine,
+                "exception-value",
+                "an exception",
+                &args[0],
+            )),
+        },
+    },
+    Function {
+        name: "int",
+        signature: "(int number) -> int",
+        doc: "A number as an int, rounding floats toward zero",
+        arity: 1,
+        fun: |_, args| match number("int", &args[0])? {
+            Number::Int(i) => Ok(int(i)),
+            Number::Float(f) if f > -1.0 && f < u64::MAX as f64 => Ok(int(f as u64)),
+            Number::Float(f) => Err(fail("int", format_args!("{} is out of range", f))),
+        },
+    },
+    Function {
+        name: "float",
+        signature: "(float number) -> float",
+        doc: "A number as a float",
+        arity: 1,
+        fun: |_, args| Ok(float(number("float", &args[0])?.float())),
+    },
+    Function {
+        name: "concat",
+        signature: "(concat a a) -> a",
+        doc: "Join two strings, or two lists",
+        arity: 2,
+        fun: |machine, args| match (&args[0], &args[1]) {
+            (Value::Lit(Literal::String(a)), Value::Lit(Literal::String(b))) => {
+                machine.reserve(a.len() + b.len())?;
+                Ok(string(format!("{}{}", a, b)))
+            }
+            (a, b) => match (machine.list(a), machine.list(b)) {
+                (Some(a), Some(b)) => {
+                    machine.reserve(items_size(a.len() + b.len()))?;
+                    let (a, b) = (
+                        machine.list(&args[0]).unwrap(),
+                        machine.list(&args[1]).unwrap(),
+                    );
+                    let items = a.iter().chain(b).cloned().collect();
+                    Ok(machine.alloc_list(items))
+                }
+                _ => Err(expected(machine, "concat", "two strings or two lists", a)),
+            },
+        },
+    },
+    Function {
+        name: "length",
+        signature: "(length string|list|map) -> int",
+        doc: "The number of characters in a string, items in a list or keys in a map",
+        arity: 1,
+        fun: |machine, args| {
+            let length = match &args[0] {
+                Value::Lit(Literal::String(s)) => s.chars().count(),
+                v => match (machine.list(v), machine.host_object::<Map>(v)) {
+                    (Some(items), _) => items.len(),
+                    (_, Some(map)) => map.0.len(),
+                    _ => return Err(expected(machine, "length", "a string, list or map", v)),
+                },
+            };
+            Ok(int(length as u64))
+        },
+    },
+    Function {
+        name: "slice",
+        signature: "(slice string|list int int) -> string|list",
+        doc: "The characters of a string or items of a list from the first index \
+              up to the second",
+        arity: 3,
+        fun: |machine, args| {
+            let (start, end) = (index("slice", &args[1])?, index("slice", &args[2])?);
+            let range = |len: usize| match start <= end && end <= len {
+                true => Ok(start..end),
+                false => Err(fail(
+                    "slice",
+                    format_args!("{}..{} is out of range of {}", start, end, len),
+                )),
+            };
+
+            match &args[0] {
+                Value::Lit(Literal::String(s)) => {
+                    let chars: Vec<_> = s.chars().collect();
+                    let slice: String = chars[range(chars.len())?].iter().collect();
+                    machine.reserve(slice.len())?;
+                    Ok(string(slice))
+                }
+                v => match machine.list(v) {
+                    Some(items) => {
+                        let range = range(items.len())?;
+                        machine.reserve(items_size(range.len()))?;
+                        let items = list(machine, "slice", &args[0])?[range].to_vec();
+                        Ok(machine.alloc_list(items))
+                    }
+                    None => Err(expected(machine, "slice", "a string or list", v)),
+                },
+            }
+        },
+    },
+    Function {
+        name: "split",
+        signature: "(split string string) -> list",
+        doc: "The parts of the first string between each occurrence of the second",
+        arity: 2,
+        fun: |machine, args| {
+            let (s, separator) = (
+                text(machine, "split", &args[0])?,
+                text(machine, "split", &args[1])?,
+            );
+            if separator.is_empty() {
+                return Err(fail("split", "the separator is empty"));
+            }
+
+            let parts: Vec<_> = s
+                .split(separator)
+                .map(|part| string(part.to_owned()))
+                .collect();
+            machine.reserve(s.len().saturating_add(items_size(parts.len())))?;
+            Ok(machine.alloc_list(parts))
+        },
+    },
+    Function {
+        name: "format",
+        signature: "(format string list) -> string",
+        doc: "The string with each `{}` replaced by the next item of the list, \
+              written as by `to-string`, and `{{` and `}}` standing for braces",
+        arity: 2,
+        fun: |machine, args| {
+            let template = text(machine, "format", &args[0])?;
+            let values = match machine.list(&args[1]) {
+                Some(values) => values,
+                None => return Err(expected(machine, "format", "a list", &args[1])),
+            };
+
+            let mut formatted = String::new();
+            let mut values = values.iter();
+            let mut chars = template.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, chars.peek()) {
+                    ('{', Some('{')) | ('}', Some('}')) => {
+                        chars.next();
+                        formatted.push(c);
+                    }
+                    ('{', Some('}')) => {
+                        chars.next();
+                        match values.next() {
+                            Some(v) => formatted.push_str(&show(machine, v)),
+                            None => return Err(fail("format", "too few values for the template")),
+                        }
+                    }
+                    ('{' | '}', _) => {
+                        return Err(fail(
+                            "format",
+                            format_args!("unmatched `{}` in the template", c),
+                        ))
+                    }
+                    (c, _) => formatted.push(c),
+                }
+            }
+
+            if values.next().is_some() {
+                return Err(fail("format", "too many values for the template"));
+            }
+            machine.reserve(formatted.len())?;
+            Ok(string(formatted))
+        },
+    },
+    Function {
+        name: "to-string",
+        signature: "(to-string value) -> string",
+        doc: "A string as it is, and anything else written as it would be printed, \
+              with lists like `[1, \"a\"]` and maps like `{\"a\": 1}`",
+        arity: 1,
+        fun: |machine, args| {
+            let shown = show(machine, &args[0]);
+            machine.reserve(shown.len())?;
+            Ok(string(shown))
+        },
+    },
+    Function {
+        name: "json-parse",
+        signature: "(json-parse string) -> value",
+        doc: "The value of a JSON document, objects become maps, arrays lists, \
+              null void and booleans 1 or 0. Ints can't be negative, so a \
+              negative whole number must be written as a float, like -2.0",
+        arity: 1,
+        fun: |machine, args| {
+            let src = text(machine, "json-parse", &args[0])?;
+            convert::from_json(machine, src).map_err(|e| match e {
+                Error::OutOfMemory => e,
+                e => fail("json-parse", e),
+            })
+        },
+    },
+    Function {
+        name: "json-stringify",
+        signature: "(json-stringify value) -> string",
+        doc: "A string, number, void, list or map written as compact JSON",
+        arity: 1,
+        fun: |machine, args| {
+            let json =
+                convert::to_json(machine, &args[0]).map_err(|e| fail("json-stringify", e))?;
+            machine.reserve(json.len())?;
+            Ok(string(json))
+        },
+    },
+    Function {
+        name: "cons",
+        signature: "(cons value list) -> list",
+        doc: "The list with the value added to its front",
+        arity: 2,
+        fun: |machine, args| {
+            let len = list(machine, "cons", &args[1])?.len();
+            machine.reserve(items_size(len + 1))?;
+            let rest = list(machine, "cons", &args[1])?;
+            let items = std::iter::once(args[0].clone())
+                .chain(rest.iter().cloned())
+                .collect();
+            Ok(machine.alloc_list(items))
+        },
+    },
+    Function {
+        name: "first",
+        signature: "(first list) -> value",
+        doc: "The first item of a list that isn't empty",
+        arity: 1,
+        fun: |machine, args| match list(machine, "first", &args[0])?.first() {
+            Some(first) => Ok(first.clone()),
+            None => Err(fail("first", "the list is empty")),
+        },
+    },
+    Function {
+        name: "rest",
+        signature: "(rest list) -> list",
+        doc: "Every item of a list that isn't empty but the first",
+        arity: 1,
+        fun: |machine, args| match list(machine, "rest", &args[0])?.len() {
+            0 => Err(fail("rest", "the list is empty")),
+            len => {
+                machine.reserve(items_size(len - 1))?;
+                let rest = list(machine, "rest", &args[0])?[1..].to_vec();
+                Ok(machine.alloc_list(rest))
+            }
+        },
+    },
+    Function {
+        name: "range",
+        signature: "(range int) -> list",
+        doc: "The ints from zero up to the one given",
+        arity: 1,
+        fun: |machine, args| {
+            let end = index("range", &args[0])?;
+            machine.reserve(items_size(end))?;
+            Ok(machine.alloc_list((0..end as u64).map(int).collect()))
+        },
+    },
+    Function {
+        name: "get",
+        signature: "(get list|map int|string) -> value",
+        doc: "The item of a list at an index, or the value of a map at a key",
+        arity: 2,
+        fun: |machine, args| {
+            if let Some(map) = machine.host_object::<Map>(&args[0]) {
+                let key = text(machine, "get", &args[1])?;
+                return match map.0.get(key) {
+                    Some(v) => Ok(v.clone()),
+                    None => Err(fail("get", format_args!("no key {}", parse::escape(key)))),
+                };
+            }
+
+            let items = match machine.list(&args[0]) {
+                Some(items) => items,
+                None => return Err(expected(machine, "get", "a list or map", &args[0])),
+            };
+            let i = index("get", &args[1])?;
+            match items.get(i) {
+                Some(v) => Ok(v.clone()),
+                None => Err(fail(
+                    "get",
+                    format_args!("index {} is out of range of {}", i, items.len()),
+                )),
+            }
+        },
+    },
+    Function {
+        name: "assoc",
+        signature: "(assoc map string value) -> map",
+        doc: "The map with the key set to the value",
+        arity: 3,
+        fun: |machine, args| {
+            let mut map = dictionary(machine, "assoc", &args[0])?.clone();
+            let key = text(machine, "assoc", &args[1])?.to_owned();
+            map.0.insert(key, args[2].clone());
+            Ok(machine.alloc_host(map))
+        },
+    },
+    Function {
+        name: "dissoc",
+        signature: "(dissoc map string) -> map",
+        doc: "The map without the key",
+        arity: 2,
+        fun: |machine, args| {
+            let mut map = dictionary(machine, "dissoc", &args[0])?.clone();
+            map.0.remove(text(machine, "dissoc", &args[1])?);
+            Ok(machine.alloc_host(map))
+        },
+    },
+    Function {
+        name: "has",
+        signature: "(has map string) -> int",
+        doc: "Whether the map has the key",
+        arity: 2,
+        fun: |machine, args| {
+            let map = dictionary(machine, "has", &args[0])?;
+            Ok(truth(map.0.contains_key(text(machine, "has", &args[1])?)))
+        },
+    },
+    Function {
+        name: "keys",
+        signature: "(keys map) -> list",
+        doc: "The keys of the map, in order",
+        arity: 1,
+        fun: |machine, args| {
+            let map = dictionary(machine, "keys", &args[0])?;
+            let keys = map.0.keys().map(|k| string(k.clone())).collect();
+            Ok(machine.alloc_list(keys))
+        },
+    },
+    Function {
+        name: "values",
+        signature: "(values map) -> list",
+        doc: "The values of the map, in the order of their keys",
+        arity: 1,
+        fun: |machine, args| {
+            let values = dictionary(machine, "values", &args[0])?
+                .0
+                .values()
+                .cloned()
+                .collect();
+            Ok(machine.alloc_list(values))
+        },
+    },
+    Function {
+        name: "map",
+        signature: "(map function list) -> list",
+        doc: "The results of calling the function with each item of the list",
+        arity: 2,
+        fun: |machine, args| {
+            list(machine, "map", &args[1])?;
+            let results = machine.alloc_host(Buffer::default());
+            mapping(
+                machine,
+                &[args[0].clone(), args[1].clone(), int(0), results],
+            )
+        },
+    },
+    Function {
+        name: "filter",
+        signature: "(filter function list) -> list",
+        doc: "The items of the list the function returns true for",
+        arity: 2,
+        fun: |machine, args| {
+            list(machine, "filter", &args[1])?;
+            let kept = machine.alloc_host(Buffer::default());
+            filtering(machine, &[args[0].clone(), args[1].clone(), int(0), kept])
+        },
+    },
+    Function {
+        name: "fold",
+        signature: "(fold function value list) -> value",
+        doc: "Call the function with the value and the first item of the list, \
+              then with the result and the next item, and so on, returning the \
+              last result, or the value for an empty list",
+        arity: 3,
+        fun: |machine, args| {
+            list(machine, "fold", &args[2])?;
+            folding(
+                machine,
+                &[args[0].clone(), args[2].clone(), int(0), args[1].clone()],
+            )
+        },
+    },
+];
+
+/// The values collected by `map` and `filter` as they go through a list
+#[derive(Default)]
+struct Buffer(Vec<Value>);
+
+impl Trace for Buffer {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.0.iter().for_each(|v| tracer.value(v));
+    }
+}
+
+/// The values of a `Buffer`, which only `map` and `filter` make, but a
+/// script calling the functions they hand to the host could pass anything
+fn buffer<'a>(
+    machine: &'a mut Machine,
+    name: &str,
+    value: &Value,
+) -> Result<&'a mut Vec<Value>, Error> {
+    match machine.host_object_mut::<Buffer>(value) {
+        Some(buffer) => Ok(&mut buffer.0),
+        None => Err(fail(name, "expected a buffer")),
+    }
+}
+
+/// `map` from the item at `i`, given the function, the list, `i`, the
+/// results so far and the result for the item before `i`, if there is one
+fn mapping(machine: &mut Machine, args: &[Value]) -> Result<Value, Error> {
+    let (f, items, i, results) = (&args[0], &args[1], index("map", &args[2])?, &args[3]);
+    if let Some(result) = args.get(4) {
+        buffer(machine, "map", results)?.push(result.clone());
+    }
+
+    match list(machine, "map", items)?.get(i).cloned() {
+        Some(item) => {
+            let state = vec![f.clone(), items.clone(), int(i as u64 + 1), results.clone()];
+            machine.call_then(f.clone(), item, state, mapping)
+        }
+        None => {
+            let results = mem::take(buffer(machine, "map", results)?);
+            Ok(machine.alloc_list(results))
+        }
+    }
+}
+
+/// `filter` from the item at `i`, as `mapping` goes through `map`
+fn filtering(machine: &mut Machine, args: &[Value]) -> Result<Value, Error> {
+    let (f, items, i, kept) = (&args[0], &args[1], index("filter", &args[2])?, &args[3]);
+    let list = list(machine, "filter", items)?;
+    let (before, next) = (
+        i.checked_sub(1).map(|i| list[i].clone()),
+        list.get(i).cloned(),
+    );
+    if let (Some(item), Some(result)) = (before, args.get(4)) {
+        if truthy(result) {
+            buffer(machine, "filter", kept)?.push(item);
+        }
+    }
+
+    match next {
+        Some(item) => {
+            let state = vec![f.clone(), items.clone(), int(i as u64 + 1), kept.clone()];
+            machine.call_then(f.clone(), item, state, filtering)
+        }
+        None => {
+            let kept = mem::take(buffer(machine, "filter", kept)?);
+            Ok(machine.alloc_list(kept))
+        }
+    }
+}
+
+/// `fold` from the item at `i`, given the function, the list, `i` and the
+/// result so far
+fn folding(machine: &mut Machine, args: &[Value]) -> Result<Value, Error> {
+    let (f, items, i, result) = (&args[0], &args[1], index("fold", &args[2])?, &args[3]);
+    match list(machine, "fold", items)?.len() > i {
+        true => {
+            let state = vec![f.clone(), items.clone(), args[2].clone()];
+            machine.call_then(f.clone(), result.clone(), state, fold_item)
+        }
+        false => Ok(result.clone()),
+    }
+}
+
+/// `fold` given the function applied to the result so far, which is then
+/// applied to the item at `i`
+fn fold_item(machine: &mut Machine, args: &[Value]) -> Result<Value, Error> {
+    let (f, items, i, partial) = (&args[0], &args[1], index("fold", &args[2])?, &args[3]);
+    let item = list(machine, "fold", items)?[i].clone();
+    let state = vec![f.clone(), items.clone(), int(i as u64 + 1)];
+    machine.call_then(partial.clone(), item, state, folding)
+}
+
+#[derive(Clone, Copy)]
+enum Number {
+    Int(u64),
+    Float(f64),
+}
+
+impl Number {
+    fn float(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+}
+
+fn int(i: u64) -> Value {
+    Value::Lit(Literal::Int(i))
+}
+
+fn float(f: f64) -> Value {
+    Value::Lit(Literal::Float(f))
+}
+
+fn string(s: String) -> Value {
+    Value::Lit(Literal::String(s))
+}
+
+fn truth(b: bool) -> Value {
+    int(b as u64)
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Lit(Literal::Int(0)))
+}
+
+/// The bytes a list of `n` items takes up, for `Machine::reserve`
+pub(crate) fn items_size(n: usize) -> usize {
+    n.saturating_mul(mem::size_of::<Value>())
+}
+
+fn fail(name: &str, message: impl Display) -> Error {
+    Error::Host(format!("{}: {}", name, message))
+}
+
+fn expected(machine: &Machine, name: &str, what: &str, got: &Value) -> Error {
+    fail(
+        name,
+        format_args!("expected {}, got {}", what, type_name(machine, got)),
+    )
+}
+
+/// `Value::type_name`, knowing that host objects can be maps
+pub fn type_name(machine: &Machine, value: &Value) -> &'static str {
+    match machine.host_object::<Map>(value) {
+        Some(_) => "map",
+        None => value.type_name(),
+    }
+}
+
+fn number(name: &str, value: &Value) -> Result<Number, Error> {
+    match value {
+        Value::Lit(Literal::Int(i)) => Ok(Number::Int(*i)),
+        Value::Lit(Literal::Float(f)) => Ok(Number::Float(*f)),
+        v => Err(fail(
+            name,
+            format_args!("expected a number, got {}", v.type_name()),
+        )),
+    }
+}
+
+fn index(name: &str, value: &Value) -> Result<usize, Error> {
+    match value {
+        Value::Lit(Literal::Int(i)) => Ok(*i as usize),
+        v => Err(fail(
+            name,
+            format_args!("expected an int, got {}", v.type_name()),
+        )),
+    }
+}
+
+fn text<'a>(machine: &Machine, name: &str, value: &'a Value) -> Result<&'a str, Error> {
+    match value {
+        Value::Lit(Literal::String(s)) => Ok(s),
+        v => Err(expected(machine, name, "a string", v)),
+    }
+}
+
+fn list<'a>(machine: &'a Machine, name: &str, value: &Value) -> Result<&'a [Value], Error> {
+    machine
+        .list(value)
+        .ok_or_else(|| expected(machine, name, "a list", value))
+}
+
+fn dictionary<'a>(machine: &'a Machine, name: &str, value: &Value) -> Result<&'a Map, Error> {
+    machine
+        .host_object(value)
+        .ok_or_else(|| expected(machine, name, "a map", value))
+}
+
+/// Apply an arithmetic operation, to ints if both arguments are ints, and
+/// to floats otherwise
+fn arithmetic(
+    name: &str,
+    args: &[Value],
+    ints: fn(u64, u64) -> Option<u64>,
+    floats: fn(f64, f64) -> f64,
+) -> Result<Value, Error> {
+    match (number(name, &args[0])?, number(name, &args[1])?) {
+        (Number::Int(a), Number::Int(b)) => ints(a, b).map(int).ok_or_else(|| match b {
+            0 => fail(name, "division by zero"),
+            _ => fail(name, "int out of range"),
+        }),
+        (a, b) => Ok(float(floats(a.float(), b.float()))),
+    }
+}
+
+fn compare(name: &str, args: &[Value]) -> Result<Option<Ordering>, Error> {
+    match (&args[0], &args[1]) {
+        (Value::Lit(Literal::String(a)), Value::Lit(Literal::String(b))) => Ok(Some(a.cmp(b))),
+        (a, b) => Ok(match (number(name, a)?, number(name, b)?) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+            (a, b) => a.float().partial_cmp(&b.float()),
+        }),
+    }
+}
+
+fn equal(machine: &Machine, a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Lit(Literal::String(a)), Value::Lit(Literal::String(b))) => a == b,
+        (Value::Lit(Literal::Void), Value::Lit(Literal::Void)) => true,
+        (Value::Lit(Literal::Int(a)), Value::Lit(Literal::Int(b))) => a == b,
+        (
+            Value::Lit(Literal::Int(_) | Literal::Float(_)),
+            Value::Lit(Literal::Int(_) | Literal::Float(_)),
+        ) => number("=", a).ok().map(Number::float) == number("=", b).ok().map(Number::float),
+        _ => {
+            if let (Some(a), Some(b)) = (machine.list(a), machine.list(b)) {
+                return a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(machine, a, b));
+            }
+            if let (Some(a), Some(b)) =
+                (machine.host_object::<Map>(a), machine.host_object::<Map>(b))
+            {
+                return a.0.len() == b.0.len()
+                    && a.0
+                        .iter()
+                        .zip(&b.0)
+                        .all(|((ka, a), (kb, b))| ka == kb && equal(machine, a, b));
+            }
+
+            a.gc().is_some() && a.gc() == b.gc()
+        }
+    }
+}
+
+/// Write a value for `to-string` and `format`
+fn show(machine: &Machine, value: &Value) -> String {
+    match value {
+        Value::Lit(Literal::String(s)) => s.clone(),
+        v => write(machine, v),
+    }
+}
+
+/// Write a value nested in a list or map, quoting strings
+fn write(machine: &Machine, value: &Value) -> String {
+    if let Value::Lit(Literal::String(s)) = value {
+        return parse::escape(s);
+    }
+    if let Some(items) = machine.list(value) {
+        let items: Vec<_> = items.iter().map(|v| write(machine, v)).collect();
+        return format!("[{}]", items.join(", "));
+    }
+    if let Some(map) = machine.host_object::<Map>(value) {
+        let entries: Vec<_> = map
+            .0
+            .iter()
+            .map(|(k, v)| format!("{}: {}", parse::escape(k), write(machine, v)))
+            .collect();
+        return format!("{{{}}}", entries.join(", "));
+    }
+
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, eval::Outcome, parse, prim::Prim};
+
+    /// Run a script with the standard library, giving what it returns as
+    /// `to-string` writes it, or the message of what it raised
+    fn run(src: &str) -> Result<String, String> {
+        let mut machine = Machine::new();
+        install(&mut machine);
+
+        let program = cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+        match machine.run(&program) {
+            Ok(v) => Ok(show(&machine, &v)),
+            Err(Error::Uncaught(e)) => Err(show(&machine, &e.value)),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn ok(s: &str) -> Result<String, String> {
+        Ok(s.to_owned())
+    }
+
+    fn err(s: &str) -> Result<String, String> {
+        Err(s.to_owned())
+    }
+
+    #[test]
+    fn does_arithmetic_across_numeric_types() {
+        assert_eq!(run("(+ 1 2)"), ok("3"));
+        assert_eq!(run("(- 2.5 3)"), ok("-0.5"));
+        assert_eq!(run("(* 2 1.5)"), ok("3"));
+        assert_eq!(run("(/ 7 2)"), ok("3"));
+        assert_eq!(run("(/ 7 2.0)"), ok("3.5"));
+        assert_eq!(run("(% 7 3)"), ok("1"));
+        assert_eq!(run("(int 2.9)"), ok("2"));
+
+        assert_eq!(run("(= 1 1.0)"), ok("1"));
+        assert_eq!(run("(< 1 0.5)"), ok("0"));
+        assert_eq!(run("(>= \"b\" \"a\")"), ok("1"));
+        assert_eq!(run("(not 0)"), ok("1"));
+        assert_eq!(run("(= (cons 1 nil) (cons 1.0 nil))"), ok("1"));
+        assert_eq!(run("(= \"1\" 1)"), ok("0"));
+    }
+
+    #[test]
+    fn fails_consistently() {
+        assert_eq!(run("(+ 1 \"a\")"), err("+: expected a number, got string"));
+        assert_eq!(run("(- 1 2)"), err("-: int out of range"));
+        assert_eq!(run("(/ 1 0)"), err("/: division by zero"));
+        assert_eq!(run("(< \"a\" 1)"), err("<: expected a number, got string"));
+        assert_eq!(run("(first nil)"), err("first: the list is empty"));
+        assert_eq!(
+            run("(length 1)"),
+            err("length: expected a string, list or map, got int")
+        );
+        assert_eq!(run("(keys nil)"), err("keys: expected a map, got list"));
+        assert_eq!(
+            run("(exception-value 1)"),
+            err("exception-value: expected an exception, got int")
+        );
+        assert_eq!(
+            run("(try (+ 1 \"a\") (catch (e) (concat \"caught \" (exception-value e))))"),
+            ok("caught +: expected a number, got string")
+        );
+    }
+
+    #[test]
+    fn works_with_strings() {
+        assert_eq!(run("(concat \"a\" \"b\")"), ok("ab"));
+        assert_eq!(run("(length \"héllo\")"), ok("5"));
+        assert_eq!(run("(slice \"héllo\" 1 3)"), ok("él"));
+        assert_eq!(
+            run("(slice \"abc\" 2 4)"),
+            err("slice: 2..4 is out of range of 3")
+        );
+        assert_eq!(
+            run("(split \"a,b,,c\" \",\")"),
+            ok(r#"["a", "b", "", "c"]"#)
+        );
+        assert_eq!(
+            run("(format \"{} + {} = {{{}}}\" (cons 1 (cons 2.5 (cons \"x\" nil))))"),
+            ok("1 + 2.5 = {x}")
+        );
+        assert_eq!(
+            run("(format \"{} {}\" (cons 1 nil))"),
+            err("format: too few values for the template")
+        );
+        assert_eq!(
+            run("(format \"{\" nil)"),
+            err("format: unmatched `{` in the template")
+        );
+    }
+
+    #[test]
+    fn works_with_lists_and_maps() {
+        assert_eq!(run("(get (cons 1 (cons 2 nil)) 1)"), ok("2"));
+        assert_eq!(run("(get nil 0)"), err("get: index 0 is out of range of 0"));
+        assert_eq!(run("(rest (concat (range 2) (cons 5 nil)))"), ok("[1, 5]"));
+        assert_eq!(run("(slice (range 5) 1 3)"), ok("[1, 2]"));
+
+        let map = "(assoc (assoc empty-map \"b\" 2) \"a\" (cons \"x\" nil))";
+        assert_eq!(run(map), ok(r#"{"a": ["x"], "b": 2}"#));
+        assert_eq!(run(&format!("(keys {})", map)), ok(r#"["a", "b"]"#));
+        assert_eq!(run(&format!("(get {} \"b\")", map)), ok("2"));
+        assert_eq!(run(&format!("(has (dissoc {} \"a\") \"a\")", map)), ok("0"));
+        assert_eq!(run(&format!("(length {})", map)), ok("2"));
+        assert_eq!(run("(get empty-map \"k\")"), err(r#"get: no key "k""#));
+    }
+
+    #[test]
+    fn reads_and_writes_json() {
+        let src = r#"(json-parse "{\"a\": [1, -2.0, 2.5, true, null], \"b\": {}, \"a\": \"x\"}")"#;
+        assert_eq!(run(src), ok(r#"{"a": "x", "b": {}}"#));
+        assert_eq!(
+            run(r#"(get (json-parse "[1, -2.0, 2.5, true, null]") 1)"#),
+            ok("-2")
+        );
+        assert_eq!(
+            run(r#"(json-parse "[-2]")"#),
+            err("json-parse: can't convert negative int -2")
+        );
+
+        let map = "(assoc (assoc empty-map \"b\" (cons void nil)) \"a\" 1.5)";
+        assert_eq!(
+            run(&format!("(json-stringify {})", map)),
+            ok(r#"{"a":1.5,"b":[null]}"#)
+        );
+        assert_eq!(
+            run(&format!("(= (json-parse (json-stringify {0})) {0})", map)),
+            ok("1")
+        );
+
+        assert_eq!(
+            run(r#"(json-parse "[1,")"#),
+            err("json-parse: EOF while parsing a value at line 1 column 3")
+        );
+        assert_eq!(
+            run("(json-stringify (cons + nil))"),
+            err("json-stringify: can't convert a value of type function")
+        );
+    }
+
+    #[test]
+    fn calls_functions_from_the_host() {
+        assert_eq!(run("(map (+ 1) (range 3))"), ok("[1, 2, 3]"));
+        assert_eq!(run("(filter (lambda (x) (< 0 x)) (range 3))"), ok("[1, 2]"));
+        assert_eq!(run("(fold + 0 (range 5))"), ok("10"));
+        assert_eq!(
+            run("(fold (lambda (xs x) (cons x xs)) nil (range 3))"),
+            ok("[2, 1, 0]")
+        );
+        assert_eq!(
+            run("(try (map (lambda (x) (raise (+ x 10))) (range 2)) (catch (e) (exception-value e)))"),
+            ok("10")
+        );
+
+        // enough garbage to collect while the host holds the results
+        let lists = "(map (lambda (x) (cons x (range 20))) (range 3000))";
+        let src = format!("(fold (lambda (n xs) (+ n (first xs))) 0 {})", lists);
+        assert_eq!(run(&src), ok("4498500"));
+    }
+
+    #[test]
+    fn calls_back_on_the_machine_loop() {
+        let program = |src: &str| cont_expr::program(parse::script(src).unwrap()).into_fexpr();
+        let mut machine = Machine::new();
+        install(&mut machine);
+        for &prim in &Prim::SCHEDULER {
+            machine.define_prim(prim);
+        }
+
+        // a task yielding in a callback lets the main program carry on
+        let tasks = program(
+            "(begin (spawn (lambda () (map (lambda (x) (yield-now)) (range 1)))) (yield-now) \"main-done\")",
+        );
+        let result = machine.run(&tasks).unwrap();
+        assert_eq!(show(&machine, &result), "main-done");
+
+        // callbacks use up the run's fuel
+        let omega = program("(map (lambda (x) ((lambda (y) (y y)) (lambda (y) (y y)))) (range 1))");
+        assert!(matches!(
+            machine.run_with_fuel(&omega, 1000),
+            Ok(Outcome::Suspended(_))
+        ));
+
+        // and count towards its step limit, even when run by `apply`
+        machine.register_curried("apply", 2, |machine, args| {
+            machine.apply(args[0].clone(), args[1].clone())
+        });
+        machine.set_step_limit(Some(600));
+        let sum = "(apply (lambda (x) (fold + 0 (range 100))) 1)";
+        assert!(machine.run(&program(sum)).is_ok());
+        let twice = program(&format!("(begin {0} {0})", sum));
+        assert!(matches!(
+            machine.run(&twice),
+            Err(Error::StepLimitExceeded(600))
+        ));
+        machine.set_step_limit(None);
+
+        // tasks can't switch while the host waits on `apply`
+        let yielding =
+            program("(try (apply (lambda (x) (yield-now)) 1) (catch (e) (exception-value e)))");
+        let caught = machine.run(&yielding).unwrap();
+        assert_eq!(
+            show(&machine, &caught),
+            "yield-now: can't wait inside a function called by the host"
+        );
+    }
+
+    #[test]
+    fn stays_under_the_memory_limit() {
+        let mut machine = Machine::new();
+        install(&mut machine);
+        let limit = 64 * 1024;
+        machine.set_memory_limit(Some(limit));
+
+        // each fits under the limit, but what is made from them doesn't
+        machine.define("commas", string(",".repeat(10_000)));
+        let numbers = format!("[{}0]", "0,".repeat(5_000));
+        machine.define("numbers", string(numbers));
+
+        for src in &[
+            "(fold (lambda (acc x) (cons x acc)) nil (range 100000))",
+            "(range 4000000000)",
+            "(cons 0 (range 800))",
+            "(split commas \",\")",
+            "(json-parse numbers)",
+        ] {
+            let caught = format!("(try {} (catch (e) (exception-value e)))", src);
+            let program = cont_expr::program(parse::script(&caught).unwrap()).into_fexpr();
+            let result = machine.run(&program).unwrap();
+            assert_eq!(show(&machine, &result), "out of memory", "{}", src);
+            // the functions fail before making what wouldn't fit
+            assert!(machine.heap().bytes() <= limit, "{}", src);
+        }
+    }
+
+    #[test]
+    fn documents_every_function() {
+        for function in PRELUDE {
+            let call = &function.signature[..function.signature.find(')').unwrap()];
+            assert!(call.starts_with(&format!("({} ", function.name)));
+            assert_eq!(
+                call.matches(' ').count(),
+                function.arity,
+                "{}",
+                function.name
+            );
+            assert!(!function.doc.is_empty());
+        }
+    }
+}
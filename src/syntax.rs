@@ -0,0 +1,630 @@
+use moniker::{Binder, BoundTerm, FreeVar, Ignore, Scope, Var};
+
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    cont_expr::{CCall, KExpr, UExpr},
+    expr::Expr,
+    flat_expr::{FExpr, UserScope},
+    literals::Literal,
+    parse::{self, ParseError, Sexp},
+    prim::Prim,
+    span::{CallSite, Span},
+};
+
+/// A canonical textual format, which unlike `pretty` can be read back in
+///
+/// Each IR prints as s-expressions in the forms its `pretty` uses, except
+/// that primitives are written `%name` and spans are left out. Binders are
+/// renamed where needed so that no name shadows another it could capture,
+/// making `T::parse_with(&t.print(), t.free_vars())` alpha-equivalent to
+/// `t`.
+pub trait Syntax: BoundTerm<String> + Clone {
+    fn to_sexp(&self, names: &mut Names) -> Sexp;
+
+    fn from_sexp(sexp: &Sexp, env: &mut Bindings) -> Result<Self, ParseError>;
+
+    fn print(&self) -> String {
+        self.to_sexp(&mut Names::new(self)).to_string()
+    }
+
+    /// Parse a single term, each free name becoming a fresh variable
+    fn parse(src: &str) -> Result<Self, ParseError> {
+        Self::parse_with(src, Vec::new())
+    }
+
+    /// Parse a single term, resolving free names to the given variables
+    fn parse_with(
+        src: &str,
+        free: impl IntoIterator<Item = FreeVar<String>>,
+    ) -> Result<Self, ParseError> {
+        let mut env = Bindings {
+            bound: Vec::new(),
+            free: free
+                .into_iter()
+                .filter_map(|v| Some((v.pretty_name.clone()?, v)))
+                .collect(),
+        };
+
+        match &parse::read(src)?[..] {
+            [sexp] => Self::from_sexp(sexp, &mut env),
+            [] => Err(ParseError::new(
+                "expected an expression",
+                Span::new(0, src.len()),
+            )),
+            [_, extra, ..] => Err(ParseError::new(
+                "expected a single expression",
+                extra.span(),
+            )),
+        }
+    }
+}
+
+/// The names printed for the variables bound around the term being printed
+pub struct Names {
+    /// The names a binder can't take: those already in scope and those of
+    /// free variables
+    taken: HashSet<String>,
+    bound: HashMap<FreeVar<String>, String>,
+}
+
+impl Names {
+    /// Names for the binders of `term`, which avoid its free variables
+    pub(crate) fn new(term: &impl BoundTerm<String>) -> Self {
+        let free = term.free_vars().into_iter();
+
+        Names {
+            taken: free.filter_map(|v| v.pretty_name).collect(),
+            bound: HashMap::new(),
+        }
+    }
+
+    /// Name a binder, keeping its own name unless that is taken or isn't
+    /// valid syntax
+    pub(crate) fn bind(&mut self, var: &FreeVar<String>) -> String {
+        let base = match &var.pretty_name {
+            Some(name) if !name.is_empty() && name.chars().all(name_char) => name.as_str(),
+            _ => "_",
+        };
+
+        let mut name = base.to_owned();
+        let mut i = 0;
+        while self.taken.contains(&name) || reserved(&name) {
+            i += 1;
+            name = match base.ends_with('_') {
+                true => format!("{}{}", base, i),
+                false => format!("{}_{}", base, i),
+            };
+        }
+
+        self.taken.insert(name.clone());
+        self.bound.insert(var.clone(), name.clone());
+
+        name
+    }
+
+    pub(crate) fn unbind(&mut self, var: &FreeVar<String>) {
+        if let Some(name) = self.bound.remove(var) {
+            self.taken.remove(&name);
+        }
+    }
+
+    pub(crate) fn var(&self, var: &Var<String>) -> String {
+        match var {
+            Var::Free(v) => match (self.bound.get(v), &v.pretty_name) {
+                (Some(name), _) | (None, Some(name)) => name.clone(),
+                (None, None) => "_".to_owned(),
+            },
+            Var::Bound(v) => v.to_string(),
+        }
+    }
+}
+
+fn name_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | '"' | ';')
+}
+
+/// Whether a name would read back as something other than a variable
+fn reserved(name: &str) -> bool {
+    let keyword = matches!(
+        name,
+        "lambda" | "generator" | "async" | "raise" | "yield" | "await" | "try" | "catch"
+    );
+
+    keyword || name.starts_with('%') || !matches!(parse::literal(name, Span::default()), Ok(None))
+}
+
+/// The names bound around the term being parsed
+pub struct Bindings {
+    bound: Vec<(String, FreeVar<String>)>,
+    free: HashMap<String, FreeVar<String>>,
+}
+
+impl Bindings {
+    fn bind(&mut self, sexp: &Sexp) -> Result<FreeVar<String>, ParseError> {
+        match sexp {
+            Sexp::Atom(name, _) if !reserved(name) => {
+                let var = FreeVar::fresh_named(name.as_str());
+                self.bound.push((name.clone(), var.clone()));
+
+                Ok(var)
+            }
+            _ => Err(ParseError::new("expected a parameter name", sexp.span())),
+        }
+    }
+
+    fn unbind(&mut self) {
+        self.bound.pop();
+    }
+
+    fn var(&mut self, name: &str) -> Var<String> {
+        let free = &mut self.free;
+        let var = self
+            .bound
+            .iter()
+            .rev()
+            .find(|(bound, _)| bound == name)
+            .map(|(_, v)| v.clone())
+            .unwrap_or_else(|| {
+                free.entry(name.to_owned())
+                    .or_insert_with(|| FreeVar::fresh_named(name))
+                    .clone()
+            });
+
+        Var::Free(var)
+    }
+
+    fn atom(&mut self, sexp: &Sexp) -> Result<Option<Atom>, ParseError> {
+        Ok(Some(match sexp {
+            Sexp::String(s, _) => Atom::Lit(Literal::String(s.clone())),
+            Sexp::Atom(a, span) => match (parse::literal(a, *span)?, a.strip_prefix('%')) {
+                (Some(l), _) => Atom::Lit(l),
+                (None, Some(name)) => match Prim::ALL.iter().find(|p| prim_name(**p) == name) {
+                    Some(&p) => Atom::Prim(p),
+                    None => {
+                        let msg = format!("unknown primitive `{}`", a);
+                        return Err(ParseError::new(msg, *span));
+                    }
+                },
+                (None, None) if reserved(a) => {
+                    return Err(ParseError::new(format!("unexpected `{}`", a), *span))
+                }
+                (None, None) => Atom::Var(self.var(a)),
+            },
+            Sexp::List(..) => return Ok(None),
+        }))
+    }
+}
+
+/// A term without any subterms
+enum Atom {
+    Var(Var<String>),
+    Lit(Literal),
+    Prim(Prim),
+}
+
+fn atom(name: impl Into<String>) -> Sexp {
+    Sexp::Atom(name.into(), Span::default())
+}
+
+fn list(items: Vec<Sexp>) -> Sexp {
+    Sexp::List(items, Span::default())
+}
+
+pub(crate) fn prim_name(p: Prim) -> &'static str {
+    p.name().trim_start_matches('%')
+}
+
+fn prim(p: Prim) -> Sexp {
+    atom(format!("%{}", prim_name(p)))
+}
+
+fn lit(l: &Literal) -> Sexp {
+    match l {
+        Literal::String(s) => Sexp::String(s.clone(), Span::default()),
+        Literal::Float(f) if f.is_nan() => atom("+nan.0".to_owned()),
+        Literal::Float(f) if f.is_infinite() => {
+            atom(format!("{}inf.0", if *f > 0.0 { "+" } else { "-" }))
+        }
+        // debug formatting keeps the `.0` that marks a whole float
+        Literal::Float(f) => atom(format!("{:?}", f)),
+        l => atom(l.to_string()),
+    }
+}
+
+/// A call at `span` to `f`, named if it's a variable
+fn call_site(f: &Sexp, span: Span) -> Ignore<Option<Rc<CallSite>>> {
+    let callee = match f {
+        Sexp::Atom(name, _) if !reserved(name) => Some(name.clone()),
+        _ => None,
+    };
+
+    Ignore(Some(Rc::new(CallSite { span, callee })))
+}
+
+fn expected(what: &str, sexp: &Sexp) -> ParseError {
+    ParseError::new(format!("expected {}", what), sexp.span())
+}
+
+fn is_keyword(sexp: &Sexp, keyword: &str) -> bool {
+    matches!(sexp, Sexp::Atom(a, _) if a == keyword)
+}
+
+/// `(keyword (x) body)`
+fn scope_sexp<T: Syntax>(
+    keyword: &str,
+    scope: &Scope<Binder<String>, Rc<T>>,
+    names: &mut Names,
+) -> Sexp {
+    let (Binder(x), body) = scope.clone().unbind();
+    let param = atom(names.bind(&x));
+    let body = body.to_sexp(names);
+    names.unbind(&x);
+
+    list(vec![atom(keyword), list(vec![param]), body])
+}
+
+// a failed parse abandons its bindings, so errors don't need to unbind
+
+fn parse_scope<T: Syntax>(
+    param: &Sexp,
+    body: &Sexp,
+    env: &mut Bindings,
+) -> Result<Scope<Binder<String>, Rc<T>>, ParseError> {
+    let x = env.bind(param)?;
+    let body = T::from_sexp(body, env)?;
+    env.unbind();
+
+    Ok(Scope::new(Binder(x), Rc::new(body)))
+}
+
+/// `(lambda (x k h) body)`
+fn user_scope_sexp<T: Syntax>(scope: &UserScope<Rc<T>>, names: &mut Names) -> Sexp {
+    let (Binder(x), scope) = scope.clone().unbind();
+    let (Binder(k), scope) = scope.unbind();
+    let (Binder(h), body) = scope.unbind();
+
+    let params = vec![
+        atom(names.bind(&x)),
+        atom(names.bind(&k)),
+        atom(names.bind(&h)),
+    ];
+    let body = body.to_sexp(names);
+    for v in &[h, k, x] {
+        names.unbind(v);
+    }
+
+    list(vec![atom("lambda"), list(params), body])
+}
+
+fn parse_user_scope<T: Syntax>(
+    (x, k, h): (&Sexp, &Sexp, &Sexp),
+    body: &Sexp,
+    env: &mut Bindings,
+) -> Result<UserScope<Rc<T>>, ParseError> {
+    let x = env.bind(x)?;
+    let k = env.bind(k)?;
+    let h = env.bind(h)?;
+    let body = T::from_sexp(body, env)?;
+    (0..3).for_each(|_| env.unbind());
+
+    Ok(Scope::new(
+        Binder(x),
+        Scope::new(Binder(k), Scope::new(Binder(h), Rc::new(body))),
+    ))
+}
+
+/// The parameters and body of `(lambda (params..) body)`
+fn lambda(items: &[Sexp]) -> Option<(&[Sexp], &Sexp)> {
+    match items {
+        [keyword, Sexp::List(params, _), body] if is_keyword(keyword, "lambda") => {
+            Some((params, body))
+        }
+        _ => None,
+    }
+}
+
+impl Syntax for Expr {
+    fn to_sexp(&self, names: &mut Names) -> Sexp {
+        let keyword =
+            |keyword, e: &Expr, names: &mut Names| list(vec![atom(keyword), e.to_sexp(names)]);
+
+        match self {
+            Expr::Var(v) => atom(names.var(v)),
+            Expr::Lit(Ignore(l)) => lit(l),
+            Expr::Lam(s) => scope_sexp("lambda", s, names),
+            Expr::Gen(s) => scope_sexp("generator", s, names),
+            Expr::Async(s) => scope_sexp("async", s, names),
+            Expr::App(f, e, _) => list(vec![f.to_sexp(names), e.to_sexp(names)]),
+            Expr::Raise(e, _) => keyword("raise", e, names),
+            Expr::Yield(e) => keyword("yield", e, names),
+            Expr::Await(e) => keyword("await", e, names),
+            Expr::Try(body, handler) => {
+                let body = body.to_sexp(names);
+                let catch = scope_sexp("catch", handler, names);
+
+                list(vec![atom("try"), body, catch])
+            }
+        }
+    }
+
+    fn from_sexp(sexp: &Sexp, env: &mut Bindings) -> Result<Self, ParseError> {
+        match env.atom(sexp)? {
+            Some(Atom::Var(v)) => return Ok(Expr::Var(v)),
+            Some(Atom::Lit(l)) => return Ok(Expr::Lit(Ignore(l))),
+            Some(Atom::Prim(_)) => return Err(expected("a variable or literal", sexp)),
+            None => {}
+        }
+
+        let (items, span) = match sexp {
+            Sexp::List(items, span) => (items, *span),
+            _ => unreachable!(),
+        };
+        let mut sub = |e| Expr::from_sexp(e, env).map(Rc::new);
+
+        match &items[..] {
+            [Sexp::Atom(keyword, _), Sexp::List(param, _), body]
+                if matches!(&keyword[..], "lambda" | "generator" | "async") =>
+            {
+                let s = match &param[..] {
+                    [param] => parse_scope(param, body, env)?,
+                    _ => return Err(expected(&format!("`({} (x) body)`", keyword), sexp)),
+                };
+
+                Ok(match &keyword[..] {
+                    "generator" => Expr::Gen(s),
+                    "async" => Expr::Async(s),
+                    _ => Expr::Lam(s),
+                })
+            }
+            [keyword, e] if is_keyword(keyword, "raise") => Ok(Expr::Raise(sub(e)?, Ignore(span))),
+            [keyword, e] if is_keyword(keyword, "yield") => Ok(Expr::Yield(sub(e)?)),
+            [keyword, e] if is_keyword(keyword, "await") => Ok(Expr::Await(sub(e)?)),
+            [keyword, body, Sexp::List(catch, _)]
+                if is_keyword(keyword, "try")
+                    && catch.first().is_some_and(|c| is_keyword(c, "catch")) =>
+            {
+                let body = sub(body)?;
+                match &catch[1..] {
+                    [Sexp::List(param, _), handler] if param.len() == 1 => {
+                        Ok(Expr::Try(body, parse_scope(&param[0], handler, env)?))
+                    }
+                    _ => Err(expected("`(catch (x) handler)`", &items[2])),
+                }
+            }
+            [Sexp::Atom(keyword, _), ..] if reserved(keyword) => {
+                Err(ParseError::new(format!("malformed `{}`", keyword), span))
+            }
+            [f, e] => Ok(Expr::App(sub(f)?, sub(e)?, Ignore(Some(span)))),
+            _ => Err(expected("an expression", sexp)),
+        }
+    }
+}
+
+impl Syntax for UExpr {
+    fn to_sexp(&self, names: &mut Names) -> Sexp {
+        match self {
+            UExpr::Lam(s) => user_scope_sexp(s, names),
+            UExpr::Var(v) => atom(names.var(v)),
+            UExpr::Lit(Ignore(l)) => lit(l),
+            UExpr::Prim(Ignore(p)) => prim(*p),
+        }
+    }
+
+    fn from_sexp(sexp: &Sexp, env: &mut Bindings) -> Result<Self, ParseError> {
+        match (env.atom(sexp)?, sexp) {
+            (Some(Atom::Var(v)), _) => Ok(UExpr::Var(v)),
+            (Some(Atom::Lit(l)), _) => Ok(UExpr::Lit(Ignore(l))),
+            (Some(Atom::Prim(p)), _) => Ok(UExpr::Prim(Ignore(p))),
+            (None, Sexp::List(items, _)) => match lambda(items) {
+                Some(([x, k, h], body)) => Ok(UExpr::Lam(parse_user_scope((x, k, h), body, env)?)),
+                _ => Err(expected("`(lambda (x k h) body)`", sexp)),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Syntax for KExpr {
+    fn to_sexp(&self, names: &mut Names) -> Sexp {
+        match self {
+            KExpr::Lam(s) => scope_sexp("lambda", s, names),
+            KExpr::Var(v) => atom(names.var(v)),
+            KExpr::Lit(Ignore(l)) => lit(l),
+        }
+    }
+
+    fn from_sexp(sexp: &Sexp, env: &mut Bindings) -> Result<Self, ParseError> {
+        match (env.atom(sexp)?, sexp) {
+            (Some(Atom::Var(v)), _) => Ok(KExpr::Var(v)),
+            (Some(Atom::Lit(l)), _) => Ok(KExpr::Lit(Ignore(l))),
+            (Some(Atom::Prim(_)), _) => Err(expected("a continuation", sexp)),
+            (None, Sexp::List(items, _)) => match lambda(items) {
+                Some(([x], body)) => Ok(KExpr::Lam(parse_scope(x, body, env)?)),
+                _ => Err(expected("`(lambda (x) body)`", sexp)),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Syntax for CCall {
+    fn to_sexp(&self, names: &mut Names) -> Sexp {
+        match self {
+            CCall::UCall(f, v, k, h, _) => list(vec![
+                f.to_sexp(names),
+                v.to_sexp(names),
+                k.to_sexp(names),
+                h.to_sexp(names),
+            ]),
+            CCall::KCall(k, v) => list(vec![k.to_sexp(names), v.to_sexp(names)]),
+            CCall::Raise(h, v, _) => list(vec![atom("raise"), h.to_sexp(names), v.to_sexp(names)]),
+        }
+    }
+
+    fn from_sexp(sexp: &Sexp, env: &mut Bindings) -> Result<Self, ParseError> {
+        let (items, span) = match sexp {
+            Sexp::List(items, span) => (items, *span),
+            _ => return Err(expected("a call", sexp)),
+        };
+
+        match &items[..] {
+            [keyword, h, v] if is_keyword(keyword, "raise") => Ok(CCall::Raise(
+                Rc::new(KExpr::from_sexp(h, env)?),
+                Rc::new(UExpr::from_sexp(v, env)?),
+                Ignore(span),
+            )),
+            [f, v, k, h] => Ok(CCall::UCall(
+                Rc::new(UExpr::from_sexp(f, env)?),
+                Rc::new(UExpr::from_sexp(v, env)?),
+                Rc::new(KExpr::from_sexp(k, env)?),
+                Rc::new(KExpr::from_sexp(h, env)?),
+                call_site(f, span),
+            )),
+            [k, v] => Ok(CCall::KCall(
+                Rc::new(KExpr::from_sexp(k, env)?),
+                Rc::new(UExpr::from_sexp(v, env)?),
+            )),
+            _ => Err(expected("a call", sexp)),
+        }
+    }
+}
+
+impl Syntax for FExpr {
+    fn to_sexp(&self, names: &mut Names) -> Sexp {
+        match self {
+            FExpr::LamOne(s) => scope_sexp("lambda", s, names),
+            FExpr::LamTwo(s) => user_scope_sexp(s, names),
+            FExpr::Var(v) => atom(names.var(v)),
+            FExpr::Lit(Ignore(l)) => lit(l),
+            FExpr::Prim(Ignore(p)) => prim(*p),
+            FExpr::CallOne(k, v) => list(vec![k.to_sexp(names), v.to_sexp(names)]),
+            FExpr::CallTwo(f, v, k, h, _) => list(vec![
+                f.to_sexp(names),
+                v.to_sexp(names),
+                k.to_sexp(names),
+                h.to_sexp(names),
+            ]),
+            FExpr::Raise(h, v, _) => list(vec![atom("raise"), h.to_sexp(names), v.to_sexp(names)]),
+        }
+    }
+
+    fn from_sexp(sexp: &Sexp, env: &mut Bindings) -> Result<Self, ParseError> {
+        match env.atom(sexp)? {
+            Some(Atom::Var(v)) => return Ok(FExpr::Var(v)),
+            Some(Atom::Lit(l)) => return Ok(FExpr::Lit(Ignore(l))),
+            Some(Atom::Prim(p)) => return Ok(FExpr::Prim(Ignore(p))),
+            None => {}
+        }
+
+        let (items, span) = match sexp {
+            Sexp::List(items, span) => (items, *span),
+            _ => unreachable!(),
+        };
+        let mut sub = |e| FExpr::from_sexp(e, env).map(Rc::new);
+
+        match (lambda(items), &items[..]) {
+            (Some(([x], body)), _) => Ok(FExpr::LamOne(parse_scope(x, body, env)?)),
+            (Some(([x, k, h], body)), _) => {
+                Ok(FExpr::LamTwo(parse_user_scope((x, k, h), body, env)?))
+            }
+            (Some(_), _) => Err(expected("one or three parameters", sexp)),
+            (None, [keyword, h, v]) if is_keyword(keyword, "raise") => {
+                Ok(FExpr::Raise(sub(h)?, sub(v)?, Ignore(span)))
+            }
+            (None, [f, v, k, h]) => Ok(FExpr::CallTwo(
+                sub(f)?,
+                sub(v)?,
+                sub(k)?,
+                sub(h)?,
+                call_site(f, span),
+            )),
+            (None, [k, v]) => Ok(FExpr::CallOne(sub(k)?, sub(v)?)),
+            _ => Err(expected("an expression", sexp)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, eval::Machine};
+
+    /// Print `t`, checking that it parses back to an alpha-equivalent term
+    /// that prints the same
+    fn round_trip<T: Syntax>(t: &T) -> String {
+        let printed = t.print();
+        let parsed = T::parse_with(&printed, t.free_vars()).unwrap();
+
+        assert!(t.term_eq(&parsed), "{} didn't round trip", printed);
+        assert_eq!(parsed.print(), printed);
+
+        printed
+    }
+
+    /// Round trip a script through every IR, returning how each prints
+    fn stages(src: &str) -> [String; 3] {
+        let expr = parse::parse(src).unwrap();
+        let cps = cont_expr::program(expr.clone());
+        let flat = cps.clone().into_fexpr();
+
+        [round_trip(&expr), round_trip(&cps), round_trip(&flat)]
+    }
+
+    #[test]
+    fn every_ir_round_trips() {
+        let scripts = [
+            "(lambda (x) ((lambda (x) x) x))",
+            "((lambda (g) (g g)) g)",
+            "(lambda (x x_1) (x x_1 \"a \\\"quoted\\\"\\n string\" 1.0 2 void))",
+            "(try (raise (f 1)) (catch (e) (h e)))",
+            "(generator (x) (yield (await ((async (_) x) 1))))",
+            "(f 1e999 -1e999 +nan.0 1e20)",
+        ];
+
+        for src in &scripts {
+            stages(src);
+        }
+        assert_eq!(stages("-1e999")[0], "-inf.0");
+    }
+
+    #[test]
+    fn golden() {
+        let [expr, cps, flat] = stages("(lambda (x) ((lambda (x) x) x))");
+        let cps_expected = "\
+(lambda (_ exit abort)
+  (exit
+    (lambda (x k h)
+      ((lambda (f) ((lambda (e) (f e k h)) x))
+        (lambda (x_1 k_1 h_1) (k_1 x_1))))))";
+
+        assert_eq!(expr, "(lambda (x) ((lambda (x_1) x_1) x))");
+        assert_eq!(cps, cps_expected);
+        assert_eq!(flat, cps_expected);
+    }
+
+    #[test]
+    fn parses_hand_written_ir() {
+        let program = FExpr::parse(
+            "(lambda (_ k h)
+               (%spawn (lambda (_ k h) (k 1)) (lambda (t) (%join t k h)) h))",
+        )
+        .unwrap();
+        let mut machine = Machine::new();
+        assert_eq!(machine.run(&program).unwrap().to_string(), "1");
+
+        let call = CCall::parse("(k (lambda (x k h) (raise h x)))").unwrap();
+        assert!(matches!(call, CCall::KCall(..)));
+
+        let error = |src| CCall::parse(src).unwrap_err().to_string();
+        assert_eq!(
+            error("(k (lambda (x) x))"),
+            "3..17: expected `(lambda (x k h) body)`"
+        );
+        assert_eq!(error("(k %nope)"), "3..8: unknown primitive `%nope`");
+        assert_eq!(error("(f 1 (lambda (x) (k x)))"), "0..24: expected a call");
+    }
+}
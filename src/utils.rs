@@ -3,3 +3,146 @@ use std::rc::Rc;
 pub fn clone_rc<T: Clone>(r: Rc<T>) -> T {
     Rc::try_unwrap(r).unwrap_or_else(|t| t.as_ref().clone())
 }
+
+/// Builders for terms in tests and benchmarks
+pub mod test {
+    use moniker::{Binder, FreeVar, Ignore, Scope, Var};
+
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        cont_expr,
+        eval::{Error, Machine, Value},
+        expr::Expr,
+        literals::Literal,
+        prim::Prim,
+        span::Span,
+    };
+
+    /// What a script passed to `log`, see `logging_machine`
+    pub type Log = Rc<RefCell<Vec<String>>>;
+
+    /// A machine with `prims` defined and a host function `log`, which
+    /// returns its argument after writing it to the log
+    pub fn logging_machine(prims: &[Prim]) -> (Machine, Log) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut machine = Machine::new();
+        for &prim in prims {
+            machine.define_prim(prim);
+        }
+
+        let sink = log.clone();
+        machine.register("log", move |_, v| {
+            sink.borrow_mut().push(v.to_string());
+            Ok(v)
+        });
+
+        (machine, log)
+    }
+
+    pub fn run(machine: &mut Machine, expr: Expr) -> Result<Value, Error> {
+        machine.run(&cont_expr::program(expr).into_fexpr())
+    }
+
+    pub fn lam(name: &str, body: impl FnOnce(Expr) -> Expr) -> Expr {
+        let v = FreeVar::fresh_named(name);
+        let body = body(Expr::Var(Var::Free(v.clone())));
+
+        Expr::Lam(Scope::new(Binder(v), Rc::new(body)))
+    }
+
+    /// A generator function, see `lam`
+    pub fn gen(name: &str, body: impl FnOnce(Expr) -> Expr) -> Expr {
+        match lam(name, body) {
+            Expr::Lam(s) => Expr::Gen(s),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn yield_(e: Expr) -> Expr {
+        Expr::Yield(Rc::new(e))
+    }
+
+    pub fn async_(name: &str, body: impl FnOnce(Expr) -> Expr) -> Expr {
+        match lam(name, body) {
+            Expr::Lam(s) => Expr::Async(s),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn await_(e: Expr) -> Expr {
+        Expr::Await(Rc::new(e))
+    }
+
+    /// Evaluate `first` then `second`, returning the value of `second`
+    pub fn seq(first: Expr, second: Expr) -> Expr {
+        app(lam("_", |_| second), first)
+    }
+
+    pub fn app(f: Expr, v: Expr) -> Expr {
+        Expr::App(Rc::new(f), Rc::new(v), Ignore(None))
+    }
+
+    /// A free variable, resolved against the machine's globals
+    pub fn global(name: &str) -> Expr {
+        Expr::Var(Var::Free(FreeVar::fresh_named(name)))
+    }
+
+    pub fn lit(l: Literal) -> Expr {
+        Expr::Lit(Ignore(l))
+    }
+
+    pub fn int(i: u64) -> Expr {
+        lit(Literal::Int(i))
+    }
+
+    pub fn string(s: &str) -> Expr {
+        lit(Literal::String(s.to_owned()))
+    }
+
+    /// A call to a global
+    pub fn call(name: &str, arg: Expr) -> Expr {
+        app(global(name), arg)
+    }
+
+    /// (log s), see `logging_machine`
+    pub fn log(s: &str) -> Expr {
+        call("log", string(s))
+    }
+
+    pub fn raise(e: Expr, start: usize, end: usize) -> Expr {
+        Expr::Raise(Rc::new(e), Ignore(Span::new(start, end)))
+    }
+
+    pub fn try_catch(body: Expr, name: &str, handler: impl FnOnce(Expr) -> Expr) -> Expr {
+        let v = FreeVar::fresh_named(name);
+        let handler = handler(Expr::Var(Var::Free(v.clone())));
+
+        Expr::Try(Rc::new(body), Scope::new(Binder(v), Rc::new(handler)))
+    }
+
+    /// The church numeral `n`
+    pub fn church(n: usize) -> Expr {
+        lam("f", |f| {
+            lam("x", |x| (0..n).fold(x, |acc, _| app(f.clone(), acc)))
+        })
+    }
+
+    /// Build a function by wrapping the identity `10^exp` times, then call
+    /// it, so that the program recurses `10^exp` deep with none of the
+    /// recursive calls in tail position. Returns `void`.
+    pub fn deep_recursion(exp: usize) -> Expr {
+        // (lambda (g) (lambda (x) ((lambda (y) y) (g x))))
+        let wrap = lam("g", |g| lam("x", |x| app(lam("y", |y| y), app(g, x))));
+        let times = app(church(exp), church(10));
+
+        app(app(app(times, wrap), lam("x", |x| x)), lit(Literal::Void))
+    }
+
+    /// ((lambda (x) (x x)) (lambda (x) (x x))), which loops forever
+    pub fn omega() -> Expr {
+        let w = || lam("x", |x| app(x.clone(), x));
+
+        app(w(), w())
+    }
+}
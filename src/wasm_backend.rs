@@ -0,0 +1,372 @@
+use moniker::{Ignore, Scope, Var};
+
+use std::{fmt, fmt::Write};
+
+use crate::{flat_expr::FExpr, literals::Literal, prim::Prim};
+
+const RUNTIME: &str = include_str!("wasm_backend/runtime.wat");
+
+/// Tags stored in the first word of every value cell, see the layout
+/// described in `wasm_backend/runtime.wat`
+pub const TAG_STRING: i32 = 0;
+pub const TAG_INT: i32 = 1;
+pub const TAG_FLOAT: i32 = 2;
+pub const TAG_VOID: i32 = 3;
+pub const TAG_CLOSURE: i32 = 4;
+pub const TAG_CONT: i32 = 5;
+pub const TAG_HALT: i32 = 6;
+pub const TAG_EXCEPTION: i32 = 7;
+pub const TAG_ABORT: i32 = 8;
+
+/// Static data starts here so that a null pointer is never a valid value
+const DATA_START: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Compiled modules have no host environment, so every free variable is
+    /// an error
+    UnboundVariable(String),
+    /// The primitives need the machine's runtime, so the forms built on
+    /// them can't be compiled
+    Unsupported(Prim),
+    NotACall,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnboundVariable(name) => write!(f, "unbound variable: {}", name),
+            Error::Unsupported(p) => write!(f, "unsupported primitive: {}", p),
+            Error::NotACall => write!(f, "lambda body is not a call"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Emit a WebAssembly text module for a program produced by
+/// `cont_expr::program`
+///
+/// The module exports its `memory` and a `run` function, which returns a
+/// pointer to the value the program exits with. If the program raised an
+/// exception it didn't catch the exported `failed` global is set to 1 and
+/// the pointer is to the exception. If it made more calls than the exported
+/// `step_limit` global allows, when that is non-zero, `run` returns 0 with
+/// the exported `step_limit_exceeded` global set to 1.
+pub fn emit(program: &FExpr) -> Result<String, Error> {
+    let mut emitter = Emitter {
+        bodies: Vec::new(),
+        data: Vec::new(),
+        data_end: DATA_START,
+    };
+    let program = emitter.atom(program)?;
+
+    let mut out = String::new();
+    writeln!(out, "(module").unwrap();
+    writeln!(out, "{}", RUNTIME).unwrap();
+
+    writeln!(out, "  (memory (export \"memory\") 1)").unwrap();
+    for (addr, bytes) in &emitter.data {
+        writeln!(out, "  (data (i32.const {}) {})", addr, wat_string(bytes)).unwrap();
+    }
+    let heap = (emitter.data_end + 15) & !15;
+    writeln!(out, "  (global $heap (mut i32) (i32.const {}))", heap).unwrap();
+
+    writeln!(out, "  (table {} funcref)", emitter.bodies.len()).unwrap();
+    if !emitter.bodies.is_empty() {
+        write!(out, "  (elem (i32.const 0)").unwrap();
+        for idx in 0..emitter.bodies.len() {
+            write!(out, " $body_{}", idx).unwrap();
+        }
+        writeln!(out, ")").unwrap();
+    }
+
+    for (idx, body) in emitter.bodies.iter().enumerate() {
+        writeln!(out).unwrap();
+        writeln!(out, "  (func $body_{} (type $body) (param $env i32)", idx).unwrap();
+        writeln!(out, "    {})", body).unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "  (func (export \"run\") (result i32)").unwrap();
+    writeln!(out, "    (local $env i32)").unwrap();
+    writeln!(
+        out,
+        "    (call $call_two {} (call $void) (call $halt) (call $abort))",
+        program
+    )
+    .unwrap();
+    writeln!(out, "    (call $trampoline)))").unwrap();
+
+    Ok(out)
+}
+
+struct Emitter {
+    bodies: Vec<String>,
+    data: Vec<(u32, Vec<u8>)>,
+    data_end: u32,
+}
+
+impl Emitter {
+    fn atom(&mut self, expr: &FExpr) -> Result<String, Error> {
+        match expr {
+            FExpr::LamOne(Scope {
+                unsafe_body: body, ..
+            }) => {
+                let idx = self.lambda(body)?;
+                Ok(closure(TAG_CONT, idx))
+            }
+            FExpr::LamTwo(Scope {
+                unsafe_body:
+                    Scope {
+                        unsafe_body:
+                            Scope {
+                                unsafe_body: body, ..
+                            },
+                        ..
+                    },
+                ..
+            }) => {
+                let idx = self.lambda(body)?;
+                Ok(closure(TAG_CLOSURE, idx))
+            }
+            FExpr::Var(Var::Bound(b)) => Ok(format!(
+                "(call $lookup (local.get $env) (i32.const {}))",
+                b.scope.0
+            )),
+            FExpr::Var(v @ Var::Free(_)) => Err(Error::UnboundVariable(
+                v.pretty_name().cloned().unwrap_or_else(|| v.to_string()),
+            )),
+            FExpr::Prim(Ignore(p)) => Err(Error::Unsupported(*p)),
+            FExpr::Lit(Ignore(l)) => Ok(self.literal(l)),
+            FExpr::CallOne(..) | FExpr::CallTwo(..) | FExpr::Raise(..) => Err(Error::NotACall),
+        }
+    }
+
+    fn lambda(&mut self, body: &FExpr) -> Result<usize, Error> {
+        // reserve the slot first so the numbering follows the source order
+        let idx = self.bodies.len();
+        self.bodies.push(String::new());
+
+        let call = match body {
+            FExpr::CallOne(k, v) => {
+                format!("(call $call_one {} {})", self.atom(k)?, self.atom(v)?)
+            }
+            FExpr::CallTwo(f, v, k, h, _) => format!(
+                "(call $call_two {} {} {} {})",
+                self.atom(f)?,
+                self.atom(v)?,
+                self.atom(k)?,
+                self.atom(h)?
+            ),
+            FExpr::Raise(h, v, Ignore(span)) => format!(
+                "(call $raise {} {} (i32.const {}) (i32.const {}))",
+                self.atom(h)?,
+                self.atom(v)?,
+                span.start,
+                span.end
+            ),
+            _ => return Err(Error::NotACall),
+        };
+
+        self.bodies[idx] = call;
+
+        Ok(idx)
+    }
+
+    fn literal(&mut self, l: &Literal) -> String {
+        match l {
+            Literal::String(s) => {
+                let addr = self.data_end;
+                self.data.push((addr, s.as_bytes().to_vec()));
+                self.data_end += s.len() as u32;
+
+                format!(
+                    "(call $string (i32.const {}) (i32.const {}))",
+                    addr,
+                    s.len()
+                )
+            }
+            Literal::Int(v) => format!("(call $int (i64.const {}))", v),
+            Literal::Float(v) if v.is_nan() => "(call $float (f64.const nan))".to_owned(),
+            Literal::Float(v) => format!("(call $float (f64.const {:?}))", v),
+            Literal::Void => "(call $void)".to_owned(),
+        }
+    }
+}
+
+fn closure(tag: i32, idx: usize) -> String {
+    format!(
+        "(call $closure (i32.const {}) (i32.const {}) (local.get $env))",
+        tag, idx
+    )
+}
+
+fn wat_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => write!(out, "\\{}", b as char).unwrap(),
+            0x20..=0x7e => out.push(b as char),
+            _ => write!(out, "\\{:02x}", b).unwrap(),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cont_expr, eval::Machine, expr::Expr, utils::test::*};
+
+    use std::convert::TryInto;
+
+    fn word(mem: &[u8], addr: usize) -> usize {
+        u32::from_le_bytes(mem[addr..addr + 4].try_into().unwrap()) as usize
+    }
+
+    /// Render a value cell the same way `eval::Value` is displayed
+    fn decode(mem: &[u8], ptr: usize) -> String {
+        let payload = mem[ptr + 8..ptr + 16].try_into().unwrap();
+
+        match word(mem, ptr) as i32 {
+            TAG_STRING => {
+                let (data, len) = (word(mem, ptr + 4), word(mem, ptr + 8));
+                format!(
+                    "\"{}\"",
+                    std::str::from_utf8(&mem[data..data + len]).unwrap()
+                )
+            }
+            TAG_INT => u64::from_le_bytes(payload).to_string(),
+            TAG_FLOAT => f64::from_le_bytes(payload).to_string(),
+            TAG_VOID => "void".to_owned(),
+            TAG_CLOSURE => "<closure>".to_owned(),
+            TAG_CONT => "<continuation>".to_owned(),
+            TAG_HALT => "<halt>".to_owned(),
+            TAG_EXCEPTION => "<exception>".to_owned(),
+            TAG_ABORT => "<abort>".to_owned(),
+            tag => panic!("bad tag {}", tag),
+        }
+    }
+
+    /// Render an uncaught exception the same way `eval::Error` is displayed
+    fn decode_uncaught(mem: &[u8], ptr: usize) -> String {
+        let mut spans = Vec::new();
+        let mut span = word(mem, ptr + 8);
+        while span != 0 {
+            spans.push(format!(
+                "\n  raised at {}..{}",
+                word(mem, span),
+                word(mem, span + 4)
+            ));
+            span = word(mem, span + 8);
+        }
+        spans.reverse();
+
+        format!(
+            "uncaught exception: {}{}",
+            decode(mem, word(mem, ptr + 4)),
+            spans.concat()
+        )
+    }
+
+    fn run_wasm(expr: Expr) -> String {
+        run_wasm_limited(expr, 0).unwrap()
+    }
+
+    /// The value the program exits with, or `None` if it hit the step limit
+    fn run_wasm_limited(expr: Expr, step_limit: i64) -> Option<String> {
+        let program = cont_expr::program(expr).into_fexpr();
+        let wasm = wat::parse_str(emit(&program).unwrap()).unwrap();
+
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &wasm[..]).unwrap();
+        let mut store = wasmi::Store::new(&engine, ());
+        let instance = wasmi::Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+
+        instance
+            .get_global(&store, "step_limit")
+            .unwrap()
+            .set(&mut store, wasmi::Val::I64(step_limit))
+            .unwrap();
+
+        let run = instance.get_typed_func::<(), i32>(&store, "run").unwrap();
+        let result = run.call(&mut store, ()).unwrap();
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        let global = |name| instance.get_global(&store, name).unwrap().get(&store);
+
+        if let wasmi::Val::I32(1) = global("step_limit_exceeded") {
+            return None;
+        }
+
+        Some(match global("failed") {
+            wasmi::Val::I32(0) => decode(memory.data(&store), result as usize),
+            _ => decode_uncaught(memory.data(&store), result as usize),
+        })
+    }
+
+    fn assert_matches_interpreter(expr: Expr) {
+        let program = cont_expr::program(expr.clone()).into_fexpr();
+        let expected = match Machine::new().run(&program) {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        assert_eq!(run_wasm(expr), expected);
+    }
+
+    #[test]
+    fn literals() {
+        assert_matches_interpreter(lit(Literal::Int(u64::MAX)));
+        assert_matches_interpreter(lit(Literal::Float(-0.25)));
+        assert_matches_interpreter(lit(Literal::Void));
+        assert_matches_interpreter(lit(Literal::String("\"wasm\" ✓".to_owned())));
+    }
+
+    #[test]
+    fn application() {
+        // ((lambda (x) (lambda (y) x)) "first") "second"
+        let k = lam("x", |x| lam("y", |_| x));
+        let expr = app(
+            app(k, lit(Literal::String("first".to_owned()))),
+            lit(Literal::String("second".to_owned())),
+        );
+
+        assert_matches_interpreter(expr);
+    }
+
+    #[test]
+    fn closure_result() {
+        assert_matches_interpreter(lam("x", |x| x));
+    }
+
+    #[test]
+    fn exceptions() {
+        // (try ((lambda (x) (raise x)) 1) (catch (e) "caught"))
+        let throws = app(lam("x", |x| raise(x, 11, 20)), lit(Literal::Int(1)));
+        assert_matches_interpreter(try_catch(throws.clone(), "e", |_| {
+            lit(Literal::String("caught".to_owned()))
+        }));
+        assert_matches_interpreter(try_catch(throws.clone(), "e", |e| e));
+        assert_matches_interpreter(try_catch(throws.clone(), "e", |e| raise(e, 30, 35)));
+        assert_matches_interpreter(throws);
+    }
+
+    #[test]
+    fn deep_recursion_runs_in_constant_stack() {
+        assert_matches_interpreter(deep_recursion(5));
+    }
+
+    #[test]
+    fn step_limit_stops_infinite_loops() {
+        assert_eq!(run_wasm_limited(omega(), 1_000_000), None);
+        assert!(run_wasm_limited(deep_recursion(2), 1_000_000).is_some());
+    }
+}
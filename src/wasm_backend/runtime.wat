@@ -0,0 +1,163 @@
+  ;; Runtime support for modules emitted by some-embedded-scripting-language.
+  ;;
+  ;; Values are pointers to 16 byte cells in linear memory:
+  ;;
+  ;;   offset 0: tag (i32)
+  ;;   string:   offset 4 data pointer (i32), offset 8 length (i32)
+  ;;   int:      offset 8 value (i64)
+  ;;   float:    offset 8 value (f64)
+  ;;   closure/continuation: offset 4 table index (i32), offset 8 env (i32)
+  ;;   exception: offset 4 raised value (i32), offset 8 spans (i32)
+  ;;
+  ;; The spans of an exception are a linked list of 12 byte nodes, start and
+  ;; end offsets followed by the next node, most recent raise first.
+  ;;
+  ;; Environments are linked lists of 8 byte frames, a value pointer followed
+  ;; by the next frame. Every lambda body ends in one call, which is stored
+  ;; in `$next_code`/`$next_env` and made by the trampoline in `run`.
+  ;; Nothing is ever freed.
+  ;;
+  ;; If an exception reaches the handler passed to the program the exported
+  ;; `failed` global is set, and `run` returns the exception instead.
+  ;;
+  ;; Setting the exported `step_limit` global to a non-zero value makes `run`
+  ;; stop once the program has made that many calls, setting the exported
+  ;; `step_limit_exceeded` global and returning 0. Traps are left for type
+  ;; errors and running out of memory.
+
+  (type $body (func (param i32)))
+
+  (global $next_code (mut i32) (i32.const 0))
+  (global $next_env (mut i32) (i32.const 0))
+  (global $halted (mut i32) (i32.const 0))
+  (global $result (mut i32) (i32.const 0))
+  (global $failed (export "failed") (mut i32) (i32.const 0))
+  (global $step_limit (export "step_limit") (mut i64) (i64.const 0))
+  (global $step_limit_exceeded (export "step_limit_exceeded") (mut i32) (i32.const 0))
+
+  (func $alloc (param $size i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (local.get $ptr) (local.get $size)))
+    (if (i32.gt_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq (memory.grow (i32.add (i32.shr_u (local.get $size) (i32.const 16))
+                                          (i32.const 1)))
+                    (i32.const -1))
+          (then unreachable))))
+    (local.get $ptr))
+
+  (func $cell (param $tag i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $alloc (i32.const 16)))
+    (i32.store (local.get $ptr) (local.get $tag))
+    (local.get $ptr))
+
+  (func $string (param $data i32) (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $cell (i32.const 0)))
+    (i32.store offset=4 (local.get $ptr) (local.get $data))
+    (i32.store offset=8 (local.get $ptr) (local.get $len))
+    (local.get $ptr))
+
+  (func $int (param $v i64) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $cell (i32.const 1)))
+    (i64.store offset=8 (local.get $ptr) (local.get $v))
+    (local.get $ptr))
+
+  (func $float (param $v f64) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $cell (i32.const 2)))
+    (f64.store offset=8 (local.get $ptr) (local.get $v))
+    (local.get $ptr))
+
+  (func $void (result i32)
+    (call $cell (i32.const 3)))
+
+  (func $closure (param $tag i32) (param $code i32) (param $env i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $cell (local.get $tag)))
+    (i32.store offset=4 (local.get $ptr) (local.get $code))
+    (i32.store offset=8 (local.get $ptr) (local.get $env))
+    (local.get $ptr))
+
+  (func $halt (result i32)
+    (call $cell (i32.const 6)))
+
+  (func $abort (result i32)
+    (call $cell (i32.const 8)))
+
+  (func $push (param $env i32) (param $v i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $alloc (i32.const 8)))
+    (i32.store (local.get $ptr) (local.get $v))
+    (i32.store offset=4 (local.get $ptr) (local.get $env))
+    (local.get $ptr))
+
+  (func $lookup (param $env i32) (param $scope i32) (result i32)
+    (block $done
+      (loop $walk
+        (br_if $done (i32.eqz (local.get $scope)))
+        (local.set $env (i32.load offset=4 (local.get $env)))
+        (local.set $scope (i32.sub (local.get $scope) (i32.const 1)))
+        (br $walk)))
+    (i32.load (local.get $env)))
+
+  (func $call_one (param $k i32) (param $v i32)
+    (if (i32.eq (i32.load (local.get $k)) (i32.const 8))
+      (then (global.set $failed (i32.const 1))))
+    (if (i32.or (i32.eq (i32.load (local.get $k)) (i32.const 6))
+                (i32.eq (i32.load (local.get $k)) (i32.const 8)))
+      (then
+        (global.set $halted (i32.const 1))
+        (global.set $result (local.get $v))
+        (return)))
+    (if (i32.ne (i32.load (local.get $k)) (i32.const 5))
+      (then unreachable))
+    (global.set $next_code (i32.load offset=4 (local.get $k)))
+    (global.set $next_env (call $push (i32.load offset=8 (local.get $k)) (local.get $v))))
+
+  (func $call_two (param $f i32) (param $v i32) (param $k i32) (param $h i32)
+    (if (i32.ne (i32.load (local.get $f)) (i32.const 4))
+      (then unreachable))
+    (global.set $next_code (i32.load offset=4 (local.get $f)))
+    (global.set $next_env
+      (call $push
+        (call $push (call $push (i32.load offset=8 (local.get $f)) (local.get $v))
+                    (local.get $k))
+        (local.get $h))))
+
+  ;; wrap `v` as an exception raised at the span, re-raising an exception
+  ;; adds the span to it
+  (func $raise (param $h i32) (param $v i32) (param $start i32) (param $end i32)
+    (local $exc i32)
+    (local $span i32)
+    (local.set $exc (call $cell (i32.const 7)))
+    (if (i32.eq (i32.load (local.get $v)) (i32.const 7))
+      (then
+        (i32.store offset=4 (local.get $exc) (i32.load offset=4 (local.get $v)))
+        (i32.store offset=8 (local.get $exc) (i32.load offset=8 (local.get $v))))
+      (else
+        (i32.store offset=4 (local.get $exc) (local.get $v))))
+    (local.set $span (call $alloc (i32.const 12)))
+    (i32.store (local.get $span) (local.get $start))
+    (i32.store offset=4 (local.get $span) (local.get $end))
+    (i32.store offset=8 (local.get $span) (i32.load offset=8 (local.get $exc)))
+    (i32.store offset=8 (local.get $exc) (local.get $span))
+    (call $call_one (local.get $h) (local.get $exc)))
+
+  (func $trampoline (result i32)
+    (local $steps i64)
+    (block $done
+      (loop $bounce
+        (br_if $done (global.get $halted))
+        (local.set $steps (i64.add (local.get $steps) (i64.const 1)))
+        (if (i32.and (i64.ne (global.get $step_limit) (i64.const 0))
+                     (i64.gt_u (local.get $steps) (global.get $step_limit)))
+          (then
+            (global.set $step_limit_exceeded (i32.const 1))
+            (return (i32.const 0))))
+        (call_indirect (type $body) (global.get $next_env) (global.get $next_code))
+        (br $bounce)))
+    (global.get $result))
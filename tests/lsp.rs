@@ -0,0 +1,97 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+};
+
+use serde_json::{json, Value as Json};
+
+fn send(stdin: &mut impl Write, message: &str) {
+    write!(
+        stdin,
+        "Content-Length: {}\r\n\r\n{}",
+        message.len(),
+        message
+    )
+    .unwrap();
+    stdin.flush().unwrap();
+}
+
+fn receive(stdout: &mut impl BufRead) -> Json {
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        match line.trim_end().strip_prefix("Content-Length: ") {
+            Some(n) => length = n.parse().unwrap(),
+            None if line.trim_end().is_empty() => break,
+            None => {}
+        }
+    }
+
+    let mut body = vec![0; length];
+    stdout.read_exact(&mut body).unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn serves_over_stdio() {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_some_embedded_scripting_language_bin"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = server.stdin.take().unwrap();
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+
+    send(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+    );
+    let reply = receive(&mut stdout);
+    assert_eq!(reply.get("id"), Some(&json!(1)));
+    let capabilities = reply.get("result").unwrap().get("capabilities").unwrap();
+    assert_eq!(capabilities.get("renameProvider"), Some(&json!(true)));
+
+    send(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+    );
+    send(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.ses","languageId":"ses","version":1,"text":"(let ((x \"hi\")) (prin x))"}}}"#,
+    );
+    let diagnostics = receive(&mut stdout);
+    assert_eq!(
+        diagnostics.get("params"),
+        Some(&json!({
+            "uri": "file:///a.ses",
+            "diagnostics": [{
+                "range": {
+                    "start": { "line": 0, "character": 17 },
+                    "end": { "line": 0, "character": 21 },
+                },
+                "severity": 1,
+                "source": "some-embedded-scripting-language",
+                "message": "unbound variable: prin",
+            }],
+        }))
+    );
+
+    send(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.ses"},"position":{"line":0,"character":22}}}"#,
+    );
+    let hover = receive(&mut stdout);
+    let contents = hover.get("result").unwrap().get("contents").unwrap();
+    assert_eq!(contents.get("value"), Some(&json!("x: string\n\nlocal")));
+
+    send(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+    );
+    assert_eq!(receive(&mut stdout).get("result"), Some(&Json::Null));
+    send(&mut stdin, r#"{"jsonrpc":"2.0","method":"exit"}"#);
+
+    assert!(server.wait().unwrap().success());
+}